@@ -0,0 +1,127 @@
+//! Measures the receive path of the agent end to end: datagrams sent by one agent over the
+//! loopback interface are read from the socket by the other, demultiplexed and read from its
+//! `AgentConn`, either copied into the buffer of `AgentConn::recv` or handed over in the pooled
+//! buffer they were received into by `AgentConn::recv_packet`. Sending is the same for both, so
+//! the difference is the copy. Run with `cargo bench --bench receive`.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use webrtc_ice::agent::agent_config::AgentConfig;
+use webrtc_ice::agent::agent_event::AgentEvent;
+use webrtc_ice::agent::agent_transport::AgentConn;
+use webrtc_ice::agent::Agent;
+use webrtc_ice::candidate::candidate_base::unmarshal_candidate;
+use webrtc_ice::candidate::{Candidate, CandidateType};
+use webrtc_ice::error::Result;
+use webrtc_ice::mdns::MulticastDnsMode;
+use webrtc_ice::network_type::NetworkType;
+use webrtc_ice::state::GatheringState;
+
+const RECEIVE_MTU: usize = 8192;
+const PACKET_SIZE: usize = 1200;
+/// The datagrams sent before they are read, few enough for the socket buffer of the receiver.
+const WINDOW: usize = 64;
+const PACKETS: usize = 200_000;
+
+async fn new_agent() -> Result<Arc<Agent>> {
+    Ok(Arc::new(
+        Agent::new(AgentConfig {
+            network_types: vec![NetworkType::Udp4],
+            candidate_types: vec![CandidateType::Host],
+            multicast_dns_mode: MulticastDnsMode::Disabled,
+            include_loopback: true,
+            interface_filter: Arc::new(Some(Box::new(|name: &str| name.starts_with("lo")))),
+            ..Default::default()
+        })
+        .await?,
+    ))
+}
+
+/// Gathers the candidates of `agent` and adds them to `peer`.
+async fn exchange_candidates(agent: &Agent, peer: &Agent) -> Result<()> {
+    let mut events = agent.subscribe().await;
+    agent.gather_candidates().await?;
+    while let Some(event) = events.recv().await {
+        if let AgentEvent::GatheringStateChange(GatheringState::Complete) = event {
+            break;
+        }
+    }
+
+    for c in agent.get_local_candidates().await? {
+        let c: Arc<dyn Candidate + Send + Sync> = Arc::new(unmarshal_candidate(c.marshal()).await?);
+        peer.add_remote_candidate(&c).await?;
+    }
+    Ok(())
+}
+
+/// Connects `a` and `b`, returning their connections.
+async fn connect(a: &Agent, b: &Agent) -> Result<(Arc<AgentConn>, Arc<AgentConn>)> {
+    exchange_candidates(a, b).await?;
+    exchange_candidates(b, a).await?;
+
+    let (a_ufrag, a_pwd) = a.get_local_user_credentials().await;
+    let (b_ufrag, b_pwd) = b.get_local_user_credentials().await;
+    let (_a_cancel_tx, a_cancel_rx) = mpsc::channel(1);
+    let (_b_cancel_tx, b_cancel_rx) = mpsc::channel(1);
+    tokio::try_join!(
+        a.dial(a_cancel_rx, b_ufrag, b_pwd),
+        b.accept(b_cancel_rx, a_ufrag, a_pwd),
+    )
+}
+
+async fn receive(sender: &AgentConn, receiver: &AgentConn, copying: bool) -> Duration {
+    // The first byte keeps the datagrams from being taken for STUN messages
+    let packet = vec![0xff; PACKET_SIZE];
+    let mut buf = vec![0u8; RECEIVE_MTU];
+
+    let start = Instant::now();
+    for _ in 0..PACKETS / WINDOW {
+        for _ in 0..WINDOW {
+            sender.send(&packet).await.unwrap();
+        }
+        for _ in 0..WINDOW {
+            let read = async {
+                if copying {
+                    receiver.recv(&mut buf).await.unwrap()
+                } else {
+                    receiver.recv_packet().await.unwrap().len()
+                }
+            };
+            let n = tokio::time::timeout(Duration::from_secs(1), read)
+                .await
+                .expect("a datagram was lost, the window is too large");
+            assert_eq!(n, PACKET_SIZE);
+        }
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    println!(
+        "{:<8} {:>8.1} ns/packet {:>8.2} Gbit/s",
+        name,
+        elapsed.as_nanos() as f64 / PACKETS as f64,
+        (PACKETS * PACKET_SIZE * 8) as f64 / elapsed.as_secs_f64() / 1e9,
+    );
+}
+
+fn main() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    rt.block_on(async {
+        let a = new_agent().await.unwrap();
+        let b = new_agent().await.unwrap();
+        let (a_conn, b_conn) = connect(&a, &b).await.unwrap();
+
+        // Warm up the allocator and the pool before measuring
+        receive(&a_conn, &b_conn, true).await;
+        receive(&a_conn, &b_conn, false).await;
+
+        report("copying", receive(&a_conn, &b_conn, true).await);
+        report("pooled", receive(&a_conn, &b_conn, false).await);
+
+        a.close().await.unwrap();
+        b.close().await.unwrap();
+    });
+}
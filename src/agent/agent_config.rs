@@ -1,11 +1,15 @@
 use super::*;
+use crate::buffer::BufferOverflowPolicy;
 use crate::error::*;
+use crate::ipv6_address_class::*;
 use crate::mdns::*;
 use crate::network_type::*;
+use crate::runtime::*;
 use crate::url::*;
 
 use util::vnet::net::*;
 
+use std::net::IpAddr;
 use std::time::Duration;
 
 /// The interval at which the agent performs candidate checks in the connecting phase.
@@ -32,12 +36,40 @@ pub(crate) const DEFAULT_PRFLX_ACCEPTANCE_MIN_WAIT: Duration = Duration::from_mi
 /// Wait time before nominating a relay candidate.
 pub(crate) const DEFAULT_RELAY_ACCEPTANCE_MIN_WAIT: Duration = Duration::from_millis(2000);
 
+/// The default time to wait for a STUN or TURN server while gathering.
+pub(crate) const DEFAULT_GATHERING_SERVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The shortest time between renewals of the credentials of a relay candidate.
+pub(crate) const MIN_RELAY_RENEWAL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The initial retransmission timeout of STUN requests while gathering (RTO of RFC 8489).
+pub(crate) const DEFAULT_STUN_RTO: Duration = Duration::from_millis(500);
+
+/// The number of STUN requests sent while gathering before giving up (Rc of RFC 8489).
+pub(crate) const DEFAULT_STUN_MAX_REQUESTS: u32 = 7;
+
+/// The multiple of the initial RTO to wait for a response after the last STUN request while
+/// gathering (Rm of RFC 8489).
+pub(crate) const DEFAULT_STUN_LAST_TIMEOUT_FACTOR: u32 = 16;
+
 /// Max binding request before considering a pair failed.
 pub(crate) const DEFAULT_MAX_BINDING_REQUESTS: u16 = 7;
 
-/// The number of bytes that can be buffered before we start to error.
+/// Max candidate pairs in the checklist, as recommended by RFC 8445 section 6.1.2.5.
+pub(crate) const DEFAULT_MAX_CANDIDATE_PAIRS: usize = 100;
+
+/// Link-local addresses are used to gather host candidates by default.
+pub(crate) const DEFAULT_INCLUDE_LINK_LOCAL: bool = true;
+
+/// The default number of bytes that can be buffered before the overflow policy applies.
 pub(crate) const MAX_BUFFER_SIZE: usize = 1000 * 1000; // 1MB
 
+/// The number of receive buffers kept for reuse.
+pub(crate) const MAX_IDLE_RECEIVE_BUFFERS: usize = 128;
+
+/// The number of events a subscriber can fall behind before it misses events.
+pub(crate) const EVENT_CHANNEL_CAPACITY: usize = 256;
+
 /// Wait time before binding requests can be deleted.
 pub(crate) const MAX_BINDING_REQUEST_TIMEOUT: Duration = Duration::from_millis(4000);
 
@@ -49,7 +81,14 @@ pub(crate) fn default_candidate_types() -> Vec<CandidateType> {
     ]
 }
 
+pub(crate) fn default_ipv6_address_classes() -> Vec<Ipv6AddressClass> {
+    vec![Ipv6AddressClass::UniqueLocal, Ipv6AddressClass::Global]
+}
+
 pub type InterfaceFilterFn = Box<dyn (Fn(&str) -> bool) + Send + Sync>;
+pub type IpFilterFn = Box<dyn (Fn(IpAddr) -> bool) + Send + Sync>;
+pub type InterfacePreferenceFn = Box<dyn (Fn(&str) -> u8) + Send + Sync>;
+pub type InterfaceCostFn = Box<dyn (Fn(&str) -> u16) + Send + Sync>;
 
 /// Collects the arguments to `ice::Agent` construction into a single structure, for
 /// future-proofness of the interface.
@@ -91,11 +130,41 @@ pub struct AgentConfig {
     pub keepalive_interval: Option<Duration>,
 
     /// An optional configuration for disabling or enabling support for specific network types.
+    /// Relays are allocated for the IP families of the UDP network types, in a dual-stack
+    /// allocation with ADDITIONAL-ADDRESS-FAMILY (rfc8656) for both families and with
+    /// REQUESTED-ADDRESS-FAMILY for IPv6 alone. The IPv6 relay is allocated on its own if the
+    /// server ignores ADDITIONAL-ADDRESS-FAMILY.
     pub network_types: Vec<NetworkType>,
 
     /// An optional configuration for disabling or enabling support for specific candidate types.
     pub candidate_types: Vec<CandidateType>,
 
+    /// The time after which gathering is cancelled, completing with the candidates gathered so
+    /// far. Gathering isn't limited when unset.
+    pub gathering_timeout: Option<Duration>,
+
+    /// The time to wait for each STUN or TURN server while gathering: for resolving the name of a
+    /// STUN server, and from resolving the address of a TURN server to receiving the relay
+    /// allocation. The binding requests to each address of a STUN server are given up on after
+    /// their retransmissions, see `stun_rto`. Defaults to 5 seconds.
+    pub gathering_server_timeout: Option<Duration>,
+
+    /// The initial retransmission timeout of the STUN requests sent to STUN servers while
+    /// gathering, doubled after each retransmission. Defaults to 500 milliseconds.
+    pub stun_rto: Option<Duration>,
+
+    /// The number of STUN requests sent to a STUN server while gathering before giving up on it.
+    /// Defaults to 7.
+    pub stun_max_requests: Option<u32>,
+
+    /// After the last STUN request to a STUN server, a response is waited for this multiple of
+    /// `stun_rto`. Defaults to 16.
+    pub stun_last_timeout_factor: Option<u32>,
+
+    /// Provides the credentials of the TURN servers in `urls`, which are used instead of their
+    /// username and password. The credentials of relay candidates are renewed before they expire.
+    pub turn_credential_provider: Option<Arc<dyn TurnCredentialProvider>>,
+
     //LoggerFactory logging.LoggerFactory
     /// Controls how often our internal task loop runs when in the connecting state.
     /// Only useful for testing.
@@ -106,6 +175,20 @@ pub struct AgentConfig {
     /// request or a nomination we set the pair as failed.
     pub max_binding_requests: Option<u16>,
 
+    /// The max amount of candidate pairs in the checklist. Once the limit is reached, a new pair
+    /// replaces the lowest priority pair that is yet to be checked, or is dropped if it has the
+    /// lowest priority itself. Defaults to 100, zero disables the limit.
+    pub max_candidate_pairs: Option<usize>,
+
+    /// The number of bytes of received application data buffered until it is read from the
+    /// `AgentConn`, and each of its demux endpoints. Defaults to 1MB.
+    pub receive_buffer_size: Option<usize>,
+
+    /// What happens to received application data once the receive buffer is full. Defaults to
+    /// dropping the newest datagrams. Dropped datagrams are counted in the
+    /// `ReceiveBufferStats` and reported to the `on_packets_dropped` handler.
+    pub receive_buffer_overflow: BufferOverflowPolicy,
+
     pub is_controlling: bool,
 
     /// lite agents do not perform connectivity check and only provide host candidates.
@@ -136,10 +219,44 @@ pub struct AgentConfig {
     /// (see (github.com/pion/transport/vnet)[github.com/pion/transport/vnet]).
     pub net: Option<Arc<Net>>,
 
+    /// The runtime used to spawn the background tasks of the agent, for its timers and to bind
+    /// UDP sockets. Defaults to `TokioRuntime`.
+    pub runtime: Option<Arc<dyn Runtime>>,
+
     /// A function that you can use in order to whitelist or blacklist the interfaces which are
     /// used to gather ICE candidates.
     pub interface_filter: Arc<Option<InterfaceFilterFn>>,
 
+    /// A function that ranks the interfaces used to gather host candidates, e.g. to prefer
+    /// Ethernet over Wi-Fi over VPN interfaces. Higher values are preferred. Host candidates on
+    /// preferred interfaces get a higher local preference and thus a higher pair priority. Within
+    /// the same rank, IPv6 and IPv4 addresses are interleaved as described in RFC 8421.
+    pub interface_preference: Arc<Option<InterfacePreferenceFn>>,
+
+    /// A function that assigns a network cost to the interfaces used to gather host candidates,
+    /// e.g. a high cost to metered cellular interfaces. The cost is signaled as `network-cost`
+    /// and pairs on cheaper networks are preferred when selecting a pair.
+    pub interface_cost: Arc<Option<InterfaceCostFn>>,
+
+    /// A function that you can use in order to whitelist or blacklist the IP addresses which are
+    /// used to gather ICE candidates. It is applied to the addresses of local interfaces as well
+    /// as to the mapped addresses of server reflexive and relay candidates.
+    pub ip_filter: Arc<Option<IpFilterFn>>,
+
+    /// Controls if loopback addresses are used to gather host candidates.
+    pub include_loopback: bool,
+
+    /// Controls if link-local addresses (169.254.0.0/16 and fe80::/10) are used to gather host
+    /// candidates, which they are by default. This is equivalent to adding
+    /// `Ipv6AddressClass::LinkLocal` to `ipv6_address_classes` for IPv6.
+    pub include_link_local: Option<bool>,
+
+    /// An optional configuration for the classes of local IPv6 addresses used to gather host
+    /// candidates. Defaults to unique local and global addresses, which leaves out deprecated and
+    /// temporary addresses. Link-local addresses are also used unless `include_link_local` is
+    /// `Some(false)`.
+    pub ipv6_address_classes: Vec<Ipv6AddressClass>,
+
     /// Controls if self-signed certificates are accepted when connecting to TURN servers via TLS or
     /// DTLS.
     pub insecure_skip_verify: bool,
@@ -147,13 +264,19 @@ pub struct AgentConfig {
 
 impl AgentConfig {
     /// Populates an agent and falls back to defaults if fields are unset.
-    pub(crate) fn init_with_defaults(&self, a: &mut AgentInternal) {
+    pub(crate) fn init_with_defaults(&self, a: &mut AgentCore) {
         if let Some(max_binding_requests) = self.max_binding_requests {
             a.max_binding_requests = max_binding_requests;
         } else {
             a.max_binding_requests = DEFAULT_MAX_BINDING_REQUESTS;
         }
 
+        if let Some(max_candidate_pairs) = self.max_candidate_pairs {
+            a.max_candidate_pairs = max_candidate_pairs;
+        } else {
+            a.max_candidate_pairs = DEFAULT_MAX_CANDIDATE_PAIRS;
+        }
+
         if let Some(host_acceptance_min_wait) = self.host_acceptance_min_wait {
             a.host_acceptance_min_wait = host_acceptance_min_wait;
         } else {
@@ -213,7 +336,7 @@ impl AgentConfig {
         {
             if ext_ip_mapper.candidate_type == CandidateType::Host {
                 if mdns_mode == MulticastDnsMode::QueryAndGather {
-                    return Err(Error::ErrMulticastDnsWithNat1to1IpMapping.into());
+                    return Err(Error::ErrMulticastDnsWithNat1to1IpMapping);
                 }
                 let mut candi_host_enabled = false;
                 for candi_type in candidate_types {
@@ -223,7 +346,7 @@ impl AgentConfig {
                     }
                 }
                 if !candi_host_enabled {
-                    return Err(Error::ErrIneffectiveNat1to1IpMappingHost.into());
+                    return Err(Error::ErrIneffectiveNat1to1IpMappingHost);
                 }
             } else if ext_ip_mapper.candidate_type == CandidateType::ServerReflexive {
                 let mut candi_srflx_enabled = false;
@@ -234,7 +357,7 @@ impl AgentConfig {
                     }
                 }
                 if !candi_srflx_enabled {
-                    return Err(Error::ErrIneffectiveNat1to1IpMappingSrflx.into());
+                    return Err(Error::ErrIneffectiveNat1to1IpMappingSrflx);
                 }
             }
 
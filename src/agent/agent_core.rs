@@ -0,0 +1,1049 @@
+use super::agent_config::*;
+use crate::candidate::candidate_base::CandidateBaseConfig;
+use crate::candidate::candidate_peer_reflexive::CandidatePeerReflexiveConfig;
+use crate::candidate::*;
+use crate::error::*;
+use crate::network_type::*;
+use crate::rand::*;
+use crate::state::*;
+use crate::util::*;
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use stun::{agent::*, attributes::*, fingerprint::*, integrity::*, message::*, xoraddr::*};
+
+/// A datagram produced by the `AgentCore`, which is to be sent from the local candidate to the
+/// remote candidate.
+pub struct Transmit {
+    pub local: Arc<dyn Candidate + Send + Sync>,
+    pub remote: Arc<dyn Candidate + Send + Sync>,
+    pub contents: Vec<u8>,
+}
+
+/// An event produced by the `AgentCore`.
+#[derive(Debug, Clone)]
+pub enum CoreEvent {
+    /// The connection state changed.
+    ConnectionStateChange(ConnectionState),
+    /// A candidate pair was selected to carry the data.
+    SelectedCandidatePairChange(Arc<CandidatePair>),
+    /// The state of a candidate pair changed.
+    CandidatePairStateChange(Arc<CandidatePair>, CandidatePairState),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BindingRequest {
+    pub(crate) timestamp: Instant,
+    pub(crate) transaction_id: TransactionId,
+    pub(crate) destination: SocketAddr,
+    pub(crate) is_use_candidate: bool,
+}
+
+impl Default for BindingRequest {
+    fn default() -> Self {
+        Self {
+            timestamp: Instant::now(),
+            transaction_id: TransactionId::default(),
+            destination: SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0),
+            is_use_candidate: false,
+        }
+    }
+}
+
+/// The ICE state machine without any I/O: it performs the connectivity checks, nominates and
+/// keeps alive the selected pair and tracks the connection state.
+///
+/// The core is driven by its caller, which feeds it the datagrams received on the local
+/// candidates with `handle_receive`, calls `handle_timeout` once the instant returned by
+/// `poll_timeout` is reached, and sends the datagrams returned by `poll_transmit`. State changes
+/// are reported by `poll_event`. The `Agent` is such a driver on top of tokio.
+pub struct AgentCore {
+    pub(crate) tie_breaker: u64,
+    pub(crate) is_controlling: bool,
+    pub(crate) lite: bool,
+    pub(crate) started: bool,
+    pub(crate) start_time: Instant,
+    pub(crate) now: Instant,
+    pub(crate) nominated_pair: Option<Arc<CandidatePair>>,
+
+    pub(crate) connection_state: ConnectionState,
+    pub(crate) last_connection_state: ConnectionState,
+    pub(crate) checking_started: Instant,
+
+    pub(crate) max_binding_requests: u16,
+    pub(crate) max_candidate_pairs: usize,
+
+    pub(crate) host_acceptance_min_wait: Duration,
+    pub(crate) srflx_acceptance_min_wait: Duration,
+    pub(crate) prflx_acceptance_min_wait: Duration,
+    pub(crate) relay_acceptance_min_wait: Duration,
+
+    // How long connectivity checks can fail before the ICE Agent
+    // goes to disconnected
+    pub(crate) disconnected_timeout: Duration,
+
+    // How long connectivity checks can fail before the ICE Agent
+    // goes to failed
+    pub(crate) failed_timeout: Duration,
+
+    // How often should we send keepalive packets?
+    // 0 means never
+    pub(crate) keepalive_interval: Duration,
+
+    // How often should we check candidates when connecting
+    pub(crate) check_interval: Duration,
+
+    pub(crate) local_ufrag: String,
+    pub(crate) local_pwd: String,
+    pub(crate) local_candidates: HashMap<NetworkType, Vec<Arc<dyn Candidate + Send + Sync>>>,
+
+    pub(crate) remote_ufrag: String,
+    pub(crate) remote_pwd: String,
+    pub(crate) remote_candidates: HashMap<NetworkType, Vec<Arc<dyn Candidate + Send + Sync>>>,
+    pub(crate) remote_candidates_changed: bool,
+
+    // LRU of outbound Binding request Transaction IDs
+    pub(crate) pending_binding_requests: Vec<BindingRequest>,
+
+    pub(crate) checklist: Vec<Arc<CandidatePair>>,
+    pub(crate) checklist_changed: bool,
+    pub(crate) selected_pair: Option<Arc<CandidatePair>>,
+    pub(crate) selected_pair_last_sent: Instant,
+    pub(crate) selected_pair_last_received: Instant,
+
+    pub(crate) next_contact: Option<Instant>,
+    pub(crate) force_contact: bool,
+
+    pub(crate) transmits: VecDeque<Transmit>,
+    pub(crate) events: VecDeque<CoreEvent>,
+    pub(crate) removed_candidates: Vec<Arc<dyn Candidate + Send + Sync>>,
+}
+
+impl AgentCore {
+    /// Creates a core with the connectivity check settings and local credentials of `config`.
+    /// Credentials that are left empty are generated.
+    pub fn new(config: &AgentConfig) -> Result<Self> {
+        let now = Instant::now();
+        let mut core = Self {
+            tie_breaker: rand::random::<u64>(),
+            is_controlling: config.is_controlling,
+            lite: config.lite,
+            started: false,
+            start_time: now,
+            now,
+            nominated_pair: None,
+
+            connection_state: ConnectionState::New,
+            last_connection_state: ConnectionState::Unspecified,
+            checking_started: now,
+
+            max_binding_requests: 0,
+            max_candidate_pairs: 0,
+
+            host_acceptance_min_wait: Duration::from_secs(0),
+            srflx_acceptance_min_wait: Duration::from_secs(0),
+            prflx_acceptance_min_wait: Duration::from_secs(0),
+            relay_acceptance_min_wait: Duration::from_secs(0),
+
+            disconnected_timeout: Duration::from_secs(0),
+            failed_timeout: Duration::from_secs(0),
+            keepalive_interval: Duration::from_secs(0),
+            check_interval: Duration::from_secs(0),
+
+            local_ufrag: String::new(),
+            local_pwd: String::new(),
+            local_candidates: HashMap::new(),
+
+            remote_ufrag: String::new(),
+            remote_pwd: String::new(),
+            remote_candidates: HashMap::new(),
+            remote_candidates_changed: false,
+
+            pending_binding_requests: vec![],
+
+            checklist: vec![],
+            checklist_changed: false,
+            selected_pair: None,
+            selected_pair_last_sent: now,
+            selected_pair_last_received: now,
+
+            next_contact: None,
+            force_contact: false,
+
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+            removed_candidates: vec![],
+        };
+
+        config.init_with_defaults(&mut core);
+        core.restart(now, config.local_ufrag.clone(), config.local_pwd.clone())?;
+
+        Ok(core)
+    }
+
+    /// Resets the core to a fresh state with the provided local credentials, removing all
+    /// candidates. Empty credentials are generated.
+    pub fn restart(&mut self, now: Instant, mut ufrag: String, mut pwd: String) -> Result<()> {
+        if ufrag.is_empty() {
+            ufrag = generate_ufrag();
+        }
+        if pwd.is_empty() {
+            pwd = generate_pwd();
+        }
+
+        if ufrag.len() * 8 < 24 {
+            return Err(Error::ErrLocalUfragInsufficientBits);
+        }
+        if pwd.len() * 8 < 128 {
+            return Err(Error::ErrLocalPwdInsufficientBits);
+        }
+
+        self.now = now;
+
+        // Clear all agent needed to take back to fresh state
+        self.local_ufrag = ufrag;
+        self.local_pwd = pwd;
+        self.remote_ufrag = String::new();
+        self.remote_pwd = String::new();
+        self.pending_binding_requests = vec![];
+        self.checklist = vec![];
+        self.checklist_changed = true;
+        self.selected_pair = None;
+
+        self.remove_all_candidates();
+        self.start_selector();
+
+        // Restart is used when creating the core. start should be used to move to checking
+        // for new agents
+        if self.connection_state != ConnectionState::New {
+            self.update_connection_state(ConnectionState::Checking);
+        }
+
+        Ok(())
+    }
+
+    /// Starts the connectivity checks with the remote agent, acting as the controlling agent if
+    /// `is_controlling` is set.
+    pub fn start(
+        &mut self,
+        now: Instant,
+        is_controlling: bool,
+        remote_ufrag: String,
+        remote_pwd: String,
+    ) -> Result<()> {
+        if self.started {
+            return Err(Error::ErrMultipleStart);
+        }
+
+        log::debug!(
+            "Started agent: isControlling? {}, remoteUfrag: {}, remotePwd: {}",
+            is_controlling,
+            remote_ufrag,
+            remote_pwd
+        );
+        self.set_remote_credentials(remote_ufrag, remote_pwd)?;
+        self.now = now;
+        self.is_controlling = is_controlling;
+        self.started = true;
+        self.start_selector();
+
+        self.update_connection_state(ConnectionState::Checking);
+        self.request_connectivity_check();
+
+        Ok(())
+    }
+
+    /// Stops the core, removing all candidates.
+    pub fn close(&mut self) {
+        self.remove_all_candidates();
+        self.started = false;
+        self.update_connection_state(ConnectionState::Closed);
+    }
+
+    /// Sets the credentials of the remote agent.
+    pub fn set_remote_credentials(
+        &mut self,
+        remote_ufrag: String,
+        remote_pwd: String,
+    ) -> Result<()> {
+        if remote_ufrag.is_empty() {
+            return Err(Error::ErrRemoteUfragEmpty);
+        } else if remote_pwd.is_empty() {
+            return Err(Error::ErrRemotePwdEmpty);
+        }
+
+        self.remote_ufrag = remote_ufrag;
+        self.remote_pwd = remote_pwd;
+        Ok(())
+    }
+
+    /// Returns the local ufrag and pwd.
+    pub fn local_credentials(&self) -> (&str, &str) {
+        (&self.local_ufrag, &self.local_pwd)
+    }
+
+    /// Returns the remote ufrag and pwd.
+    pub fn remote_credentials(&self) -> (&str, &str) {
+        (&self.remote_ufrag, &self.remote_pwd)
+    }
+
+    /// Returns the current connection state.
+    pub fn connection_state(&self) -> ConnectionState {
+        self.connection_state
+    }
+
+    /// Returns the selected candidate pair, if any.
+    pub fn selected_pair(&self) -> Option<Arc<CandidatePair>> {
+        self.selected_pair.clone()
+    }
+
+    /// Returns the candidate pairs being checked.
+    pub fn checklist(&self) -> &[Arc<CandidatePair>] {
+        &self.checklist
+    }
+
+    /// Returns the local candidates.
+    pub fn get_local_candidates(&self) -> Vec<Arc<dyn Candidate + Send + Sync>> {
+        self.local_candidates.values().flatten().cloned().collect()
+    }
+
+    /// Returns the remote candidates.
+    pub fn get_remote_candidates(&self) -> Vec<Arc<dyn Candidate + Send + Sync>> {
+        self.remote_candidates.values().flatten().cloned().collect()
+    }
+
+    /// Adds a local candidate and pairs it with the remote candidates. Returns false if an equal
+    /// candidate was already added.
+    pub fn add_local_candidate(&mut self, c: &Arc<dyn Candidate + Send + Sync>) -> bool {
+        let network_type = c.network_type();
+
+        let cands = self.local_candidates.entry(network_type).or_default();
+        if cands.iter().any(|cand| cand.equal(&**c)) {
+            return false;
+        }
+        cands.push(c.clone());
+
+        let remote_cands = self
+            .remote_candidates
+            .get(&network_type)
+            .cloned()
+            .unwrap_or_default();
+        for cand in remote_cands {
+            self.add_pair(c.clone(), cand);
+        }
+
+        self.request_connectivity_check();
+        true
+    }
+
+    /// Adds a remote candidate and pairs it with the local candidates. Returns false if an equal
+    /// candidate was already added.
+    pub fn add_remote_candidate(&mut self, c: &Arc<dyn Candidate + Send + Sync>) -> bool {
+        let network_type = c.network_type();
+
+        let cands = self.remote_candidates.entry(network_type).or_default();
+        if cands.iter().any(|cand| cand.equal(&**c)) {
+            return false;
+        }
+        cands.push(c.clone());
+        self.remote_candidates_changed = true;
+
+        let local_cands = self
+            .local_candidates
+            .get(&network_type)
+            .cloned()
+            .unwrap_or_default();
+        for cand in local_cands {
+            self.add_pair(cand, c.clone());
+        }
+
+        self.request_connectivity_check();
+        true
+    }
+
+    /// Processes a datagram received on `local` from `remote`. STUN messages are consumed, for
+    /// other datagrams true is returned if they come from a known remote candidate and are thus
+    /// to be delivered to the application.
+    pub fn handle_receive(
+        &mut self,
+        now: Instant,
+        local: &Arc<dyn Candidate + Send + Sync>,
+        remote: SocketAddr,
+        buf: &[u8],
+    ) -> bool {
+        self.now = now;
+
+        if is_message(buf) {
+            self.handle_stun_message(now, local, remote, buf.to_vec());
+            false
+        } else {
+            self.validate_non_stun_traffic(local, remote)
+        }
+    }
+
+    /// Processes a STUN message received on `local` from `remote`, like `handle_receive` but
+    /// taking `raw` without copying it. The buffer is handed back for reuse.
+    pub fn handle_stun_message(
+        &mut self,
+        now: Instant,
+        local: &Arc<dyn Candidate + Send + Sync>,
+        remote: SocketAddr,
+        raw: Vec<u8>,
+    ) -> Vec<u8> {
+        self.now = now;
+
+        let mut m = Message {
+            raw,
+            ..Message::default()
+        };
+        if let Err(err) = m.decode() {
+            log::warn!(
+                "Failed to handle decode ICE from {} to {}: {}",
+                remote,
+                local,
+                err
+            );
+        } else {
+            self.handle_inbound(&mut m, local, remote);
+        }
+        m.raw
+    }
+
+    /// Runs the connectivity checks and keepalives that are due at `now`.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        self.now = now;
+        if !self.started {
+            return;
+        }
+        if !self.force_contact && self.next_contact.is_some_and(|t| now < t) {
+            return;
+        }
+
+        self.contact();
+
+        self.force_contact = false;
+        self.next_contact = Some(now + self.contact_interval());
+    }
+
+    /// Returns the instant at which `handle_timeout` is to be called next, if any.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        if !self.started {
+            None
+        } else if self.force_contact {
+            Some(self.now)
+        } else {
+            self.next_contact
+        }
+    }
+
+    /// Returns the next datagram to send.
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    /// Returns the next event.
+    pub fn poll_event(&mut self) -> Option<CoreEvent> {
+        self.events.pop_front()
+    }
+
+    /// Returns the next candidate that was removed, e.g. by a restart or because the connection
+    /// failed, and whose resources are to be released.
+    pub fn poll_removed_candidate(&mut self) -> Option<Arc<dyn Candidate + Send + Sync>> {
+        self.removed_candidates.pop()
+    }
+
+    fn contact(&mut self) {
+        if self.connection_state == ConnectionState::Failed {
+            // The connection is currently failed so don't send any checks
+            // In the future it may be restarted though
+            self.last_connection_state = self.connection_state;
+            return;
+        }
+        if self.connection_state == ConnectionState::Checking {
+            // We have just entered checking for the first time so update our checking timer
+            if self.last_connection_state != self.connection_state {
+                self.checking_started = self.now;
+            }
+
+            // We have been in checking longer then Disconnect+Failed timeout, set the connection to Failed
+            if self.now.duration_since(self.checking_started)
+                > self.disconnected_timeout + self.failed_timeout
+            {
+                self.update_connection_state(ConnectionState::Failed);
+                self.last_connection_state = self.connection_state;
+                return;
+            }
+        }
+
+        self.contact_candidates();
+
+        self.last_connection_state = self.connection_state;
+    }
+
+    /// Returns how long to wait until the candidates are contacted again, which is the minimum of
+    /// the configured intervals and timeouts relevant to the connection state.
+    fn contact_interval(&self) -> Duration {
+        const ZERO_DURATION: Duration = Duration::from_secs(0);
+        let mut interval = DEFAULT_CHECK_INTERVAL;
+
+        let mut update_interval = |x: Duration| {
+            if x != ZERO_DURATION && (interval == ZERO_DURATION || interval > x) {
+                interval = x;
+            }
+        };
+
+        match self.last_connection_state {
+            ConnectionState::New | ConnectionState::Checking => {
+                // While connecting, check candidates more frequently
+                update_interval(self.check_interval);
+            }
+            ConnectionState::Connected | ConnectionState::Disconnected => {
+                update_interval(self.keepalive_interval);
+            }
+            _ => {}
+        };
+        // Ensure we run our task loop as quickly as the minimum of our various configured timeouts
+        update_interval(self.disconnected_timeout);
+        update_interval(self.failed_timeout);
+
+        interval
+    }
+
+    pub(crate) fn update_connection_state(&mut self, new_state: ConnectionState) {
+        if self.connection_state != new_state {
+            // Connection has gone to failed, release all gathered candidates
+            if new_state == ConnectionState::Failed {
+                self.remove_all_candidates();
+            }
+
+            log::info!("Setting new connection state: {}", new_state);
+            self.connection_state = new_state;
+            self.events
+                .push_back(CoreEvent::ConnectionStateChange(new_state));
+        }
+    }
+
+    pub(crate) fn set_selected_pair(&mut self, p: Option<Arc<CandidatePair>>) {
+        log::trace!("Set selected candidate pair: {:?}", p);
+
+        if let Some(p) = p {
+            p.nominated.store(true, Ordering::SeqCst);
+            self.selected_pair = Some(Arc::clone(&p));
+            self.selected_pair_last_sent = self.now;
+            self.selected_pair_last_received = self.now;
+
+            self.update_connection_state(ConnectionState::Connected);
+
+            // Notify when the selected pair changes
+            self.events
+                .push_back(CoreEvent::SelectedCandidatePairChange(p));
+        } else {
+            self.selected_pair = None;
+        }
+    }
+
+    pub(crate) fn ping_all_candidates(&mut self) {
+        log::trace!("pinging all candidates");
+
+        if self.checklist.is_empty() {
+            log::warn!(
+                "pingAllCandidates called with no candidate pairs. Connection is not possible yet."
+            );
+        }
+
+        let mut pairs: Vec<(
+            Arc<dyn Candidate + Send + Sync>,
+            Arc<dyn Candidate + Send + Sync>,
+        )> = vec![];
+
+        for p in &self.checklist {
+            let p_state = p.state.load(Ordering::SeqCst);
+            if p_state == CandidatePairState::Waiting as u8 {
+                set_pair_state(&mut self.events, p, CandidatePairState::InProgress);
+            } else if p_state != CandidatePairState::InProgress as u8 {
+                continue;
+            }
+
+            if p.binding_request_count.load(Ordering::SeqCst) > self.max_binding_requests {
+                log::trace!("max requests reached for pair {}, marking it as failed", p);
+                set_pair_state(&mut self.events, p, CandidatePairState::Failed);
+            } else {
+                p.binding_request_count.fetch_add(1, Ordering::SeqCst);
+                pairs.push((p.local.clone(), p.remote.clone()));
+            }
+        }
+
+        for (local, remote) in pairs {
+            self.ping_candidate(&local, &remote);
+        }
+    }
+
+    pub(crate) fn add_pair(
+        &mut self,
+        local: Arc<dyn Candidate + Send + Sync>,
+        remote: Arc<dyn Candidate + Send + Sync>,
+    ) {
+        let p = Arc::new(CandidatePair::new(local, remote, self.is_controlling));
+
+        // RFC 8445 6.1.2.4: a pair is redundant if its local candidate has the same base as the
+        // local candidate of another pair with the same remote candidate, e.g. the srflx
+        // candidates discovered from one socket. Only the higher priority pair is kept.
+        if let Some(pos) = self
+            .checklist
+            .iter()
+            .position(|other| other.remote.equal(&*p.remote) && same_base(&*other.local, &*p.local))
+        {
+            let other = &self.checklist[pos];
+            if other.priority() >= p.priority()
+                || other.state.load(Ordering::SeqCst) != CandidatePairState::Waiting as u8
+            {
+                log::trace!("pruning redundant candidate pair {}", p);
+                return;
+            }
+            log::trace!("pruning redundant candidate pair {}", other);
+            self.checklist.remove(pos);
+        }
+
+        // RFC 8445 6.1.2.5: limit the checklist by dropping the lowest priority pairs.
+        if self.max_candidate_pairs != 0 && self.checklist.len() >= self.max_candidate_pairs {
+            let lowest = self
+                .checklist
+                .iter()
+                .enumerate()
+                .filter(|(_, other)| {
+                    other.state.load(Ordering::SeqCst) == CandidatePairState::Waiting as u8
+                })
+                .min_by_key(|(_, other)| other.priority());
+            match lowest {
+                Some((pos, lowest)) if lowest.priority() < p.priority() => {
+                    log::debug!(
+                        "candidate pair limit {} reached, dropping {}",
+                        self.max_candidate_pairs,
+                        lowest
+                    );
+                    self.checklist.remove(pos);
+                }
+                _ => {
+                    log::debug!(
+                        "candidate pair limit {} reached, dropping {}",
+                        self.max_candidate_pairs,
+                        p
+                    );
+                    return;
+                }
+            }
+        }
+
+        self.checklist.push(p);
+        self.checklist_changed = true;
+    }
+
+    pub(crate) fn find_pair(
+        &self,
+        local: &Arc<dyn Candidate + Send + Sync>,
+        remote: &Arc<dyn Candidate + Send + Sync>,
+    ) -> Option<Arc<CandidatePair>> {
+        self.checklist
+            .iter()
+            .find(|p| p.local.equal(&**local) && p.remote.equal(&**remote))
+            .cloned()
+    }
+
+    pub(crate) fn get_best_available_candidate_pair(&self) -> Option<Arc<CandidatePair>> {
+        best_candidate_pair(&self.checklist, |state| {
+            state != CandidatePairState::Failed as u8
+        })
+    }
+
+    pub(crate) fn get_best_valid_candidate_pair(&self) -> Option<Arc<CandidatePair>> {
+        best_candidate_pair(&self.checklist, |state| {
+            state == CandidatePairState::Succeeded as u8
+        })
+    }
+
+    /// Checks if the selected pair is (still) valid.
+    pub(crate) fn validate_selected_pair(&mut self) -> bool {
+        if self.selected_pair.is_none() {
+            return false;
+        }
+        let disconnected_time = self
+            .now
+            .saturating_duration_since(self.selected_pair_last_received);
+
+        // Only allow transitions to failed if a.failedTimeout is non-zero
+        let mut total_time_to_failure = self.failed_timeout;
+        if total_time_to_failure != Duration::from_secs(0) {
+            total_time_to_failure += self.disconnected_timeout;
+        }
+
+        if total_time_to_failure != Duration::from_secs(0)
+            && disconnected_time > total_time_to_failure
+        {
+            self.update_connection_state(ConnectionState::Failed);
+        } else if self.disconnected_timeout != Duration::from_secs(0)
+            && disconnected_time > self.disconnected_timeout
+        {
+            self.update_connection_state(ConnectionState::Disconnected);
+        } else {
+            self.update_connection_state(ConnectionState::Connected);
+        }
+
+        true
+    }
+
+    /// Sends STUN Binding requests to the selected pair if nothing was sent or received on it in
+    /// the last keepalive interval.
+    pub(crate) fn check_keepalive(&mut self) {
+        if let Some(selected_pair) = self.selected_pair.clone() {
+            let last_sent = self
+                .now
+                .saturating_duration_since(self.selected_pair_last_sent);
+            let last_received = self
+                .now
+                .saturating_duration_since(self.selected_pair_last_received);
+
+            if (self.keepalive_interval != Duration::from_secs(0))
+                && ((last_sent > self.keepalive_interval)
+                    || (last_received > self.keepalive_interval))
+            {
+                // we use binding request instead of indication to support refresh consent schemas
+                // see https://tools.ietf.org/html/rfc7675
+                self.ping_candidate(&selected_pair.local, &selected_pair.remote);
+            }
+        }
+    }
+
+    /// Makes the next `handle_timeout` contact the candidates immediately.
+    pub(crate) fn request_connectivity_check(&mut self) {
+        self.force_contact = true;
+    }
+
+    /// Moves all candidates to the removed candidates, which are released by the driver.
+    pub(crate) fn remove_all_candidates(&mut self) {
+        for (_, cs) in self.local_candidates.drain() {
+            self.removed_candidates.extend(cs);
+        }
+        for (_, cs) in self.remote_candidates.drain() {
+            self.removed_candidates.extend(cs);
+        }
+        self.remote_candidates_changed = true;
+    }
+
+    pub(crate) fn find_remote_candidate(
+        &self,
+        network_type: NetworkType,
+        addr: SocketAddr,
+    ) -> Option<Arc<dyn Candidate + Send + Sync>> {
+        find_candidate(&self.remote_candidates, network_type, addr)
+    }
+
+    pub(crate) fn send_binding_request(
+        &mut self,
+        m: &Message,
+        local: &Arc<dyn Candidate + Send + Sync>,
+        remote: &Arc<dyn Candidate + Send + Sync>,
+    ) {
+        log::trace!("ping STUN from {} to {}", local, remote);
+
+        self.invalidate_pending_binding_requests(self.now);
+        self.pending_binding_requests.push(BindingRequest {
+            timestamp: self.now,
+            transaction_id: m.transaction_id,
+            destination: remote.addr(),
+            is_use_candidate: m.contains(ATTR_USE_CANDIDATE),
+        });
+
+        self.send_stun(m, local, remote);
+    }
+
+    pub(crate) fn send_binding_success(
+        &mut self,
+        m: &Message,
+        local: &Arc<dyn Candidate + Send + Sync>,
+        remote: &Arc<dyn Candidate + Send + Sync>,
+    ) {
+        let addr = remote.addr();
+        let (ip, port) = (addr.ip(), addr.port());
+
+        let (out, result) = {
+            let mut out = Message::new();
+            let result = out.build(&[
+                Box::new(m.clone()),
+                Box::new(BINDING_SUCCESS),
+                Box::new(XorMappedAddress { ip, port }),
+                Box::new(MessageIntegrity::new_short_term_integrity(
+                    self.local_pwd.clone(),
+                )),
+                Box::new(FINGERPRINT),
+            ]);
+            (out, result)
+        };
+
+        if let Err(err) = result {
+            log::warn!(
+                "Failed to handle inbound ICE from: {} to: {} error: {}",
+                local,
+                remote,
+                err
+            );
+        } else {
+            self.send_stun(&out, local, remote);
+        }
+    }
+
+    /// Removes pending binding requests that are over `maxBindingRequestTimeout` old Let HTO be the
+    /// transaction timeout, which SHOULD be 2*RTT if RTT is known or 500 ms otherwise.
+    ///
+    /// reference: (IETF ref-8445)[https://tools.ietf.org/html/rfc8445#appendix-B.1].
+    pub(crate) fn invalidate_pending_binding_requests(&mut self, filter_time: Instant) {
+        let initial_size = self.pending_binding_requests.len();
+
+        self.pending_binding_requests.retain(|binding_request| {
+            filter_time.saturating_duration_since(binding_request.timestamp)
+                < MAX_BINDING_REQUEST_TIMEOUT
+        });
+
+        let bind_requests_removed = initial_size - self.pending_binding_requests.len();
+        if bind_requests_removed > 0 {
+            log::trace!(
+                "Discarded {} binding requests because they expired",
+                bind_requests_removed
+            );
+        }
+    }
+
+    /// Assert that the passed `TransactionID` is in our `pendingBindingRequests` and returns the
+    /// destination, If the bindingRequest was valid remove it from our pending cache.
+    pub(crate) fn handle_inbound_binding_success(
+        &mut self,
+        id: TransactionId,
+    ) -> Option<BindingRequest> {
+        self.invalidate_pending_binding_requests(self.now);
+        let pos = self
+            .pending_binding_requests
+            .iter()
+            .position(|binding_request| binding_request.transaction_id == id)?;
+        Some(self.pending_binding_requests.remove(pos))
+    }
+
+    /// Processes STUN traffic from a remote candidate.
+    pub(crate) fn handle_inbound(
+        &mut self,
+        m: &mut Message,
+        local: &Arc<dyn Candidate + Send + Sync>,
+        remote: SocketAddr,
+    ) {
+        if m.typ.method != METHOD_BINDING
+            || !(m.typ.class == CLASS_SUCCESS_RESPONSE
+                || m.typ.class == CLASS_REQUEST
+                || m.typ.class == CLASS_INDICATION)
+        {
+            log::trace!(
+                "unhandled STUN from {} to {} class({}) method({})",
+                remote,
+                local,
+                m.typ.class,
+                m.typ.method
+            );
+            return;
+        }
+
+        if self.is_controlling {
+            if m.contains(ATTR_ICE_CONTROLLING) {
+                log::debug!("inbound isControlling && a.isControlling == true");
+                return;
+            } else if m.contains(ATTR_USE_CANDIDATE) {
+                log::debug!("useCandidate && a.isControlling == true");
+                return;
+            }
+        } else if m.contains(ATTR_ICE_CONTROLLED) {
+            log::debug!("inbound isControlled && a.isControlling == false");
+            return;
+        }
+
+        let mut remote_candidate = self.find_remote_candidate(local.network_type(), remote);
+        if m.typ.class == CLASS_SUCCESS_RESPONSE {
+            if let Err(err) = assert_inbound_message_integrity(m, self.remote_pwd.as_bytes()) {
+                log::warn!("discard message from ({}), {}", remote, err);
+                return;
+            }
+
+            if let Some(rc) = &remote_candidate {
+                self.handle_success_response(m, local, rc, remote);
+            } else {
+                log::warn!("discard success message from ({}), no such remote", remote);
+                return;
+            }
+        } else if m.typ.class == CLASS_REQUEST {
+            let username = self.local_ufrag.clone() + ":" + self.remote_ufrag.as_str();
+            if let Err(err) = assert_inbound_username(m, &username) {
+                log::warn!("discard message from ({}), {}", remote, err);
+                return;
+            } else if let Err(err) = assert_inbound_message_integrity(m, self.local_pwd.as_bytes())
+            {
+                log::warn!("discard message from ({}), {}", remote, err);
+                return;
+            }
+
+            if remote_candidate.is_none() {
+                let (ip, port, network_type) = (remote.ip(), remote.port(), NetworkType::Udp4);
+
+                let prflx_candidate_config = CandidatePeerReflexiveConfig {
+                    base_config: CandidateBaseConfig {
+                        network: network_type.to_string(),
+                        address: ip.to_string(),
+                        port,
+                        component: local.component(),
+                        ..CandidateBaseConfig::default()
+                    },
+                    rel_addr: "".to_owned(),
+                    rel_port: 0,
+                };
+
+                match prflx_candidate_config.build() {
+                    Ok(prflx_candidate) => remote_candidate = Some(Arc::new(prflx_candidate)),
+                    Err(err) => {
+                        log::error!("Failed to create new remote prflx candidate ({})", err);
+                        return;
+                    }
+                };
+
+                log::debug!("adding a new peer-reflexive candidate: {} ", remote);
+                if let Some(rc) = &remote_candidate {
+                    self.add_remote_candidate(rc);
+                }
+            }
+
+            log::trace!("inbound STUN (Request) from {} to {}", remote, local);
+
+            if let Some(rc) = &remote_candidate {
+                self.handle_binding_request(m, local, rc);
+            }
+        }
+
+        if let Some(rc) = remote_candidate {
+            self.seen(&rc);
+        }
+    }
+
+    /// Processes non STUN traffic from a remote candidate, and returns true if it is an actual
+    /// remote candidate.
+    pub(crate) fn validate_non_stun_traffic(
+        &mut self,
+        local: &Arc<dyn Candidate + Send + Sync>,
+        remote: SocketAddr,
+    ) -> bool {
+        if let Some(remote_candidate) = self.find_remote_candidate(local.network_type(), remote) {
+            self.seen(&remote_candidate);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Records that application data was received on the selected pair at `at`, outside of
+    /// `handle_receive`.
+    pub(crate) fn selected_pair_received(&mut self, at: Instant) {
+        if self.selected_pair.is_some() && at > self.selected_pair_last_received {
+            self.selected_pair_last_received = at;
+        }
+    }
+
+    /// Records that a datagram was received from the remote candidate.
+    fn seen(&mut self, remote: &Arc<dyn Candidate + Send + Sync>) {
+        remote.seen(false);
+        if let Some(selected_pair) = &self.selected_pair {
+            if selected_pair.remote.equal(&**remote) {
+                self.selected_pair_last_received = self.now;
+            }
+        }
+    }
+
+    pub(crate) fn send_stun(
+        &mut self,
+        msg: &Message,
+        local: &Arc<dyn Candidate + Send + Sync>,
+        remote: &Arc<dyn Candidate + Send + Sync>,
+    ) {
+        if let Some(selected_pair) = &self.selected_pair {
+            if selected_pair.local.equal(&**local) && selected_pair.remote.equal(&**remote) {
+                self.selected_pair_last_sent = self.now;
+            }
+        }
+
+        self.transmits.push_back(Transmit {
+            local: Arc::clone(local),
+            remote: Arc::clone(remote),
+            contents: msg.raw.clone(),
+        });
+    }
+}
+
+/// Returns the candidate of `candidates` with the address `addr`.
+pub(crate) fn find_candidate(
+    candidates: &HashMap<NetworkType, Vec<Arc<dyn Candidate + Send + Sync>>>,
+    network_type: NetworkType,
+    addr: SocketAddr,
+) -> Option<Arc<dyn Candidate + Send + Sync>> {
+    let (ip, port) = (addr.ip(), addr.port());
+
+    if let Some(cands) = candidates.get(&network_type) {
+        for c in cands {
+            // The zone ID of remote addresses is not significant for the lookup
+            let address = c.address();
+            let address = address.split('%').next().unwrap_or_default();
+            if address == ip.to_string() && c.port() == port {
+                return Some(c.clone());
+            }
+        }
+    }
+    None
+}
+
+/// Sets the state of the candidate pair `p`, producing an event if it changed.
+pub(crate) fn set_pair_state(
+    events: &mut VecDeque<CoreEvent>,
+    p: &Arc<CandidatePair>,
+    state: CandidatePairState,
+) {
+    if p.state.swap(state as u8, Ordering::SeqCst) != state as u8 {
+        events.push_back(CoreEvent::CandidatePairStateChange(Arc::clone(p), state));
+    }
+}
+
+/// Returns the preferred pair of `checklist` among the ones whose state is accepted by `filter`.
+pub(crate) fn best_candidate_pair(
+    checklist: &[Arc<CandidatePair>],
+    filter: impl Fn(u8) -> bool,
+) -> Option<Arc<CandidatePair>> {
+    let mut best: Option<&Arc<CandidatePair>> = None;
+
+    for p in checklist {
+        if !filter(p.state.load(Ordering::SeqCst)) {
+            continue;
+        }
+
+        if let Some(b) = &mut best {
+            if p.is_preferred_over(b) {
+                *b = p;
+            }
+        } else {
+            best = Some(p);
+        }
+    }
+
+    best.cloned()
+}
+
+/// Returns true if two local candidates have the same base, the socket they send from. Candidates
+/// without a socket are their own base.
+fn same_base(a: &(dyn Candidate + Send + Sync), b: &(dyn Candidate + Send + Sync)) -> bool {
+    if a.get_conn().is_some() && b.get_conn().is_some() {
+        shares_conn(a, b)
+    } else {
+        a.address() == b.address() && a.port() == b.port()
+    }
+}
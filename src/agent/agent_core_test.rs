@@ -0,0 +1,170 @@
+use super::*;
+use crate::candidate::candidate_base::CandidateBaseConfig;
+use crate::candidate::candidate_host::CandidateHostConfig;
+
+async fn new_host_candidate(address: &str, port: u16) -> Result<Arc<dyn Candidate + Send + Sync>> {
+    let c = CandidateHostConfig {
+        base_config: CandidateBaseConfig {
+            network: "udp".to_owned(),
+            address: address.to_owned(),
+            port,
+            component: 1,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+    .new_candidate_host()
+    .await?;
+    Ok(Arc::new(c))
+}
+
+/// Delivers the datagrams of `from` to `to`, which has the single local candidate `to_local`.
+fn deliver(
+    from: &mut AgentCore,
+    to: &mut AgentCore,
+    to_local: &Arc<dyn Candidate + Send + Sync>,
+    now: Instant,
+) {
+    while let Some(transmit) = from.poll_transmit() {
+        assert!(transmit.remote.equal(&**to_local));
+        to.handle_receive(now, to_local, transmit.local.addr(), &transmit.contents);
+    }
+}
+
+fn connection_states(core: &mut AgentCore) -> Vec<ConnectionState> {
+    let mut states = vec![];
+    while let Some(event) = core.poll_event() {
+        if let CoreEvent::ConnectionStateChange(state) = event {
+            states.push(state);
+        }
+    }
+    states
+}
+
+#[test]
+fn test_core_credentials() -> Result<()> {
+    let mut core = AgentCore::new(&AgentConfig::default())?;
+    let (ufrag, pwd) = core.local_credentials();
+    assert!(ufrag.len() * 8 >= 24);
+    assert!(pwd.len() * 8 >= 128);
+
+    let now = Instant::now();
+    assert_eq!(
+        core.restart(now, "ab".to_owned(), "".to_owned()),
+        Err(Error::ErrLocalUfragInsufficientBits)
+    );
+    core.restart(now, "ufrag".to_owned(), "passwordpassword".to_owned())?;
+    assert_eq!(core.local_credentials(), ("ufrag", "passwordpassword"));
+
+    assert_eq!(
+        core.set_remote_credentials("".to_owned(), "pwd".to_owned()),
+        Err(Error::ErrRemoteUfragEmpty)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_core_start() -> Result<()> {
+    let mut core = AgentCore::new(&AgentConfig::default())?;
+    let now = Instant::now();
+    assert_eq!(core.poll_timeout(), None, "an idle core has no timeout");
+
+    core.start(now, true, "ufrag".to_owned(), "pwd".to_owned())?;
+    assert_eq!(core.connection_state(), ConnectionState::Checking);
+    assert_eq!(
+        connection_states(&mut core),
+        vec![ConnectionState::Checking]
+    );
+    assert_eq!(core.poll_timeout(), Some(now), "checks are due right away");
+
+    core.handle_timeout(now);
+    assert_eq!(core.poll_timeout(), Some(now + DEFAULT_CHECK_INTERVAL));
+
+    assert_eq!(
+        core.start(now, true, "ufrag".to_owned(), "pwd".to_owned()),
+        Err(Error::ErrMultipleStart)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_core_connectivity() -> Result<()> {
+    let mut a = AgentCore::new(&AgentConfig::default())?;
+    let mut b = AgentCore::new(&AgentConfig::default())?;
+
+    let a_local = new_host_candidate("192.168.0.1", 1000).await?;
+    let b_local = new_host_candidate("192.168.0.2", 2000).await?;
+    assert!(a.add_local_candidate(&a_local));
+    assert!(!a.add_local_candidate(&a_local), "duplicates are ignored");
+    assert!(b.add_local_candidate(&b_local));
+    a.add_remote_candidate(&new_host_candidate("192.168.0.2", 2000).await?);
+    b.add_remote_candidate(&new_host_candidate("192.168.0.1", 1000).await?);
+
+    let mut now = Instant::now();
+    let (a_ufrag, a_pwd) = (a.local_ufrag.clone(), a.local_pwd.clone());
+    let (b_ufrag, b_pwd) = (b.local_ufrag.clone(), b.local_pwd.clone());
+    a.start(now, true, b_ufrag, b_pwd)?;
+    b.start(now, false, a_ufrag, a_pwd)?;
+
+    for _ in 0..10 {
+        for core in [&mut a, &mut b] {
+            if core.poll_timeout().is_some_and(|t| t <= now) {
+                core.handle_timeout(now);
+            }
+        }
+        deliver(&mut a, &mut b, &b_local, now);
+        deliver(&mut b, &mut a, &a_local, now);
+
+        if a.selected_pair().is_some() && b.selected_pair().is_some() {
+            break;
+        }
+        now += DEFAULT_CHECK_INTERVAL;
+    }
+
+    assert_eq!(a.connection_state(), ConnectionState::Connected);
+    assert_eq!(b.connection_state(), ConnectionState::Connected);
+    assert!(
+        connection_states(&mut a).ends_with(&[ConnectionState::Connected]),
+        "the state changes are reported as events"
+    );
+
+    // Datagrams which are not STUN are handed to the caller if they come from a known remote
+    let b_addr = b_local.addr();
+    assert!(a.handle_receive(now, &a_local, b_addr, b"data"));
+    assert!(!a.handle_receive(now, &a_local, "10.0.0.1:1".parse()?, b"data"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_core_failed_removes_candidates() -> Result<()> {
+    let mut core = AgentCore::new(&AgentConfig::default())?;
+    let local = new_host_candidate("192.168.0.1", 1000).await?;
+    core.add_local_candidate(&local);
+    core.add_remote_candidate(&new_host_candidate("192.168.0.2", 2000).await?);
+
+    let now = Instant::now();
+    core.start(now, true, "ufrag".to_owned(), "pwd".to_owned())?;
+    core.handle_timeout(now);
+    assert!(
+        core.poll_transmit().is_some(),
+        "the pair is checked right away"
+    );
+
+    // Nobody answers, so the core gives up once the disconnected and failed timeouts passed
+    let later =
+        now + DEFAULT_DISCONNECTED_TIMEOUT + DEFAULT_FAILED_TIMEOUT + Duration::from_secs(1);
+    core.handle_timeout(later);
+    assert_eq!(core.connection_state(), ConnectionState::Failed);
+    assert!(core.get_local_candidates().is_empty());
+
+    let mut removed = 0;
+    while core.poll_removed_candidate().is_some() {
+        removed += 1;
+    }
+    assert_eq!(removed, 2, "the driver has to release the candidates");
+
+    Ok(())
+}
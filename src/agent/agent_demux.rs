@@ -0,0 +1,213 @@
+use super::agent_transport::AgentConn;
+use super::*;
+use crate::buffer::{Dropped, PacketQueue, PooledBuffer};
+
+use async_trait::async_trait;
+use std::io;
+use util::buffer::error::Error as BufferError;
+use util::Conn;
+
+/// The classes of datagrams sharing an ICE transport, told apart by their first byte as
+/// specified by RFC 7983 and extended for QUIC by RFC 9443.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PacketClass {
+    /// ZRTP, first byte 16 to 19.
+    Zrtp,
+    /// DTLS, first byte 20 to 63.
+    Dtls,
+    /// RTP and RTCP, SRTP and SRTCP included, first byte 128 to 191.
+    Rtp,
+    /// QUIC, first byte 64 to 127 and 192 to 255. RFC 9443 assigns 64 to 79 to TURN channels as
+    /// well, but those are terminated by the relay candidates and never reach the `AgentConn`.
+    Quic,
+}
+
+impl PacketClass {
+    /// Classifies a datagram by its first byte. STUN messages, empty datagrams and the
+    /// unassigned first bytes 4 to 15 have no class.
+    pub fn classify(buf: &[u8]) -> Option<Self> {
+        match buf.first()? {
+            16..=19 => Some(PacketClass::Zrtp),
+            20..=63 => Some(PacketClass::Dtls),
+            64..=127 | 192..=255 => Some(PacketClass::Quic),
+            128..=191 => Some(PacketClass::Rtp),
+            _ => None,
+        }
+    }
+}
+
+impl AgentConn {
+    /// Returns an endpoint receiving the datagrams of `class`, which from then on no longer
+    /// reach `recv` of the connection. Datagrams without a class, or of a class without an
+    /// endpoint, are still read from the connection. Fails if the class has an endpoint already.
+    pub fn demux(self: &Arc<Self>, class: PacketClass) -> Result<Arc<DemuxEndpoint>> {
+        // Checked under the lock, so that `close_buffers` closes the new buffer as well
+        let mut endpoints = self.endpoints.write().unwrap();
+        if self.buffer.is_closed() {
+            return Err(Error::ErrClosed);
+        }
+        if endpoints.contains_key(&class) {
+            return Err(Error::ErrDemuxEndpointExists);
+        }
+
+        let buffer = Arc::new(PacketQueue::new(self.buffer.limit(), self.buffer.policy()));
+        endpoints.insert(class, Arc::clone(&buffer));
+
+        Ok(Arc::new(DemuxEndpoint {
+            class,
+            conn: Arc::clone(self),
+            buffer,
+        }))
+    }
+
+    /// Queues a received datagram for the endpoint of its class, or the connection itself.
+    pub(crate) async fn route(&self, buf: PooledBuffer) -> Result<Dropped> {
+        let endpoint = {
+            let endpoints = self.endpoints.read().unwrap();
+            if endpoints.is_empty() {
+                None
+            } else {
+                PacketClass::classify(&buf)
+                    .and_then(|c| endpoints.get(&c))
+                    .map(Arc::clone)
+            }
+        };
+        match endpoint {
+            Some(endpoint) => endpoint.push(buf).await,
+            None => self.buffer.push(buf).await,
+        }
+    }
+
+    /// Closes the buffers of the connection and its endpoints, waking their readers.
+    pub(crate) fn close_buffers(&self) {
+        self.buffer.close();
+        for buffer in self.endpoints.read().unwrap().values() {
+            buffer.close();
+        }
+    }
+}
+
+/// An endpoint of an `AgentConn` receiving only the datagrams of one `PacketClass` into its own
+/// buffer, so that the readers of the different protocols don't take each other's datagrams.
+/// Datagrams are sent on the connection unchanged.
+///
+/// Dropping or closing the endpoint hands its class back to the connection.
+pub struct DemuxEndpoint {
+    class: PacketClass,
+    conn: Arc<AgentConn>,
+    buffer: Arc<PacketQueue>,
+}
+
+impl DemuxEndpoint {
+    /// Returns the class of the datagrams received by the endpoint.
+    pub fn class(&self) -> PacketClass {
+        self.class
+    }
+
+    /// Reads a datagram of the endpoint's class into `buf`.
+    pub async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let packet = self.recv_packet().await?;
+        let n = packet.len().min(buf.len());
+        buf[..n].copy_from_slice(&packet[..n]);
+        if n < packet.len() {
+            return Err(Error::Buffer(BufferError::ErrBufferShort));
+        }
+
+        Ok(n)
+    }
+
+    /// Takes the next datagram of the endpoint's class without copying it, like
+    /// `AgentConn::recv_packet`.
+    pub async fn recv_packet(&self) -> Result<PooledBuffer> {
+        if self.conn.done.load(Ordering::SeqCst) {
+            return Err(Error::ErrClosed);
+        }
+
+        let packet = self.buffer.pop().await?;
+        self.conn
+            .bytes_received
+            .fetch_add(packet.len(), Ordering::SeqCst);
+
+        Ok(packet)
+    }
+
+    /// Reads a datagram like `recv`, returning the address of the remote candidate as well.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        if let Some(raddr) = self.conn.remote_addr().await {
+            let n = self.recv(buf).await?;
+            Ok((n, raddr))
+        } else {
+            Err(Error::ErrNoCandidatePairs)
+        }
+    }
+
+    /// Sends `buf` on the connection, see `AgentConn::send`.
+    pub async fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.conn.send(buf).await
+    }
+
+    /// Returns the address of the local candidate of the selected pair.
+    pub async fn local_addr(&self) -> Result<SocketAddr> {
+        self.conn.local_addr().await
+    }
+
+    /// Returns the address of the remote candidate of the selected pair.
+    pub async fn remote_addr(&self) -> Option<SocketAddr> {
+        self.conn.remote_addr().await
+    }
+
+    /// Stops receiving datagrams of the endpoint's class, which go to the connection again.
+    /// Datagrams which were already received can still be read.
+    pub fn close(&self) {
+        let mut endpoints = self.conn.endpoints.write().unwrap();
+        if endpoints
+            .get(&self.class)
+            .is_some_and(|buffer| Arc::ptr_eq(buffer, &self.buffer))
+        {
+            endpoints.remove(&self.class);
+        }
+        self.buffer.close();
+    }
+}
+
+impl Drop for DemuxEndpoint {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[async_trait]
+impl Conn for DemuxEndpoint {
+    async fn connect(&self, _addr: SocketAddr) -> anyhow::Result<()> {
+        Err(io::Error::other("Not applicable").into())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        Ok(DemuxEndpoint::recv(self, buf).await?)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> anyhow::Result<(usize, SocketAddr)> {
+        Ok(DemuxEndpoint::recv_from(self, buf).await?)
+    }
+
+    async fn send(&self, buf: &[u8]) -> anyhow::Result<usize> {
+        Ok(DemuxEndpoint::send(self, buf).await?)
+    }
+
+    async fn send_to(&self, _buf: &[u8], _target: SocketAddr) -> anyhow::Result<usize> {
+        Err(io::Error::other("Not applicable").into())
+    }
+
+    async fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(DemuxEndpoint::local_addr(self).await?)
+    }
+
+    async fn remote_addr(&self) -> Option<SocketAddr> {
+        DemuxEndpoint::remote_addr(self).await
+    }
+
+    async fn close(&self) -> anyhow::Result<()> {
+        DemuxEndpoint::close(self);
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use super::agent_demux::*;
+use super::agent_transport_test::pipe;
+use super::*;
+
+use std::time::Duration;
+
+#[test]
+fn test_packet_class() {
+    let tests = vec![
+        (vec![], None),
+        (vec![0, 1], None),
+        (vec![3], None),
+        (vec![4], None),
+        (vec![15], None),
+        (vec![16], Some(PacketClass::Zrtp)),
+        (vec![19], Some(PacketClass::Zrtp)),
+        (vec![20], Some(PacketClass::Dtls)),
+        (vec![63], Some(PacketClass::Dtls)),
+        (vec![64], Some(PacketClass::Quic)),
+        (vec![127], Some(PacketClass::Quic)),
+        (vec![128], Some(PacketClass::Rtp)),
+        (vec![191], Some(PacketClass::Rtp)),
+        (vec![192], Some(PacketClass::Quic)),
+        (vec![255], Some(PacketClass::Quic)),
+    ];
+
+    for (buf, expected) in tests {
+        assert_eq!(
+            PacketClass::classify(&buf),
+            expected,
+            "first bytes {:?}",
+            buf
+        );
+    }
+}
+
+async fn recv(conn: &AgentConn) -> Result<Vec<u8>> {
+    let packet = tokio::time::timeout(Duration::from_secs(5), conn.recv_packet())
+        .await
+        .expect("a packet is received")?;
+    Ok(packet.to_vec())
+}
+
+async fn recv_endpoint(endpoint: &DemuxEndpoint) -> Result<Vec<u8>> {
+    let packet = tokio::time::timeout(Duration::from_secs(5), endpoint.recv_packet())
+        .await
+        .expect("a packet is received")?;
+    Ok(packet.to_vec())
+}
+
+#[tokio::test]
+async fn test_demux() -> Result<()> {
+    let (ca, cb, a_agent, b_agent) = pipe(None, None).await?;
+
+    let dtls = cb.demux(PacketClass::Dtls)?;
+    let rtp = cb.demux(PacketClass::Rtp)?;
+    assert_eq!(dtls.class(), PacketClass::Dtls);
+    assert_eq!(
+        cb.demux(PacketClass::Dtls).err(),
+        Some(Error::ErrDemuxEndpointExists)
+    );
+
+    ca.send(&[22, 1]).await?;
+    ca.send(&[128, 2]).await?;
+    ca.send(&[5, 3]).await?;
+    ca.send(&[64, 4]).await?;
+
+    // Each reader only gets the datagrams of its class, whatever the order they arrived in
+    assert_eq!(recv_endpoint(&rtp).await?, vec![128, 2]);
+    assert_eq!(recv_endpoint(&dtls).await?, vec![22, 1]);
+    assert_eq!(
+        recv(&cb).await?,
+        vec![5, 3],
+        "datagrams without a class stay on the connection"
+    );
+    assert_eq!(
+        recv(&cb).await?,
+        vec![64, 4],
+        "datagrams of classes without an endpoint stay on the connection"
+    );
+
+    // Dropping an endpoint hands its class back to the connection
+    drop(rtp);
+    ca.send(&[128, 5]).await?;
+    assert_eq!(recv(&cb).await?, vec![128, 5]);
+    let rtp = cb.demux(PacketClass::Rtp)?;
+    ca.send(&[128, 6]).await?;
+    assert_eq!(recv_endpoint(&rtp).await?, vec![128, 6]);
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+    assert!(dtls.recv_packet().await.is_err());
+    assert_eq!(cb.demux(PacketClass::Quic).err(), Some(Error::ErrClosed));
+
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use super::*;
+use crate::url::Url;
+
+use futures_core::Stream;
+use std::fmt;
+use std::task::{Context, Poll};
+use tokio::sync::broadcast::error::RecvError;
+
+/// The error code of a `CandidateError` when the server couldn't be reached, or failed without
+/// answering with an error response.
+pub const CANDIDATE_ERROR_UNREACHABLE: u16 = 701;
+
+/// A STUN or TURN server which failed to provide a candidate while gathering, like the
+/// `icecandidateerror` event of WebRTC.
+#[derive(Debug, Clone)]
+pub struct CandidateError {
+    /// The URL of the server.
+    pub url: Url,
+    /// The local address the server was contacted from, `None` if it failed before any socket
+    /// was bound.
+    pub local_addr: Option<SocketAddr>,
+    /// The STUN error code of the error response of the server, or
+    /// `CANDIDATE_ERROR_UNREACHABLE`.
+    pub error_code: u16,
+    /// The reason phrase of the error response of the server, or a description of the error.
+    pub error_text: String,
+    /// Why no candidate was gathered from it.
+    pub error: Arc<Error>,
+}
+
+impl CandidateError {
+    pub(crate) fn new(url: Url, local_addr: Option<SocketAddr>, error: Error) -> Self {
+        let (error_code, error_text) = error
+            .server_error_code()
+            .unwrap_or_else(|| (CANDIDATE_ERROR_UNREACHABLE, error.to_string()));
+        CandidateError {
+            url,
+            local_addr,
+            error_code,
+            error_text,
+            error: Arc::new(error),
+        }
+    }
+}
+
+impl fmt::Display for CandidateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.url)?;
+        if let Some(local_addr) = self.local_addr {
+            write!(f, " from {}", local_addr)?;
+        }
+        write!(f, ": {} {}", self.error_code, self.error_text)
+    }
+}
+
+/// An event of the `Agent`, delivered to the streams returned by `Agent::subscribe`.
+#[derive(Clone)]
+pub enum AgentEvent {
+    /// The connection state changed.
+    ConnectionStateChange(ConnectionState),
+    /// The gathering state changed.
+    GatheringStateChange(GatheringState),
+    /// A local candidate was gathered.
+    LocalCandidate(Arc<dyn Candidate + Send + Sync>),
+    /// A STUN or TURN server failed to provide a candidate.
+    CandidateError(CandidateError),
+    /// The state of a candidate pair changed. The pair may have changed its state again by the
+    /// time the event is received, the state it changed to is passed along.
+    CandidatePairStateChange(Arc<CandidatePair>, CandidatePairState),
+    /// A candidate pair was selected to carry the data.
+    SelectedCandidatePairChange(Arc<CandidatePair>),
+    /// Received packets were dropped because the receive buffer of the `AgentConn` is full.
+    PacketsDropped(ReceiveBufferStats),
+}
+
+impl fmt::Debug for AgentEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AgentEvent::ConnectionStateChange(state) => {
+                write!(f, "ConnectionStateChange({})", state)
+            }
+            AgentEvent::GatheringStateChange(state) => {
+                write!(f, "GatheringStateChange({})", state)
+            }
+            AgentEvent::LocalCandidate(c) => write!(f, "LocalCandidate({})", c),
+            AgentEvent::CandidateError(err) => write!(f, "CandidateError({})", err),
+            AgentEvent::CandidatePairStateChange(p, state) => {
+                write!(f, "CandidatePairStateChange({}, {})", p, state)
+            }
+            AgentEvent::SelectedCandidatePairChange(p) => {
+                write!(f, "SelectedCandidatePairChange({})", p)
+            }
+            AgentEvent::PacketsDropped(stats) => write!(f, "PacketsDropped({:?})", stats),
+        }
+    }
+}
+
+type RecvFuture = Pin<
+    Box<
+        dyn Future<
+                Output = (
+                    std::result::Result<AgentEvent, RecvError>,
+                    broadcast::Receiver<AgentEvent>,
+                ),
+            > + Send,
+    >,
+>;
+
+/// A subscription to the events of an `Agent`, returned by `Agent::subscribe`. It is a `Stream`
+/// of the events which happen after subscribing, ending once the agent is closed.
+///
+/// Each subscriber receives all events. A subscriber which falls behind by more than the
+/// capacity of the channel misses the oldest events it hasn't received yet.
+pub struct AgentEvents {
+    next: Option<RecvFuture>,
+}
+
+impl AgentEvents {
+    pub(crate) fn new(rx: Option<broadcast::Receiver<AgentEvent>>) -> Self {
+        AgentEvents {
+            next: rx.map(Self::recv_future),
+        }
+    }
+
+    fn recv_future(mut rx: broadcast::Receiver<AgentEvent>) -> RecvFuture {
+        Box::pin(async move {
+            let result = rx.recv().await;
+            (result, rx)
+        })
+    }
+
+    /// Waits for the next event, returning `None` once the agent is closed.
+    pub async fn recv(&mut self) -> Option<AgentEvent> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl Stream for AgentEvents {
+    type Item = AgentEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<AgentEvent>> {
+        let this = self.get_mut();
+        loop {
+            let next = match &mut this.next {
+                Some(next) => next,
+                None => return Poll::Ready(None),
+            };
+            let (result, rx) = futures_core::ready!(next.as_mut().poll(cx));
+            match result {
+                Ok(event) => {
+                    this.next = Some(Self::recv_future(rx));
+                    return Poll::Ready(Some(event));
+                }
+                Err(RecvError::Lagged(n)) => {
+                    log::warn!("agent event subscriber lagged, missed {} events", n);
+                    this.next = Some(Self::recv_future(rx));
+                }
+                Err(RecvError::Closed) => {
+                    this.next = None;
+                    return Poll::Ready(None);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use super::agent_event::*;
+use super::agent_vnet_test::connect_with_vnet;
+use super::*;
+use crate::url::{ProtoType, SchemeType, Url};
+
+use std::time::Duration;
+
+fn loopback_config() -> AgentConfig {
+    AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        include_loopback: true,
+        interface_filter: Arc::new(Some(Box::new(|name: &str| name.starts_with("lo")))),
+        ..Default::default()
+    }
+}
+
+async fn next_event(events: &mut AgentEvents) -> Option<AgentEvent> {
+    tokio::time::timeout(Duration::from_secs(10), events.recv())
+        .await
+        .expect("an event is received")
+}
+
+// Receives events until `last` matches, returning all of them.
+async fn recv_until(
+    events: &mut AgentEvents,
+    last: impl Fn(&AgentEvent) -> bool,
+) -> Vec<AgentEvent> {
+    let mut received = vec![];
+    while let Some(event) = next_event(events).await {
+        let done = last(&event);
+        received.push(event);
+        if done {
+            break;
+        }
+    }
+    received
+}
+
+fn is_gathering_complete(event: &AgentEvent) -> bool {
+    matches!(
+        event,
+        AgentEvent::GatheringStateChange(GatheringState::Complete)
+    )
+}
+
+#[tokio::test]
+async fn test_subscribe_gathering() -> Result<()> {
+    let a = Agent::new(loopback_config()).await?;
+    let mut events1 = a.subscribe().await;
+    let mut events2 = a.subscribe().await;
+
+    // Subscribers receive the candidates, so no on_candidate handler is needed
+    a.gather_candidates().await?;
+
+    let received1 = recv_until(&mut events1, is_gathering_complete).await;
+    let received2 = recv_until(&mut events2, is_gathering_complete).await;
+    assert!(matches!(
+        received1.first(),
+        Some(AgentEvent::GatheringStateChange(GatheringState::Gathering))
+    ));
+    assert!(is_gathering_complete(received1.last().unwrap()));
+    let candidates = received1
+        .iter()
+        .filter(|event| matches!(event, AgentEvent::LocalCandidate(_)))
+        .count();
+    assert!(candidates > 0, "{:?}", received1);
+    assert_eq!(
+        candidates,
+        a.get_local_candidates().await?.len(),
+        "each local candidate is reported"
+    );
+    assert_eq!(
+        received1.len(),
+        received2.len(),
+        "all subscribers get all events"
+    );
+
+    a.close().await?;
+    let received = recv_until(&mut events1, |_| false).await;
+    assert!(
+        matches!(
+            received.as_slice(),
+            [AgentEvent::ConnectionStateChange(ConnectionState::Closed)]
+        ),
+        "{:?}",
+        received
+    );
+    assert!(
+        a.subscribe().await.recv().await.is_none(),
+        "the stream of a closed agent ends right away"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_subscribe_connection() -> Result<()> {
+    let a_agent = Arc::new(Agent::new(loopback_config()).await?);
+    let b_agent = Arc::new(Agent::new(loopback_config()).await?);
+    let mut events = b_agent.subscribe().await;
+
+    let (_a_conn, _b_conn) = connect_with_vnet(&a_agent, &b_agent).await?;
+
+    let received = recv_until(&mut events, |event| {
+        matches!(event, AgentEvent::SelectedCandidatePairChange(_))
+    })
+    .await;
+    assert!(
+        received.iter().any(|event| matches!(
+            event,
+            AgentEvent::ConnectionStateChange(ConnectionState::Checking)
+        )),
+        "{:?}",
+        received
+    );
+    assert!(
+        received.iter().any(|event| matches!(
+            event,
+            AgentEvent::CandidatePairStateChange(_, CandidatePairState::Succeeded)
+        )),
+        "{:?}",
+        received
+    );
+    assert!(
+        received.iter().any(|event| matches!(
+            event,
+            AgentEvent::ConnectionStateChange(ConnectionState::Connected)
+        )),
+        "{:?}",
+        received
+    );
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_subscribe_candidate_error() -> Result<()> {
+    // Nothing answers on the port of the STUN server
+    let port = {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        socket.local_addr()?.port()
+    };
+    let stun_server_url = Url {
+        scheme: SchemeType::Stun,
+        host: "127.0.0.1".to_owned(),
+        port,
+        proto: ProtoType::Udp,
+        ..Default::default()
+    };
+
+    let a = Agent::new(AgentConfig {
+        urls: vec![stun_server_url.clone()],
+        candidate_types: vec![CandidateType::ServerReflexive],
+        stun_rto: Some(Duration::from_millis(20)),
+        stun_max_requests: Some(2),
+        ..loopback_config()
+    })
+    .await?;
+    let mut events = a.subscribe().await;
+    a.gather_candidates().await?;
+
+    let received = recv_until(&mut events, is_gathering_complete).await;
+    let errors: Vec<&CandidateError> = received
+        .iter()
+        .filter_map(|event| match event {
+            AgentEvent::CandidateError(err) => Some(err),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(errors.len(), 1, "{:?}", received);
+    assert_eq!(errors[0].url.to_string(), stun_server_url.to_string());
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_subscribe_close_while_gathering() -> Result<()> {
+    // Nothing answers on the port of the STUN server, so gathering lasts
+    let port = {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        socket.local_addr()?.port()
+    };
+    let a = Agent::new(AgentConfig {
+        urls: vec![Url {
+            scheme: SchemeType::Stun,
+            host: "127.0.0.1".to_owned(),
+            port,
+            proto: ProtoType::Udp,
+            ..Default::default()
+        }],
+        candidate_types: vec![CandidateType::ServerReflexive],
+        ..loopback_config()
+    })
+    .await?;
+    let mut events = a.subscribe().await;
+    a.gather_candidates().await?;
+    assert!(matches!(
+        next_event(&mut events).await,
+        Some(AgentEvent::GatheringStateChange(GatheringState::Gathering))
+    ));
+
+    // The gathering tasks still running don't keep the stream open
+    a.close().await?;
+    let received = recv_until(&mut events, |_| false).await;
+    assert!(
+        received.iter().all(|event| !is_gathering_complete(event)
+            && !matches!(event, AgentEvent::CandidateError(_))),
+        "{:?}",
+        received
+    );
+
+    Ok(())
+}
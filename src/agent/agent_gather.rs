@@ -1,7 +1,12 @@
 use super::*;
+use crate::agent::agent_relay_conn::RelayFamilyConn;
+use crate::agent::agent_srflx_conn::SrflxConn;
+use crate::agent::agent_turn_conn::{AllocationFamily, TurnRequestConn};
 use crate::error::*;
+use crate::ipv6_address_class::Ipv6AddressClass;
 use crate::network_type::*;
-use crate::url::{ProtoType, SchemeType, Url};
+use crate::turn_credentials::{OauthToken, TurnCredentialProvider, TurnCredentials};
+use crate::url::{CredentialType, ProtoType, SchemeType, Url};
 use crate::util::*;
 
 use util::{vnet::net::*, Conn};
@@ -11,12 +16,71 @@ use crate::candidate::candidate_host::CandidateHostConfig;
 use crate::candidate::candidate_relay::CandidateRelayConfig;
 use crate::candidate::candidate_server_reflexive::CandidateServerReflexiveConfig;
 use crate::candidate::*;
+use std::collections::HashSet;
 use std::net::{Ipv4Addr, Ipv6Addr};
-use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::watch;
 use waitgroup::WaitGroup;
 
-const STUN_GATHER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Stops the tasks of one gathering once it is cancelled or its deadline passed.
+#[derive(Clone)]
+pub(crate) struct GatherCancel {
+    cancel_rx: watch::Receiver<bool>,
+    deadline: Option<Instant>,
+    runtime: Arc<dyn Runtime>,
+}
+
+impl GatherCancel {
+    /// Returns the sender cancelling the gathering and the cancellation observed by its tasks,
+    /// which is cancelled after `timeout` as well.
+    pub(crate) fn new(
+        runtime: Arc<dyn Runtime>,
+        timeout: Option<Duration>,
+    ) -> (watch::Sender<bool>, Self) {
+        let (cancel_tx, cancel_rx) = watch::channel(false);
+        let cancel = GatherCancel {
+            cancel_rx,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            runtime,
+        };
+        (cancel_tx, cancel)
+    }
+
+    /// Returns true once the gathering is cancelled, its deadline passed or the agent is gone.
+    pub(crate) fn is_cancelled(&self) -> bool {
+        *self.cancel_rx.borrow()
+            || self.cancel_rx.has_changed().is_err()
+            || self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    /// Completes once the gathering is cancelled, its deadline passed or the agent is gone.
+    pub(crate) async fn cancelled(&self) {
+        let mut cancel_rx = self.cancel_rx.clone();
+        let cancelled = async move {
+            let _ = cancel_rx.wait_for(|cancelled| *cancelled).await;
+        };
+        match self.deadline {
+            Some(deadline) => {
+                let sleep = self.runtime.sleep_until(deadline);
+                tokio::select! {
+                    _ = cancelled => {}
+                    _ = sleep => {}
+                }
+            }
+            None => cancelled.await,
+        }
+    }
+
+    /// Runs `future` unless the gathering is cancelled first, in which case it returns `None`.
+    pub(crate) async fn run<F: Future>(&self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            output = future => Some(output),
+            _ = self.cancelled() => None,
+        }
+    }
+}
 
 pub(crate) struct GatherCandidatesInternalParams {
     pub(crate) candidate_types: Vec<CandidateType>,
@@ -27,11 +91,23 @@ pub(crate) struct GatherCandidatesInternalParams {
     pub(crate) mdns_mode: MulticastDnsMode,
     pub(crate) mdns_name: String,
     pub(crate) net: Arc<Net>,
+    pub(crate) runtime: Arc<dyn Runtime>,
     pub(crate) interface_filter: Arc<Option<InterfaceFilterFn>>,
+    pub(crate) interface_preference: Arc<Option<InterfacePreferenceFn>>,
+    pub(crate) interface_cost: Arc<Option<InterfaceCostFn>>,
+    pub(crate) ip_filter: Arc<Option<IpFilterFn>>,
+    pub(crate) include_loopback: bool,
+    pub(crate) include_link_local: bool,
+    pub(crate) ipv6_address_classes: Vec<Ipv6AddressClass>,
     pub(crate) ext_ip_mapper: Arc<Option<ExternalIpMapper>>,
     pub(crate) agent_internal: Arc<Mutex<AgentInternal>>,
     pub(crate) gathering_state: Arc<AtomicU8>,
     pub(crate) chan_candidate_tx: ChanCandidateTx,
+    pub(crate) events_tx: Option<broadcast::WeakSender<AgentEvent>>,
+    pub(crate) cancel: GatherCancel,
+    pub(crate) server_timeout: Duration,
+    pub(crate) stun_retransmission: StunRetransmission,
+    pub(crate) credential_provider: Option<Arc<dyn TurnCredentialProvider>>,
 }
 
 struct GatherCandidatesLocalParams {
@@ -41,33 +117,66 @@ struct GatherCandidatesLocalParams {
     mdns_mode: MulticastDnsMode,
     mdns_name: String,
     interface_filter: Arc<Option<InterfaceFilterFn>>,
+    interface_preference: Arc<Option<InterfacePreferenceFn>>,
+    interface_cost: Arc<Option<InterfaceCostFn>>,
+    ip_filter: Arc<Option<IpFilterFn>>,
+    include_loopback: bool,
+    include_link_local: bool,
+    ipv6_address_classes: Vec<Ipv6AddressClass>,
     ext_ip_mapper: Arc<Option<ExternalIpMapper>>,
     net: Arc<Net>,
+    runtime: Arc<dyn Runtime>,
     agent_internal: Arc<Mutex<AgentInternal>>,
+    cancel: GatherCancel,
 }
 
 struct GatherCandidatesSrflxMappedParasm {
     network_types: Vec<NetworkType>,
     port_max: u16,
     port_min: u16,
+    ip_filter: Arc<Option<IpFilterFn>>,
     ext_ip_mapper: Arc<Option<ExternalIpMapper>>,
     net: Arc<Net>,
+    runtime: Arc<dyn Runtime>,
     agent_internal: Arc<Mutex<AgentInternal>>,
+    cancel: GatherCancel,
 }
 
+#[derive(Clone)]
 struct GatherCandidatesSrflxParams {
     urls: Vec<Url>,
     network_types: Vec<NetworkType>,
     port_max: u16,
     port_min: u16,
+    ip_filter: Arc<Option<IpFilterFn>>,
     net: Arc<Net>,
+    runtime: Arc<dyn Runtime>,
     agent_internal: Arc<Mutex<AgentInternal>>,
+    events_tx: Option<broadcast::WeakSender<AgentEvent>>,
+    cancel: GatherCancel,
+    server_timeout: Duration,
+    stun_retransmission: StunRetransmission,
+}
+
+#[derive(Clone)]
+pub(crate) struct GatherCandidatesRelayParams {
+    pub(crate) urls: Vec<Url>,
+    pub(crate) network_types: Vec<NetworkType>,
+    pub(crate) ip_filter: Arc<Option<IpFilterFn>>,
+    pub(crate) net: Arc<Net>,
+    pub(crate) runtime: Arc<dyn Runtime>,
+    pub(crate) agent_internal: Arc<Mutex<AgentInternal>>,
+    pub(crate) events_tx: Option<broadcast::WeakSender<AgentEvent>>,
+    pub(crate) cancel: GatherCancel,
+    pub(crate) server_timeout: Duration,
+    pub(crate) credential_provider: Option<Arc<dyn TurnCredentialProvider>>,
 }
 
 impl Agent {
     pub(crate) async fn gather_candidates_internal(params: GatherCandidatesInternalParams) {
         Self::set_gathering_state(
             &params.chan_candidate_tx,
+            &params.events_tx,
             &params.gathering_state,
             GatheringState::Gathering,
         )
@@ -85,13 +194,21 @@ impl Agent {
                         mdns_mode: params.mdns_mode,
                         mdns_name: params.mdns_name.clone(),
                         interface_filter: Arc::clone(&params.interface_filter),
+                        interface_preference: Arc::clone(&params.interface_preference),
+                        interface_cost: Arc::clone(&params.interface_cost),
+                        ip_filter: Arc::clone(&params.ip_filter),
+                        include_loopback: params.include_loopback,
+                        include_link_local: params.include_link_local,
+                        ipv6_address_classes: params.ipv6_address_classes.clone(),
                         ext_ip_mapper: Arc::clone(&params.ext_ip_mapper),
                         net: Arc::clone(&params.net),
+                        runtime: Arc::clone(&params.runtime),
                         agent_internal: Arc::clone(&params.agent_internal),
+                        cancel: params.cancel.clone(),
                     };
 
                     let w = wg.worker();
-                    tokio::spawn(async move {
+                    runtime::spawn(&params.runtime, async move {
                         let _d = w;
 
                         Self::gather_candidates_local(local_params).await;
@@ -103,11 +220,17 @@ impl Agent {
                         network_types: params.network_types.clone(),
                         port_max: params.port_max,
                         port_min: params.port_min,
+                        ip_filter: Arc::clone(&params.ip_filter),
                         net: Arc::clone(&params.net),
+                        runtime: Arc::clone(&params.runtime),
                         agent_internal: Arc::clone(&params.agent_internal),
+                        events_tx: params.events_tx.clone(),
+                        cancel: params.cancel.clone(),
+                        server_timeout: params.server_timeout,
+                        stun_retransmission: params.stun_retransmission,
                     };
                     let w1 = wg.worker();
-                    tokio::spawn(async move {
+                    runtime::spawn(&params.runtime, async move {
                         let _d = w1;
 
                         Self::gather_candidates_srflx(srflx_params).await;
@@ -118,12 +241,15 @@ impl Agent {
                                 network_types: params.network_types.clone(),
                                 port_max: params.port_max,
                                 port_min: params.port_min,
+                                ip_filter: Arc::clone(&params.ip_filter),
                                 ext_ip_mapper: Arc::clone(&params.ext_ip_mapper),
                                 net: Arc::clone(&params.net),
+                                runtime: Arc::clone(&params.runtime),
                                 agent_internal: Arc::clone(&params.agent_internal),
+                                cancel: params.cancel.clone(),
                             };
                             let w2 = wg.worker();
-                            tokio::spawn(async move {
+                            runtime::spawn(&params.runtime, async move {
                                 let _d = w2;
 
                                 Self::gather_candidates_srflx_mapped(srflx_mapped_params).await;
@@ -132,14 +258,23 @@ impl Agent {
                     }
                 }
                 CandidateType::Relay => {
-                    let urls = params.urls.clone();
-                    let net = Arc::clone(&params.net);
-                    let agent_internal = Arc::clone(&params.agent_internal);
+                    let relay_params = GatherCandidatesRelayParams {
+                        urls: params.urls.clone(),
+                        network_types: params.network_types.clone(),
+                        ip_filter: Arc::clone(&params.ip_filter),
+                        net: Arc::clone(&params.net),
+                        runtime: Arc::clone(&params.runtime),
+                        agent_internal: Arc::clone(&params.agent_internal),
+                        events_tx: params.events_tx.clone(),
+                        cancel: params.cancel.clone(),
+                        server_timeout: params.server_timeout,
+                        credential_provider: params.credential_provider.clone(),
+                    };
                     let w = wg.worker();
-                    tokio::spawn(async move {
+                    runtime::spawn(&params.runtime, async move {
                         let _d = w;
 
-                        Self::gather_candidates_relay(urls, net, agent_internal).await;
+                        Self::gather_candidates_relay(relay_params).await;
                     });
                 }
                 _ => {}
@@ -148,9 +283,13 @@ impl Agent {
 
         // Block until all STUN and TURN URLs have been gathered (or timed out)
         wg.wait().await;
+        if params.cancel.is_cancelled() {
+            log::debug!("gathering was cancelled or timed out");
+        }
 
         Self::set_gathering_state(
             &params.chan_candidate_tx,
+            &params.events_tx,
             &params.gathering_state,
             GatheringState::Complete,
         )
@@ -159,18 +298,22 @@ impl Agent {
 
     async fn set_gathering_state(
         chan_candidate_tx: &ChanCandidateTx,
+        events_tx: &Option<broadcast::WeakSender<AgentEvent>>,
         gathering_state: &Arc<AtomicU8>,
         new_state: GatheringState,
     ) {
-        if GatheringState::from(gathering_state.load(Ordering::SeqCst)) != new_state
-            && new_state == GatheringState::Complete
-        {
+        let changed = GatheringState::from(gathering_state.load(Ordering::SeqCst)) != new_state;
+        if changed && new_state == GatheringState::Complete {
             if let Some(tx) = chan_candidate_tx {
                 let _ = tx.send(None).await;
             }
         }
 
         gathering_state.store(new_state as u8, Ordering::SeqCst);
+
+        if changed {
+            emit(events_tx, AgentEvent::GatheringStateChange(new_state));
+        }
     }
 
     async fn gather_candidates_local(params: GatherCandidatesLocalParams) {
@@ -181,6 +324,7 @@ impl Agent {
             mdns_mode,
             mdns_name,
             interface_filter,
+            ip_filter,
             ext_ip_mapper,
             net,
             agent_internal,
@@ -191,13 +335,36 @@ impl Agent {
             params.mdns_mode,
             params.mdns_name,
             params.interface_filter,
+            params.ip_filter,
             params.ext_ip_mapper,
             params.net,
             params.agent_internal,
         );
 
-        let ips = local_interfaces(&net, &*interface_filter, &network_types).await;
-        for ip in ips {
+        let ips = local_interfaces(
+            &net,
+            &interface_filter,
+            &ip_filter,
+            &network_types,
+            params.include_loopback,
+            params.include_link_local,
+            &params.ipv6_address_classes,
+        )
+        .await;
+        let preferences = local_preferences(&ips, &params.interface_preference);
+        let network_ids = network_ids(&ips);
+        for ((iface_addr, local_preference), network_id) in
+            ips.into_iter().zip(preferences).zip(network_ids)
+        {
+            if params.cancel.is_cancelled() {
+                break;
+            }
+
+            let ip = iface_addr.ip;
+            let network_cost = match &*params.interface_cost {
+                Some(cost) => cost(&iface_addr.name),
+                None => 0,
+            };
             let mut mapped_ip = ip;
 
             if mdns_mode != MulticastDnsMode::QueryAndGather && ext_ip_mapper.is_some() {
@@ -217,6 +384,9 @@ impl Agent {
 
             let address = if mdns_mode == MulticastDnsMode::QueryAndGather {
                 mdns_name.clone()
+            } else if mapped_ip == iface_addr.ip {
+                // keeps the zone ID of link-local addresses, e.g. fe80::1%2
+                iface_addr.to_string()
             } else {
                 mapped_ip.to_string()
             };
@@ -244,15 +414,16 @@ impl Agent {
 
                 let conn: Arc<dyn Conn + Send + Sync> = match listen_udp_in_port_range(
                     &net,
+                    &params.runtime,
                     port_max,
                     port_min,
-                    SocketAddr::new(ip, 0),
+                    iface_addr.socket_addr(0),
                 )
                 .await
                 {
                     Ok(conn) => conn,
                     Err(err) => {
-                        log::warn!("could not listen {} {}: {}", network, ip, err);
+                        log::warn!("could not listen {} {}: {}", network, iface_addr, err);
                         continue;
                     }
                 };
@@ -271,6 +442,10 @@ impl Agent {
                         address,
                         port,
                         component: COMPONENT_RTP,
+                        scope_id: iface_addr.scope_id,
+                        local_preference,
+                        network_id,
+                        network_cost,
                         conn: Some(conn),
                         ..CandidateBaseConfig::default()
                     },
@@ -306,27 +481,17 @@ impl Agent {
                         }
                     };
 
-                {
-                    let mut ai = agent_internal.lock().await;
-                    if let Err(err) = ai.add_candidate(&candidate, &agent_internal).await {
-                        if let Err(close_err) = candidate.close().await {
-                            log::warn!("Failed to close candidate: {}", close_err);
-                        }
-                        log::warn!(
-                            "Failed to append to localCandidates and run onCandidateHdlr: {}",
-                            err
-                        );
-                    }
-                }
+                add_gathered_candidate(&agent_internal, &candidate, &params.cancel).await;
             }
         }
     }
 
     async fn gather_candidates_srflx_mapped(params: GatherCandidatesSrflxMappedParasm) {
-        let (network_types, port_max, port_min, ext_ip_mapper, net, agent_internal) = (
+        let (network_types, port_max, port_min, ip_filter, ext_ip_mapper, net, agent_internal) = (
             params.network_types,
             params.port_max,
             params.port_min,
+            params.ip_filter,
             params.ext_ip_mapper,
             params.net,
             params.agent_internal,
@@ -342,14 +507,18 @@ impl Agent {
             let network = network_type.to_string();
             let net2 = Arc::clone(&net);
             let agent_internal2 = Arc::clone(&agent_internal);
+            let ip_filter2 = Arc::clone(&ip_filter);
             let ext_ip_mapper2 = Arc::clone(&ext_ip_mapper);
+            let runtime2 = Arc::clone(&params.runtime);
+            let cancel2 = params.cancel.clone();
 
             let w = wg.worker();
-            tokio::spawn(async move {
+            runtime::spawn(&params.runtime, async move {
                 let _d = w;
 
                 let conn: Arc<dyn Conn + Send + Sync> = match listen_udp_in_port_range(
                     &net2,
+                    &runtime2,
                     port_max,
                     port_min,
                     if network_type.is_ipv4() {
@@ -387,6 +556,11 @@ impl Agent {
                     }
                 };
 
+                if !is_ip_allowed(mapped_ip, &ip_filter2) {
+                    log::debug!("server reflexive address {} is filtered out", mapped_ip);
+                    return Ok(());
+                }
+
                 let srflx_config = CandidateServerReflexiveConfig {
                     base_config: CandidateBaseConfig {
                         network: network.clone(),
@@ -415,18 +589,7 @@ impl Agent {
                         }
                     };
 
-                {
-                    let mut ai = agent_internal2.lock().await;
-                    if let Err(err) = ai.add_candidate(&candidate, &agent_internal2).await {
-                        if let Err(close_err) = candidate.close().await {
-                            log::warn!("Failed to close candidate: {}", close_err);
-                        }
-                        log::warn!(
-                            "Failed to append to localCandidates and run onCandidateHdlr: {}",
-                            err
-                        );
-                    }
-                }
+                add_gathered_candidate(&agent_internal2, &candidate, &cancel2).await;
 
                 Result::<()>::Ok(())
             });
@@ -436,277 +599,690 @@ impl Agent {
     }
 
     async fn gather_candidates_srflx(params: GatherCandidatesSrflxParams) {
-        let (urls, network_types, port_max, port_min, net, agent_internal) = (
-            params.urls,
-            params.network_types,
-            params.port_max,
-            params.port_min,
-            params.net,
-            params.agent_internal,
-        );
+        // The mapped addresses gathered so far with their base, as servers behind the same NAT
+        // mapping report the same address
+        let mapped_addrs = Arc::new(std::sync::Mutex::new(HashSet::new()));
 
         let wg = WaitGroup::new();
-        for network_type in network_types {
+        for network_type in &params.network_types {
             if network_type.is_tcp() {
                 continue;
             }
 
-            for url in &urls {
-                let network = network_type.to_string();
-                let is_ipv4 = network_type.is_ipv4();
-                let url = url.clone();
-                let net2 = Arc::clone(&net);
-                let agent_internal2 = Arc::clone(&agent_internal);
-
-                let w = wg.worker();
-                tokio::spawn(async move {
-                    let _d = w;
-
-                    let host_port = format!("{}:{}", url.host, url.port);
-                    let server_addr = match net2.resolve_addr(is_ipv4, &host_port).await {
-                        Ok(addr) => addr,
-                        Err(err) => {
-                            log::warn!("failed to resolve stun host: {}: {}", host_port, err);
-                            return Ok(());
-                        }
-                    };
-
-                    let conn: Arc<dyn Conn + Send + Sync> = match listen_udp_in_port_range(
-                        &net2,
-                        port_max,
-                        port_min,
-                        if is_ipv4 {
-                            SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0)
-                        } else {
-                            SocketAddr::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0).into(), 0)
-                        },
-                    )
-                    .await
-                    {
-                        Ok(conn) => conn,
-                        Err(err) => {
-                            log::warn!("Failed to listen for {}: {}", server_addr, err);
-                            return Ok(());
-                        }
-                    };
-
-                    let xoraddr =
-                        match get_xormapped_addr(&conn, server_addr, STUN_GATHER_TIMEOUT).await {
-                            Ok(xoraddr) => xoraddr,
-                            Err(err) => {
-                                log::warn!(
-                                    "could not get server reflexive address {} {}: {}",
-                                    network,
-                                    url,
-                                    err
-                                );
-                                return Ok(());
-                            }
-                        };
-
-                    let (ip, port) = (xoraddr.ip, xoraddr.port);
-
-                    let laddr = conn.local_addr().await?;
-                    let srflx_config = CandidateServerReflexiveConfig {
-                        base_config: CandidateBaseConfig {
-                            network: network.clone(),
-                            address: ip.to_string(),
-                            port,
-                            component: COMPONENT_RTP,
-                            conn: Some(conn),
-                            ..CandidateBaseConfig::default()
-                        },
-                        rel_addr: laddr.ip().to_string(),
-                        rel_port: laddr.port(),
-                    };
-
-                    let candidate: Arc<dyn Candidate + Send + Sync> =
-                        match srflx_config.new_candidate_server_reflexive().await {
-                            Ok(candidate) => Arc::new(candidate),
-                            Err(err) => {
-                                log::warn!(
-                                    "Failed to create server reflexive candidate: {} {} {}: {:?}",
-                                    network,
-                                    ip,
-                                    port,
-                                    err
-                                );
-                                return Ok(());
-                            }
-                        };
-
-                    {
-                        let mut ai = agent_internal2.lock().await;
-                        if let Err(err) = ai.add_candidate(&candidate, &agent_internal2).await {
-                            if let Err(close_err) = candidate.close().await {
-                                log::warn!("Failed to close candidate: {}", close_err);
-                            }
-                            log::warn!(
-                                "Failed to append to localCandidates and run onCandidateHdlr: {}",
-                                err
-                            );
-                        }
-                    }
-
-                    Result::<()>::Ok(())
-                });
-            }
+            let params2 = params.clone();
+            let network_type = *network_type;
+            let mapped_addrs2 = Arc::clone(&mapped_addrs);
+            let w = wg.worker();
+            runtime::spawn(&params.runtime, async move {
+                let _d = w;
+                Self::gather_candidates_srflx_base(params2, network_type, mapped_addrs2).await;
+            });
         }
 
         wg.wait().await;
     }
 
-    pub(crate) async fn gather_candidates_relay(
-        urls: Vec<Url>,
-        net: Arc<Net>,
-        agent_internal: Arc<Mutex<AgentInternal>>,
+    /// Queries all STUN servers from one socket of `network_type`, which is the base of the server
+    /// reflexive candidates of all mapped addresses they report.
+    async fn gather_candidates_srflx_base(
+        params: GatherCandidatesSrflxParams,
+        network_type: NetworkType,
+        mapped_addrs: Arc<std::sync::Mutex<HashSet<(SocketAddr, SocketAddr)>>>,
     ) {
-        let wg = WaitGroup::new();
-
-        for url in urls {
-            if url.scheme != SchemeType::Turn && url.scheme != SchemeType::Turns {
-                continue;
-            }
-            if url.username.is_empty() {
-                log::error!(
-                    "Failed to gather relay candidates: {:?}",
-                    Error::ErrUsernameEmpty
-                );
+        let network = network_type.to_string();
+        let conn = match listen_udp_in_port_range(
+            &params.net,
+            &params.runtime,
+            params.port_max,
+            params.port_min,
+            if network_type.is_ipv4() {
+                SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0)
+            } else {
+                SocketAddr::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0).into(), 0)
+            },
+        )
+        .await
+        {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::warn!("Failed to listen {}: {}", network, err);
+                if let Some(url) = params.urls.first() {
+                    let error = CandidateError::new(url.clone(), None, err);
+                    for url in &params.urls {
+                        let error = CandidateError {
+                            url: url.clone(),
+                            ..error.clone()
+                        };
+                        emit(&params.events_tx, AgentEvent::CandidateError(error));
+                    }
+                }
                 return;
             }
-            if url.password.is_empty() {
-                log::error!(
-                    "Failed to gather relay candidates: {:?}",
-                    Error::ErrPasswordEmpty
-                );
+        };
+        let base = match conn.local_addr().await {
+            Ok(base) => base,
+            Err(err) => {
+                log::warn!("could not get local addr: {}", err);
+                let _ = conn.close().await;
                 return;
             }
+        };
+        let conn = Arc::new(SrflxConn::new(&params.runtime, conn));
 
-            let network = NetworkType::Udp4.to_string();
-            let net2 = Arc::clone(&net);
-            let agent_internal2 = Arc::clone(&agent_internal);
+        let gathered = Arc::new(AtomicBool::new(false));
+        let wg = WaitGroup::new();
+        for url in &params.urls {
+            let network = network.clone();
+            let url = url.clone();
+            let params2 = params.clone();
+            let conn2 = Arc::clone(&conn);
+            let mapped_addrs2 = Arc::clone(&mapped_addrs);
+            let gathered2 = Arc::clone(&gathered);
 
             let w = wg.worker();
-            tokio::spawn(async move {
+            runtime::spawn(&params.runtime, async move {
                 let _d = w;
 
-                let turn_server_addr = format!("{}:{}", url.host, url.port);
-
-                let (loc_conn, rel_addr, rel_port) =
-                    if url.proto == ProtoType::Udp && url.scheme == SchemeType::Turn {
-                        let loc_conn = match net2.bind(SocketAddr::from_str("0.0.0.0:0")?).await {
-                            Ok(c) => c,
-                            Err(err) => {
-                                log::warn!("Failed to listen due to error: {}", err);
-                                return Ok(());
-                            }
-                        };
+                let host_port = format!("{}:{}", url.host, url.port);
+                let resolve = runtime::timeout(
+                    &params2.runtime,
+                    params2.server_timeout,
+                    runtime::resolve_addrs(
+                        &params2.net,
+                        &params2.runtime,
+                        network_type.is_ipv4(),
+                        &host_port,
+                    ),
+                );
+                let server_addrs = match params2.cancel.run(resolve).await {
+                    Some(Ok(Ok(addrs))) => addrs,
+                    Some(Ok(Err(err))) | Some(Err(err)) => {
+                        log::warn!("failed to resolve stun host: {}: {}", host_port, err);
+                        emit_candidate_error(&params2.events_tx, &url, None, err);
+                        return;
+                    }
+                    None => return,
+                };
 
-                        let local_addr = loc_conn.local_addr().await?;
-                        let rel_addr = local_addr.ip().to_string();
-                        let rel_port = local_addr.port();
-                        (loc_conn, rel_addr, rel_port)
-                    /*TODO: case url.proto == ProtoType::UDP && url.scheme == SchemeType::TURNS{
-                    case a.proxyDialer != nil && url.Proto == ProtoTypeTCP && (url.Scheme == SchemeTypeTURN || url.Scheme == SchemeTypeTURNS):
-                    case url.Proto == ProtoTypeTCP && url.Scheme == SchemeTypeTURN:
-                    case url.Proto == ProtoTypeTCP && url.Scheme == SchemeTypeTURNS:*/
-                    } else {
-                        log::warn!("Unable to handle URL in gather_candidates_relay {}", url);
-                        return Ok(());
+                // The addresses of the server are tried in turn until one of them answers, each
+                // for as long as the retransmissions of the binding request take
+                let mut result = Err(Error::ErrTimeout);
+                for server_addr in server_addrs {
+                    let request = conn2.get_xormapped_addr(
+                        &params2.runtime,
+                        server_addr,
+                        &params2.stun_retransmission,
+                    );
+                    result = match params2.cancel.run(request).await {
+                        Some(result) => result,
+                        None => return,
                     };
-
-                let cfg = turn::client::ClientConfig {
-                    stun_serv_addr: String::new(),
-                    turn_serv_addr: turn_server_addr.clone(),
-                    username: url.username,
-                    password: url.password,
-                    realm: String::new(),
-                    software: String::new(),
-                    rto_in_ms: 0,
-                    conn: loc_conn,
-                    vnet: Some(Arc::clone(&net2)),
-                };
-                let client = match turn::client::Client::new(cfg).await {
-                    Ok(client) => Arc::new(client),
-                    Err(err) => {
-                        log::warn!(
-                            "Failed to build new turn.Client {} {}\n",
-                            turn_server_addr,
+                    match &result {
+                        Ok(_) => break,
+                        Err(err) => log::debug!(
+                            "no server reflexive address from {} at {}: {}",
+                            url,
+                            server_addr,
                             err
-                        );
-                        return Ok(());
+                        ),
                     }
-                };
-                if let Err(err) = client.listen().await {
-                    let _ = client.close().await;
-                    log::warn!(
-                        "Failed to listen on turn.Client {} {}",
-                        turn_server_addr,
-                        err
-                    );
-                    return Ok(());
                 }
 
-                let relay_conn = match client.allocate().await {
-                    Ok(conn) => conn,
+                let xoraddr = match result {
+                    Ok(xoraddr) => xoraddr,
                     Err(err) => {
-                        let _ = client.close().await;
                         log::warn!(
-                            "Failed to allocate on turn.Client {} {}",
-                            turn_server_addr,
+                            "could not get server reflexive address {} {}: {}",
+                            network,
+                            url,
                             err
                         );
-                        return Ok(());
+                        emit_candidate_error(&params2.events_tx, &url, Some(base), err);
+                        return;
                     }
                 };
 
-                let raddr = relay_conn.local_addr().await?;
-                let relay_config = CandidateRelayConfig {
+                let (ip, port) = (xoraddr.ip, xoraddr.port);
+                if !is_ip_allowed(ip, &params2.ip_filter) {
+                    log::debug!("server reflexive address {} is filtered out", ip);
+                    return;
+                }
+                if !mapped_addrs2
+                    .lock()
+                    .unwrap()
+                    .insert((base, SocketAddr::new(ip, port)))
+                {
+                    log::debug!(
+                        "server reflexive address {}:{} already gathered from {}",
+                        ip,
+                        port,
+                        base
+                    );
+                    return;
+                }
+
+                let srflx_config = CandidateServerReflexiveConfig {
                     base_config: CandidateBaseConfig {
                         network: network.clone(),
-                        address: raddr.ip().to_string(),
-                        port: raddr.port(),
+                        address: ip.to_string(),
+                        port,
                         component: COMPONENT_RTP,
-                        conn: Some(Arc::new(relay_conn)),
+                        conn: Some(conn2),
                         ..CandidateBaseConfig::default()
                     },
-                    rel_addr,
-                    rel_port,
-                    relay_client: Some(Arc::clone(&client)),
+                    rel_addr: base.ip().to_string(),
+                    rel_port: base.port(),
                 };
 
                 let candidate: Arc<dyn Candidate + Send + Sync> =
-                    match relay_config.new_candidate_relay().await {
+                    match srflx_config.new_candidate_server_reflexive().await {
                         Ok(candidate) => Arc::new(candidate),
                         Err(err) => {
-                            let _ = client.close().await;
                             log::warn!(
-                                "Failed to create relay candidate: {} {}: {}",
+                                "Failed to create server reflexive candidate: {} {} {}: {:?}",
                                 network,
-                                raddr,
+                                ip,
+                                port,
                                 err
                             );
-                            return Ok(());
+                            return;
                         }
                     };
 
+                if add_gathered_candidate(&params2.agent_internal, &candidate, &params2.cancel)
+                    .await
                 {
-                    let mut ai = agent_internal2.lock().await;
-                    if let Err(err) = ai.add_candidate(&candidate, &agent_internal2).await {
-                        if let Err(close_err) = candidate.close().await {
-                            log::warn!("Failed to close candidate: {}", close_err);
-                        }
+                    gathered2.store(true, Ordering::SeqCst);
+                }
+            });
+        }
+
+        wg.wait().await;
+        conn.finish();
+        if !gathered.load(Ordering::SeqCst) {
+            let _ = conn.close().await;
+        }
+    }
+
+    pub(crate) async fn gather_candidates_relay(params: GatherCandidatesRelayParams) {
+        let wg = WaitGroup::new();
+
+        for url in &params.urls {
+            if url.scheme != SchemeType::Turn && url.scheme != SchemeType::Turns {
+                continue;
+            }
+            /*TODO: case url.proto == ProtoType::UDP && url.scheme == SchemeType::TURNS{
+            case a.proxyDialer != nil && url.Proto == ProtoTypeTCP && (url.Scheme == SchemeTypeTURN || url.Scheme == SchemeTypeTURNS):
+            case url.Proto == ProtoTypeTCP && url.Scheme == SchemeTypeTURN:
+            case url.Proto == ProtoTypeTCP && url.Scheme == SchemeTypeTURNS:*/
+            if url.proto != ProtoType::Udp || url.scheme != SchemeType::Turn {
+                log::warn!("Unable to handle URL in gather_candidates_relay {}", url);
+                emit_candidate_error(&params.events_tx, url, None, Error::ErrProtoType);
+                continue;
+            }
+
+            let params2 = params.clone();
+            let url = url.clone();
+            let w = wg.worker();
+            runtime::spawn(&params.runtime, async move {
+                let _d = w;
+
+                let server_addrs = match Self::resolve_relay_server(&params2, &url).await {
+                    Some(server_addrs) => server_addrs,
+                    None => return,
+                };
+                let credentials = match Self::relay_credentials(&params2, &url).await {
+                    Some(credentials) => credentials,
+                    None => return,
+                };
+
+                // One allocation for both families, on the IPv4 address of the server if it has
+                // one
+                let udp = |is_ipv4| {
+                    params2
+                        .network_types
+                        .iter()
+                        .any(|t| t.is_udp() && t.is_ipv4() == is_ipv4)
+                };
+                let family = match (udp(true), udp(false)) {
+                    (true, true) => AllocationFamily::DualStack,
+                    (true, false) => AllocationFamily::Ipv4,
+                    (false, _) => AllocationFamily::Ipv6,
+                };
+                let turn_conn = Self::allocate_relays(
+                    &params2,
+                    &url,
+                    server_addrs[0],
+                    family,
+                    credentials.clone(),
+                )
+                .await;
+
+                // Servers which don't know ADDITIONAL-ADDRESS-FAMILY ignore it and allocate an
+                // IPv4 relay only, the IPv6 one is allocated on its own then
+                let ipv6_missing = turn_conn.is_some_and(|turn_conn| {
+                    !turn_conn.relayed_addrs().iter().any(SocketAddr::is_ipv6)
+                        && turn_conn.address_error().is_none()
+                });
+                if family == AllocationFamily::DualStack && ipv6_missing {
+                    if let Some(server_addr) = server_addrs.iter().find(|a| a.is_ipv6()) {
+                        Self::allocate_relays(
+                            &params2,
+                            &url,
+                            *server_addr,
+                            AllocationFamily::Ipv6,
+                            credentials,
+                        )
+                        .await;
+                    }
+                }
+            });
+        }
+
+        wg.wait().await;
+    }
+
+    /// Resolves the TURN server at `url` to an address of each IP family of the UDP network
+    /// types, the families it has no address of are skipped.
+    async fn resolve_relay_server(
+        params: &GatherCandidatesRelayParams,
+        url: &Url,
+    ) -> Option<Vec<SocketAddr>> {
+        let host_port = if url.host.contains(':') {
+            format!("[{}]:{}", url.host, url.port)
+        } else {
+            format!("{}:{}", url.host, url.port)
+        };
+
+        let mut server_addrs = vec![];
+        let mut last_err = None;
+        for is_ipv4 in [true, false] {
+            if !params
+                .network_types
+                .iter()
+                .any(|t| t.is_udp() && t.is_ipv4() == is_ipv4)
+            {
+                continue;
+            }
+
+            let resolve = runtime::timeout(
+                &params.runtime,
+                params.server_timeout,
+                runtime::resolve_addrs(&params.net, &params.runtime, is_ipv4, &host_port),
+            );
+            match params.cancel.run(resolve).await {
+                Some(Ok(Ok(addrs))) => {
+                    if let Some(addr) = addrs.into_iter().find(|a| a.is_ipv4() == is_ipv4) {
+                        server_addrs.push(addr);
+                    }
+                }
+                Some(Ok(Err(err))) | Some(Err(err)) => {
+                    log::debug!("failed to resolve turn host: {}: {}", host_port, err);
+                    last_err = Some(err);
+                }
+                None => return None,
+            }
+        }
+
+        if server_addrs.is_empty() {
+            if let Some(err) = last_err {
+                log::warn!("failed to resolve turn host: {}: {}", host_port, err);
+                emit_candidate_error(&params.events_tx, url, None, err);
+            }
+            return None;
+        }
+        Some(server_addrs)
+    }
+
+    /// Returns the credentials for the TURN server at `url`, from the credential provider if
+    /// there is one and from the URL otherwise.
+    async fn relay_credentials(
+        params: &GatherCandidatesRelayParams,
+        url: &Url,
+    ) -> Option<(TurnCredentials, Option<OauthToken>)> {
+        let credentials = match &params.credential_provider {
+            Some(credential_provider) => credential_provider.credentials(url).await,
+            None if url.username.is_empty() => Err(Error::ErrUsernameEmpty),
+            None if url.password.is_empty() => Err(Error::ErrPasswordEmpty),
+            None => Ok(TurnCredentials::from(url)),
+        };
+        let credentials = credentials.and_then(|credentials| {
+            let oauth_token = match credentials.credential_type {
+                CredentialType::Password => None,
+                CredentialType::Oauth => Some(OauthToken::new(&credentials)?),
+            };
+            Ok((credentials, oauth_token))
+        });
+        match credentials {
+            Ok(credentials) => Some(credentials),
+            Err(err) => {
+                log::error!("Failed to gather relay candidates from {}: {}", url, err);
+                emit_candidate_error(&params.events_tx, url, None, err);
+                None
+            }
+        }
+    }
+
+    /// Allocates the relays of `family` on the TURN server at `url`, reached at `server_addr`,
+    /// adds their candidates and renews the credentials they were allocated with until they are
+    /// closed. Returns the connection of the TURN client of the allocation, if it was added.
+    async fn allocate_relays(
+        params: &GatherCandidatesRelayParams,
+        url: &Url,
+        server_addr: SocketAddr,
+        family: AllocationFamily,
+        credentials: (TurnCredentials, Option<OauthToken>),
+    ) -> Option<Arc<TurnRequestConn>> {
+        let (candidates, turn_conn, expires) =
+            Self::gather_relay_candidates(params, url, server_addr, family, credentials).await?;
+        if let Some(expires) = expires {
+            runtime::spawn(
+                &params.runtime,
+                Self::renew_relay_credentials(
+                    params.clone(),
+                    url.clone(),
+                    candidates,
+                    Arc::clone(&turn_conn),
+                    expires,
+                ),
+            );
+        }
+        Some(turn_conn)
+    }
+
+    /// Allocates the relays of `family` on the TURN server at `url`, reached at `server_addr`,
+    /// and adds their candidates, returning them along with the connection of their TURN client
+    /// and the expiry of the credentials they were allocated with.
+    async fn gather_relay_candidates(
+        params: &GatherCandidatesRelayParams,
+        url: &Url,
+        server_addr: SocketAddr,
+        family: AllocationFamily,
+        (credentials, oauth_token): (TurnCredentials, Option<OauthToken>),
+    ) -> Option<(
+        Vec<Arc<dyn Candidate + Send + Sync>>,
+        Arc<TurnRequestConn>,
+        Option<SystemTime>,
+    )> {
+        let turn_server_addr = server_addr.to_string();
+
+        let bind_addr = if server_addr.is_ipv4() {
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+        } else {
+            SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
+        };
+        let loc_conn = match runtime::bind(&params.net, &params.runtime, bind_addr).await {
+            Ok(c) => c,
+            Err(err) => {
+                log::warn!("Failed to listen due to error: {}", err);
+                emit_candidate_error(&params.events_tx, url, None, err);
+                return None;
+            }
+        };
+        let local_addr = match loc_conn.local_addr().await {
+            Ok(local_addr) => local_addr,
+            Err(err) => {
+                log::warn!("could not get local addr: {}", err);
+                return None;
+            }
+        };
+
+        let turn_conn = Arc::new(TurnRequestConn::new(
+            loc_conn,
+            credentials.username.clone(),
+            credentials.password.clone(),
+            oauth_token,
+            family,
+        ));
+        let cfg = turn::client::ClientConfig {
+            stun_serv_addr: String::new(),
+            turn_serv_addr: turn_server_addr.clone(),
+            username: credentials.username,
+            password: credentials.password,
+            realm: String::new(),
+            software: String::new(),
+            rto_in_ms: 0,
+            conn: Arc::clone(&turn_conn) as Arc<dyn Conn + Send + Sync>,
+            vnet: Some(Arc::clone(&params.net)),
+        };
+        let client = match turn::client::Client::new(cfg).await {
+            Ok(client) => Arc::new(client),
+            Err(err) => {
+                log::warn!(
+                    "Failed to build new turn.Client {} {}\n",
+                    turn_server_addr,
+                    err
+                );
+                emit_candidate_error(&params.events_tx, url, Some(local_addr), err.into());
+                return None;
+            }
+        };
+        let allocate = runtime::timeout(&params.runtime, params.server_timeout, async {
+            client.listen().await?;
+            client
+                .allocate()
+                .await
+                .map_err(|err| Error::from_turn_allocate(err, turn_conn.allocate_error()))
+        });
+        let relay_conn = match params.cancel.run(allocate).await {
+            Some(Ok(Ok(conn))) => conn,
+            Some(Ok(Err(err))) | Some(Err(err)) => {
+                let _ = client.close().await;
+                log::warn!(
+                    "Failed to allocate on turn.Client {} {}",
+                    turn_server_addr,
+                    err
+                );
+                emit_candidate_error(&params.events_tx, url, Some(local_addr), err);
+                return None;
+            }
+            None => {
+                // Stops the allocation which may be in flight
+                let _ = client.close().await;
+                return None;
+            }
+        };
+
+        // The client only reports the first relayed address of a dual-stack allocation
+        let mut raddrs = turn_conn.relayed_addrs();
+        if raddrs.is_empty() {
+            match relay_conn.local_addr().await {
+                Ok(raddr) => raddrs.push(raddr),
+                Err(err) => {
+                    let _ = client.close().await;
+                    log::warn!("could not get relay addr: {}", err);
+                    return None;
+                }
+            }
+        }
+        if let Some((code, reason)) = turn_conn.address_error() {
+            log::warn!(
+                "turn server {} allocated no relay of one family: {} {}",
+                turn_server_addr,
+                code,
+                reason
+            );
+        }
+        raddrs.retain(|raddr| {
+            let allowed = is_ip_allowed(raddr.ip(), &params.ip_filter);
+            if !allowed {
+                log::debug!("relay address {} is filtered out", raddr);
+            }
+            allowed
+        });
+        let relay_conn: Arc<dyn Conn + Send + Sync> = Arc::new(relay_conn);
+        let relay_conns: Vec<Arc<dyn Conn + Send + Sync>> = match raddrs.len() {
+            0 => {
+                let _ = client.close().await;
+                return None;
+            }
+            1 => vec![relay_conn],
+            _ => RelayFamilyConn::split(&params.runtime, relay_conn, &raddrs)
+                .into_iter()
+                .map(|conn| Arc::new(conn) as Arc<dyn Conn + Send + Sync>)
+                .collect(),
+        };
+
+        let mut candidates = vec![];
+        for (raddr, relay_conn) in raddrs.into_iter().zip(relay_conns) {
+            // The server may not know REQUESTED-ADDRESS-FAMILY and allocate another family
+            let network = if raddr.is_ipv4() {
+                NetworkType::Udp4
+            } else {
+                NetworkType::Udp6
+            }
+            .to_string();
+            let relay_config = CandidateRelayConfig {
+                base_config: CandidateBaseConfig {
+                    network: network.clone(),
+                    address: raddr.ip().to_string(),
+                    port: raddr.port(),
+                    component: COMPONENT_RTP,
+                    conn: Some(relay_conn),
+                    ..CandidateBaseConfig::default()
+                },
+                rel_addr: local_addr.ip().to_string(),
+                rel_port: local_addr.port(),
+                relay_client: Some(Arc::clone(&client)),
+            };
+
+            let candidate: Arc<dyn Candidate + Send + Sync> =
+                match relay_config.new_candidate_relay().await {
+                    Ok(candidate) => Arc::new(candidate),
+                    Err(err) => {
                         log::warn!(
-                            "Failed to append to localCandidates and run onCandidateHdlr: {}",
+                            "Failed to create relay candidate: {} {}: {}",
+                            network,
+                            raddr,
                             err
                         );
+                        continue;
                     }
-                }
+                };
 
-                Result::<()>::Ok(())
-            });
+            if add_gathered_candidate(&params.agent_internal, &candidate, &params.cancel).await {
+                candidates.push(candidate);
+            }
         }
 
-        wg.wait().await;
+        if candidates.is_empty() {
+            let _ = client.close().await;
+            return None;
+        }
+        Some((candidates, turn_conn, credentials.expires))
+    }
+
+    /// Renews the credentials of the relay `candidates` of an allocation before the credentials
+    /// it was allocated with `expires`. The TURN client refreshes its allocation and permissions
+    /// with the credentials it was created with, which the server rejects once they expired, so
+    /// its `turn_conn` signs the requests with the renewed ones instead.
+    ///
+    /// Renewals happen halfway to the expiry, like the allocations are refreshed halfway through
+    /// their lifetime, until the candidates are closed by a restart or the agent closing, both
+    /// of which close all candidates of the allocation.
+    async fn renew_relay_credentials(
+        params: GatherCandidatesRelayParams,
+        url: Url,
+        candidates: Vec<Arc<dyn Candidate + Send + Sync>>,
+        turn_conn: Arc<TurnRequestConn>,
+        mut expires: SystemTime,
+    ) {
+        let candidate = &candidates[0];
+        let mut closed_rx = {
+            let closed_ch = candidate.get_closed_ch();
+            let closed_ch = closed_ch.lock().await;
+            match &*closed_ch {
+                Some(closed_ch) => closed_ch.subscribe(),
+                None => return,
+            }
+        };
+
+        loop {
+            let remaining = expires
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+            if remaining / 2 < MIN_RELAY_RENEWAL_INTERVAL {
+                log::warn!(
+                    "credentials of relay candidate {} expire too soon to renew them",
+                    candidate
+                );
+                return;
+            }
+
+            let renewal = async {
+                params
+                    .runtime
+                    .sleep_until(Instant::now() + remaining / 2)
+                    .await;
+                log::debug!("renewing credentials of relay candidate {}", candidate);
+                Self::relay_credentials(&params, &url).await
+            };
+            let credentials = tokio::select! {
+                credentials = renewal => credentials,
+                _ = closed_rx.recv() => return,
+            };
+
+            if let Some((credentials, oauth_token)) = credentials {
+                turn_conn.update_credentials(
+                    credentials.username,
+                    credentials.password,
+                    oauth_token,
+                );
+                expires = match credentials.expires {
+                    Some(expires) => expires,
+                    None => return,
+                };
+            }
+        }
+    }
+}
+
+/// Sends an event to the subscribers, unless the agent closed and dropped its sender.
+fn emit(events_tx: &Option<broadcast::WeakSender<AgentEvent>>, event: AgentEvent) {
+    if let Some(events_tx) = events_tx.as_ref().and_then(broadcast::WeakSender::upgrade) {
+        let _ = events_tx.send(event);
+    }
+}
+
+fn emit_candidate_error(
+    events_tx: &Option<broadcast::WeakSender<AgentEvent>>,
+    url: &Url,
+    local_addr: Option<SocketAddr>,
+    err: Error,
+) {
+    emit(
+        events_tx,
+        AgentEvent::CandidateError(CandidateError::new(url.clone(), local_addr, err)),
+    );
+}
+
+/// Adds a gathered candidate to the agent, or closes it if the gathering was cancelled.
+async fn add_gathered_candidate(
+    agent_internal: &Arc<Mutex<AgentInternal>>,
+    candidate: &Arc<dyn Candidate + Send + Sync>,
+    cancel: &GatherCancel,
+) -> bool {
+    let mut ai = agent_internal.lock().await;
+    if cancel.is_cancelled() {
+        log::debug!(
+            "gathering was cancelled, discarding candidate {}",
+            candidate
+        );
+        if let Err(err) = candidate.close().await {
+            log::warn!("Failed to close candidate: {}", err);
+        }
+        return false;
+    }
+
+    if let Err(err) = ai.add_candidate(candidate, agent_internal).await {
+        if let Err(close_err) = candidate.close().await {
+            log::warn!("Failed to close candidate: {}", close_err);
+        }
+        log::warn!(
+            "Failed to append to localCandidates and run onCandidateHdlr: {}",
+            err
+        );
+        return false;
     }
+    true
 }
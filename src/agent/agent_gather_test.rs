@@ -1,10 +1,18 @@
+use super::agent_gather::GatherCandidatesRelayParams;
+use super::agent_turn_conn_test::fake_turn_server;
 use super::agent_vnet_test::*;
 use super::*;
+use crate::candidate::candidate_base::CandidateBaseConfig;
+use crate::candidate::candidate_host::CandidateHostConfig;
+use crate::runtime::{Runtime, TokioRuntime};
+use crate::turn_credentials::TurnRestCredentialProvider;
 use crate::util::*;
 
+use async_trait::async_trait;
 use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
-use util::vnet::*;
+use util::{vnet::*, Conn};
 
 #[tokio::test]
 async fn test_vnet_gather_no_local_ip_address() -> Result<()> {
@@ -16,7 +24,16 @@ async fn test_vnet_gather_no_local_ip_address() -> Result<()> {
     })
     .await?;
 
-    let local_ips = local_interfaces(&vnet, &a.interface_filter, &[NetworkType::Udp4]).await;
+    let local_ips = local_interfaces(
+        &vnet,
+        &a.interface_filter,
+        &a.ip_filter,
+        &[NetworkType::Udp4],
+        a.include_loopback,
+        a.include_link_local,
+        &a.ipv6_address_classes,
+    )
+    .await;
     assert!(local_ips.is_empty(), "should return no local IP");
 
     a.close().await?;
@@ -25,7 +42,7 @@ async fn test_vnet_gather_no_local_ip_address() -> Result<()> {
 }
 
 #[tokio::test]
-async fn test_vnet_gather_dynamic_ip_address() -> Result<()> {
+async fn test_vnet_gather_dynamic_ip_address() -> anyhow::Result<()> {
     let cider = "1.2.3.0/24";
     let ipnet = IpNet::from_str(cider)?;
 
@@ -42,14 +59,23 @@ async fn test_vnet_gather_dynamic_ip_address() -> Result<()> {
     })
     .await?;
 
-    let local_ips = local_interfaces(&nw, &a.interface_filter, &[NetworkType::Udp4]).await;
+    let local_ips = local_interfaces(
+        &nw,
+        &a.interface_filter,
+        &a.ip_filter,
+        &[NetworkType::Udp4],
+        a.include_loopback,
+        a.include_link_local,
+        &a.ipv6_address_classes,
+    )
+    .await;
     assert!(!local_ips.is_empty(), "should have one local IP");
 
     for ip in &local_ips {
-        if ip.is_loopback() {
+        if ip.ip.is_loopback() {
             panic!("should not return loopback IP");
         }
-        if !ipnet.contains(ip) {
+        if !ipnet.contains(&ip.ip) {
             panic!("{} should be contained in the CIDR {}", ip, ipnet);
         }
     }
@@ -75,20 +101,30 @@ async fn test_vnet_gather_listen_udp() -> Result<()> {
     })
     .await?;
 
-    let local_ips = local_interfaces(&nw, &a.interface_filter, &[NetworkType::Udp4]).await;
+    let local_ips = local_interfaces(
+        &nw,
+        &a.interface_filter,
+        &a.ip_filter,
+        &[NetworkType::Udp4],
+        a.include_loopback,
+        a.include_link_local,
+        &a.ipv6_address_classes,
+    )
+    .await;
     assert!(!local_ips.is_empty(), "should have one local IP");
 
-    let ip = local_ips[0];
+    let ip = local_ips[0].ip;
+    let runtime: Arc<dyn Runtime> = Arc::new(TokioRuntime);
 
-    let _ = listen_udp_in_port_range(&nw, 0, 0, SocketAddr::new(ip, 0)).await?;
+    let _ = listen_udp_in_port_range(&nw, &runtime, 0, 0, SocketAddr::new(ip, 0)).await?;
 
-    let result = listen_udp_in_port_range(&nw, 4999, 5000, SocketAddr::new(ip, 0)).await;
+    let result = listen_udp_in_port_range(&nw, &runtime, 4999, 5000, SocketAddr::new(ip, 0)).await;
     assert!(
         result.is_err(),
         "listenUDP with invalid port range did not return ErrPort"
     );
 
-    let conn = listen_udp_in_port_range(&nw, 5000, 5000, SocketAddr::new(ip, 0)).await?;
+    let conn = listen_udp_in_port_range(&nw, &runtime, 5000, 5000, SocketAddr::new(ip, 0)).await?;
     let port = conn.local_addr().await?.port();
     assert_eq!(
         port, 5000,
@@ -332,7 +368,16 @@ async fn test_vnet_gather_with_interface_filter() -> Result<()> {
         })
         .await?;
 
-        let local_ips = local_interfaces(&nw, &a.interface_filter, &[NetworkType::Udp4]).await;
+        let local_ips = local_interfaces(
+            &nw,
+            &a.interface_filter,
+            &a.ip_filter,
+            &[NetworkType::Udp4],
+            a.include_loopback,
+            a.include_link_local,
+            &a.ipv6_address_classes,
+        )
+        .await;
         assert!(
             local_ips.is_empty(),
             "InterfaceFilter should have excluded everything"
@@ -352,7 +397,16 @@ async fn test_vnet_gather_with_interface_filter() -> Result<()> {
         })
         .await?;
 
-        let local_ips = local_interfaces(&nw, &a.interface_filter, &[NetworkType::Udp4]).await;
+        let local_ips = local_interfaces(
+            &nw,
+            &a.interface_filter,
+            &a.ip_filter,
+            &[NetworkType::Udp4],
+            a.include_loopback,
+            a.include_link_local,
+            &a.ipv6_address_classes,
+        )
+        .await;
         assert_eq!(
             local_ips.len(),
             1,
@@ -365,6 +419,48 @@ async fn test_vnet_gather_with_interface_filter() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_vnet_gather_with_ip_filter() -> Result<()> {
+    let r = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "10.0.0.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let nw = Arc::new(net::Net::new(Some(net::NetConfig {
+        static_ips: vec!["10.0.0.1".to_owned(), "10.0.0.2".to_owned()],
+        ..Default::default()
+    })));
+    connect_net2router(&nw, &r).await?;
+
+    let a = Agent::new(AgentConfig {
+        net: Some(Arc::clone(&nw)),
+        ip_filter: Arc::new(Some(Box::new(|ip: IpAddr| -> bool {
+            ip != IpAddr::from_str("10.0.0.2").unwrap()
+        }))),
+        ..Default::default()
+    })
+    .await?;
+
+    let local_ips = local_interfaces(
+        &nw,
+        &a.interface_filter,
+        &a.ip_filter,
+        &[NetworkType::Udp4],
+        a.include_loopback,
+        a.include_link_local,
+        &a.ipv6_address_classes,
+    )
+    .await;
+    assert_eq!(
+        local_ips.iter().map(|ip| ip.ip).collect::<Vec<IpAddr>>(),
+        vec![IpAddr::from_str("10.0.0.1")?],
+        "IpFilter should have excluded 10.0.0.2"
+    );
+
+    a.close().await?;
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_vnet_gather_turn_connection_leak() -> Result<()> {
     let turn_server_url = Url {
@@ -374,6 +470,7 @@ async fn test_vnet_gather_turn_connection_leak() -> Result<()> {
         username: "user".to_owned(),
         password: "pass".to_owned(),
         proto: ProtoType::Udp,
+        ..Default::default()
     };
 
     // buildVNet with a Symmetric NATs for both LANs
@@ -398,11 +495,19 @@ async fn test_vnet_gather_turn_connection_leak() -> Result<()> {
 
     {
         let agent_internal = Arc::clone(&a_agent.agent_internal);
-        Agent::gather_candidates_relay(
-            vec![turn_server_url.clone()],
-            Arc::clone(&v.net0),
+        let (_cancel_tx, cancel) = GatherCancel::new(Arc::clone(&a_agent.runtime), None);
+        Agent::gather_candidates_relay(GatherCandidatesRelayParams {
+            urls: vec![turn_server_url.clone()],
+            network_types: a_agent.network_types.clone(),
+            ip_filter: Arc::clone(&a_agent.ip_filter),
+            net: Arc::clone(&v.net0),
+            runtime: Arc::clone(&a_agent.runtime),
             agent_internal,
-        )
+            events_tx: None,
+            cancel,
+            server_timeout: a_agent.gathering_server_timeout,
+            credential_provider: None,
+        })
         .await;
     }
 
@@ -412,3 +517,782 @@ async fn test_vnet_gather_turn_connection_leak() -> Result<()> {
 
     Ok(())
 }
+
+// Gathers host candidates on loopback and a server reflexive candidate from a STUN server which
+// never answers.
+async fn gather_unresponsive_server(config: AgentConfig) -> Result<(Agent, AgentEvents)> {
+    let port = {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        socket.local_addr()?.port()
+    };
+    let a = Agent::new(AgentConfig {
+        urls: vec![Url {
+            scheme: SchemeType::Stun,
+            host: "127.0.0.1".to_owned(),
+            port,
+            proto: ProtoType::Udp,
+            ..Default::default()
+        }],
+        network_types: vec![NetworkType::Udp4],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        include_loopback: true,
+        interface_filter: Arc::new(Some(Box::new(|name: &str| name.starts_with("lo")))),
+        ..config
+    })
+    .await?;
+    let events = a.subscribe().await;
+    a.gather_candidates().await?;
+    Ok((a, events))
+}
+
+// Waits for gathering to complete, returning the errors of the servers.
+async fn gathering_complete(events: &mut AgentEvents) -> Vec<Arc<Error>> {
+    let mut errors = vec![];
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(3), events.recv())
+            .await
+            .expect("gathering completes")
+            .expect("the agent is open");
+        match event {
+            AgentEvent::GatheringStateChange(GatheringState::Complete) => return errors,
+            AgentEvent::CandidateError(err) => errors.push(err.error),
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_gather_cancel() -> Result<()> {
+    let (a, mut events) = gather_unresponsive_server(AgentConfig {
+        gathering_server_timeout: Some(Duration::from_secs(30)),
+        ..Default::default()
+    })
+    .await?;
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    a.cancel_gathering();
+    assert!(gathering_complete(&mut events).await.is_empty());
+    assert!(
+        !a.get_local_candidates().await?.is_empty(),
+        "the candidates gathered before cancelling are kept"
+    );
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gather_timeout() -> Result<()> {
+    let (a, mut events) = gather_unresponsive_server(AgentConfig {
+        gathering_timeout: Some(Duration::from_millis(200)),
+        gathering_server_timeout: Some(Duration::from_secs(30)),
+        ..Default::default()
+    })
+    .await?;
+
+    assert!(gathering_complete(&mut events).await.is_empty());
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gather_stun_timeout() -> Result<()> {
+    // The server is given up on after the retransmissions, not the server timeout
+    let (a, mut events) = gather_unresponsive_server(AgentConfig {
+        gathering_server_timeout: Some(Duration::from_secs(30)),
+        stun_rto: Some(Duration::from_millis(20)),
+        stun_max_requests: Some(3),
+        stun_last_timeout_factor: Some(4),
+        ..Default::default()
+    })
+    .await?;
+
+    let errors = gathering_complete(&mut events).await;
+    assert_eq!(errors.len(), 1);
+    assert_eq!(*errors[0], Error::ErrTimeout);
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vnet_gather_relay_candidate_errors() -> Result<()> {
+    let turn_server_url = Url {
+        scheme: SchemeType::Turn,
+        host: VNET_STUN_SERVER_IP.to_owned(),
+        port: VNET_STUN_SERVER_PORT,
+        username: "user".to_owned(),
+        password: "pass".to_owned(),
+        proto: ProtoType::Udp,
+        ..Default::default()
+    };
+    let no_username_url = Url {
+        username: String::new(),
+        ..turn_server_url.clone()
+    };
+    let wrong_password_url = Url {
+        password: "wrong".to_owned(),
+        ..turn_server_url.clone()
+    };
+
+    let v = build_vnet(Default::default(), Default::default()).await?;
+
+    // The bad URLs come first, they must not keep the valid one from being used
+    let a = Agent::new(AgentConfig {
+        urls: vec![
+            no_username_url.clone(),
+            wrong_password_url.clone(),
+            turn_server_url,
+        ],
+        network_types: supported_network_types(),
+        candidate_types: vec![CandidateType::Relay],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        net: Some(Arc::clone(&v.net0)),
+        ..Default::default()
+    })
+    .await?;
+    let mut events = a.subscribe().await;
+    a.gather_candidates().await?;
+
+    let mut errors = vec![];
+    let mut relays = 0;
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(10), events.recv())
+            .await
+            .expect("gathering completes")
+            .expect("the agent is open");
+        match event {
+            AgentEvent::GatheringStateChange(GatheringState::Complete) => break,
+            AgentEvent::CandidateError(err) => errors.push(err),
+            AgentEvent::LocalCandidate(c) => {
+                assert_eq!(c.candidate_type(), CandidateType::Relay);
+                relays += 1;
+            }
+            _ => {}
+        }
+    }
+    assert_eq!(relays, 1);
+    errors.sort_by_key(|err| err.error_code);
+    assert_eq!(errors.len(), 2, "{:?}", errors);
+
+    assert_eq!(errors[0].url.to_string(), wrong_password_url.to_string());
+    // The test server rejects the integrity of the request as a bad request
+    assert_eq!(errors[0].error_code, 400);
+    assert!(errors[0].local_addr.is_some());
+    assert!(matches!(
+        *errors[0].error,
+        Error::ErrServerErrorResponse(400, _)
+    ));
+
+    assert_eq!(errors[1].url.to_string(), no_username_url.to_string());
+    assert_eq!(errors[1].error_code, CANDIDATE_ERROR_UNREACHABLE);
+    assert_eq!(errors[1].local_addr, None);
+    assert_eq!(*errors[1].error, Error::ErrUsernameEmpty);
+
+    a.close().await?;
+    v.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vnet_gather_relay_oauth() -> Result<()> {
+    let oauth_url = Url {
+        scheme: SchemeType::Turn,
+        host: VNET_STUN_SERVER_IP.to_owned(),
+        port: VNET_STUN_SERVER_PORT,
+        username: VNET_OAUTH_KID.to_owned(),
+        password: VNET_OAUTH_MAC_KEY.to_owned(),
+        proto: ProtoType::Udp,
+        credential_type: CredentialType::Oauth,
+        access_token: VNET_OAUTH_ACCESS_TOKEN.to_owned(),
+    };
+    let wrong_mac_key_url = Url {
+        password: base64::encode(b"wrong mac key"),
+        ..oauth_url.clone()
+    };
+    let no_access_token_url = Url {
+        access_token: String::new(),
+        ..oauth_url.clone()
+    };
+
+    let v = build_vnet(Default::default(), Default::default()).await?;
+
+    let a = Agent::new(AgentConfig {
+        urls: vec![
+            no_access_token_url.clone(),
+            wrong_mac_key_url.clone(),
+            oauth_url,
+        ],
+        network_types: supported_network_types(),
+        candidate_types: vec![CandidateType::Relay],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        net: Some(Arc::clone(&v.net0)),
+        ..Default::default()
+    })
+    .await?;
+    let mut events = a.subscribe().await;
+    a.gather_candidates().await?;
+
+    let mut errors = vec![];
+    let mut relays = 0;
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(10), events.recv())
+            .await
+            .expect("gathering completes")
+            .expect("the agent is open");
+        match event {
+            AgentEvent::GatheringStateChange(GatheringState::Complete) => break,
+            AgentEvent::CandidateError(err) => errors.push(err),
+            AgentEvent::LocalCandidate(c) => {
+                assert_eq!(c.candidate_type(), CandidateType::Relay);
+                relays += 1;
+            }
+            _ => {}
+        }
+    }
+    assert_eq!(relays, 1);
+    errors.sort_by_key(|err| err.error_code);
+    assert_eq!(errors.len(), 2, "{:?}", errors);
+
+    // The request is signed with the wrong MAC key
+    assert_eq!(errors[0].url.to_string(), wrong_mac_key_url.to_string());
+    assert_eq!(errors[0].error_code, 400);
+
+    assert_eq!(errors[1].url.to_string(), no_access_token_url.to_string());
+    assert_eq!(*errors[1].error, Error::ErrAccessTokenEmpty);
+
+    a.close().await?;
+    v.close().await?;
+
+    Ok(())
+}
+
+// A runtime which resolves every host to the given addresses.
+struct ResolveRuntime(Vec<SocketAddr>);
+
+#[async_trait]
+impl Runtime for ResolveRuntime {
+    fn spawn(&self, future: runtime::BoxFuture<()>) {
+        TokioRuntime.spawn(future);
+    }
+
+    fn sleep_until(&self, deadline: std::time::Instant) -> runtime::BoxFuture<()> {
+        TokioRuntime.sleep_until(deadline)
+    }
+
+    async fn bind_udp(&self, addr: SocketAddr) -> Result<Arc<dyn Conn + Send + Sync>> {
+        TokioRuntime.bind_udp(addr).await
+    }
+
+    async fn resolve_addr(&self, _use_ipv4: bool, _host: &str) -> Result<SocketAddr> {
+        Ok(self.0[0])
+    }
+
+    async fn resolve_addrs(&self, _use_ipv4: bool, _host: &str) -> Result<Vec<SocketAddr>> {
+        Ok(self.0.clone())
+    }
+}
+
+// Gathers the server reflexive candidates from `urls`.
+async fn gather_srflx(
+    urls: Vec<Url>,
+    config: AgentConfig,
+) -> Result<Vec<Arc<dyn Candidate + Send + Sync>>> {
+    let a = Agent::new(AgentConfig {
+        urls,
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::ServerReflexive],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        ..config
+    })
+    .await?;
+    let mut events = a.subscribe().await;
+    a.gather_candidates().await?;
+
+    let mut candidates = vec![];
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("gathering completes")
+            .expect("the agent is open");
+        match event {
+            AgentEvent::GatheringStateChange(GatheringState::Complete) => break,
+            AgentEvent::LocalCandidate(c) => candidates.push(c),
+            AgentEvent::CandidateError(err) => panic!("unexpected candidate error: {}", err),
+            _ => {}
+        }
+    }
+    a.close().await?;
+
+    Ok(candidates)
+}
+
+fn stun_url(host: &str, port: u16) -> Url {
+    Url {
+        scheme: SchemeType::Stun,
+        host: host.to_owned(),
+        port,
+        proto: ProtoType::Udp,
+        ..Default::default()
+    }
+}
+
+// Runs a STUN server on loopback, returning it with its address.
+async fn loopback_stun_server() -> Result<(turn::server::Server, SocketAddr)> {
+    let conn = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await?);
+    let server_addr = conn.local_addr()?;
+    let server = turn::server::Server::new(turn::server::config::ServerConfig {
+        conn_configs: vec![turn::server::config::ConnConfig {
+            conn,
+            relay_addr_generator: Box::new(turn::relay::relay_none::RelayAddressGeneratorNone {
+                address: "127.0.0.1".to_owned(),
+                net: Arc::new(net::Net::new(None)),
+            }),
+        }],
+        realm: "webrtc.rs".to_owned(),
+        auth_handler: Arc::new(Box::new(TestAuthHandler::new())),
+        channel_bind_timeout: Duration::from_secs(0),
+    })
+    .await?;
+    Ok((server, server_addr))
+}
+
+// Adds a STUN server at `ip` to the WAN of `v`.
+async fn add_wan_stun_server(
+    v: &super::agent_vnet_test::VNet,
+    ip: &str,
+) -> Result<turn::server::Server> {
+    let wnet = Arc::new(net::Net::new(Some(net::NetConfig {
+        static_ip: ip.to_owned(),
+        ..Default::default()
+    })));
+    connect_net2router(&wnet, &v.wan).await?;
+
+    let conn = wnet
+        .bind(SocketAddr::new(ip.parse()?, VNET_STUN_SERVER_PORT))
+        .await?;
+    let server = turn::server::Server::new(turn::server::config::ServerConfig {
+        conn_configs: vec![turn::server::config::ConnConfig {
+            conn,
+            relay_addr_generator: Box::new(turn::relay::relay_none::RelayAddressGeneratorNone {
+                address: ip.to_owned(),
+                net: wnet,
+            }),
+        }],
+        realm: "webrtc.rs".to_owned(),
+        auth_handler: Arc::new(Box::new(TestAuthHandler::new())),
+        channel_bind_timeout: Duration::from_secs(0),
+    })
+    .await?;
+    Ok(server)
+}
+
+// Gathers the server reflexive candidates of net0 behind a NAT of `mapping_behavior` from two
+// STUN servers on the WAN.
+async fn gather_srflx_behind_nat(
+    mapping_behavior: nat::EndpointDependencyType,
+) -> Result<Vec<Arc<dyn Candidate + Send + Sync>>> {
+    let nat_type = nat::NatType {
+        mapping_behavior,
+        filtering_behavior: nat::EndpointDependencyType::EndpointIndependent,
+        ..Default::default()
+    };
+    let v = build_vnet(nat_type, nat_type).await?;
+    let server = add_wan_stun_server(&v, "1.2.3.5").await?;
+
+    let candidates = gather_srflx(
+        vec![
+            stun_url(VNET_STUN_SERVER_IP, VNET_STUN_SERVER_PORT),
+            stun_url("1.2.3.5", VNET_STUN_SERVER_PORT),
+        ],
+        AgentConfig {
+            net: Some(Arc::clone(&v.net0)),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    server.close()?;
+    v.close().await?;
+
+    Ok(candidates)
+}
+
+#[tokio::test]
+async fn test_gather_srflx_server_failover() -> Result<()> {
+    let (server, server_addr) = loopback_stun_server().await?;
+    let unresponsive_addr = {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        socket.local_addr()?
+    };
+
+    // The first address of the server doesn't answer, the second one does
+    let candidates = gather_srflx(
+        vec![stun_url("stun.example.com", 3478)],
+        AgentConfig {
+            runtime: Some(Arc::new(ResolveRuntime(vec![
+                unresponsive_addr,
+                server_addr,
+            ]))),
+            stun_rto: Some(Duration::from_millis(20)),
+            stun_max_requests: Some(2),
+            stun_last_timeout_factor: Some(2),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    // The server maps the base, which sent the request from loopback
+    assert_eq!(candidates.len(), 1);
+    let base_port = candidates[0].related_address().expect("has a base").port;
+    assert_eq!(
+        candidates[0].addr(),
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), base_port)
+    );
+
+    server.close()?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vnet_gather_srflx_dedup() -> Result<()> {
+    // Behind a NAT with endpoint independent mapping, both servers see the same mapped address
+    let candidates =
+        gather_srflx_behind_nat(nat::EndpointDependencyType::EndpointIndependent).await?;
+    assert_eq!(
+        candidates.len(),
+        1,
+        "the identical candidate is gathered once"
+    );
+    assert_eq!(candidates[0].address(), VNET_GLOBAL_IPA);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vnet_gather_srflx_shared_base() -> Result<()> {
+    // Behind a symmetric NAT, each server sees its own mapping of the same base
+    let candidates =
+        gather_srflx_behind_nat(nat::EndpointDependencyType::EndpointAddrPortDependent).await?;
+    assert_eq!(candidates.len(), 2);
+    assert_ne!(candidates[0].addr(), candidates[1].addr());
+    assert_eq!(
+        candidates[0].related_address(),
+        candidates[1].related_address()
+    );
+    assert!(
+        shares_conn(&*candidates[0], &*candidates[1]),
+        "all servers are queried from the same base"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vnet_redundant_candidate_pair_pruning() -> Result<()> {
+    // Behind a symmetric NAT, both servers give a srflx candidate of the same base
+    let nat_type = nat::NatType {
+        mapping_behavior: nat::EndpointDependencyType::EndpointAddrPortDependent,
+        filtering_behavior: nat::EndpointDependencyType::EndpointIndependent,
+        ..Default::default()
+    };
+    let v = build_vnet(nat_type, nat_type).await?;
+    let server = add_wan_stun_server(&v, "1.2.3.5").await?;
+
+    let a = Agent::new(AgentConfig {
+        urls: vec![
+            stun_url(VNET_STUN_SERVER_IP, VNET_STUN_SERVER_PORT),
+            stun_url("1.2.3.5", VNET_STUN_SERVER_PORT),
+        ],
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Host, CandidateType::ServerReflexive],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        net: Some(Arc::clone(&v.net0)),
+        ..Default::default()
+    })
+    .await?;
+    let mut events = a.subscribe().await;
+    a.gather_candidates().await?;
+    assert!(gathering_complete(&mut events).await.is_empty());
+
+    let remote: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "udp".to_owned(),
+                address: VNET_GLOBAL_IPB.to_owned(),
+                port: 12350,
+                component: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .new_candidate_host()
+        .await?,
+    );
+
+    {
+        let mut ai = a.agent_internal.lock().await;
+        let locals = ai.core.get_local_candidates();
+        let srflx: Vec<_> = locals
+            .iter()
+            .filter(|c| c.candidate_type() == CandidateType::ServerReflexive)
+            .collect();
+        assert_eq!(srflx.len(), 2);
+        assert_eq!(locals.len(), 3);
+
+        // The host candidate has its own base, one of the srflx pairs is redundant
+        ai.core.add_remote_candidate(&remote);
+        let checklist = ai.core.checklist();
+        assert_eq!(checklist.len(), 2, "redundant pairs should be pruned");
+        assert!(checklist
+            .iter()
+            .any(|p| p.local.candidate_type() == CandidateType::Host));
+        assert!(checklist
+            .iter()
+            .any(|p| p.local.candidate_type() == CandidateType::ServerReflexive));
+    }
+
+    a.close().await?;
+    server.close()?;
+    v.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vnet_gather_relay_credential_renewal() -> Result<()> {
+    // The username and password of the URL are left empty, they come from the provider
+    let turn_server_url = Url {
+        scheme: SchemeType::Turn,
+        host: VNET_STUN_SERVER_IP.to_owned(),
+        port: VNET_STUN_SERVER_PORT,
+        proto: ProtoType::Udp,
+        ..Default::default()
+    };
+
+    let v = build_vnet(Default::default(), Default::default()).await?;
+
+    let a = Agent::new(AgentConfig {
+        urls: vec![turn_server_url],
+        network_types: supported_network_types(),
+        candidate_types: vec![CandidateType::Relay],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        net: Some(Arc::clone(&v.net0)),
+        turn_credential_provider: Some(Arc::new(TurnRestCredentialProvider::new(
+            VNET_TURN_REST_SECRET.to_owned(),
+            "alice".to_owned(),
+            Duration::from_secs(4),
+        ))),
+        ..Default::default()
+    })
+    .await?;
+    let mut events = a.subscribe().await;
+    a.gather_candidates().await?;
+    assert!(gathering_complete(&mut events).await.is_empty());
+    let relays = a.get_local_candidates().await?;
+    assert_eq!(relays.len(), 1);
+
+    // Once the credentials it was allocated with expired, the allocation is still usable with
+    // the renewed ones: a permission is created for a new peer
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    let peer: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "udp".to_owned(),
+                address: VNET_GLOBAL_IPB.to_owned(),
+                port: 12350,
+                component: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .new_candidate_host()
+        .await?,
+    );
+    relays[0].write_to(b"data", &*peer).await?;
+
+    // The candidate is kept rather than replaced
+    let local_candidates = a.get_local_candidates().await?;
+    assert_eq!(local_candidates.len(), 1);
+    assert!(local_candidates[0].equal(&*relays[0]));
+
+    a.close().await?;
+    v.close().await?;
+
+    Ok(())
+}
+
+// Allocates the relays on the given address whatever the family requested, as the TURN server
+// only allocates IPv4 relays by itself.
+struct LoopbackRelayAddressGenerator(IpAddr);
+
+#[async_trait]
+impl turn::relay::RelayAddressGenerator for LoopbackRelayAddressGenerator {
+    fn validate(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn allocate_conn(
+        &self,
+        _use_ipv4: bool,
+        requested_port: u16,
+    ) -> anyhow::Result<(Arc<dyn Conn + Send + Sync>, SocketAddr)> {
+        let conn = tokio::net::UdpSocket::bind(SocketAddr::new(self.0, requested_port)).await?;
+        let relay_addr = conn.local_addr()?;
+        Ok((Arc::new(conn), relay_addr))
+    }
+}
+
+async fn loopback_turn_server(ip: IpAddr) -> Result<(SocketAddr, turn::server::Server)> {
+    let conn = Arc::new(tokio::net::UdpSocket::bind(SocketAddr::new(ip, 0)).await?);
+    let server_addr = conn.local_addr()?;
+    let server = turn::server::Server::new(turn::server::config::ServerConfig {
+        realm: "webrtc.rs".to_owned(),
+        auth_handler: Arc::new(Box::new(TestAuthHandler::new())),
+        conn_configs: vec![turn::server::config::ConnConfig {
+            conn,
+            relay_addr_generator: Box::new(LoopbackRelayAddressGenerator(ip)),
+        }],
+        channel_bind_timeout: Duration::from_secs(0),
+    })
+    .await?;
+    Ok((server_addr, server))
+}
+
+#[tokio::test]
+async fn test_gather_relay_address_families() -> Result<()> {
+    let (server4_addr, server4) = loopback_turn_server(Ipv4Addr::LOCALHOST.into()).await?;
+    let (server6_addr, server6) = loopback_turn_server(Ipv6Addr::LOCALHOST.into()).await?;
+    let turn_server_url = Url {
+        scheme: SchemeType::Turn,
+        host: "turn.example.com".to_owned(),
+        port: 3478,
+        username: "user".to_owned(),
+        password: "pass".to_owned(),
+        proto: ProtoType::Udp,
+        ..Default::default()
+    };
+
+    for (network_types, expected) in [
+        // The TURN server ignores ADDITIONAL-ADDRESS-FAMILY, so the IPv6 relay is allocated on its
+        // own
+        (
+            vec![NetworkType::Udp4, NetworkType::Udp6],
+            vec![
+                (NetworkType::Udp4, Ipv4Addr::LOCALHOST.to_string()),
+                (NetworkType::Udp6, Ipv6Addr::LOCALHOST.to_string()),
+            ],
+        ),
+        (
+            vec![NetworkType::Udp6],
+            vec![(NetworkType::Udp6, Ipv6Addr::LOCALHOST.to_string())],
+        ),
+    ] {
+        let a = Agent::new(AgentConfig {
+            urls: vec![turn_server_url.clone()],
+            network_types,
+            candidate_types: vec![CandidateType::Relay],
+            multicast_dns_mode: MulticastDnsMode::Disabled,
+            runtime: Some(Arc::new(ResolveRuntime(vec![server4_addr, server6_addr]))),
+            ..Default::default()
+        })
+        .await?;
+        let mut events = a.subscribe().await;
+        a.gather_candidates().await?;
+
+        let mut relays = vec![];
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+                .await
+                .expect("gathering completes")
+                .expect("the agent is open");
+            match event {
+                AgentEvent::GatheringStateChange(GatheringState::Complete) => break,
+                AgentEvent::LocalCandidate(c) => {
+                    assert_eq!(c.candidate_type(), CandidateType::Relay);
+                    // The local socket is of the family of the relay
+                    assert_eq!(
+                        c.related_address().unwrap().address.contains(':'),
+                        c.network_type() == NetworkType::Udp6
+                    );
+                    relays.push((c.network_type(), c.address()));
+                }
+                AgentEvent::CandidateError(err) => panic!("unexpected candidate error: {}", err),
+                _ => {}
+            }
+        }
+        relays.sort_by_key(|(_, address)| address.clone());
+        assert_eq!(relays, expected);
+
+        a.close().await?;
+    }
+
+    server4.close()?;
+    server6.close()?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gather_relay_dual_stack() -> Result<()> {
+    let relayed4 = SocketAddr::from_str("192.0.2.1:50000")?;
+    let relayed6 = SocketAddr::from_str("[2001:db8::1]:50001")?;
+    let server_addr = fake_turn_server("user", "pass", vec![relayed4, relayed6]).await?;
+    let turn_server_url = Url {
+        scheme: SchemeType::Turn,
+        host: "turn.example.com".to_owned(),
+        port: 3478,
+        username: "user".to_owned(),
+        password: "pass".to_owned(),
+        proto: ProtoType::Udp,
+        ..Default::default()
+    };
+
+    let a = Agent::new(AgentConfig {
+        urls: vec![turn_server_url],
+        network_types: vec![NetworkType::Udp4, NetworkType::Udp6],
+        candidate_types: vec![CandidateType::Relay],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        runtime: Some(Arc::new(ResolveRuntime(vec![server_addr]))),
+        ..Default::default()
+    })
+    .await?;
+    let mut events = a.subscribe().await;
+    a.gather_candidates().await?;
+
+    let mut relays = vec![];
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("gathering completes")
+            .expect("the agent is open");
+        match event {
+            AgentEvent::GatheringStateChange(GatheringState::Complete) => break,
+            AgentEvent::LocalCandidate(c) => {
+                // Both relays of the allocation share its local socket
+                assert_eq!(
+                    c.related_address().unwrap().address,
+                    Ipv4Addr::UNSPECIFIED.to_string()
+                );
+                relays.push((c.network_type(), c.addr()));
+            }
+            AgentEvent::CandidateError(err) => panic!("unexpected candidate error: {}", err),
+            _ => {}
+        }
+    }
+    relays.sort_by_key(|(network_type, _)| network_type.to_string());
+    assert_eq!(
+        relays,
+        vec![(NetworkType::Udp4, relayed4), (NetworkType::Udp6, relayed6)]
+    );
+
+    a.close().await?;
+
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use crate::candidate::RECEIVE_MTU;
+use crate::error::*;
+use crate::runtime::{self, Runtime};
+
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use util::Conn;
+
+/// The datagrams queued for the relayed address of each family until its candidate reads them,
+/// anything beyond is dropped.
+const RELAY_CONN_QUEUE_SIZE: usize = 128;
+
+/// The relayed address of one family of a dual-stack TURN allocation (rfc8656). The relayed
+/// addresses of both families share the connection of the allocation, which a single task reads
+/// and hands each datagram to the family of the peer it came from. Datagrams are sent on the
+/// shared connection, the server relays them from the relayed address of the family of the peer.
+pub(crate) struct RelayFamilyConn {
+    conn: Arc<dyn Conn + Send + Sync>,
+    relayed_addr: SocketAddr,
+    packets_rx: Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+}
+
+impl RelayFamilyConn {
+    /// Splits the `conn` of an allocation into a connection for each of the `relayed_addrs`,
+    /// which are of different families, and starts reading it on `runtime` until it fails or
+    /// all of them are dropped.
+    pub(crate) fn split(
+        runtime: &Arc<dyn Runtime>,
+        conn: Arc<dyn Conn + Send + Sync>,
+        relayed_addrs: &[SocketAddr],
+    ) -> Vec<Self> {
+        let mut packets_txs = vec![];
+        let mut conns = vec![];
+        for relayed_addr in relayed_addrs {
+            let (packets_tx, packets_rx) = mpsc::channel(RELAY_CONN_QUEUE_SIZE);
+            packets_txs.push((relayed_addr.is_ipv4(), packets_tx));
+            conns.push(RelayFamilyConn {
+                conn: Arc::clone(&conn),
+                relayed_addr: *relayed_addr,
+                packets_rx: Mutex::new(packets_rx),
+            });
+        }
+
+        runtime::spawn(runtime, async move {
+            let mut buf = vec![0u8; RECEIVE_MTU];
+            while let Ok((n, src_addr)) = conn.recv_from(&mut buf).await {
+                if packets_txs
+                    .iter()
+                    .all(|(_, packets_tx)| packets_tx.is_closed())
+                {
+                    break;
+                }
+                let packets_tx = packets_txs
+                    .iter()
+                    .find(|(is_ipv4, _)| *is_ipv4 == src_addr.is_ipv4())
+                    .map(|(_, packets_tx)| packets_tx);
+                match packets_tx {
+                    Some(packets_tx) => {
+                        if packets_tx.try_send((buf[..n].to_vec(), src_addr)).is_err() {
+                            log::trace!("dropping relayed datagram from {}", src_addr);
+                        }
+                    }
+                    None => log::trace!("no relayed address of the family of {}", src_addr),
+                }
+            }
+        });
+
+        conns
+    }
+}
+
+#[async_trait]
+impl Conn for RelayFamilyConn {
+    async fn connect(&self, addr: SocketAddr) -> anyhow::Result<()> {
+        self.conn.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let (n, _) = self.recv_from(buf).await?;
+        Ok(n)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> anyhow::Result<(usize, SocketAddr)> {
+        let mut packets_rx = self.packets_rx.lock().await;
+        match packets_rx.recv().await {
+            Some((packet, src_addr)) => {
+                let n = packet.len().min(buf.len());
+                buf[..n].copy_from_slice(&packet[..n]);
+                Ok((n, src_addr))
+            }
+            None => Err(Error::ErrClosed.into()),
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> anyhow::Result<usize> {
+        self.conn.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> anyhow::Result<usize> {
+        self.conn.send_to(buf, target).await
+    }
+
+    async fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.relayed_addr)
+    }
+
+    async fn remote_addr(&self) -> Option<SocketAddr> {
+        self.conn.remote_addr().await
+    }
+
+    // The shared connection is closed along with the TURN client of the allocation
+    async fn close(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
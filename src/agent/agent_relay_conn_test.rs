@@ -0,0 +1,108 @@
+use super::agent_relay_conn::*;
+use crate::error::Result;
+use crate::runtime::{Runtime, TokioRuntime};
+
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use util::Conn;
+
+// The connection of an allocation, receiving what is sent on `packets_tx` and recording what is
+// sent on it.
+struct TestRelayConn {
+    packets_rx: Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    sent: std::sync::Mutex<Vec<(Vec<u8>, SocketAddr)>>,
+}
+
+#[async_trait]
+impl Conn for TestRelayConn {
+    async fn connect(&self, _addr: SocketAddr) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> anyhow::Result<usize> {
+        unimplemented!()
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> anyhow::Result<(usize, SocketAddr)> {
+        match self.packets_rx.lock().await.recv().await {
+            Some((packet, src_addr)) => {
+                buf[..packet.len()].copy_from_slice(&packet);
+                Ok((packet.len(), src_addr))
+            }
+            None => Err(crate::error::Error::ErrClosed.into()),
+        }
+    }
+
+    async fn send(&self, _buf: &[u8]) -> anyhow::Result<usize> {
+        unimplemented!()
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> anyhow::Result<usize> {
+        self.sent.lock().unwrap().push((buf.to_vec(), target));
+        Ok(buf.len())
+    }
+
+    async fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(SocketAddr::from_str("192.0.2.1:50000")?)
+    }
+
+    async fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    async fn close(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_relay_family_conn_split() -> Result<()> {
+    let runtime: Arc<dyn Runtime> = Arc::new(TokioRuntime);
+    let (packets_tx, packets_rx) = mpsc::channel(8);
+    let conn = Arc::new(TestRelayConn {
+        packets_rx: Mutex::new(packets_rx),
+        sent: std::sync::Mutex::new(vec![]),
+    });
+    let relayed4 = SocketAddr::from_str("192.0.2.1:50000")?;
+    let relayed6 = SocketAddr::from_str("[2001:db8::1]:50001")?;
+    let conns = RelayFamilyConn::split(
+        &runtime,
+        Arc::clone(&conn) as Arc<dyn Conn + Send + Sync>,
+        &[relayed4, relayed6],
+    );
+    assert_eq!(conns.len(), 2);
+    assert_eq!(conns[0].local_addr().await?, relayed4);
+    assert_eq!(conns[1].local_addr().await?, relayed6);
+
+    // Each datagram goes to the relayed address of the family of its peer
+    let peer4 = SocketAddr::from_str("198.51.100.1:4000")?;
+    let peer6 = SocketAddr::from_str("[2001:db8::2]:6000")?;
+    packets_tx.send((b"six".to_vec(), peer6)).await.unwrap();
+    packets_tx.send((b"four".to_vec(), peer4)).await.unwrap();
+
+    let mut buf = vec![0u8; 16];
+    for (conn, expected, peer) in [(&conns[0], &b"four"[..], peer4), (&conns[1], b"six", peer6)] {
+        let (n, src_addr) = tokio::time::timeout(Duration::from_secs(5), conn.recv_from(&mut buf))
+            .await
+            .expect("the datagram is handed to its family")?;
+        assert_eq!(&buf[..n], expected);
+        assert_eq!(src_addr, peer);
+    }
+
+    // Both send on the connection of the allocation
+    conns[1].send_to(b"data", peer6).await?;
+    assert_eq!(
+        conn.sent.lock().unwrap().as_slice(),
+        &[(b"data".to_vec(), peer6)]
+    );
+
+    // They are closed once the connection is
+    drop(packets_tx);
+    assert!(conns[0].recv_from(&mut buf).await.is_err());
+
+    Ok(())
+}
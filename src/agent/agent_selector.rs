@@ -1,4 +1,4 @@
-use crate::agent::agent_internal::*;
+use crate::agent::agent_core::*;
 use crate::candidate::*;
 use crate::control::*;
 use crate::priority::*;
@@ -6,29 +6,26 @@ use crate::use_candidate::*;
 
 use stun::{agent::*, attributes::*, fingerprint::*, integrity::*, message::*, textattrs::*};
 
-use async_trait::async_trait;
 use std::net::SocketAddr;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::time::Instant;
 
-#[async_trait]
 trait ControllingSelector {
     fn start(&mut self);
-    async fn contact_candidates(&mut self);
-    async fn ping_candidate(
+    fn contact_candidates(&mut self);
+    fn ping_candidate(
         &mut self,
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
     );
-    async fn handle_success_response(
+    fn handle_success_response(
         &mut self,
         m: &Message,
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
         remote_addr: SocketAddr,
     );
-    async fn handle_binding_request(
+    fn handle_binding_request(
         &mut self,
         m: &Message,
         local: &Arc<dyn Candidate + Send + Sync>,
@@ -36,23 +33,22 @@ trait ControllingSelector {
     );
 }
 
-#[async_trait]
 trait ControlledSelector {
     fn start(&mut self);
-    async fn contact_candidates(&mut self);
-    async fn ping_candidate(
+    fn contact_candidates(&mut self);
+    fn ping_candidate(
         &mut self,
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
     );
-    async fn handle_success_response(
+    fn handle_success_response(
         &mut self,
         m: &Message,
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
         remote_addr: SocketAddr,
     );
-    async fn handle_binding_request(
+    fn handle_binding_request(
         &mut self,
         m: &Message,
         local: &Arc<dyn Candidate + Send + Sync>,
@@ -60,23 +56,31 @@ trait ControlledSelector {
     );
 }
 
-impl AgentInternal {
-    async fn is_nominatable(&self, c: &Arc<dyn Candidate + Send + Sync>) -> bool {
+impl AgentCore {
+    fn is_nominatable(&self, c: &Arc<dyn Candidate + Send + Sync>) -> bool {
         match c.candidate_type() {
             CandidateType::Host => {
-                Instant::now().duration_since(self.start_time).as_nanos()
+                self.now
+                    .saturating_duration_since(self.start_time)
+                    .as_nanos()
                     > self.host_acceptance_min_wait.as_nanos()
             }
             CandidateType::ServerReflexive => {
-                Instant::now().duration_since(self.start_time).as_nanos()
+                self.now
+                    .saturating_duration_since(self.start_time)
+                    .as_nanos()
                     > self.srflx_acceptance_min_wait.as_nanos()
             }
             CandidateType::PeerReflexive => {
-                Instant::now().duration_since(self.start_time).as_nanos()
+                self.now
+                    .saturating_duration_since(self.start_time)
+                    .as_nanos()
                     > self.prflx_acceptance_min_wait.as_nanos()
             }
             CandidateType::Relay => {
-                Instant::now().duration_since(self.start_time).as_nanos()
+                self.now
+                    .saturating_duration_since(self.start_time)
+                    .as_nanos()
                     > self.relay_acceptance_min_wait.as_nanos()
             }
             CandidateType::Unspecified => {
@@ -89,7 +93,7 @@ impl AgentInternal {
         }
     }
 
-    async fn nominate_pair(&mut self) {
+    fn nominate_pair(&mut self) {
         if let Some(pair) = &self.nominated_pair {
             // The controlling agent MUST include the USE-CANDIDATE attribute in
             // order to nominate a candidate pair (Section 8.1.1).  The controlled
@@ -124,12 +128,12 @@ impl AgentInternal {
                 );
                 let local = pair.local.clone();
                 let remote = pair.remote.clone();
-                self.send_binding_request(&msg, &local, &remote).await;
+                self.send_binding_request(&msg, &local, &remote);
             }
         }
     }
 
-    pub(crate) fn start(&mut self) {
+    pub(crate) fn start_selector(&mut self) {
         if self.is_controlling {
             ControllingSelector::start(self);
         } else {
@@ -137,27 +141,27 @@ impl AgentInternal {
         }
     }
 
-    pub(crate) async fn contact_candidates(&mut self) {
+    pub(crate) fn contact_candidates(&mut self) {
         if self.is_controlling {
-            ControllingSelector::contact_candidates(self).await;
+            ControllingSelector::contact_candidates(self);
         } else {
-            ControlledSelector::contact_candidates(self).await;
+            ControlledSelector::contact_candidates(self);
         }
     }
 
-    pub(crate) async fn ping_candidate(
+    pub(crate) fn ping_candidate(
         &mut self,
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
     ) {
         if self.is_controlling {
-            ControllingSelector::ping_candidate(self, local, remote).await;
+            ControllingSelector::ping_candidate(self, local, remote);
         } else {
-            ControlledSelector::ping_candidate(self, local, remote).await;
+            ControlledSelector::ping_candidate(self, local, remote);
         }
     }
 
-    pub(crate) async fn handle_success_response(
+    pub(crate) fn handle_success_response(
         &mut self,
         m: &Message,
         local: &Arc<dyn Candidate + Send + Sync>,
@@ -165,57 +169,55 @@ impl AgentInternal {
         remote_addr: SocketAddr,
     ) {
         if self.is_controlling {
-            ControllingSelector::handle_success_response(self, m, local, remote, remote_addr).await;
+            ControllingSelector::handle_success_response(self, m, local, remote, remote_addr);
         } else {
-            ControlledSelector::handle_success_response(self, m, local, remote, remote_addr).await;
+            ControlledSelector::handle_success_response(self, m, local, remote, remote_addr);
         }
     }
 
-    pub(crate) async fn handle_binding_request(
+    pub(crate) fn handle_binding_request(
         &mut self,
         m: &Message,
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
     ) {
         if self.is_controlling {
-            ControllingSelector::handle_binding_request(self, m, local, remote).await;
+            ControllingSelector::handle_binding_request(self, m, local, remote);
         } else {
-            ControlledSelector::handle_binding_request(self, m, local, remote).await;
+            ControlledSelector::handle_binding_request(self, m, local, remote);
         }
     }
 }
 
-#[async_trait]
-impl ControllingSelector for AgentInternal {
+impl ControllingSelector for AgentCore {
     fn start(&mut self) {
-        self.start_time = Instant::now();
+        self.start_time = self.now;
         self.nominated_pair = None;
     }
 
-    async fn contact_candidates(&mut self) {
+    fn contact_candidates(&mut self) {
         // A lite selector should not contact candidates
         if self.lite {
             // This only happens if both peers are lite. See RFC 8445 S6.1.1 and S6.2
             log::trace!("now falling back to full agent");
         }
 
-        if self.agent_conn.get_selected_pair().await.is_some() {
-            if self.validate_selected_pair().await {
+        if self.selected_pair.is_some() {
+            if self.validate_selected_pair() {
                 log::trace!("checking keepalive");
-                self.check_keepalive().await;
+                self.check_keepalive();
             }
         } else if self.nominated_pair.is_some() {
-            self.nominate_pair().await;
+            self.nominate_pair();
         } else {
-            let has_nominated_pair =
-                if let Some(p) = self.agent_conn.get_best_valid_candidate_pair().await {
-                    self.is_nominatable(&p.local).await && self.is_nominatable(&p.remote).await
-                } else {
-                    false
-                };
+            let has_nominated_pair = if let Some(p) = self.get_best_valid_candidate_pair() {
+                self.is_nominatable(&p.local) && self.is_nominatable(&p.remote)
+            } else {
+                false
+            };
 
             if has_nominated_pair {
-                if let Some(p) = self.agent_conn.get_best_valid_candidate_pair().await {
+                if let Some(p) = self.get_best_valid_candidate_pair() {
                     log::trace!(
                         "Nominatable pair found, nominating ({}, {})",
                         p.local.to_string(),
@@ -225,14 +227,14 @@ impl ControllingSelector for AgentInternal {
                     self.nominated_pair = Some(p);
                 }
 
-                self.nominate_pair().await;
+                self.nominate_pair();
             } else {
-                self.ping_all_candidates().await;
+                self.ping_all_candidates();
             }
         }
     }
 
-    async fn ping_candidate(
+    fn ping_candidate(
         &mut self,
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
@@ -257,11 +259,11 @@ impl ControllingSelector for AgentInternal {
         if let Err(err) = result {
             log::error!("{}", err);
         } else {
-            self.send_binding_request(&msg, local, remote).await;
+            self.send_binding_request(&msg, local, remote);
         }
     }
 
-    async fn handle_success_response(
+    fn handle_success_response(
         &mut self,
         m: &Message,
         local: &Arc<dyn Candidate + Send + Sync>,
@@ -283,11 +285,10 @@ impl ControllingSelector for AgentInternal {
                 remote,
                 local
             );
-            let selected_pair_is_none = self.agent_conn.get_selected_pair().await.is_none();
+            let selected_pair_is_none = self.selected_pair.is_none();
 
-            if let Some(p) = self.find_pair(local, remote).await {
-                p.state
-                    .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+            if let Some(p) = self.find_pair(local, remote) {
+                set_pair_state(&mut self.events, &p, CandidatePairState::Succeeded);
                 log::trace!(
                     "Found valid candidate pair: {}, p.state: {}, isUseCandidate: {}, {}",
                     p,
@@ -296,7 +297,7 @@ impl ControllingSelector for AgentInternal {
                     selected_pair_is_none
                 );
                 if pending_request.is_use_candidate && selected_pair_is_none {
-                    self.set_selected_pair(Some(Arc::clone(&p))).await;
+                    self.set_selected_pair(Some(Arc::clone(&p)));
                 }
             } else {
                 // This shouldn't happen
@@ -311,40 +312,40 @@ impl ControllingSelector for AgentInternal {
         }
     }
 
-    async fn handle_binding_request(
+    fn handle_binding_request(
         &mut self,
         m: &Message,
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
     ) {
-        self.send_binding_success(m, local, remote).await;
+        self.send_binding_success(m, local, remote);
         log::trace!("controllingSelector: sendBindingSuccess");
 
-        if let Some(p) = self.find_pair(local, remote).await {
+        if let Some(p) = self.find_pair(local, remote) {
             log::trace!(
                 "controllingSelector: after findPair {}, p.state: {}, {}, {}",
                 p,
                 p.state.load(Ordering::SeqCst),
                 self.nominated_pair.is_none(),
-                self.agent_conn.get_selected_pair().await.is_none()
+                self.selected_pair.is_none()
             );
             if p.state.load(Ordering::SeqCst) == CandidatePairState::Succeeded as u8
                 && self.nominated_pair.is_none()
-                && self.agent_conn.get_selected_pair().await.is_none()
+                && self.selected_pair.is_none()
             {
-                if let Some(best_pair) = self.agent_conn.get_best_available_candidate_pair().await {
+                if let Some(best_pair) = self.get_best_available_candidate_pair() {
                     log::trace!(
                         "controllingSelector: getBestAvailableCandidatePair {}",
                         best_pair
                     );
                     if best_pair == p
-                        && self.is_nominatable(&p.local).await
-                        && self.is_nominatable(&p.remote).await
+                        && self.is_nominatable(&p.local)
+                        && self.is_nominatable(&p.remote)
                     {
                         log::trace!("The candidate ({}, {}) is the best candidate available, marking it as nominated",
                             p.local, p.remote);
                         self.nominated_pair = Some(p);
-                        self.nominate_pair().await;
+                        self.nominate_pair();
                     }
                 } else {
                     log::trace!("No best pair available");
@@ -352,30 +353,29 @@ impl ControllingSelector for AgentInternal {
             }
         } else {
             log::trace!("controllingSelector: addPair");
-            self.add_pair(local.clone(), remote.clone()).await;
+            self.add_pair(local.clone(), remote.clone());
         }
     }
 }
 
-#[async_trait]
-impl ControlledSelector for AgentInternal {
+impl ControlledSelector for AgentCore {
     fn start(&mut self) {}
 
-    async fn contact_candidates(&mut self) {
+    fn contact_candidates(&mut self) {
         // A lite selector should not contact candidates
         if self.lite {
-            self.validate_selected_pair().await;
-        } else if self.agent_conn.get_selected_pair().await.is_some() {
-            if self.validate_selected_pair().await {
+            self.validate_selected_pair();
+        } else if self.selected_pair.is_some() {
+            if self.validate_selected_pair() {
                 log::trace!("checking keepalive");
-                self.check_keepalive().await;
+                self.check_keepalive();
             }
         } else {
-            self.ping_all_candidates().await;
+            self.ping_all_candidates();
         }
     }
 
-    async fn ping_candidate(
+    fn ping_candidate(
         &mut self,
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
@@ -400,11 +400,11 @@ impl ControlledSelector for AgentInternal {
         if let Err(err) = result {
             log::error!("{}", err);
         } else {
-            self.send_binding_request(&msg, local, remote).await;
+            self.send_binding_request(&msg, local, remote);
         }
     }
 
-    async fn handle_success_response(
+    fn handle_success_response(
         &mut self,
         m: &Message,
         local: &Arc<dyn Candidate + Send + Sync>,
@@ -433,9 +433,8 @@ impl ControlledSelector for AgentInternal {
                 local
             );
 
-            if let Some(p) = self.find_pair(local, remote).await {
-                p.state
-                    .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+            if let Some(p) = self.find_pair(local, remote) {
+                set_pair_state(&mut self.events, &p, CandidatePairState::Succeeded);
                 log::trace!("Found valid candidate pair: {}", p);
             } else {
                 // This shouldn't happen
@@ -450,17 +449,17 @@ impl ControlledSelector for AgentInternal {
         }
     }
 
-    async fn handle_binding_request(
+    fn handle_binding_request(
         &mut self,
         m: &Message,
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
     ) {
-        if self.find_pair(local, remote).await.is_none() {
-            self.add_pair(local.clone(), remote.clone()).await;
+        if self.find_pair(local, remote).is_none() {
+            self.add_pair(local.clone(), remote.clone());
         }
 
-        if let Some(p) = self.find_pair(local, remote).await {
+        if let Some(p) = self.find_pair(local, remote) {
             let use_candidate = m.contains(ATTR_USE_CANDIDATE);
             if use_candidate {
                 // https://tools.ietf.org/html/rfc8445#section-7.3.1.5
@@ -470,10 +469,10 @@ impl ControlledSelector for AgentInternal {
                     // previously sent by this pair produced a successful response and
                     // generated a valid pair (Section 7.2.5.3.2).  The agent sets the
                     // nominated flag value of the valid pair to true.
-                    if self.agent_conn.get_selected_pair().await.is_none() {
-                        self.set_selected_pair(Some(Arc::clone(&p))).await;
+                    if self.selected_pair.is_none() {
+                        self.set_selected_pair(Some(Arc::clone(&p)));
                     }
-                    self.send_binding_success(m, local, remote).await;
+                    self.send_binding_success(m, local, remote);
                 } else {
                     // If the received Binding request triggered a new check to be
                     // enqueued in the triggered-check queue (Section 7.3.1.4), once the
@@ -483,11 +482,11 @@ impl ControlledSelector for AgentInternal {
                     // MUST remove the candidate pair from the valid list, set the
                     // candidate pair state to Failed, and set the checklist state to
                     // Failed.
-                    self.ping_candidate(local, remote).await;
+                    self.ping_candidate(local, remote);
                 }
             } else {
-                self.send_binding_success(m, local, remote).await;
-                self.ping_candidate(local, remote).await;
+                self.send_binding_success(m, local, remote);
+                self.ping_candidate(local, remote);
             }
         }
     }
@@ -0,0 +1,206 @@
+use crate::candidate::RECEIVE_MTU;
+use crate::error::*;
+use crate::runtime::{self, Runtime};
+use crate::util::{xormapped_addr, StunRetransmission};
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use stun::agent::TransactionId;
+use stun::message::*;
+use stun::xoraddr::XorMappedAddress;
+use tokio::sync::{mpsc, oneshot, Mutex, Notify};
+use util::Conn;
+
+/// The datagrams received while the binding requests are pending which are queued for the
+/// candidates, anything beyond is dropped.
+const SRFLX_CONN_QUEUE_SIZE: usize = 64;
+
+type Transactions =
+    std::sync::Mutex<HashMap<TransactionId, (SocketAddr, oneshot::Sender<Message>)>>;
+
+/// The socket of the server reflexive candidates of one base, on which all STUN servers are
+/// queried. While gathering, a single task reads the socket and hands the responses of the STUN
+/// servers to the pending binding requests and anything else to `recv_from`. Once gathering is
+/// finished, `recv_from` reads the socket directly.
+pub(crate) struct SrflxConn {
+    conn: Arc<dyn Conn + Send + Sync>,
+    transactions: Arc<Transactions>,
+    packets_rx: Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    finished: Arc<Notify>,
+}
+
+impl SrflxConn {
+    /// Wraps `conn` and starts reading it on `runtime` until `finish` is called.
+    pub(crate) fn new(runtime: &Arc<dyn Runtime>, conn: Arc<dyn Conn + Send + Sync>) -> Self {
+        let transactions = Arc::new(Transactions::default());
+        let finished = Arc::new(Notify::new());
+        let (packets_tx, packets_rx) = mpsc::channel(SRFLX_CONN_QUEUE_SIZE);
+
+        let (conn2, transactions2, finished2) = (
+            Arc::clone(&conn),
+            Arc::clone(&transactions),
+            Arc::clone(&finished),
+        );
+        runtime::spawn(runtime, async move {
+            let mut buf = vec![0u8; RECEIVE_MTU];
+            loop {
+                let (n, src_addr) = tokio::select! {
+                    result = conn2.recv_from(&mut buf) => match result {
+                        Ok(result) => result,
+                        Err(_) => break,
+                    },
+                    _ = finished2.notified() => break,
+                };
+
+                if let Some((response_tx, response)) =
+                    Self::take_response(&transactions2, &buf[..n], src_addr)
+                {
+                    let _ = response_tx.send(response);
+                } else if packets_tx.try_send((buf[..n].to_vec(), src_addr)).is_err() {
+                    log::trace!("dropping datagram from {} while gathering", src_addr);
+                }
+            }
+        });
+
+        SrflxConn {
+            conn,
+            transactions,
+            packets_rx: Mutex::new(packets_rx),
+            finished,
+        }
+    }
+
+    /// Returns the response `raw` together with the pending binding request it answers, which is
+    /// removed from `transactions`, or `None` if it answers none of them.
+    fn take_response(
+        transactions: &Transactions,
+        raw: &[u8],
+        src_addr: SocketAddr,
+    ) -> Option<(oneshot::Sender<Message>, Message)> {
+        if !is_message(raw) {
+            return None;
+        }
+        let mut m = Message::new();
+        m.raw = raw.to_vec();
+        if m.decode().is_err() || m.typ.class == CLASS_REQUEST {
+            return None;
+        }
+
+        let mut transactions = transactions.lock().unwrap();
+        match transactions.get(&m.transaction_id) {
+            Some((server_addr, _)) if *server_addr == src_addr => {
+                let (_, response_tx) = transactions.remove(&m.transaction_id)?;
+                Some((response_tx, m))
+            }
+            _ => None,
+        }
+    }
+
+    /// Sends a binding request to `server_addr` and returns the mapped address of its response,
+    /// retransmitting the request until a response arrives or `Error::ErrTimeout` once all
+    /// retransmissions went unanswered.
+    pub(crate) async fn get_xormapped_addr(
+        &self,
+        runtime: &Arc<dyn Runtime>,
+        server_addr: SocketAddr,
+        retransmission: &StunRetransmission,
+    ) -> Result<XorMappedAddress> {
+        let mut request = Message::new();
+        request.build(&[Box::new(BINDING_REQUEST), Box::new(TransactionId::new())])?;
+
+        let (response_tx, mut response_rx) = oneshot::channel();
+        self.transactions
+            .lock()
+            .unwrap()
+            .insert(request.transaction_id, (server_addr, response_tx));
+
+        let result = self
+            .transact(
+                runtime,
+                server_addr,
+                retransmission,
+                &request,
+                &mut response_rx,
+            )
+            .await;
+        self.transactions
+            .lock()
+            .unwrap()
+            .remove(&request.transaction_id);
+
+        xormapped_addr(&result?)
+    }
+
+    async fn transact(
+        &self,
+        runtime: &Arc<dyn Runtime>,
+        server_addr: SocketAddr,
+        retransmission: &StunRetransmission,
+        request: &Message,
+        response_rx: &mut oneshot::Receiver<Message>,
+    ) -> Result<Message> {
+        for n in 1..=retransmission.max_requests.max(1) {
+            self.conn.send_to(&request.raw, server_addr).await?;
+            match runtime::timeout(runtime, retransmission.timeout(n), &mut *response_rx).await {
+                Ok(Ok(response)) => return Ok(response),
+                Ok(Err(_)) => return Err(Error::ErrClosed),
+                Err(_) => log::trace!("no response to STUN request {} from {}", n, server_addr),
+            }
+        }
+
+        Err(Error::ErrTimeout)
+    }
+
+    /// Stops reading the socket for binding requests, which must all be completed.
+    pub(crate) fn finish(&self) {
+        self.finished.notify_one();
+    }
+}
+
+#[async_trait]
+impl Conn for SrflxConn {
+    async fn connect(&self, addr: SocketAddr) -> anyhow::Result<()> {
+        self.conn.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let (n, _) = self.recv_from(buf).await?;
+        Ok(n)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> anyhow::Result<(usize, SocketAddr)> {
+        // The datagrams queued while gathering come first, the queue is closed after it
+        let mut packets_rx = self.packets_rx.lock().await;
+        match packets_rx.recv().await {
+            Some((packet, src_addr)) => {
+                let n = packet.len().min(buf.len());
+                buf[..n].copy_from_slice(&packet[..n]);
+                Ok((n, src_addr))
+            }
+            None => self.conn.recv_from(buf).await,
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> anyhow::Result<usize> {
+        self.conn.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> anyhow::Result<usize> {
+        self.conn.send_to(buf, target).await
+    }
+
+    async fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        self.conn.local_addr().await
+    }
+
+    async fn remote_addr(&self) -> Option<SocketAddr> {
+        self.conn.remote_addr().await
+    }
+
+    async fn close(&self) -> anyhow::Result<()> {
+        self.finish();
+        self.conn.close().await
+    }
+}
@@ -0,0 +1,96 @@
+use super::agent_srflx_conn::*;
+use crate::candidate::RECEIVE_MTU;
+use crate::error::Result;
+use crate::runtime::{Runtime, TokioRuntime};
+use crate::util::StunRetransmission;
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use stun::message::*;
+use stun::xoraddr::XorMappedAddress;
+use tokio::net::UdpSocket;
+use util::Conn;
+
+const RETRANSMISSION: StunRetransmission = StunRetransmission {
+    rto: Duration::from_millis(50),
+    max_requests: 3,
+    last_timeout_factor: 2,
+};
+
+// A peer which sends `data` before answering the binding request it receives with `mapped_addr`.
+async fn peer(mapped_addr: SocketAddr, data: &'static [u8]) -> Result<SocketAddr> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let addr = socket.local_addr()?;
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 1500];
+        let (n, src_addr) = socket.recv_from(&mut buf).await?;
+        let mut request = Message::new();
+        request.raw = buf[..n].to_vec();
+        request.decode()?;
+
+        socket.send_to(data, src_addr).await?;
+        let mut response = Message::new();
+        response.build(&[
+            Box::new(request),
+            Box::new(BINDING_SUCCESS),
+            Box::new(XorMappedAddress {
+                ip: mapped_addr.ip(),
+                port: mapped_addr.port(),
+            }),
+        ])?;
+        socket.send_to(&response.raw, src_addr).await?;
+        Result::<()>::Ok(())
+    });
+    Ok(addr)
+}
+
+#[tokio::test]
+async fn test_srflx_conn_shared_by_requests() -> Result<()> {
+    let runtime: Arc<dyn Runtime> = Arc::new(TokioRuntime);
+    let socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let conn = SrflxConn::new(&runtime, socket);
+
+    let mapped_addr1 = SocketAddr::from_str("203.0.113.1:5000")?;
+    let mapped_addr2 = SocketAddr::from_str("203.0.113.2:5000")?;
+    // A datagram larger than an Ethernet MTU is kept whole
+    let server_addr1 = peer(mapped_addr1, &[0x42; 3000]).await?;
+    let server_addr2 = peer(mapped_addr2, b"data2").await?;
+
+    // Both requests are pending at once on the same socket
+    let (addr1, addr2) = tokio::join!(
+        conn.get_xormapped_addr(&runtime, server_addr1, &RETRANSMISSION),
+        conn.get_xormapped_addr(&runtime, server_addr2, &RETRANSMISSION),
+    );
+    let (addr1, addr2) = (addr1?, addr2?);
+    assert_eq!(SocketAddr::new(addr1.ip, addr1.port), mapped_addr1);
+    assert_eq!(SocketAddr::new(addr2.ip, addr2.port), mapped_addr2);
+
+    // Anything else received meanwhile is kept for the candidates
+    conn.finish();
+    let mut buf = vec![0u8; RECEIVE_MTU];
+    let mut received = vec![];
+    for _ in 0..2 {
+        let (n, src_addr) = conn.recv_from(&mut buf).await?;
+        received.push((buf[..n].to_vec(), src_addr));
+    }
+    received.sort();
+    let mut expected = vec![
+        (vec![0x42; 3000], server_addr1),
+        (b"data2".to_vec(), server_addr2),
+    ];
+    expected.sort();
+    assert_eq!(received, expected);
+
+    // Once gathering is finished, the socket is read directly
+    let sender = UdpSocket::bind("127.0.0.1:0").await?;
+    sender.send_to(b"data3", conn.local_addr().await?).await?;
+    let (n, src_addr) = conn.recv_from(&mut buf).await?;
+    assert_eq!(&buf[..n], b"data3");
+    assert_eq!(src_addr, sender.local_addr()?);
+
+    conn.close().await?;
+
+    Ok(())
+}
@@ -1,6 +1,6 @@
 use crate::candidate::{CandidatePairState, CandidateType};
 
-use crate::agent::agent_internal::AgentInternal;
+use crate::agent::agent_core::AgentCore;
 use crate::network_type::NetworkType;
 use std::sync::atomic::Ordering;
 use tokio::time::Instant;
@@ -211,12 +211,31 @@ impl Default for CandidateStats {
     }
 }
 
-impl AgentInternal {
+/// Contains the statistics of the buffers application data received on the `AgentConn` is
+/// queued in until it is read, those of its demux endpoints included.
+#[derive(Debug, Clone)]
+pub struct ReceiveBufferStats {
+    /// The timestamp associated with this struct.
+    pub timestamp: Instant,
+
+    /// The number of packets waiting to be read.
+    pub packets_queued: usize,
+
+    /// The total size of the packets waiting to be read.
+    pub bytes_queued: usize,
+
+    /// The total number of packets dropped because the buffer was full.
+    pub packets_dropped: u64,
+
+    /// The total size of the packets dropped because the buffer was full.
+    pub bytes_dropped: u64,
+}
+
+impl AgentCore {
     /// Returns a list of candidate pair stats.
-    pub(crate) async fn get_candidate_pairs_stats(&self) -> Vec<CandidatePairStats> {
-        let checklist = self.agent_conn.checklist.lock().await;
-        let mut res = Vec::with_capacity(checklist.len());
-        for cp in &*checklist {
+    pub fn get_candidate_pairs_stats(&self) -> Vec<CandidatePairStats> {
+        let mut res = Vec::with_capacity(self.checklist.len());
+        for cp in &self.checklist {
             let stat = CandidatePairStats {
                 timestamp: Instant::now(),
                 local_candidate_id: cp.local.id(),
@@ -231,7 +250,7 @@ impl AgentInternal {
     }
 
     /// Returns a list of local candidates stats.
-    pub(crate) fn get_local_candidates_stats(&self) -> Vec<CandidateStats> {
+    pub fn get_local_candidates_stats(&self) -> Vec<CandidateStats> {
         let mut res = Vec::with_capacity(self.local_candidates.len());
         for (network_type, local_candidates) in &self.local_candidates {
             for c in local_candidates {
@@ -255,7 +274,7 @@ impl AgentInternal {
     }
 
     /// Returns a list of remote candidates stats.
-    pub(crate) fn get_remote_candidates_stats(&self) -> Vec<CandidateStats> {
+    pub fn get_remote_candidates_stats(&self) -> Vec<CandidateStats> {
         let mut res = Vec::with_capacity(self.remote_candidates.len());
         for (network_type, remote_candidates) in &self.remote_candidates {
             for c in remote_candidates {
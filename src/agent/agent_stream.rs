@@ -0,0 +1,196 @@
+use super::agent_transport::AgentConn;
+use super::*;
+use crate::buffer::PooledBuffer;
+
+use bytes::Bytes;
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::io;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use util::buffer::error::Error as BufferError;
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send + 'static>>;
+
+/// A poll-based interface to an `AgentConn`, for codecs and libraries which expect poll-style
+/// I/O rather than async methods.
+///
+/// It is a `Stream` of the received datagrams and a `Sink` of datagrams to send. It implements
+/// `AsyncRead` and `AsyncWrite` as well, where each write sends one datagram and each read
+/// returns at most one datagram. A datagram which doesn't fit into the read buffer is returned
+/// over several reads, so that nothing is lost when it is read as a byte stream. The stream
+/// ends, and reads return end of file, once the connection is closed.
+pub struct AgentStream {
+    conn: Arc<AgentConn>,
+    recv: Option<BoxFuture<Result<PooledBuffer>>>,
+    send: Option<BoxFuture<Result<usize>>>,
+    // The rest of a datagram which didn't fit into the buffer of `poll_read`
+    pending: Option<(PooledBuffer, usize)>,
+}
+
+impl AgentStream {
+    /// Wraps the connection returned by `Agent::dial` or `Agent::accept`.
+    pub fn new(conn: Arc<AgentConn>) -> Self {
+        AgentStream {
+            conn,
+            recv: None,
+            send: None,
+            pending: None,
+        }
+    }
+
+    /// Returns the wrapped connection.
+    pub fn get_ref(&self) -> &Arc<AgentConn> {
+        &self.conn
+    }
+
+    /// Polls for the next received datagram, handing over the buffer it was received into like
+    /// `AgentConn::recv_packet`.
+    pub fn poll_recv_packet(&mut self, cx: &mut Context<'_>) -> Poll<Result<PooledBuffer>> {
+        let conn = &self.conn;
+        let recv = self.recv.get_or_insert_with(|| {
+            let conn = Arc::clone(conn);
+            Box::pin(async move { conn.recv_packet().await })
+        });
+        let result = futures_core::ready!(recv.as_mut().poll(cx));
+        self.recv = None;
+        Poll::Ready(result)
+    }
+
+    /// Polls for sending `buf` as one datagram like `AgentConn::send`. A datagram still on its
+    /// way is completed first, returning `Poll::Pending` without taking `buf` until it is. The
+    /// datagram is taken once its send started: if it doesn't complete right away, the length of
+    /// `buf` is returned and the result of the send is reported by the next call or
+    /// `poll_flush`, like with a buffered writer.
+    pub fn poll_send(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        futures_core::ready!(self.poll_flush_send(cx))?;
+
+        let conn = Arc::clone(&self.conn);
+        let datagram = buf.to_vec();
+        let mut send: BoxFuture<Result<usize>> =
+            Box::pin(async move { conn.send(&datagram).await });
+        match send.as_mut().poll(cx) {
+            Poll::Ready(result) => Poll::Ready(result),
+            Poll::Pending => {
+                self.send = Some(send);
+                Poll::Ready(Ok(buf.len()))
+            }
+        }
+    }
+
+    fn poll_flush_send(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        let send = match &mut self.send {
+            Some(send) => send,
+            None => return Poll::Ready(Ok(0)),
+        };
+        let result = futures_core::ready!(send.as_mut().poll(cx));
+        self.send = None;
+        Poll::Ready(result)
+    }
+}
+
+impl From<Arc<AgentConn>> for AgentStream {
+    fn from(conn: Arc<AgentConn>) -> Self {
+        AgentStream::new(conn)
+    }
+}
+
+fn is_closed(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::ErrClosed | Error::Buffer(BufferError::ErrBufferClosed)
+    )
+}
+
+fn into_io_error(err: Error) -> io::Error {
+    match err {
+        Error::Io(IoError(err)) => err,
+        err => io::Error::other(err),
+    }
+}
+
+impl Stream for AgentStream {
+    type Item = Bytes;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        match futures_core::ready!(self.get_mut().poll_recv_packet(cx)) {
+            Ok(packet) => Poll::Ready(Some(Bytes::copy_from_slice(&packet))),
+            Err(err) => {
+                if !is_closed(&err) {
+                    log::warn!("failed to receive packet: {}", err);
+                }
+                Poll::Ready(None)
+            }
+        }
+    }
+}
+
+impl Sink<Bytes> for AgentStream {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_flush_send(cx).map_ok(|_| ())
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<()> {
+        let this = self.get_mut();
+        let conn = Arc::clone(&this.conn);
+        this.send = Some(Box::pin(async move { conn.send(&item).await }));
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_flush_send(cx).map_ok(|_| ())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Sink::poll_flush(self, cx)
+    }
+}
+
+impl AsyncRead for AgentStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let (packet, offset) = match this.pending.take() {
+            Some(pending) => pending,
+            None => match futures_core::ready!(this.poll_recv_packet(cx)) {
+                Ok(packet) => (packet, 0),
+                Err(err) if is_closed(&err) => return Poll::Ready(Ok(())),
+                Err(err) => return Poll::Ready(Err(into_io_error(err))),
+            },
+        };
+
+        let n = (packet.len() - offset).min(buf.remaining());
+        buf.put_slice(&packet[offset..offset + n]);
+        if offset + n < packet.len() {
+            this.pending = Some((packet, offset + n));
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for AgentStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_send(cx, buf).map_err(into_io_error)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .poll_flush_send(cx)
+            .map_ok(|_| ())
+            .map_err(into_io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(self, cx)
+    }
+}
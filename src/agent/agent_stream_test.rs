@@ -0,0 +1,116 @@
+use super::agent_stream::*;
+use super::agent_transport_test::pipe;
+use super::*;
+
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Framed, LinesCodec};
+
+#[tokio::test]
+async fn test_agent_stream_sink() -> Result<()> {
+    let (ca, cb, a_agent, b_agent) = pipe(None, None).await?;
+    let mut sa = AgentStream::new(ca);
+    let mut sb = AgentStream::new(cb);
+
+    sa.send(Bytes::from_static(b"hello")).await?;
+    sa.send(Bytes::from_static(b"world")).await?;
+
+    let next = tokio::time::timeout(Duration::from_secs(5), sb.next())
+        .await
+        .expect("a datagram is received");
+    assert_eq!(next, Some(Bytes::from_static(b"hello")));
+    assert_eq!(sb.next().await, Some(Bytes::from_static(b"world")));
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+    assert_eq!(
+        sb.next().await,
+        None,
+        "the stream ends once the agent is closed"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_stream_read_write() -> Result<()> {
+    let (ca, cb, a_agent, b_agent) = pipe(None, None).await?;
+    let mut sa = AgentStream::new(ca);
+    let mut sb = AgentStream::new(cb);
+
+    sa.write_all(b"0123456789").await?;
+
+    // A datagram which doesn't fit is returned over several reads
+    let mut buf = [0u8; 4];
+    let mut read = vec![];
+    while read.len() < 10 {
+        let n = tokio::time::timeout(Duration::from_secs(5), sb.read(&mut buf))
+            .await
+            .expect("a datagram is received")?;
+        assert!(n > 0);
+        read.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(read, b"0123456789");
+
+    // Codecs work on top of it
+    let mut fa = Framed::new(sa, LinesCodec::new());
+    let mut fb = Framed::new(sb, LinesCodec::new());
+    fa.send("ping").await.unwrap();
+    let line = tokio::time::timeout(Duration::from_secs(5), fb.next())
+        .await
+        .expect("a line is received");
+    assert_eq!(line.transpose().unwrap(), Some("ping".to_owned()));
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+    let mut buf = [0u8; 4];
+    assert_eq!(
+        fb.get_mut().read(&mut buf).await?,
+        0,
+        "reads return end of file once the agent is closed"
+    );
+
+    Ok(())
+}
+
+// Polls a write of `buf` once.
+async fn poll_write_once(s: &mut AgentStream, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+    std::future::poll_fn(|cx| Poll::Ready(Pin::new(&mut *s).poll_write(cx, buf))).await
+}
+
+#[tokio::test]
+async fn test_agent_stream_pending_write() -> Result<()> {
+    let (ca, cb, a_agent, b_agent) = pipe(None, None).await?;
+    let mut sa = AgentStream::new(Arc::clone(&ca));
+    let mut sb = AgentStream::new(cb);
+
+    // Sends wait for the selected pair while it is locked
+    let selected_pair = ca.selected_pair.lock().await;
+    match poll_write_once(&mut sa, b"first").await {
+        Poll::Ready(n) => assert_eq!(n?, 5, "the datagram is taken"),
+        Poll::Pending => panic!("the first write is taken while its send is pending"),
+    }
+    assert!(
+        poll_write_once(&mut sa, b"second").await.is_pending(),
+        "nothing is taken while the first send is pending"
+    );
+    drop(selected_pair);
+
+    // The write may be retried with other data, only the datagrams taken are sent
+    sa.write_all(b"third").await?;
+    AsyncWriteExt::flush(&mut sa).await?;
+    for expected in [&b"first"[..], &b"third"[..]] {
+        let next = tokio::time::timeout(Duration::from_secs(5), sb.next())
+            .await
+            .expect("a datagram is received");
+        assert_eq!(next.as_deref(), Some(expected));
+    }
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    Ok(())
+}
@@ -14,8 +14,8 @@ use async_trait::async_trait;
 use std::net::Ipv4Addr;
 use std::ops::Sub;
 use std::str::FromStr;
-use stun::message::*;
 use stun::textattrs::Username;
+use stun::{agent::TransactionId, attributes::*, fingerprint::*, integrity::*, message::*};
 use util::{vnet::*, Conn};
 use waitgroup::{WaitGroup, Worker};
 
@@ -27,14 +27,13 @@ async fn test_pair_search() -> Result<()> {
     {
         let ai = a.agent_internal.lock().await;
         {
-            let checklist = ai.agent_conn.checklist.lock().await;
             assert!(
-                checklist.is_empty(),
+                ai.core.checklist().is_empty(),
                 "TestPairSearch is only a valid test if a.validPairs is empty on construction"
             );
         }
 
-        let cp = ai.agent_conn.get_best_available_candidate_pair().await;
+        let cp = ai.core.get_best_available_candidate_pair();
         assert!(cp.is_none(), "No Candidate pairs should exist");
     }
 
@@ -124,16 +123,16 @@ async fn test_pair_priority() -> Result<()> {
     {
         let mut ai = a.agent_internal.lock().await;
         for remote in remotes {
-            if ai.find_pair(&host_local, &remote).await.is_none() {
-                ai.add_pair(host_local.clone(), remote.clone()).await;
+            if ai.core.find_pair(&host_local, &remote).is_none() {
+                ai.core.add_pair(host_local.clone(), remote.clone());
             }
 
-            if let Some(p) = ai.find_pair(&host_local, &remote).await {
+            if let Some(p) = ai.core.find_pair(&host_local, &remote) {
                 p.state
                     .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
             }
 
-            if let Some(best_pair) = ai.agent_conn.get_best_available_candidate_pair().await {
+            if let Some(best_pair) = ai.core.get_best_available_candidate_pair() {
                 assert_eq!(
                     best_pair.to_string(),
                     CandidatePair {
@@ -204,7 +203,8 @@ async fn test_on_selected_candidate_pair_change() -> Result<()> {
     ));
     {
         let mut ai = a.agent_internal.lock().await;
-        ai.set_selected_pair(Some(p)).await;
+        ai.core.set_selected_pair(Some(p));
+        ai.flush().await;
     }
 
     // ensure that the callback fired on setting the pair
@@ -237,9 +237,9 @@ async fn test_handle_peer_reflexive_udp_pflx_candidate() -> Result<()> {
         let ai = a.agent_internal.lock().await;
 
         (
-            ai.local_ufrag.to_owned() + ":" + ai.remote_ufrag.as_str(),
-            ai.local_pwd.clone(),
-            ai.tie_breaker,
+            ai.core.local_ufrag.to_owned() + ":" + ai.core.remote_ufrag.as_str(),
+            ai.core.local_pwd.clone(),
+            ai.core.tie_breaker,
         )
     };
 
@@ -257,17 +257,17 @@ async fn test_handle_peer_reflexive_udp_pflx_candidate() -> Result<()> {
 
     {
         let mut ai = a.agent_internal.lock().await;
-        ai.handle_inbound(&mut msg, &local, remote).await;
+        ai.core.handle_inbound(&mut msg, &local, remote);
 
         // length of remote candidate list must be one now
         assert_eq!(
-            ai.remote_candidates.len(),
+            ai.core.remote_candidates.len(),
             1,
             "failed to add a network type to the remote candidate list"
         );
 
         // length of remote candidate list for a network type must be 1
-        if let Some(cands) = ai.remote_candidates.get(&local.network_type()) {
+        if let Some(cands) = ai.core.remote_candidates.get(&local.network_type()) {
             assert_eq!(
                 cands.len(),
                 1,
@@ -306,13 +306,13 @@ async fn test_handle_peer_reflexive_unknown_remote() -> Result<()> {
 
     let remote_pwd = {
         let mut ai = a.agent_internal.lock().await;
-        ai.pending_binding_requests = vec![BindingRequest {
+        ai.core.pending_binding_requests = vec![BindingRequest {
             timestamp: Instant::now(),
             transaction_id: tid,
             destination: SocketAddr::from_str("0.0.0.0:0")?,
             is_use_candidate: false,
         }];
-        ai.remote_pwd.clone()
+        ai.core.remote_pwd.clone()
     };
 
     let host_config = CandidateHostConfig {
@@ -340,10 +340,10 @@ async fn test_handle_peer_reflexive_unknown_remote() -> Result<()> {
 
     {
         let mut ai = a.agent_internal.lock().await;
-        ai.handle_inbound(&mut msg, &local, remote).await;
+        ai.core.handle_inbound(&mut msg, &local, remote);
 
         assert_eq!(
-            ai.remote_candidates.len(),
+            ai.core.remote_candidates.len(),
             0,
             "unknown remote was able to create a candidate"
         );
@@ -549,27 +549,27 @@ struct MockPacketConn;
 
 #[async_trait]
 impl Conn for MockPacketConn {
-    async fn connect(&self, _addr: SocketAddr) -> Result<()> {
+    async fn connect(&self, _addr: SocketAddr) -> anyhow::Result<()> {
         Ok(())
     }
 
-    async fn recv(&self, _buf: &mut [u8]) -> Result<usize> {
+    async fn recv(&self, _buf: &mut [u8]) -> anyhow::Result<usize> {
         Ok(0)
     }
 
-    async fn recv_from(&self, _buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+    async fn recv_from(&self, _buf: &mut [u8]) -> anyhow::Result<(usize, SocketAddr)> {
         Ok((0, SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0)))
     }
 
-    async fn send(&self, _buf: &[u8]) -> Result<usize> {
+    async fn send(&self, _buf: &[u8]) -> anyhow::Result<usize> {
         Ok(0)
     }
 
-    async fn send_to(&self, _buf: &[u8], _target: SocketAddr) -> Result<usize> {
+    async fn send_to(&self, _buf: &[u8], _target: SocketAddr) -> anyhow::Result<usize> {
         Ok(0)
     }
 
-    async fn local_addr(&self) -> Result<SocketAddr> {
+    async fn local_addr(&self) -> anyhow::Result<SocketAddr> {
         Ok(SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0))
     }
 
@@ -577,7 +577,7 @@ impl Conn for MockPacketConn {
         None
     }
 
-    async fn close(&self) -> Result<()> {
+    async fn close(&self) -> anyhow::Result<()> {
         Ok(())
     }
 }
@@ -635,28 +635,26 @@ async fn test_inbound_validity() -> Result<()> {
         {
             let mut ai = a.agent_internal.lock().await;
 
-            let local_pwd = ai.local_pwd.clone();
-            ai.handle_inbound(
+            let local_pwd = ai.core.local_pwd.clone();
+            ai.core.handle_inbound(
                 &mut build_msg(CLASS_REQUEST, "invalid".to_owned(), local_pwd)?,
                 &local,
                 remote,
-            )
-            .await;
+            );
             assert_ne!(
-                ai.remote_candidates.len(),
+                ai.core.remote_candidates.len(),
                 1,
                 "Binding with invalid Username was able to create prflx candidate"
             );
 
-            let username = format!("{}:{}", ai.local_ufrag, ai.remote_ufrag);
-            ai.handle_inbound(
+            let username = format!("{}:{}", ai.core.local_ufrag, ai.core.remote_ufrag);
+            ai.core.handle_inbound(
                 &mut build_msg(CLASS_REQUEST, username, "Invalid".to_owned())?,
                 &local,
                 remote,
-            )
-            .await;
+            );
             assert_ne!(
-                ai.remote_candidates.len(),
+                ai.core.remote_candidates.len(),
                 1,
                 "Binding with invalid MessageIntegrity was able to create prflx candidate"
             );
@@ -672,15 +670,14 @@ async fn test_inbound_validity() -> Result<()> {
         {
             let mut ai = a.agent_internal.lock().await;
 
-            let username = format!("{}:{}", ai.local_ufrag, ai.remote_ufrag);
-            ai.handle_inbound(
+            let username = format!("{}:{}", ai.core.local_ufrag, ai.core.remote_ufrag);
+            ai.core.handle_inbound(
                 &mut build_msg(CLASS_SUCCESS_RESPONSE, username, "Invalid".to_owned())?,
                 &local,
                 remote,
-            )
-            .await;
+            );
             assert_ne!(
-                ai.remote_candidates.len(),
+                ai.core.remote_candidates.len(),
                 1,
                 "Binding with invalid Username was able to create prflx candidate"
             );
@@ -696,15 +693,14 @@ async fn test_inbound_validity() -> Result<()> {
         {
             let mut ai = a.agent_internal.lock().await;
 
-            let username = format!("{}:{}", ai.local_ufrag, ai.remote_ufrag);
-            ai.handle_inbound(
+            let username = format!("{}:{}", ai.core.local_ufrag, ai.core.remote_ufrag);
+            ai.core.handle_inbound(
                 &mut build_msg(CLASS_ERROR_RESPONSE, username, "Invalid".to_owned())?,
                 &local,
                 remote,
-            )
-            .await;
+            );
             assert_ne!(
-                ai.remote_candidates.len(),
+                ai.core.remote_candidates.len(),
                 1,
                 "non-binding message was able to create prflxRemote"
             );
@@ -720,16 +716,15 @@ async fn test_inbound_validity() -> Result<()> {
         {
             let mut ai = a.agent_internal.lock().await;
 
-            let username = format!("{}:{}", ai.local_ufrag, ai.remote_ufrag);
-            let local_pwd = ai.local_pwd.clone();
-            ai.handle_inbound(
+            let username = format!("{}:{}", ai.core.local_ufrag, ai.core.remote_ufrag);
+            let local_pwd = ai.core.local_pwd.clone();
+            ai.core.handle_inbound(
                 &mut build_msg(CLASS_REQUEST, username, local_pwd)?,
                 &local,
                 remote,
-            )
-            .await;
+            );
             assert_eq!(
-                ai.remote_candidates.len(),
+                ai.core.remote_candidates.len(),
                 1,
                 "Binding with valid values was unable to create prflx candidate"
             );
@@ -745,8 +740,8 @@ async fn test_inbound_validity() -> Result<()> {
         {
             let mut ai = a.agent_internal.lock().await;
 
-            let username = format!("{}:{}", ai.local_ufrag, ai.remote_ufrag);
-            let local_pwd = ai.local_pwd.clone();
+            let username = format!("{}:{}", ai.core.local_ufrag, ai.core.remote_ufrag);
+            let local_pwd = ai.core.local_pwd.clone();
 
             let mut msg = Message::new();
             msg.build(&[
@@ -756,9 +751,9 @@ async fn test_inbound_validity() -> Result<()> {
                 Box::new(MessageIntegrity::new_short_term_integrity(local_pwd)),
             ])?;
 
-            ai.handle_inbound(&mut msg, &local, remote).await;
+            ai.core.handle_inbound(&mut msg, &local, remote);
             assert_eq!(
-                ai.remote_candidates.len(),
+                ai.core.remote_candidates.len(),
                 1,
                 "Binding with valid values (but no fingerprint) was unable to create prflx candidate"
             );
@@ -778,7 +773,7 @@ async fn test_inbound_validity() -> Result<()> {
             let mut t_id = TransactionId::default();
             t_id.0[..3].copy_from_slice(b"ABC");
 
-            let remote_pwd = ai.remote_pwd.clone();
+            let remote_pwd = ai.core.remote_pwd.clone();
 
             let mut msg = Message::new();
             msg.build(&[
@@ -788,9 +783,9 @@ async fn test_inbound_validity() -> Result<()> {
                 Box::new(FINGERPRINT),
             ])?;
 
-            ai.handle_inbound(&mut msg, &local, remote).await;
+            ai.core.handle_inbound(&mut msg, &local, remote);
             assert_eq!(
-                ai.remote_candidates.len(),
+                ai.core.remote_candidates.len(),
                 0,
                 "unknown remote was able to create a candidate"
             );
@@ -810,14 +805,14 @@ async fn test_invalid_agent_starts() -> Result<()> {
     let result = a.dial(cancel_rx1, "".to_owned(), "bar".to_owned()).await;
     assert!(result.is_err());
     if let Err(err) = result {
-        assert!(Error::ErrRemoteUfragEmpty.equal(&err));
+        assert_eq!(err, Error::ErrRemoteUfragEmpty);
     }
 
     let (_cancel_tx2, cancel_rx2) = mpsc::channel(1);
     let result = a.dial(cancel_rx2, "foo".to_owned(), "".to_owned()).await;
     assert!(result.is_err());
     if let Err(err) = result {
-        assert!(Error::ErrRemotePwdEmpty.equal(&err));
+        assert_eq!(err, Error::ErrRemotePwdEmpty);
     }
 
     let (cancel_tx3, cancel_rx3) = mpsc::channel(1);
@@ -829,14 +824,14 @@ async fn test_invalid_agent_starts() -> Result<()> {
     let result = a.dial(cancel_rx3, "foo".to_owned(), "bar".to_owned()).await;
     assert!(result.is_err());
     if let Err(err) = result {
-        assert!(Error::ErrCanceledByCaller.equal(&err));
+        assert_eq!(err, Error::ErrCanceledByCaller);
     }
 
     let (_cancel_tx4, cancel_rx4) = mpsc::channel(1);
     let result = a.dial(cancel_rx4, "foo".to_owned(), "bar".to_owned()).await;
     assert!(result.is_err());
     if let Err(err) = result {
-        assert!(Error::ErrMultipleStart.equal(&err));
+        assert_eq!(err, Error::ErrMultipleStart);
     }
 
     a.close().await?;
@@ -968,7 +963,7 @@ async fn test_invalid_gather() -> Result<()> {
 
     if let Err(err) = a.gather_candidates().await {
         assert!(
-            Error::ErrNoOnCandidateHandler.equal(&err),
+            err == Error::ErrNoOnCandidateHandler,
             "trickle GatherCandidates succeeded without OnCandidate"
         );
     }
@@ -1068,17 +1063,17 @@ async fn test_candidate_pair_stats() -> Result<()> {
         Arc::clone(&host_remote),
     ] {
         let mut ai = a.agent_internal.lock().await;
-        let p = ai.find_pair(&host_local, remote).await;
+        let p = ai.core.find_pair(&host_local, remote);
 
         if p.is_none() {
-            ai.add_pair(Arc::clone(&host_local), Arc::clone(remote))
-                .await;
+            ai.core
+                .add_pair(Arc::clone(&host_local), Arc::clone(remote));
         }
     }
 
     {
         let ai = a.agent_internal.lock().await;
-        if let Some(p) = ai.find_pair(&host_local, &prflx_remote).await {
+        if let Some(p) = ai.core.find_pair(&host_local, &prflx_remote) {
             p.state
                 .store(CandidatePairState::Failed as u8, Ordering::SeqCst);
         }
@@ -1183,7 +1178,7 @@ async fn test_local_candidate_stats() -> Result<()> {
 
     {
         let mut ai = a.agent_internal.lock().await;
-        ai.local_candidates.insert(
+        ai.core.local_candidates.insert(
             NetworkType::Udp4,
             vec![Arc::clone(&host_local), Arc::clone(&srflx_local)],
         );
@@ -1309,7 +1304,7 @@ async fn test_remote_candidate_stats() -> Result<()> {
 
     {
         let mut ai = a.agent_internal.lock().await;
-        ai.remote_candidates.insert(
+        ai.core.remote_candidates.insert(
             NetworkType::Udp4,
             vec![
                 Arc::clone(&relay_remote),
@@ -1424,7 +1419,7 @@ async fn test_init_ext_ip_mapping() -> Result<()> {
     .await
     {
         assert!(
-            Error::ErrIneffectiveNat1to1IpMappingHost.equal(&err),
+            err == Error::ErrIneffectiveNat1to1IpMappingHost,
             "Unexpected error: {}",
             err
         );
@@ -1443,7 +1438,7 @@ async fn test_init_ext_ip_mapping() -> Result<()> {
     .await
     {
         assert!(
-            Error::ErrIneffectiveNat1to1IpMappingSrflx.equal(&err),
+            err == Error::ErrIneffectiveNat1to1IpMappingSrflx,
             "Unexpected error: {}",
             err
         );
@@ -1462,7 +1457,7 @@ async fn test_init_ext_ip_mapping() -> Result<()> {
     .await
     {
         assert!(
-            Error::ErrMulticastDnsWithNat1to1IpMapping.equal(&err),
+            err == Error::ErrMulticastDnsWithNat1to1IpMapping,
             "Unexpected error: {}",
             err
         );
@@ -1479,7 +1474,7 @@ async fn test_init_ext_ip_mapping() -> Result<()> {
     .await
     {
         assert!(
-            Error::ErrInvalidNat1to1IpMapping.equal(&err),
+            err == Error::ErrInvalidNat1to1IpMapping,
             "Unexpected error: {}",
             err
         );
@@ -1499,25 +1494,25 @@ async fn test_binding_request_timeout() -> Result<()> {
     let now = Instant::now();
     {
         let mut ai = a.agent_internal.lock().await;
-        ai.pending_binding_requests.push(BindingRequest {
+        ai.core.pending_binding_requests.push(BindingRequest {
             timestamp: now, // valid
             ..Default::default()
         });
-        ai.pending_binding_requests.push(BindingRequest {
+        ai.core.pending_binding_requests.push(BindingRequest {
             timestamp: now.sub(Duration::from_millis(3900)), // valid
             ..Default::default()
         });
-        ai.pending_binding_requests.push(BindingRequest {
+        ai.core.pending_binding_requests.push(BindingRequest {
             timestamp: now.sub(Duration::from_millis(4100)), // invalid
             ..Default::default()
         });
-        ai.pending_binding_requests.push(BindingRequest {
+        ai.core.pending_binding_requests.push(BindingRequest {
             timestamp: now.sub(Duration::from_secs(75)), // invalid
             ..Default::default()
         });
 
-        ai.invalidate_pending_binding_requests(now);
-        assert_eq!(EXPECTED_REMOVAL_COUNT, ai.pending_binding_requests.len(), "Binding invalidation due to timeout did not remove the correct number of binding requests")
+        ai.core.invalidate_pending_binding_requests(now);
+        assert_eq!(EXPECTED_REMOVAL_COUNT, ai.core.pending_binding_requests.len(), "Binding invalidation due to timeout did not remove the correct number of binding requests")
     }
 
     a.close().await?;
@@ -1535,8 +1530,8 @@ async fn test_agent_credentials() -> Result<()> {
     let a = Agent::new(AgentConfig::default()).await?;
     {
         let ai = a.agent_internal.lock().await;
-        assert!(ai.local_ufrag.as_bytes().len() * 8 >= 24);
-        assert!(ai.local_pwd.as_bytes().len() * 8 >= 128);
+        assert!(ai.core.local_ufrag.as_bytes().len() * 8 >= 24);
+        assert!(ai.core.local_pwd.as_bytes().len() * 8 >= 128);
     }
     a.close().await?;
 
@@ -1551,7 +1546,7 @@ async fn test_agent_credentials() -> Result<()> {
     })
     .await
     {
-        assert!(Error::ErrLocalUfragInsufficientBits.equal(&err));
+        assert_eq!(err, Error::ErrLocalUfragInsufficientBits);
     } else {
         panic!("expected error, but got ok");
     }
@@ -1562,7 +1557,7 @@ async fn test_agent_credentials() -> Result<()> {
     })
     .await
     {
-        assert!(Error::ErrLocalPwdInsufficientBits.equal(&err));
+        assert_eq!(err, Error::ErrLocalPwdInsufficientBits);
     } else {
         panic!("expected error, but got ok");
     }
@@ -1614,8 +1609,8 @@ async fn test_connection_state_failed_delete_all_candidates() -> Result<()> {
 
     {
         let ai = a_agent.agent_internal.lock().await;
-        assert_eq!(ai.remote_candidates.len(), 0);
-        assert_eq!(ai.local_candidates.len(), 0);
+        assert_eq!(ai.core.remote_candidates.len(), 0);
+        assert_eq!(ai.core.local_candidates.len(), 0);
     }
 
     a_agent.close().await?;
@@ -1718,7 +1713,7 @@ async fn test_agent_restart_during_gather() -> Result<()> {
         .store(GatheringState::Gathering as u8, Ordering::SeqCst);
 
     if let Err(err) = agent.restart("".to_owned(), "".to_owned()).await {
-        assert!(Error::ErrRestartWhenGathering.equal(&err));
+        assert_eq!(err, Error::ErrRestartWhenGathering);
     } else {
         panic!("expected error, but got ok");
     }
@@ -1736,7 +1731,7 @@ async fn test_agent_restart_when_closed() -> Result<()> {
     agent.close().await?;
 
     if let Err(err) = agent.restart("".to_owned(), "".to_owned()).await {
-        assert!(Error::ErrClosed.equal(&err));
+        assert_eq!(err, Error::ErrClosed);
     } else {
         panic!("expected error, but got ok");
     }
@@ -1873,9 +1868,12 @@ async fn test_get_remote_credentials() -> Result<()> {
 
     let (remote_ufrag, remote_pwd) = {
         let mut ai = a.agent_internal.lock().await;
-        ai.remote_ufrag = "remoteUfrag".to_owned();
-        ai.remote_pwd = "remotePwd".to_owned();
-        (ai.remote_ufrag.to_owned(), ai.remote_pwd.to_owned())
+        ai.core.remote_ufrag = "remoteUfrag".to_owned();
+        ai.core.remote_pwd = "remotePwd".to_owned();
+        (
+            ai.core.remote_ufrag.to_owned(),
+            ai.core.remote_pwd.to_owned(),
+        )
     };
 
     let (actual_ufrag, actual_pwd) = a.get_remote_user_credentials().await;
@@ -2147,3 +2145,66 @@ async fn test_lite_lifecycle() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_max_candidate_pairs() -> Result<()> {
+    let a = Agent::new(AgentConfig {
+        max_candidate_pairs: Some(2),
+        ..Default::default()
+    })
+    .await?;
+
+    let host_local: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "udp".to_owned(),
+                address: "192.168.1.1".to_owned(),
+                port: 19216,
+                component: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .new_candidate_host()
+        .await?,
+    );
+
+    let mut remotes: Vec<Arc<dyn Candidate + Send + Sync>> = vec![];
+    for (port, priority) in [(10001, 300), (10002, 100), (10003, 200), (10004, 50)] {
+        remotes.push(Arc::new(
+            CandidateHostConfig {
+                base_config: CandidateBaseConfig {
+                    network: "udp".to_owned(),
+                    address: "1.2.3.5".to_owned(),
+                    port,
+                    component: 1,
+                    priority,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }
+            .new_candidate_host()
+            .await?,
+        ));
+    }
+
+    {
+        let mut ai = a.agent_internal.lock().await;
+        for remote in &remotes {
+            ai.core
+                .add_pair(Arc::clone(&host_local), Arc::clone(remote));
+        }
+
+        // The pairs with priority 100 and 50 should have been dropped
+        assert!(ai.core.find_pair(&host_local, &remotes[0]).is_some());
+        assert!(ai.core.find_pair(&host_local, &remotes[1]).is_none());
+        assert!(ai.core.find_pair(&host_local, &remotes[2]).is_some());
+        assert!(ai.core.find_pair(&host_local, &remotes[3]).is_none());
+
+        assert_eq!(ai.core.checklist().len(), 2, "checklist should be limited");
+    }
+
+    a.close().await?;
+
+    Ok(())
+}
@@ -1,9 +1,15 @@
+use super::agent_core::{best_candidate_pair, find_candidate};
+use super::agent_demux::PacketClass;
+use super::agent_stats::ReceiveBufferStats;
 use super::*;
+use crate::buffer::{BufferOverflowPolicy, BufferPool, Dropped, PacketQueue, PooledBuffer};
 use crate::error::*;
 
 use async_trait::async_trait;
+use std::collections::HashMap;
 use std::io;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::RwLock;
 use util::Conn;
 
 impl Agent {
@@ -17,7 +23,7 @@ impl Agent {
         mut cancel_rx: mpsc::Receiver<()>,
         remote_ufrag: String,
         remote_pwd: String,
-    ) -> Result<Arc<impl Conn>> {
+    ) -> Result<Arc<AgentConn>> {
         let (on_connected_rx, agent_conn) = {
             let agent_internal = Arc::clone(&self.agent_internal);
             let mut ai = self.agent_internal.lock().await;
@@ -31,7 +37,7 @@ impl Agent {
             tokio::select! {
                 _ = on_connected_rx.recv() => {},
                 _ = cancel_rx.recv() => {
-                    return Err(Error::ErrCanceledByCaller.into());
+                    return Err(Error::ErrCanceledByCaller);
                 }
             }
         }
@@ -48,7 +54,7 @@ impl Agent {
         mut cancel_rx: mpsc::Receiver<()>,
         remote_ufrag: String,
         remote_pwd: String,
-    ) -> Result<Arc<impl Conn>> {
+    ) -> Result<Arc<AgentConn>> {
         let (on_connected_rx, agent_conn) = {
             let agent_internal = Arc::clone(&self.agent_internal);
             let mut ai = self.agent_internal.lock().await;
@@ -62,7 +68,7 @@ impl Agent {
             tokio::select! {
                 _ = on_connected_rx.recv() => {},
                 _ = cancel_rx.recv() => {
-                    return Err(Error::ErrCanceledByCaller.into());
+                    return Err(Error::ErrCanceledByCaller);
                 }
             }
         }
@@ -71,27 +77,53 @@ impl Agent {
     }
 }
 
-pub(crate) struct AgentConn {
+/// The connection established by `dial` and `accept`, sending and receiving over the selected
+/// candidate pair. Besides implementing `Conn`, it provides the same methods returning the typed
+/// errors of this crate.
+pub struct AgentConn {
     pub(crate) selected_pair: Mutex<Option<Arc<CandidatePair>>>,
     pub(crate) checklist: Mutex<Vec<Arc<CandidatePair>>>,
 
-    pub(crate) buffer: Buffer,
+    // The remote candidates application data is accepted from, mirrored from the core so that
+    // received data is delivered without taking the agent lock.
+    pub(crate) remote_candidates:
+        RwLock<HashMap<NetworkType, Vec<Arc<dyn Candidate + Send + Sync>>>>,
+    // When data was last received on the selected pair, passed on to the core by the taskLoop
+    pub(crate) selected_pair_last_received: std::sync::Mutex<Option<Instant>>,
+
+    pub(crate) pool: BufferPool,
+    pub(crate) buffer: PacketQueue,
+    // The buffers of the demux endpoints, receiving the datagrams of their class
+    pub(crate) endpoints: RwLock<HashMap<PacketClass, Arc<PacketQueue>>>,
     pub(crate) bytes_received: AtomicUsize,
     pub(crate) bytes_sent: AtomicUsize,
+    pub(crate) packets_dropped: AtomicU64,
+    pub(crate) bytes_dropped: AtomicU64,
+    // Wakes the routine calling the on_packets_dropped handler, taken when the agent closes
+    pub(crate) chan_packets_dropped_tx: std::sync::Mutex<Option<mpsc::Sender<()>>>,
     pub(crate) done: AtomicBool,
 }
 
 impl AgentConn {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(
+        buffer_size: usize,
+        overflow: BufferOverflowPolicy,
+        chan_packets_dropped_tx: Option<mpsc::Sender<()>>,
+    ) -> Self {
         Self {
             selected_pair: Mutex::new(None),
             checklist: Mutex::new(vec![]),
-            // Make sure the buffer doesn't grow indefinitely.
-            // NOTE: We actually won't get anywhere close to this limit.
-            // SRTP will constantly read from the endpoint and drop packets if it's full.
-            buffer: Buffer::new(0, MAX_BUFFER_SIZE),
+            remote_candidates: RwLock::new(HashMap::new()),
+            selected_pair_last_received: std::sync::Mutex::new(None),
+            pool: BufferPool::new(RECEIVE_MTU, MAX_IDLE_RECEIVE_BUFFERS),
+            // Make sure the buffer doesn't grow indefinitely
+            buffer: PacketQueue::new(buffer_size, overflow),
+            endpoints: RwLock::new(HashMap::new()),
             bytes_received: AtomicUsize::new(0),
             bytes_sent: AtomicUsize::new(0),
+            packets_dropped: AtomicU64::new(0),
+            bytes_dropped: AtomicU64::new(0),
+            chan_packets_dropped_tx: std::sync::Mutex::new(chan_packets_dropped_tx),
             done: AtomicBool::new(false),
         }
     }
@@ -101,45 +133,104 @@ impl AgentConn {
     }
 
     pub(crate) async fn get_best_available_candidate_pair(&self) -> Option<Arc<CandidatePair>> {
-        let mut best: Option<&Arc<CandidatePair>> = None;
-
         let checklist = self.checklist.lock().await;
-        for p in &*checklist {
-            if p.state.load(Ordering::SeqCst) == CandidatePairState::Failed as u8 {
-                continue;
+        best_candidate_pair(&checklist, |state| {
+            state != CandidatePairState::Failed as u8
+        })
+    }
+
+    /// Delivers application data received on the local candidate `local` from `remote`, unless
+    /// it doesn't come from a known remote candidate. Only the locks of the connection are
+    /// taken, so the data path doesn't wait for the connectivity checks.
+    pub(crate) async fn handle_data(
+        &self,
+        local: &Arc<dyn Candidate + Send + Sync>,
+        remote: SocketAddr,
+        buf: PooledBuffer,
+    ) {
+        let remote_candidate = {
+            let remote_candidates = self.remote_candidates.read().unwrap();
+            find_candidate(&remote_candidates, local.network_type(), remote)
+        };
+        let remote_candidate = match remote_candidate {
+            Some(remote_candidate) => remote_candidate,
+            None => {
+                log::warn!(
+                    "Discarded message from {}, not a valid remote candidate",
+                    local.addr()
+                );
+                return;
             }
+        };
 
-            if let Some(b) = &mut best {
-                if b.priority() < p.priority() {
-                    *b = p;
-                }
-            } else {
-                best = Some(p);
+        remote_candidate.seen(false);
+        if let Some(selected_pair) = self.get_selected_pair().await {
+            if selected_pair.remote.equal(&*remote_candidate) {
+                *self.selected_pair_last_received.lock().unwrap() = Some(Instant::now());
             }
         }
 
-        best.cloned()
+        match self.route(buf).await {
+            Ok(dropped) => self.count_dropped(dropped),
+            Err(err) => log::warn!("failed to write packet: {}", err),
+        }
     }
 
-    pub(crate) async fn get_best_valid_candidate_pair(&self) -> Option<Arc<CandidatePair>> {
-        let mut best: Option<&Arc<CandidatePair>> = None;
+    /// Drops application data received while another datagram waits for room in the receive
+    /// buffer with `BufferOverflowPolicy::Block`.
+    pub(crate) fn drop_data(&self, buf: PooledBuffer) {
+        self.count_dropped(Dropped {
+            packets: 1,
+            bytes: buf.len(),
+        });
+    }
 
-        let checklist = self.checklist.lock().await;
-        for p in &*checklist {
-            if p.state.load(Ordering::SeqCst) != CandidatePairState::Succeeded as u8 {
-                continue;
-            }
+    fn count_dropped(&self, dropped: Dropped) {
+        if dropped.packets == 0 {
+            return;
+        }
+        let total = self
+            .packets_dropped
+            .fetch_add(dropped.packets as u64, Ordering::SeqCst);
+        self.bytes_dropped
+            .fetch_add(dropped.bytes as u64, Ordering::SeqCst);
+        log::trace!(
+            "receive buffer full, dropped {} packets, {} in total",
+            dropped.packets,
+            total + dropped.packets as u64
+        );
+
+        // The handler reads the totals, so a pending notification covers this drop as well
+        if let Some(tx) = &*self.chan_packets_dropped_tx.lock().unwrap() {
+            let _ = tx.try_send(());
+        }
+    }
 
-            if let Some(b) = &mut best {
-                if b.priority() < p.priority() {
-                    *b = p;
-                }
-            } else {
-                best = Some(p);
-            }
+    /// Returns the statistics of the receive buffers, those of the demux endpoints included.
+    pub fn receive_buffer_stats(&self) -> ReceiveBufferStats {
+        let (mut packets_queued, mut bytes_queued) = (self.buffer.len(), self.buffer.size());
+        for buffer in self.endpoints.read().unwrap().values() {
+            packets_queued += buffer.len();
+            bytes_queued += buffer.size();
         }
 
-        best.cloned()
+        ReceiveBufferStats {
+            timestamp: tokio::time::Instant::now(),
+            packets_queued,
+            bytes_queued,
+            packets_dropped: self.packets_dropped(),
+            bytes_dropped: self.bytes_dropped(),
+        }
+    }
+
+    /// Returns the number of received packets dropped because the receive buffer was full.
+    pub fn packets_dropped(&self) -> u64 {
+        self.packets_dropped.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of bytes dropped because the receive buffer was full.
+    pub fn bytes_dropped(&self) -> u64 {
+        self.bytes_dropped.load(Ordering::SeqCst)
     }
 
     /// Returns the number of bytes sent.
@@ -151,84 +242,122 @@ impl AgentConn {
     pub fn bytes_received(&self) -> usize {
         self.bytes_received.load(Ordering::SeqCst)
     }
-}
 
-#[async_trait]
-impl Conn for AgentConn {
-    async fn connect(&self, _addr: SocketAddr) -> Result<()> {
-        Err(io::Error::new(io::ErrorKind::Other, "Not applicable").into())
+    /// Reads a packet received on the selected candidate pair into `buf`.
+    pub async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        if self.done.load(Ordering::SeqCst) {
+            return Err(Error::ErrClosed);
+        }
+
+        let packet = self.recv_packet().await?;
+        let n = packet.len().min(buf.len());
+        buf[..n].copy_from_slice(&packet[..n]);
+        if n < packet.len() {
+            return Err(Error::Buffer(util::buffer::error::Error::ErrBufferShort));
+        }
+
+        Ok(n)
     }
 
-    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+    /// Takes the next packet received on the selected candidate pair, handing over the buffer it
+    /// was received into instead of copying it. The buffer returns to the pool once dropped.
+    pub async fn recv_packet(&self) -> Result<PooledBuffer> {
         if self.done.load(Ordering::SeqCst) {
-            return Err(io::Error::new(io::ErrorKind::Other, "Conn is closed").into());
+            return Err(Error::ErrClosed);
         }
 
-        let n = match self.buffer.read(buf, None).await {
-            Ok(n) => n,
-            Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err.to_string()).into()),
-        };
-        self.bytes_received.fetch_add(n, Ordering::SeqCst);
+        let packet = self.buffer.pop().await?;
+        self.bytes_received
+            .fetch_add(packet.len(), Ordering::SeqCst);
 
-        Ok(n)
+        Ok(packet)
     }
 
-    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+    /// Reads a packet like `recv`, returning the address of the remote candidate as well.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
         if let Some(raddr) = self.remote_addr().await {
             let n = self.recv(buf).await?;
             Ok((n, raddr))
         } else {
-            Err(io::Error::new(io::ErrorKind::Other, "Not applicable").into())
+            Err(Error::ErrNoCandidatePairs)
         }
     }
 
-    async fn send(&self, buf: &[u8]) -> Result<usize> {
+    /// Sends `buf` on the selected candidate pair, or the best available one if none was selected
+    /// yet. STUN messages are rejected.
+    pub async fn send(&self, buf: &[u8]) -> Result<usize> {
         if self.done.load(Ordering::SeqCst) {
-            return Err(io::Error::new(io::ErrorKind::Other, "Conn is closed").into());
+            return Err(Error::ErrClosed);
         }
 
         if is_message(buf) {
-            return Err(Error::ErrIceWriteStunMessage.into());
+            return Err(Error::ErrIceWriteStunMessage);
         }
 
-        let result = if let Some(pair) = self.get_selected_pair().await {
-            pair.write(buf).await
+        let n = if let Some(pair) = self.get_selected_pair().await {
+            pair.write(buf).await?
         } else if let Some(pair) = self.get_best_available_candidate_pair().await {
-            pair.write(buf).await
+            pair.write(buf).await?
         } else {
-            Ok(0)
+            0
         };
+        self.bytes_sent.fetch_add(buf.len(), Ordering::SeqCst);
 
-        match result {
-            Ok(n) => {
-                self.bytes_sent.fetch_add(buf.len(), Ordering::SeqCst);
-                Ok(n)
-            }
-            Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.to_string()).into()),
+        Ok(n)
+    }
+
+    /// Returns the address of the local candidate of the selected pair.
+    pub async fn local_addr(&self) -> Result<SocketAddr> {
+        if let Some(pair) = self.get_selected_pair().await {
+            Ok(pair.local.addr())
+        } else {
+            Err(Error::ErrNoCandidatePairs)
         }
     }
 
-    async fn send_to(&self, _buf: &[u8], _target: SocketAddr) -> Result<usize> {
+    /// Returns the address of the remote candidate of the selected pair.
+    pub async fn remote_addr(&self) -> Option<SocketAddr> {
+        self.get_selected_pair()
+            .await
+            .map(|pair| pair.remote.addr())
+    }
+
+    pub async fn close(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Conn for AgentConn {
+    async fn connect(&self, _addr: SocketAddr) -> anyhow::Result<()> {
         Err(io::Error::new(io::ErrorKind::Other, "Not applicable").into())
     }
 
-    async fn local_addr(&self) -> Result<SocketAddr> {
-        if let Some(pair) = self.get_selected_pair().await {
-            Ok(pair.local.addr().await)
-        } else {
-            Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "Addr Not Available").into())
-        }
+    async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        Ok(AgentConn::recv(self, buf).await?)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> anyhow::Result<(usize, SocketAddr)> {
+        Ok(AgentConn::recv_from(self, buf).await?)
+    }
+
+    async fn send(&self, buf: &[u8]) -> anyhow::Result<usize> {
+        Ok(AgentConn::send(self, buf).await?)
+    }
+
+    async fn send_to(&self, _buf: &[u8], _target: SocketAddr) -> anyhow::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable").into())
+    }
+
+    async fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(AgentConn::local_addr(self).await?)
     }
 
     async fn remote_addr(&self) -> Option<SocketAddr> {
-        if let Some(pair) = self.get_selected_pair().await {
-            Some(pair.remote.addr().await)
-        } else {
-            None
-        }
+        AgentConn::remote_addr(self).await
     }
 
-    async fn close(&self) -> Result<()> {
-        Ok(())
+    async fn close(&self) -> anyhow::Result<()> {
+        Ok(AgentConn::close(self).await?)
     }
 }
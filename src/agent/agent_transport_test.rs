@@ -1,5 +1,6 @@
 use super::agent_vnet_test::*;
 use super::*;
+use crate::buffer::BufferOverflowPolicy;
 
 use util::{vnet::*, Conn};
 use waitgroup::WaitGroup;
@@ -7,7 +8,7 @@ use waitgroup::WaitGroup;
 pub(crate) async fn pipe(
     default_config0: Option<AgentConfig>,
     default_config1: Option<AgentConfig>,
-) -> Result<(Arc<impl Conn>, Arc<impl Conn>, Arc<Agent>, Arc<Agent>)> {
+) -> Result<(Arc<AgentConn>, Arc<AgentConn>, Arc<Agent>, Arc<Agent>)> {
     let (a_notifier, mut a_connected) = on_connected();
     let (b_notifier, mut b_connected) = on_connected();
 
@@ -68,7 +69,8 @@ async fn test_remote_local_addr() -> Result<()> {
 
     //"Disconnected Returns nil"
     {
-        let disconnected_conn = AgentConn::new();
+        let disconnected_conn =
+            AgentConn::new(MAX_BUFFER_SIZE, BufferOverflowPolicy::DropNewest, None);
         let result = disconnected_conn.local_addr().await;
         assert!(result.is_err(), "Disconnected Returns nil");
     }
@@ -129,3 +131,133 @@ async fn test_conn_stats() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_recv_while_agent_locked() -> Result<()> {
+    let (ca, cb, a_agent, b_agent) = pipe(None, None).await?;
+
+    // Application data is delivered without the agent lock, which the connectivity checks hold
+    {
+        let _ai = b_agent.agent_internal.lock().await;
+        ca.send(b"data").await?;
+
+        let mut buf = vec![0u8; 10];
+        let nb = tokio::time::timeout(Duration::from_secs(5), cb.recv(&mut buf))
+            .await
+            .expect("data is delivered while the agent is locked")?;
+        assert_eq!(&buf[..nb], b"data");
+    }
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_receive_buffer_overflow() -> Result<()> {
+    let cfg1 = AgentConfig {
+        receive_buffer_size: Some(10),
+        receive_buffer_overflow: BufferOverflowPolicy::DropOldest,
+        ..Default::default()
+    };
+    let (ca, cb, a_agent, b_agent) = pipe(None, Some(cfg1)).await?;
+
+    let (dropped_tx, mut dropped_rx) = mpsc::channel(4);
+    let dropped_tx = Arc::new(dropped_tx);
+    let agent_internal = Arc::clone(&b_agent.agent_internal);
+    b_agent
+        .on_packets_dropped(Box::new(move |stats: ReceiveBufferStats| {
+            let dropped_tx = Arc::clone(&dropped_tx);
+            let agent_internal = Arc::clone(&agent_internal);
+            Box::pin(async move {
+                // The handler isn't called with the agent locked, so it may use the agent
+                let _ai = agent_internal.lock().await;
+                let _ = dropped_tx.send(stats).await;
+            })
+        }))
+        .await;
+
+    ca.send(b"abcd").await?;
+    ca.send(b"efgh").await?;
+    ca.send(b"ijkl").await?;
+
+    let stats = tokio::time::timeout(Duration::from_secs(5), dropped_rx.recv())
+        .await
+        .expect("the handler is fired")
+        .unwrap();
+    assert_eq!(stats.packets_dropped, 1);
+    assert_eq!(stats.bytes_dropped, 4);
+    assert_eq!(stats.packets_queued, 2);
+    assert_eq!(stats.bytes_queued, 8);
+    assert_eq!(cb.packets_dropped(), 1);
+
+    let mut buf = vec![0u8; 10];
+    let n = cb.recv(&mut buf).await?;
+    assert_eq!(&buf[..n], b"efgh", "the oldest packet was dropped");
+    let n = cb.recv(&mut buf).await?;
+    assert_eq!(&buf[..n], b"ijkl");
+
+    let stats = b_agent.get_receive_buffer_stats().await;
+    assert_eq!(stats.packets_dropped, 1);
+    assert_eq!(stats.packets_queued, 0);
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_receive_buffer_block_keeps_connectivity() -> Result<()> {
+    let cfg0 = AgentConfig {
+        keepalive_interval: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let cfg1 = AgentConfig {
+        receive_buffer_size: Some(8),
+        receive_buffer_overflow: BufferOverflowPolicy::Block,
+        keepalive_interval: Some(Duration::from_millis(100)),
+        disconnected_timeout: Some(Duration::from_millis(500)),
+        failed_timeout: Some(Duration::from_secs(5)),
+        ..Default::default()
+    };
+    let (ca, cb, a_agent, b_agent) = pipe(Some(cfg0), Some(cfg1)).await?;
+    let mut events = b_agent.subscribe().await;
+
+    ca.send(b"abcd").await?;
+    ca.send(b"efgh").await?;
+    // Waits for room until the buffer is read
+    ca.send(b"ijkl").await?;
+    // Received while the previous datagram waits, so it is dropped
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    ca.send(b"mnop").await?;
+
+    // The keepalives are answered with the receive buffer full, so the agent stays connected
+    let disconnected = tokio::time::timeout(Duration::from_secs(2), async {
+        while let Some(event) = events.recv().await {
+            if let AgentEvent::ConnectionStateChange(state) = event {
+                return state;
+            }
+        }
+        ConnectionState::Unspecified
+    })
+    .await;
+    assert!(
+        disconnected.is_err(),
+        "the connection state changed to {}",
+        disconnected.unwrap()
+    );
+    assert_eq!(cb.packets_dropped(), 1);
+
+    let mut buf = vec![0u8; 10];
+    for expected in [&b"abcd"[..], b"efgh", b"ijkl"] {
+        let n = cb.recv(&mut buf).await?;
+        assert_eq!(&buf[..n], expected);
+    }
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    Ok(())
+}
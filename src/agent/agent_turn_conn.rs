@@ -0,0 +1,321 @@
+use crate::error::Result;
+use crate::turn_credentials::OauthToken;
+use crate::util::error_code;
+
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use stun::attributes::*;
+use stun::fingerprint::FINGERPRINT;
+use stun::integrity::MessageIntegrity;
+use stun::message::*;
+use stun::textattrs::TextAttribute;
+use turn::proto::relayaddr::RelayedAddress;
+use turn::proto::reqfamily::REQUESTED_FAMILY_IPV6;
+use util::Conn;
+
+/// ACCESS-TOKEN attribute of third-party authorization (rfc7635).
+pub(crate) const ATTR_ACCESS_TOKEN: AttrType = AttrType(0x001B);
+/// ADDITIONAL-ADDRESS-FAMILY attribute requesting a dual-stack allocation (rfc8656).
+pub(crate) const ATTR_ADDITIONAL_ADDRESS_FAMILY: AttrType = AttrType(0x8000);
+/// ADDRESS-ERROR-CODE attribute of a dual-stack allocation lacking one of the families
+/// (rfc8656).
+pub(crate) const ATTR_ADDRESS_ERROR_CODE: AttrType = AttrType(0x8001);
+
+/// The address families of the relays an Allocate request asks for (rfc8656 section 7.1).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum AllocationFamily {
+    /// An IPv4 relay, which is allocated by default.
+    Ipv4,
+    /// An IPv6 relay, requested with REQUESTED-ADDRESS-FAMILY.
+    Ipv6,
+    /// An IPv4 and an IPv6 relay, requested with ADDITIONAL-ADDRESS-FAMILY.
+    DualStack,
+}
+
+/// Rewrites the requests of a TURN client before they are sent, for what the client can't do
+/// itself: requesting the address families of the relays (rfc8656), authenticating with an OAuth
+/// token (rfc7635) and authenticating with renewed credentials. Responses are passed through
+/// unchanged, the error code of an error response to an Allocate request is kept as the client
+/// only reports it as text, and so are the relayed addresses of a dual-stack allocation as the
+/// client only reports the first one. The server signs the responses to re-signed requests with other
+/// credentials than those of the client, which works as the client doesn't check the integrity
+/// of responses.
+pub(crate) struct TurnRequestConn {
+    conn: Arc<dyn Conn + Send + Sync>,
+    credentials: std::sync::Mutex<RequestCredentials>,
+    family: AllocationFamily,
+    allocate_error: std::sync::Mutex<Option<(u16, String)>>,
+    relayed_addrs: std::sync::Mutex<Vec<SocketAddr>>,
+    address_error: std::sync::Mutex<Option<(u16, String)>>,
+}
+
+struct RequestCredentials {
+    username: String,
+    password: String,
+    oauth_token: Option<OauthToken>,
+    // Whether they differ from the credentials the client signs its requests with
+    renewed: bool,
+}
+
+impl TurnRequestConn {
+    /// Wraps the `conn` of a TURN client which signs its requests with `username` and
+    /// `password`. Signed requests are re-signed with the MAC key of `oauth_token` if there is
+    /// one, and Allocate requests ask for the relays of `family`.
+    pub(crate) fn new(
+        conn: Arc<dyn Conn + Send + Sync>,
+        username: String,
+        password: String,
+        oauth_token: Option<OauthToken>,
+        family: AllocationFamily,
+    ) -> Self {
+        TurnRequestConn {
+            conn,
+            credentials: std::sync::Mutex::new(RequestCredentials {
+                username,
+                password,
+                oauth_token,
+                renewed: false,
+            }),
+            family,
+            allocate_error: std::sync::Mutex::new(None),
+            relayed_addrs: std::sync::Mutex::new(vec![]),
+            address_error: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Signs the requests sent from now on with `username`, `password` and `oauth_token`
+    /// instead, so the client keeps refreshing its allocation and permissions once the
+    /// credentials it was created with expired.
+    pub(crate) fn update_credentials(
+        &self,
+        username: String,
+        password: String,
+        oauth_token: Option<OauthToken>,
+    ) {
+        *self.credentials.lock().unwrap() = RequestCredentials {
+            username,
+            password,
+            oauth_token,
+            renewed: true,
+        };
+    }
+
+    /// Returns the error code and reason phrase of the error response to the last Allocate
+    /// request, if it was answered with one.
+    pub(crate) fn allocate_error(&self) -> Option<(u16, String)> {
+        self.allocate_error.lock().unwrap().clone()
+    }
+
+    /// Returns the relayed addresses of the success response to the last Allocate request.
+    pub(crate) fn relayed_addrs(&self) -> Vec<SocketAddr> {
+        self.relayed_addrs.lock().unwrap().clone()
+    }
+
+    /// Returns the error code and reason phrase of the ADDRESS-ERROR-CODE of the success
+    /// response to the last Allocate request, if the server refused one of the families of a
+    /// dual-stack allocation.
+    pub(crate) fn address_error(&self) -> Option<(u16, String)> {
+        self.address_error.lock().unwrap().clone()
+    }
+
+    /// Tracks the Allocate requests sent in `raw` and the responses received in it.
+    fn observe(&self, raw: &[u8]) {
+        if !is_message(raw) {
+            return;
+        }
+        let typ = u16::from_be_bytes([raw[0], raw[1]]);
+        if typ == MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST).value() {
+            *self.allocate_error.lock().unwrap() = None;
+            self.relayed_addrs.lock().unwrap().clear();
+            *self.address_error.lock().unwrap() = None;
+            return;
+        }
+
+        let success = typ == MessageType::new(METHOD_ALLOCATE, CLASS_SUCCESS_RESPONSE).value();
+        if !success && typ != MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE).value() {
+            return;
+        }
+        let mut m = Message::new();
+        m.raw = raw.to_vec();
+        if m.decode().is_err() {
+            return;
+        }
+        if success {
+            *self.relayed_addrs.lock().unwrap() = relayed_addrs(&m);
+            *self.address_error.lock().unwrap() = address_error_code(&m);
+        } else {
+            *self.allocate_error.lock().unwrap() = error_code(&m).ok();
+        }
+    }
+
+    /// Returns the rewritten `raw`, or `None` if it is left as is.
+    pub(crate) fn rewrite(&self, raw: &[u8]) -> Option<Vec<u8>> {
+        let credentials = self.credentials.lock().unwrap();
+        let resign = credentials.oauth_token.is_some() || credentials.renewed;
+        if !is_message(raw) || (!resign && self.family == AllocationFamily::Ipv4) {
+            return None;
+        }
+        let mut m = Message::new();
+        m.raw = raw.to_vec();
+        if m.decode().is_err() {
+            return None;
+        }
+
+        let signed = m.contains(ATTR_MESSAGE_INTEGRITY);
+        let family_attr = match self.family {
+            _ if m.typ != MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST) => None,
+            AllocationFamily::Ipv4 => None,
+            AllocationFamily::Ipv6 => Some(ATTR_REQUESTED_ADDRESS_FAMILY),
+            AllocationFamily::DualStack => Some(ATTR_ADDITIONAL_ADDRESS_FAMILY),
+        };
+        if family_attr.is_none() && !(signed && resign) {
+            return None;
+        }
+
+        match Self::rewrite_message(&m, &credentials, signed, family_attr) {
+            Ok(raw) => Some(raw),
+            Err(err) => {
+                // The server rejects the request if the credentials of the client expired
+                log::warn!(
+                    "failed to rewrite TURN {} request, sending it as the client built it: {}",
+                    m.typ.method,
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Copies `m` with the USERNAME and integrity of `credentials` if it is `signed`, and with the
+    /// `family_attr` requesting an IPv6 relay if there is one.
+    fn rewrite_message(
+        m: &Message,
+        credentials: &RequestCredentials,
+        signed: bool,
+        family_attr: Option<AttrType>,
+    ) -> Result<Vec<u8>> {
+        let mut out = Message::new();
+        out.typ = m.typ;
+        out.transaction_id = m.transaction_id;
+        out.write_header();
+        // MESSAGE-INTEGRITY and FINGERPRINT are the last attributes, they are recomputed
+        for attr in &m.attributes.0 {
+            if attr.typ == ATTR_MESSAGE_INTEGRITY || attr.typ == ATTR_FINGERPRINT {
+                break;
+            }
+            if attr.typ == ATTR_USERNAME && signed {
+                out.add(ATTR_USERNAME, credentials.username.as_bytes());
+            } else if attr.typ != ATTR_ACCESS_TOKEN
+                && attr.typ != ATTR_REQUESTED_ADDRESS_FAMILY
+                && attr.typ != ATTR_ADDITIONAL_ADDRESS_FAMILY
+            {
+                out.add(attr.typ, &attr.value);
+            }
+        }
+        if let Some(family_attr) = family_attr {
+            // Both attributes carry the family followed by three reserved bytes
+            out.add(family_attr, &[REQUESTED_FAMILY_IPV6.0, 0, 0, 0]);
+        }
+        if signed {
+            let integrity = match &credentials.oauth_token {
+                Some(token) => {
+                    out.add(ATTR_ACCESS_TOKEN, &token.access_token);
+                    MessageIntegrity(token.mac_key.clone())
+                }
+                None => {
+                    let realm = TextAttribute::get_from_as(m, ATTR_REALM)?;
+                    MessageIntegrity::new_long_term_integrity(
+                        credentials.username.clone(),
+                        realm.text,
+                        credentials.password.clone(),
+                    )
+                }
+            };
+            integrity.add_to(&mut out)?;
+        }
+        if m.contains(ATTR_FINGERPRINT) {
+            FINGERPRINT.add_to(&mut out)?;
+        }
+        Ok(out.raw)
+    }
+}
+
+/// Returns the addresses of all XOR-RELAYED-ADDRESS attributes of `m`, of which there are two in
+/// the response to a dual-stack Allocate request.
+fn relayed_addrs(m: &Message) -> Vec<SocketAddr> {
+    let mut addrs = vec![];
+    for attr in &m.attributes.0 {
+        if attr.typ != ATTR_XOR_RELAYED_ADDRESS {
+            continue;
+        }
+        // The address is decoded from a message of the attribute alone, the getter takes the
+        // first one only
+        let mut single = Message::new();
+        single.transaction_id = m.transaction_id;
+        single.add(attr.typ, &attr.value);
+        let mut relayed = RelayedAddress::default();
+        if relayed.get_from(&single).is_ok() {
+            addrs.push(SocketAddr::new(relayed.ip, relayed.port));
+        }
+    }
+    addrs
+}
+
+/// Returns the error code and reason phrase of the ADDRESS-ERROR-CODE of `m`, which are
+/// preceded by the family and a reserved byte instead of two reserved bytes of ERROR-CODE.
+fn address_error_code(m: &Message) -> Option<(u16, String)> {
+    let v = m.get(ATTR_ADDRESS_ERROR_CODE).ok()?;
+    if v.len() < 4 {
+        return None;
+    }
+    let code = u16::from(v[2] & 0x07) * 100 + u16::from(v[3]);
+    Some((code, String::from_utf8_lossy(&v[4..]).into_owned()))
+}
+
+#[async_trait]
+impl Conn for TurnRequestConn {
+    async fn connect(&self, addr: SocketAddr) -> anyhow::Result<()> {
+        self.conn.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let n = self.conn.recv(buf).await?;
+        self.observe(&buf[..n]);
+        Ok(n)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> anyhow::Result<(usize, SocketAddr)> {
+        let (n, src_addr) = self.conn.recv_from(buf).await?;
+        self.observe(&buf[..n]);
+        Ok((n, src_addr))
+    }
+
+    async fn send(&self, buf: &[u8]) -> anyhow::Result<usize> {
+        self.observe(buf);
+        match self.rewrite(buf) {
+            Some(raw) => self.conn.send(&raw).await,
+            None => self.conn.send(buf).await,
+        }
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> anyhow::Result<usize> {
+        self.observe(buf);
+        match self.rewrite(buf) {
+            Some(raw) => self.conn.send_to(&raw, target).await,
+            None => self.conn.send_to(buf, target).await,
+        }
+    }
+
+    async fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        self.conn.local_addr().await
+    }
+
+    async fn remote_addr(&self) -> Option<SocketAddr> {
+        self.conn.remote_addr().await
+    }
+
+    async fn close(&self) -> anyhow::Result<()> {
+        self.conn.close().await
+    }
+}
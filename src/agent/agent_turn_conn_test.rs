@@ -0,0 +1,392 @@
+use super::agent_turn_conn::*;
+use crate::error::Result;
+use crate::turn_credentials::OauthToken;
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use stun::agent::TransactionId;
+use stun::attributes::*;
+use stun::error_code::{ErrorCodeAttribute, CODE_UNAUTHORIZED};
+use stun::fingerprint::FINGERPRINT;
+use stun::integrity::MessageIntegrity;
+use stun::message::*;
+use stun::textattrs::*;
+use stun::xoraddr::XorMappedAddress;
+use turn::proto::lifetime::Lifetime;
+use turn::proto::relayaddr::RelayedAddress;
+use turn::proto::reqfamily::{RequestedAddressFamily, REQUESTED_FAMILY_IPV6};
+use util::Conn;
+
+async fn turn_request_conn(
+    oauth_token: Option<OauthToken>,
+    family: AllocationFamily,
+) -> Result<TurnRequestConn> {
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(
+        tokio::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).await?,
+    );
+    Ok(TurnRequestConn::new(
+        conn,
+        "kid".to_owned(),
+        "password".to_owned(),
+        oauth_token,
+        family,
+    ))
+}
+
+// A request of the TURN client, signed with the long-term key of "kid", "realm" and "password".
+fn signed_request(method: Method) -> Result<Message> {
+    let mut m = Message::new();
+    m.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(method, CLASS_REQUEST)),
+        Box::new(Username::new(ATTR_USERNAME, "kid".to_owned())),
+        Box::new(Realm::new(ATTR_REALM, "realm".to_owned())),
+        Box::new(Nonce::new(ATTR_NONCE, "nonce".to_owned())),
+        Box::new(MessageIntegrity::new_long_term_integrity(
+            "kid".to_owned(),
+            "realm".to_owned(),
+            "password".to_owned(),
+        )),
+        Box::new(FINGERPRINT),
+    ])?;
+    Ok(m)
+}
+
+/// Starts a TURN server on the loopback interface which allocates the `relayed` addresses to
+/// clients authenticating with `username` and `password`, and signs its responses with their
+/// long-term key. The IPv6 ones are only allocated with ADDITIONAL-ADDRESS-FAMILY. It doesn't
+/// relay anything.
+pub(crate) async fn fake_turn_server(
+    username: &'static str,
+    password: &'static str,
+    relayed: Vec<SocketAddr>,
+) -> Result<SocketAddr> {
+    let server =
+        tokio::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).await?;
+    let server_addr = server.local_addr()?;
+    let integrity = MessageIntegrity::new_long_term_integrity(
+        username.to_owned(),
+        "realm".to_owned(),
+        password.to_owned(),
+    );
+
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 1500];
+        while let Ok((n, src)) = server.recv_from(&mut buf).await {
+            let mut request = match decode(buf[..n].to_vec()) {
+                Ok(request) if request.typ.class == CLASS_REQUEST => request,
+                _ => continue,
+            };
+            let authenticated = request.get(ATTR_USERNAME).ok().as_deref()
+                == Some(username.as_bytes())
+                && integrity.check(&mut request).is_ok();
+
+            let mut response = Message::new();
+            let result = if !authenticated {
+                response.build(&[
+                    Box::new(request.clone()),
+                    Box::new(MessageType::new(request.typ.method, CLASS_ERROR_RESPONSE)),
+                    Box::new(ErrorCodeAttribute {
+                        code: CODE_UNAUTHORIZED,
+                        reason: b"Unauthorized".to_vec(),
+                    }),
+                    Box::new(Realm::new(ATTR_REALM, "realm".to_owned())),
+                    Box::new(Nonce::new(ATTR_NONCE, "nonce".to_owned())),
+                ])
+            } else {
+                let mut setters: Vec<Box<dyn Setter>> = vec![
+                    Box::new(request.clone()),
+                    Box::new(MessageType::new(request.typ.method, CLASS_SUCCESS_RESPONSE)),
+                ];
+                if request.typ.method == METHOD_ALLOCATE {
+                    let dual_stack = request.contains(ATTR_ADDITIONAL_ADDRESS_FAMILY);
+                    for addr in relayed.iter().filter(|a| a.is_ipv4() || dual_stack) {
+                        setters.push(Box::new(RelayedAddress {
+                            ip: addr.ip(),
+                            port: addr.port(),
+                        }));
+                    }
+                    setters.push(Box::new(XorMappedAddress {
+                        ip: src.ip(),
+                        port: src.port(),
+                    }));
+                    setters.push(Box::new(Lifetime(Duration::from_secs(600))));
+                }
+                setters.push(Box::new(integrity.clone()));
+                setters.push(Box::new(FINGERPRINT));
+                response.build(&setters)
+            };
+            if result.is_ok() {
+                let _ = server.send_to(&response.raw, src).await;
+            }
+        }
+    });
+
+    Ok(server_addr)
+}
+
+fn decode(raw: Vec<u8>) -> Result<Message> {
+    let mut m = Message::new();
+    m.raw = raw;
+    m.decode()?;
+    Ok(m)
+}
+
+#[tokio::test]
+async fn test_turn_request_conn_oauth() -> Result<()> {
+    let token = OauthToken {
+        mac_key: b"mac key".to_vec(),
+        access_token: b"token".to_vec(),
+    };
+    let conn = turn_request_conn(Some(token), AllocationFamily::Ipv4).await?;
+
+    let m = signed_request(METHOD_REFRESH)?;
+    let mut authorized = decode(conn.rewrite(&m.raw).expect("a signed request"))?;
+    assert_eq!(authorized.typ, m.typ);
+    assert_eq!(authorized.transaction_id, m.transaction_id);
+    assert_eq!(
+        authorized.get(ATTR_USERNAME)?,
+        b"kid",
+        "the username is the kid"
+    );
+    assert_eq!(authorized.get(ATTR_ACCESS_TOKEN)?, b"token");
+    assert!(!authorized.contains(ATTR_REQUESTED_ADDRESS_FAMILY));
+    MessageIntegrity(b"mac key".to_vec()).check(&mut authorized)?;
+    FINGERPRINT.check(&authorized)?;
+
+    // Neither unsigned messages nor other packets are changed
+    let mut unsigned = Message::new();
+    unsigned.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST)),
+    ])?;
+    assert_eq!(conn.rewrite(&unsigned.raw), None);
+    assert_eq!(conn.rewrite(&[0x40, 0x00, 0x00, 0x04, 1, 2, 3, 4]), None);
+
+    // The size of what was sent is reported, not the size of the request of the client
+    let peer = tokio::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).await?;
+    let n = conn.send_to(&m.raw, peer.local_addr()?).await?;
+    assert_eq!(n, authorized.raw.len());
+    assert_ne!(n, m.raw.len());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_turn_request_conn_requested_family() -> Result<()> {
+    let conn = turn_request_conn(None, AllocationFamily::Ipv6).await?;
+
+    // The unauthenticated Allocate request
+    let mut unsigned = Message::new();
+    unsigned.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST)),
+        Box::new(FINGERPRINT),
+    ])?;
+    let rewritten = decode(conn.rewrite(&unsigned.raw).expect("an Allocate request"))?;
+    let mut family = RequestedAddressFamily::default();
+    family.get_from(&rewritten)?;
+    assert_eq!(family, REQUESTED_FAMILY_IPV6);
+    assert!(!rewritten.contains(ATTR_MESSAGE_INTEGRITY));
+    FINGERPRINT.check(&rewritten)?;
+
+    // The authenticated one is signed again with the long-term key
+    let m = signed_request(METHOD_ALLOCATE)?;
+    let mut rewritten = decode(conn.rewrite(&m.raw).expect("an Allocate request"))?;
+    family.get_from(&rewritten)?;
+    assert_eq!(family, REQUESTED_FAMILY_IPV6);
+    assert!(!rewritten.contains(ATTR_ACCESS_TOKEN));
+    MessageIntegrity::new_long_term_integrity(
+        "kid".to_owned(),
+        "realm".to_owned(),
+        "password".to_owned(),
+    )
+    .check(&mut rewritten)?;
+    FINGERPRINT.check(&rewritten)?;
+
+    // Other requests are left to the TURN client
+    assert_eq!(conn.rewrite(&signed_request(METHOD_REFRESH)?.raw), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_turn_request_conn_renewed_credentials() -> Result<()> {
+    let conn = turn_request_conn(None, AllocationFamily::Ipv4).await?;
+
+    // The requests are signed with the credentials of the client until they are renewed
+    let m = signed_request(METHOD_REFRESH)?;
+    assert_eq!(conn.rewrite(&m.raw), None);
+
+    conn.update_credentials("kid2".to_owned(), "password2".to_owned(), None);
+    let mut renewed = decode(conn.rewrite(&m.raw).expect("a signed request"))?;
+    assert_eq!(renewed.typ, m.typ);
+    assert_eq!(renewed.transaction_id, m.transaction_id);
+    assert_eq!(renewed.get(ATTR_USERNAME)?, b"kid2");
+    assert_eq!(renewed.get(ATTR_NONCE)?, b"nonce");
+    MessageIntegrity::new_long_term_integrity(
+        "kid2".to_owned(),
+        "realm".to_owned(),
+        "password2".to_owned(),
+    )
+    .check(&mut renewed)?;
+    FINGERPRINT.check(&renewed)?;
+
+    // Without a REALM the long-term key can't be derived, the request is sent as it is
+    let mut m = Message::new();
+    m.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_REFRESH, CLASS_REQUEST)),
+        Box::new(Username::new(ATTR_USERNAME, "kid".to_owned())),
+        Box::new(MessageIntegrity::new_short_term_integrity(
+            "password".to_owned(),
+        )),
+    ])?;
+    assert_eq!(conn.rewrite(&m.raw), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_turn_request_conn_client_ignores_response_integrity() -> Result<()> {
+    let relayed = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).into(), 50000);
+    let server_addr = fake_turn_server("kid2", "password2", vec![relayed]).await?;
+
+    let conn = Arc::new(turn_request_conn(None, AllocationFamily::Ipv4).await?);
+    conn.update_credentials("kid2".to_owned(), "password2".to_owned(), None);
+    let client = turn::client::Client::new(turn::client::ClientConfig {
+        stun_serv_addr: String::new(),
+        turn_serv_addr: server_addr.to_string(),
+        username: "kid".to_owned(),
+        password: "password".to_owned(),
+        realm: String::new(),
+        software: String::new(),
+        rto_in_ms: 0,
+        conn: Arc::clone(&conn) as Arc<dyn Conn + Send + Sync>,
+        vnet: None,
+    })
+    .await?;
+    client.listen().await?;
+
+    // The renewed credentials of the requests work only as long as the client doesn't check the
+    // responses, which the server signs with them instead of the ones of the client. If it
+    // starts to, it has to be recreated on renewal, losing the allocation.
+    let relay_conn = tokio::time::timeout(Duration::from_secs(5), client.allocate())
+        .await
+        .expect("the Allocate request is answered")?;
+    assert_eq!(relay_conn.local_addr().await?, relayed);
+
+    client.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_turn_request_conn_allocate_error() -> Result<()> {
+    let conn = turn_request_conn(None, AllocationFamily::Ipv4).await?;
+    let server =
+        tokio::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).await?;
+    let server_addr = server.local_addr()?;
+
+    let request = signed_request(METHOD_ALLOCATE)?;
+    conn.send_to(&request.raw, server_addr).await?;
+    let mut response = Message::new();
+    response.build(&[
+        Box::new(request),
+        Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE)),
+        Box::new(stun::error_code::ErrorCodeAttribute {
+            code: stun::error_code::CODE_WRONG_CREDENTIALS,
+            reason: b"Wrong Credentials".to_vec(),
+        }),
+    ])?;
+    server
+        .send_to(&response.raw, conn.local_addr().await?)
+        .await?;
+
+    let mut buf = vec![0u8; 1500];
+    let (n, _) = conn.recv_from(&mut buf).await?;
+    assert_eq!(&buf[..n], &response.raw[..]);
+    assert_eq!(
+        conn.allocate_error(),
+        Some((441, "Wrong Credentials".to_owned()))
+    );
+
+    // The next Allocate request starts over
+    conn.send_to(&signed_request(METHOD_ALLOCATE)?.raw, server_addr)
+        .await?;
+    assert_eq!(conn.allocate_error(), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_turn_request_conn_dual_stack() -> Result<()> {
+    let conn = turn_request_conn(None, AllocationFamily::DualStack).await?;
+    let server =
+        tokio::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).await?;
+
+    let mut request = Message::new();
+    request.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST)),
+        Box::new(FINGERPRINT),
+    ])?;
+    let rewritten = decode(conn.rewrite(&request.raw).expect("an Allocate request"))?;
+    assert_eq!(
+        rewritten.get(ATTR_ADDITIONAL_ADDRESS_FAMILY)?,
+        [REQUESTED_FAMILY_IPV6.0, 0, 0, 0]
+    );
+    assert!(!rewritten.contains(ATTR_REQUESTED_ADDRESS_FAMILY));
+    FINGERPRINT.check(&rewritten)?;
+    assert_eq!(conn.rewrite(&signed_request(METHOD_REFRESH)?.raw), None);
+
+    // Both relayed addresses of the response are kept
+    let relayed4 = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).into(), 50000);
+    let relayed6 = SocketAddr::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(), 50001);
+    let mut response = Message::new();
+    response.build(&[
+        Box::new(request.clone()),
+        Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_SUCCESS_RESPONSE)),
+        Box::new(RelayedAddress {
+            ip: relayed4.ip(),
+            port: relayed4.port(),
+        }),
+        Box::new(RelayedAddress {
+            ip: relayed6.ip(),
+            port: relayed6.port(),
+        }),
+    ])?;
+    server
+        .send_to(&response.raw, conn.local_addr().await?)
+        .await?;
+    let mut buf = vec![0u8; 1500];
+    conn.recv_from(&mut buf).await?;
+    assert_eq!(conn.relayed_addrs(), vec![relayed4, relayed6]);
+    assert_eq!(conn.address_error(), None);
+
+    // As is the refusal of one of the families
+    let mut response = Message::new();
+    response.build(&[
+        Box::new(request),
+        Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_SUCCESS_RESPONSE)),
+        Box::new(RelayedAddress {
+            ip: relayed4.ip(),
+            port: relayed4.port(),
+        }),
+    ])?;
+    let mut address_error = vec![REQUESTED_FAMILY_IPV6.0, 0, 4, 40];
+    address_error.extend_from_slice(b"Address Family not Supported");
+    response.add(ATTR_ADDRESS_ERROR_CODE, &address_error);
+    server
+        .send_to(&response.raw, conn.local_addr().await?)
+        .await?;
+    conn.recv_from(&mut buf).await?;
+    assert_eq!(conn.relayed_addrs(), vec![relayed4]);
+    assert_eq!(
+        conn.address_error(),
+        Some((440, "Address Family not Supported".to_owned()))
+    );
+
+    Ok(())
+}
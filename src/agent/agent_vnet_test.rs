@@ -1,10 +1,13 @@
 use super::*;
 
 use crate::candidate::candidate_base::unmarshal_candidate;
+use crate::turn_credentials::TurnRestCredentialProvider;
 use async_trait::async_trait;
+use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr};
 use std::str::FromStr;
 use std::sync::atomic::AtomicU64;
+use std::time::{SystemTime, UNIX_EPOCH};
 use util::vnet::chunk::Chunk;
 use util::{vnet::router::Nic, vnet::*, Conn};
 use waitgroup::WaitGroup;
@@ -13,28 +16,28 @@ pub(crate) struct MockConn;
 
 #[async_trait]
 impl Conn for MockConn {
-    async fn connect(&self, _addr: SocketAddr) -> Result<()> {
+    async fn connect(&self, _addr: SocketAddr) -> anyhow::Result<()> {
         Ok(())
     }
-    async fn recv(&self, _buf: &mut [u8]) -> Result<usize> {
+    async fn recv(&self, _buf: &mut [u8]) -> anyhow::Result<usize> {
         Ok(0)
     }
-    async fn recv_from(&self, _buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+    async fn recv_from(&self, _buf: &mut [u8]) -> anyhow::Result<(usize, SocketAddr)> {
         Ok((0, SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0)))
     }
-    async fn send(&self, _buf: &[u8]) -> Result<usize> {
+    async fn send(&self, _buf: &[u8]) -> anyhow::Result<usize> {
         Ok(0)
     }
-    async fn send_to(&self, _buf: &[u8], _target: SocketAddr) -> Result<usize> {
+    async fn send_to(&self, _buf: &[u8], _target: SocketAddr) -> anyhow::Result<usize> {
         Ok(0)
     }
-    async fn local_addr(&self) -> Result<SocketAddr> {
+    async fn local_addr(&self) -> anyhow::Result<SocketAddr> {
         Ok(SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0))
     }
     async fn remote_addr(&self) -> Option<SocketAddr> {
         None
     }
-    async fn close(&self) -> Result<()> {
+    async fn close(&self) -> anyhow::Result<()> {
         Ok(())
     }
 }
@@ -63,6 +66,10 @@ pub(crate) const VNET_LOCAL_IPB: &str = "10.2.0.1";
 pub(crate) const VNET_LOCAL_SUBNET_MASK_B: &str = "24";
 pub(crate) const VNET_STUN_SERVER_IP: &str = "1.2.3.4";
 pub(crate) const VNET_STUN_SERVER_PORT: u16 = 3478;
+pub(crate) const VNET_TURN_REST_SECRET: &str = "north";
+pub(crate) const VNET_OAUTH_KID: &str = "oauth-kid";
+pub(crate) const VNET_OAUTH_MAC_KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZmdoaWo=";
+pub(crate) const VNET_OAUTH_ACCESS_TOKEN: &str = "c2VsZi1jb250YWluZWQtdG9rZW4=";
 
 pub(crate) async fn build_simple_vnet(
     _nat_type0: nat::NatType,
@@ -191,15 +198,42 @@ impl TestAuthHandler {
             "user".to_owned(),
             turn::auth::generate_auth_key("user", "webrtc.rs", "pass"),
         );
+        // The server can't decrypt access tokens, it knows the MAC key of the kid
+        cred_map.insert(
+            VNET_OAUTH_KID.to_owned(),
+            base64::decode(VNET_OAUTH_MAC_KEY).unwrap(),
+        );
 
         TestAuthHandler { cred_map }
     }
 }
 
 impl turn::auth::AuthHandler for TestAuthHandler {
-    fn auth_handle(&self, username: &str, _realm: &str, _src_addr: SocketAddr) -> Result<Vec<u8>> {
+    fn auth_handle(
+        &self,
+        username: &str,
+        _realm: &str,
+        _src_addr: SocketAddr,
+    ) -> anyhow::Result<Vec<u8>> {
         if let Some(pw) = self.cred_map.get(username) {
             Ok(pw.to_vec())
+        } else if let Some((expiry, user)) = username.split_once(':') {
+            // TURN REST API credentials
+            let expires = UNIX_EPOCH + Duration::from_secs(expiry.parse()?);
+            if expires < SystemTime::now() {
+                return Err(Error::new(format!("expired username {}", username)).into());
+            }
+            let provider = TurnRestCredentialProvider::new(
+                VNET_TURN_REST_SECRET.to_owned(),
+                user.to_owned(),
+                Duration::ZERO,
+            );
+            let credentials = provider.credentials_until(expires)?;
+            Ok(turn::auth::generate_auth_key(
+                username,
+                "webrtc.rs",
+                &credentials.password,
+            ))
         } else {
             Err(Error::new("fake error".to_owned()).into())
         }
@@ -238,7 +272,7 @@ pub(crate) async fn add_vnet_stun(wan_net: Arc<net::Net>) -> Result<turn::server
 pub(crate) async fn connect_with_vnet(
     a_agent: &Arc<Agent>,
     b_agent: &Arc<Agent>,
-) -> Result<(Arc<impl Conn>, Arc<impl Conn>)> {
+) -> Result<(Arc<AgentConn>, Arc<AgentConn>)> {
     // Manual signaling
     let (a_ufrag, a_pwd) = a_agent.get_local_user_credentials().await;
     let (b_ufrag, b_pwd) = b_agent.get_local_user_credentials().await;
@@ -264,7 +298,7 @@ pub(crate) async fn connect_with_vnet(
     if let Some(a_conn) = accepted_rx.recv().await {
         Ok((a_conn, b_conn))
     } else {
-        Err(Error::new("no a_conn".to_owned()).into())
+        Err(Error::new("no a_conn".to_owned()))
     }
 }
 
@@ -403,7 +437,7 @@ pub(crate) async fn gather_and_exchange_candidates(
 
 pub(crate) async fn start_router(router: &Arc<Mutex<router::Router>>) -> Result<()> {
     let mut w = router.lock().await;
-    w.start().await
+    Ok(w.start().await?)
 }
 
 pub(crate) async fn connect_net2router(
@@ -526,6 +560,7 @@ async fn test_connectivity_vnet_full_cone_nats_on_both_ends() -> Result<()> {
         username: "user".to_owned(),
         password: "pass".to_owned(),
         proto: ProtoType::Udp,
+        ..Default::default()
     };
 
     // buildVNet with a Full-cone NATs both LANs
@@ -588,6 +623,7 @@ async fn test_connectivity_vnet_symmetric_nats_on_both_ends() -> Result<()> {
         username: "user".to_owned(),
         password: "pass".to_owned(),
         proto: ProtoType::Udp,
+        ..Default::default()
     };
 
     // buildVNet with a Symmetric NATs for both LANs
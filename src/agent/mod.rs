@@ -1,67 +1,71 @@
 #[cfg(test)]
+mod agent_core_test;
+#[cfg(test)]
+mod agent_demux_test;
+#[cfg(test)]
+mod agent_event_test;
+#[cfg(test)]
 mod agent_gather_test;
 #[cfg(test)]
+mod agent_relay_conn_test;
+#[cfg(test)]
+mod agent_srflx_conn_test;
+#[cfg(test)]
+mod agent_stream_test;
+#[cfg(test)]
 mod agent_test;
 #[cfg(test)]
 mod agent_transport_test;
 #[cfg(test)]
+pub(crate) mod agent_turn_conn_test;
+#[cfg(test)]
 pub(crate) mod agent_vnet_test;
 
 pub mod agent_config;
+pub mod agent_core;
+pub mod agent_demux;
+pub mod agent_event;
 pub mod agent_gather;
 pub(crate) mod agent_internal;
+pub(crate) mod agent_relay_conn;
 pub mod agent_selector;
+pub(crate) mod agent_srflx_conn;
 pub mod agent_stats;
+pub mod agent_stream;
 pub mod agent_transport;
+pub(crate) mod agent_turn_conn;
 
 use crate::candidate::*;
 use crate::error::*;
 use crate::external_ip_mapper::*;
+use crate::ipv6_address_class::*;
 use crate::mdns::*;
 use crate::network_type::*;
+use crate::runtime::{self, Runtime, TokioRuntime};
 use crate::state::*;
 use crate::url::*;
 use agent_config::*;
+use agent_core::*;
+use agent_event::*;
 use agent_internal::*;
 use agent_stats::*;
 
-use anyhow::Result;
 use mdns::conn::*;
-use std::collections::HashMap;
-use std::net::{Ipv4Addr, SocketAddr};
-use stun::{agent::*, attributes::*, fingerprint::*, integrity::*, message::*, xoraddr::*};
-use util::{vnet::net::*, Buffer};
+use std::net::SocketAddr;
+use stun::message::*;
+use util::vnet::net::*;
 
-use crate::agent::agent_gather::GatherCandidatesInternalParams;
+use crate::agent::agent_gather::{GatherCancel, GatherCandidatesInternalParams};
 use crate::agent::agent_transport::AgentConn;
-use crate::rand::*;
 use crate::tcp_type::TcpType;
+use crate::turn_credentials::TurnCredentialProvider;
+use crate::util::StunRetransmission;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, Instant};
 use tokio::sync::{broadcast, mpsc, Mutex};
-use tokio::time::{Duration, Instant};
-
-#[derive(Debug, Clone)]
-pub(crate) struct BindingRequest {
-    pub(crate) timestamp: Instant,
-    pub(crate) transaction_id: TransactionId,
-    pub(crate) destination: SocketAddr,
-    pub(crate) is_use_candidate: bool,
-}
-
-impl Default for BindingRequest {
-    fn default() -> Self {
-        Self {
-            timestamp: Instant::now(),
-            transaction_id: TransactionId::default(),
-            destination: SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0),
-            is_use_candidate: false,
-        }
-    }
-}
 
 pub type OnConnectionStateChangeHdlrFn = Box<
     dyn (FnMut(ConnectionState) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>)
@@ -83,6 +87,11 @@ pub type OnCandidateHdlrFn = Box<
         + Send
         + Sync,
 >;
+pub type OnPacketsDroppedHdlrFn = Box<
+    dyn (FnMut(ReceiveBufferStats) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>)
+        + Send
+        + Sync,
+>;
 pub type GatherCandidateCancelFn = Box<dyn Fn() + Send + Sync>;
 
 /// Represents the ICE agent.
@@ -92,10 +101,17 @@ pub struct Agent {
     pub(crate) port_min: u16,
     pub(crate) port_max: u16,
     pub(crate) interface_filter: Arc<Option<InterfaceFilterFn>>,
+    pub(crate) interface_preference: Arc<Option<InterfacePreferenceFn>>,
+    pub(crate) interface_cost: Arc<Option<InterfaceCostFn>>,
+    pub(crate) ip_filter: Arc<Option<IpFilterFn>>,
+    pub(crate) include_loopback: bool,
+    pub(crate) include_link_local: bool,
+    pub(crate) ipv6_address_classes: Vec<Ipv6AddressClass>,
     pub(crate) mdns_mode: MulticastDnsMode,
     pub(crate) mdns_name: String,
     pub(crate) mdns_conn: Option<Arc<DnsConn>>,
     pub(crate) net: Arc<Net>,
+    pub(crate) runtime: Arc<dyn Runtime>,
 
     // 1:1 D-NAT IP address mapping
     pub(crate) ext_ip_mapper: Arc<Option<ExternalIpMapper>>,
@@ -103,15 +119,19 @@ pub struct Agent {
     pub(crate) candidate_types: Vec<CandidateType>,
     pub(crate) urls: Vec<Url>,
     pub(crate) network_types: Vec<NetworkType>,
+    pub(crate) gathering_timeout: Option<Duration>,
+    pub(crate) gathering_server_timeout: Duration,
+    pub(crate) stun_retransmission: StunRetransmission,
+    pub(crate) turn_credential_provider: Option<Arc<dyn TurnCredentialProvider>>,
 
-    pub(crate) gather_candidate_cancel: Option<GatherCandidateCancelFn>,
+    pub(crate) gather_candidate_cancel: std::sync::Mutex<Option<GatherCandidateCancelFn>>,
 }
 
 impl Agent {
     /// Creates a new Agent.
     pub async fn new(config: AgentConfig) -> Result<Self> {
         if config.port_max < config.port_min {
-            return Err(Error::ErrPort.into());
+            return Err(Error::ErrPort);
         }
 
         let mut mdns_name = config.multicast_dns_host_name.clone();
@@ -120,7 +140,7 @@ impl Agent {
         }
 
         if !mdns_name.ends_with(".local") || mdns_name.split('.').count() != 2 {
-            return Err(Error::ErrInvalidMulticastDnshostName.into());
+            return Err(Error::ErrInvalidMulticastDnshostName);
         }
 
         let mut mdns_mode = config.multicast_dns_mode;
@@ -141,12 +161,27 @@ impl Agent {
         let (chan_state_tx, chan_state_rx) = mpsc::channel(1);
         let (chan_candidate_tx, chan_candidate_rx) = mpsc::channel(1);
         let (chan_candidate_pair_tx, chan_candidate_pair_rx) = mpsc::channel(1);
+        let (chan_packets_dropped_tx, chan_packets_dropped_rx) = mpsc::channel(1);
+        let (events_tx, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
         let (on_connected_tx, on_connected_rx) = mpsc::channel(1);
         let (done_tx, done_rx) = mpsc::channel(1);
         let (force_candidate_contact_tx, force_candidate_contact_rx) = mpsc::channel(1);
         let (started_ch_tx, _) = broadcast::channel(1);
 
-        let mut ai = AgentInternal {
+        let runtime = config
+            .runtime
+            .clone()
+            .unwrap_or_else(|| Arc::new(TokioRuntime));
+
+        let core = match AgentCore::new(&config) {
+            Ok(core) => core,
+            Err(err) => {
+                Self::close_multicast_conn(&mdns_conn).await;
+                return Err(err);
+            }
+        };
+
+        let ai = AgentInternal {
             on_connected_tx: Some(on_connected_tx),
             on_connected_rx: Some(on_connected_rx),
 
@@ -160,72 +195,38 @@ impl Agent {
             chan_state_tx: Some(chan_state_tx),
             chan_candidate_tx: Some(Arc::new(chan_candidate_tx)),
             chan_candidate_pair_tx: Some(chan_candidate_pair_tx),
+            events_tx: Some(events_tx),
 
             on_connection_state_change_hdlr: None,
             on_selected_candidate_pair_change_hdlr: None,
             on_candidate_hdlr: None,
-
-            tie_breaker: rand::random::<u64>(),
-
-            lite: config.lite,
-            is_controlling: config.is_controlling,
-            start_time: Instant::now(),
-            nominated_pair: None,
-
-            connection_state: ConnectionState::New,
-            local_candidates: HashMap::new(),
-            remote_candidates: HashMap::new(),
+            on_packets_dropped_hdlr: None,
 
             insecure_skip_verify: config.insecure_skip_verify,
 
             started_ch_tx: Some(started_ch_tx),
 
-            max_binding_requests: 0,
-
-            host_acceptance_min_wait: Duration::from_secs(0),
-            srflx_acceptance_min_wait: Duration::from_secs(0),
-            prflx_acceptance_min_wait: Duration::from_secs(0),
-            relay_acceptance_min_wait: Duration::from_secs(0),
-
-            // How long connectivity checks can fail before the ICE Agent
-            // goes to disconnected
-            disconnected_timeout: Duration::from_secs(0),
-
-            // How long connectivity checks can fail before the ICE Agent
-            // goes to failed
-            failed_timeout: Duration::from_secs(0),
-
-            // How often should we send keepalive packets?
-            // 0 means never
-            keepalive_interval: Duration::from_secs(0),
-
-            // How often should we run our internal taskLoop to check for state changes when connecting
-            check_interval: Duration::from_secs(0),
-
-            local_ufrag: String::new(),
-            local_pwd: String::new(),
-
-            remote_ufrag: String::new(),
-            remote_pwd: String::new(),
-
-            // LRU of outbound Binding request Transaction IDs
-            pending_binding_requests: vec![],
+            core,
+            runtime: Arc::clone(&runtime),
 
             // AgentConn
-            agent_conn: Arc::new(AgentConn::new()),
+            agent_conn: Arc::new(AgentConn::new(
+                config.receive_buffer_size.unwrap_or(MAX_BUFFER_SIZE),
+                config.receive_buffer_overflow,
+                Some(chan_packets_dropped_tx),
+            )),
         };
 
-        config.init_with_defaults(&mut ai);
-
         let candidate_types = if config.candidate_types.is_empty() {
             default_candidate_types()
         } else {
             config.candidate_types.clone()
         };
 
-        if ai.lite && (candidate_types.len() != 1 || candidate_types[0] != CandidateType::Host) {
+        if ai.core.lite && (candidate_types.len() != 1 || candidate_types[0] != CandidateType::Host)
+        {
             Self::close_multicast_conn(&mdns_conn).await;
-            return Err(Error::ErrLiteUsingNonHostCandidates.into());
+            return Err(Error::ErrLiteUsingNonHostCandidates);
         }
 
         if !config.urls.is_empty()
@@ -233,7 +234,7 @@ impl Agent {
             && !contains_candidate_type(CandidateType::Relay, &candidate_types)
         {
             Self::close_multicast_conn(&mdns_conn).await;
-            return Err(Error::ErrUselessUrlsProvided.into());
+            return Err(Error::ErrUselessUrlsProvided);
         }
 
         let ext_ip_mapper = match config.init_ext_ip_mapping(mdns_mode, &candidate_types) {
@@ -262,39 +263,69 @@ impl Agent {
             port_max: config.port_max,
             agent_internal: Arc::new(Mutex::new(ai)),
             interface_filter: Arc::clone(&config.interface_filter),
+            interface_preference: Arc::clone(&config.interface_preference),
+            interface_cost: Arc::clone(&config.interface_cost),
+            ip_filter: Arc::clone(&config.ip_filter),
+            include_loopback: config.include_loopback,
+            include_link_local: config
+                .include_link_local
+                .unwrap_or(DEFAULT_INCLUDE_LINK_LOCAL),
+            ipv6_address_classes: if config.ipv6_address_classes.is_empty() {
+                default_ipv6_address_classes()
+            } else {
+                config.ipv6_address_classes.clone()
+            },
             mdns_mode,
             mdns_name,
             mdns_conn,
             net,
+            runtime,
             ext_ip_mapper: Arc::new(ext_ip_mapper),
-            gathering_state: Arc::new(AtomicU8::new(0)), //GatheringState::New,
+            gathering_state: Arc::new(AtomicU8::new(GatheringState::New as u8)),
             candidate_types,
             urls: config.urls.clone(),
             network_types: config.network_types.clone(),
-
-            gather_candidate_cancel: None,
+            gathering_timeout: config.gathering_timeout,
+            gathering_server_timeout: config
+                .gathering_server_timeout
+                .unwrap_or(DEFAULT_GATHERING_SERVER_TIMEOUT),
+            stun_retransmission: StunRetransmission {
+                rto: config.stun_rto.unwrap_or(DEFAULT_STUN_RTO),
+                max_requests: config
+                    .stun_max_requests
+                    .unwrap_or(DEFAULT_STUN_MAX_REQUESTS),
+                last_timeout_factor: config
+                    .stun_last_timeout_factor
+                    .unwrap_or(DEFAULT_STUN_LAST_TIMEOUT_FACTOR),
+            },
+            turn_credential_provider: config.turn_credential_provider.clone(),
+
+            gather_candidate_cancel: std::sync::Mutex::new(None),
         };
 
         let agent_internal = Arc::clone(&a.agent_internal);
 
         Self::start_on_connection_state_change_routine(
+            &a.runtime,
             agent_internal,
             chan_state_rx,
             chan_candidate_rx,
             chan_candidate_pair_rx,
+            chan_packets_dropped_rx,
         )
         .await;
 
-        // Restart is also used to initialize the agent for the first time
-        if let Err(err) = a.restart(config.local_ufrag, config.local_pwd).await {
-            Self::close_multicast_conn(&a.mdns_conn).await;
-            let _ = a.close().await;
-            return Err(err);
-        }
-
         Ok(a)
     }
 
+    /// Returns a stream of the events of the agent, starting with the events which happen after
+    /// subscribing. Any number of subscribers can receive the events, and the streams end once
+    /// the agent is closed.
+    pub async fn subscribe(&self) -> AgentEvents {
+        let ai = self.agent_internal.lock().await;
+        AgentEvents::new(ai.events_tx.as_ref().map(broadcast::Sender::subscribe))
+    }
+
     /// Sets a handler that is fired when the connection state changes.
     pub async fn on_connection_state_change(&self, f: OnConnectionStateChangeHdlrFn) {
         let mut ai = self.agent_internal.lock().await;
@@ -314,14 +345,42 @@ impl Agent {
         ai.on_candidate_hdlr = Some(f);
     }
 
+    /// Sets a handler that is fired when received packets are dropped because the receive buffer
+    /// of the `AgentConn` is full, with the statistics of the buffer. Drops happening while the
+    /// handler runs are reported in one call afterwards.
+    pub async fn on_packets_dropped(&self, f: OnPacketsDroppedHdlrFn) {
+        let mut ai = self.agent_internal.lock().await;
+        ai.on_packets_dropped_hdlr = Some(f);
+    }
+
     async fn start_on_connection_state_change_routine(
+        runtime: &Arc<dyn Runtime>,
         agent_internal: Arc<Mutex<AgentInternal>>,
         mut chan_state_rx: mpsc::Receiver<ConnectionState>,
         mut chan_candidate_rx: mpsc::Receiver<Option<Arc<dyn Candidate + Send + Sync>>>,
         mut chan_candidate_pair_rx: mpsc::Receiver<()>,
+        mut chan_packets_dropped_rx: mpsc::Receiver<()>,
     ) {
+        let agent_internal_dropped = Arc::clone(&agent_internal);
+        runtime::spawn(runtime, async move {
+            while chan_packets_dropped_rx.recv().await.is_some() {
+                // The handler runs without the agent lock, so it may use the agent
+                let on_packets_dropped = {
+                    let mut ai = agent_internal_dropped.lock().await;
+                    let stats = ai.agent_conn.receive_buffer_stats();
+                    ai.emit(AgentEvent::PacketsDropped(stats.clone()));
+                    ai.on_packets_dropped_hdlr
+                        .as_mut()
+                        .map(|on_packets_dropped| on_packets_dropped(stats))
+                };
+                if let Some(on_packets_dropped) = on_packets_dropped {
+                    on_packets_dropped.await;
+                }
+            }
+        });
+
         let agent_internal_pair = Arc::clone(&agent_internal);
-        tokio::spawn(async move {
+        runtime::spawn(runtime, async move {
             // CandidatePair and ConnectionState are usually changed at once.
             // Blocking one by the other one causes deadlock.
             while chan_candidate_pair_rx.recv().await.is_some() {
@@ -340,7 +399,7 @@ impl Agent {
             }
         });
 
-        tokio::spawn(async move {
+        runtime::spawn(runtime, async move {
             loop {
                 tokio::select! {
                     opt_state = chan_state_rx.recv() => {
@@ -400,13 +459,13 @@ impl Agent {
             }
 
             if c.candidate_type() != CandidateType::Host {
-                return Err(Error::ErrAddressParseFailed.into());
+                return Err(Error::ErrAddressParseFailed);
             }
 
             let agent_internal = Arc::clone(&self.agent_internal);
             let host_candidate = Arc::clone(c);
             let mdns_conn = self.mdns_conn.clone();
-            tokio::spawn(async move {
+            runtime::spawn(&self.runtime, async move {
                 if let Some(mdns_conn) = mdns_conn {
                     if let Ok(candidate) =
                         Self::resolve_and_add_multicast_candidate(mdns_conn, host_candidate).await
@@ -419,7 +478,7 @@ impl Agent {
         } else {
             let agent_internal = Arc::clone(&self.agent_internal);
             let candidate = Arc::clone(c);
-            tokio::spawn(async move {
+            runtime::spawn(&self.runtime, async move {
                 let mut ai = agent_internal.lock().await;
                 ai.add_remote_candidate(&candidate).await;
             });
@@ -434,11 +493,7 @@ impl Agent {
 
         {
             let ai = self.agent_internal.lock().await;
-            for candidates in ai.local_candidates.values() {
-                for candidate in candidates {
-                    res.push(Arc::clone(candidate));
-                }
-            }
+            res.extend(ai.core.get_local_candidates());
         }
 
         Ok(res)
@@ -447,20 +502,18 @@ impl Agent {
     /// Returns the local user credentials.
     pub async fn get_local_user_credentials(&self) -> (String, String) {
         let ai = self.agent_internal.lock().await;
-        (ai.local_ufrag.clone(), ai.local_pwd.clone())
+        (ai.core.local_ufrag.clone(), ai.core.local_pwd.clone())
     }
 
     /// Returns the remote user credentials.
     pub async fn get_remote_user_credentials(&self) -> (String, String) {
         let ai = self.agent_internal.lock().await;
-        (ai.remote_ufrag.clone(), ai.remote_pwd.clone())
+        (ai.core.remote_ufrag.clone(), ai.core.remote_pwd.clone())
     }
 
     /// Cleans up the Agent.
     pub async fn close(&self) -> Result<()> {
-        if let Some(gather_candidate_cancel) = &self.gather_candidate_cancel {
-            gather_candidate_cancel();
-        }
+        self.cancel_gathering();
 
         let mut ai = self.agent_internal.lock().await;
         ai.close().await
@@ -469,7 +522,7 @@ impl Agent {
     /// Returns the selected pair or nil if there is none
     pub async fn get_selected_candidate_pair(&self) -> Option<Arc<CandidatePair>> {
         let ai = self.agent_internal.lock().await;
-        ai.agent_conn.get_selected_pair().await
+        ai.core.selected_pair()
     }
 
     /// Sets the credentials of the remote agent.
@@ -479,7 +532,7 @@ impl Agent {
         remote_pwd: String,
     ) -> Result<()> {
         let mut ai = self.agent_internal.lock().await;
-        ai.set_remote_credentials(remote_ufrag, remote_pwd)
+        ai.core.set_remote_credentials(remote_ufrag, remote_pwd)
     }
 
     /// Restarts the ICE Agent with the provided ufrag/pwd
@@ -487,55 +540,28 @@ impl Agent {
     ///
     /// Restart must only be called when `GatheringState` is `GatheringStateComplete`
     /// a user must then call `GatherCandidates` explicitly to start generating new ones.
-    pub async fn restart(&self, mut ufrag: String, mut pwd: String) -> Result<()> {
-        if ufrag.is_empty() {
-            ufrag = generate_ufrag();
-        }
-        if pwd.is_empty() {
-            pwd = generate_pwd();
-        }
-
-        if ufrag.len() * 8 < 24 {
-            return Err(Error::ErrLocalUfragInsufficientBits.into());
-        }
-        if pwd.len() * 8 < 128 {
-            return Err(Error::ErrLocalPwdInsufficientBits.into());
-        }
-
+    pub async fn restart(&self, ufrag: String, pwd: String) -> Result<()> {
         if GatheringState::from(self.gathering_state.load(Ordering::SeqCst))
             == GatheringState::Gathering
         {
-            return Err(Error::ErrRestartWhenGathering.into());
+            return Err(Error::ErrRestartWhenGathering);
         }
-        self.gathering_state
-            .store(GatheringState::New as u8, Ordering::SeqCst);
 
         let mut ai = self.agent_internal.lock().await;
 
         if ai.done_tx.is_none() {
-            return Err(Error::ErrClosed.into());
+            return Err(Error::ErrClosed);
         }
 
-        // Clear all agent needed to take back to fresh state
-        ai.local_ufrag = ufrag;
-        ai.local_pwd = pwd;
-        ai.remote_ufrag = String::new();
-        ai.remote_pwd = String::new();
-        ai.pending_binding_requests = vec![];
+        ai.core.restart(Instant::now(), ufrag, pwd)?;
+        ai.flush().await;
 
+        if self
+            .gathering_state
+            .swap(GatheringState::New as u8, Ordering::SeqCst)
+            != GatheringState::New as u8
         {
-            let mut checklist = ai.agent_conn.checklist.lock().await;
-            *checklist = vec![];
-        }
-
-        ai.set_selected_pair(None).await;
-        ai.delete_all_candidates().await;
-        ai.start();
-
-        // Restart is used by NewAgent. Accept/Connect should be used to move to checking
-        // for new Agents
-        if ai.connection_state != ConnectionState::New {
-            ai.update_connection_state(ConnectionState::Checking).await;
+            ai.emit(AgentEvent::GatheringStateChange(GatheringState::New));
         }
 
         Ok(())
@@ -544,23 +570,36 @@ impl Agent {
     /// Initiates the trickle based gathering process.
     pub async fn gather_candidates(&self) -> Result<()> {
         if self.gathering_state.load(Ordering::SeqCst) != GatheringState::New as u8 {
-            return Err(Error::ErrMultipleGatherAttempted.into());
+            return Err(Error::ErrMultipleGatherAttempted);
         }
 
-        let chan_candidate_tx = {
+        let (chan_candidate_tx, events_tx) = {
             let ai = self.agent_internal.lock().await;
-            if ai.on_candidate_hdlr.is_none() {
-                return Err(Error::ErrNoOnCandidateHandler.into());
+            let has_subscribers = ai
+                .events_tx
+                .as_ref()
+                .is_some_and(|events_tx| events_tx.receiver_count() > 0);
+            if ai.on_candidate_hdlr.is_none() && !has_subscribers {
+                return Err(Error::ErrNoOnCandidateHandler);
             }
-            ai.chan_candidate_tx.clone()
+            (
+                ai.chan_candidate_tx.clone(),
+                ai.events_tx.as_ref().map(broadcast::Sender::downgrade),
+            )
         };
 
-        if let Some(gather_candidate_cancel) = &self.gather_candidate_cancel {
-            gather_candidate_cancel(); // Cancel previous gathering routine
+        let (cancel_tx, cancel) =
+            GatherCancel::new(Arc::clone(&self.runtime), self.gathering_timeout);
+        {
+            let mut gather_candidate_cancel = self.gather_candidate_cancel.lock().unwrap();
+            if let Some(gather_candidate_cancel) = &*gather_candidate_cancel {
+                gather_candidate_cancel(); // Cancel previous gathering routine
+            }
+            *gather_candidate_cancel = Some(Box::new(move || {
+                cancel_tx.send_replace(true);
+            }));
         }
 
-        //TODO: a.gatherCandidateCancel = cancel
-
         let params = GatherCandidatesInternalParams {
             candidate_types: self.candidate_types.clone(),
             urls: self.urls.clone(),
@@ -570,35 +609,61 @@ impl Agent {
             mdns_mode: self.mdns_mode,
             mdns_name: self.mdns_name.clone(),
             net: Arc::clone(&self.net),
+            runtime: Arc::clone(&self.runtime),
             interface_filter: self.interface_filter.clone(),
+            interface_preference: self.interface_preference.clone(),
+            interface_cost: self.interface_cost.clone(),
+            ip_filter: self.ip_filter.clone(),
+            include_loopback: self.include_loopback,
+            include_link_local: self.include_link_local,
+            ipv6_address_classes: self.ipv6_address_classes.clone(),
             ext_ip_mapper: Arc::clone(&self.ext_ip_mapper),
             agent_internal: Arc::clone(&self.agent_internal),
             gathering_state: Arc::clone(&self.gathering_state),
             chan_candidate_tx,
+            events_tx,
+            cancel,
+            server_timeout: self.gathering_server_timeout,
+            stun_retransmission: self.stun_retransmission,
+            credential_provider: self.turn_credential_provider.clone(),
         };
-        tokio::spawn(async move {
+        runtime::spawn(&self.runtime, async move {
             Self::gather_candidates_internal(params).await;
         });
 
         Ok(())
     }
 
+    /// Cancels gathering. The sockets and TURN allocations of the servers which are still being
+    /// gathered from are closed, and gathering completes with the candidates gathered so far.
+    pub fn cancel_gathering(&self) {
+        if let Some(gather_candidate_cancel) = &*self.gather_candidate_cancel.lock().unwrap() {
+            gather_candidate_cancel();
+        }
+    }
+
     /// Returns a list of candidate pair stats.
     pub async fn get_candidate_pairs_stats(&self) -> Vec<CandidatePairStats> {
         let ai = self.agent_internal.lock().await;
-        ai.get_candidate_pairs_stats().await
+        ai.core.get_candidate_pairs_stats()
     }
 
     /// Returns a list of local candidates stats.
     pub async fn get_local_candidates_stats(&self) -> Vec<CandidateStats> {
         let ai = self.agent_internal.lock().await;
-        ai.get_local_candidates_stats()
+        ai.core.get_local_candidates_stats()
     }
 
     /// Returns a list of remote candidates stats.
     pub async fn get_remote_candidates_stats(&self) -> Vec<CandidateStats> {
         let ai = self.agent_internal.lock().await;
-        ai.get_remote_candidates_stats()
+        ai.core.get_remote_candidates_stats()
+    }
+
+    /// Returns the stats of the receive buffers of the `AgentConn`.
+    pub async fn get_receive_buffer_stats(&self) -> ReceiveBufferStats {
+        let ai = self.agent_internal.lock().await;
+        ai.agent_conn.receive_buffer_stats()
     }
 
     async fn resolve_and_add_multicast_candidate(
@@ -611,7 +676,7 @@ impl Agent {
             Ok((_, src)) => src,
             Err(err) => {
                 log::warn!("Failed to discover mDNS candidate {}: {}", c.address(), err);
-                return Err(err);
+                return Err(err.into());
             }
         };
 
@@ -0,0 +1,177 @@
+use super::*;
+
+use std::time::Duration;
+
+fn packet(pool: &BufferPool, data: &[u8]) -> PooledBuffer {
+    let mut buf = pool.get();
+    buf[..data.len()].copy_from_slice(data);
+    buf.truncate(data.len());
+    buf
+}
+
+#[test]
+fn test_buffer_pool_recycles() {
+    let pool = BufferPool::new(16, 1);
+    let mut a = pool.get();
+    assert_eq!(a.len(), 16);
+    a.truncate(4);
+    assert_eq!(a.len(), 4);
+    let b = pool.get();
+    assert_eq!(pool.idle(), 0);
+
+    drop(a);
+    assert_eq!(pool.idle(), 1);
+    drop(b);
+    assert_eq!(pool.idle(), 1, "no more than max_idle buffers are kept");
+
+    let c = pool.get();
+    assert_eq!(c.len(), 16, "recycled buffers have their full size again");
+    assert_eq!(pool.idle(), 0);
+}
+
+#[test]
+fn test_buffer_pool_into_vec() {
+    let pool = BufferPool::new(16, 4);
+    let buf = packet(&pool, b"data");
+    let raw = buf.into_vec();
+    assert_eq!(raw, b"data");
+    assert_eq!(pool.idle(), 0, "the buffer was taken out of the pool");
+
+    pool.recycle(raw);
+    assert_eq!(pool.idle(), 1);
+    pool.recycle(vec![0; 4]);
+    assert_eq!(pool.idle(), 1, "buffers which are too small are dropped");
+}
+
+#[tokio::test]
+async fn test_packet_queue() -> Result<()> {
+    let pool = BufferPool::new(16, 4);
+    let queue = PacketQueue::new(8, BufferOverflowPolicy::DropNewest);
+
+    assert_eq!(queue.push(packet(&pool, b"abc")).await?, Dropped::default());
+    assert_eq!(
+        queue.push(packet(&pool, b"defgh")).await?,
+        Dropped::default()
+    );
+    assert_eq!(
+        queue.push(packet(&pool, b"i")).await?,
+        Dropped {
+            packets: 1,
+            bytes: 1
+        }
+    );
+    assert_eq!(queue.len(), 2);
+    assert_eq!(queue.size(), 8);
+
+    assert_eq!(&*queue.pop().await?, b"abc");
+    queue.push(packet(&pool, b"ijk")).await?;
+    assert_eq!(&*queue.pop().await?, b"defgh");
+
+    queue.close();
+    assert_eq!(
+        queue.push(packet(&pool, b"l")).await,
+        Err(Error::Buffer(BufferError::ErrBufferClosed))
+    );
+    assert_eq!(&*queue.pop().await?, b"ijk", "queued packets can be read");
+    assert_eq!(
+        queue.pop().await.err(),
+        Some(Error::Buffer(BufferError::ErrBufferClosed))
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_packet_queue_wakes_readers() -> Result<()> {
+    let pool = BufferPool::new(16, 4);
+    let queue = Arc::new(PacketQueue::new(64, BufferOverflowPolicy::DropNewest));
+
+    let reader = {
+        let queue = Arc::clone(&queue);
+        tokio::spawn(async move { queue.pop().await.map(|p| p.to_vec()) })
+    };
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    queue.push(packet(&pool, b"data")).await?;
+    assert_eq!(reader.await.unwrap()?, b"data");
+
+    let reader = {
+        let queue = Arc::clone(&queue);
+        tokio::spawn(async move { queue.pop().await.map(|p| p.to_vec()) })
+    };
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    queue.close();
+    assert_eq!(
+        reader.await.unwrap(),
+        Err(Error::Buffer(BufferError::ErrBufferClosed))
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_packet_queue_drop_oldest() -> Result<()> {
+    let pool = BufferPool::new(16, 4);
+    let queue = PacketQueue::new(8, BufferOverflowPolicy::DropOldest);
+
+    queue.push(packet(&pool, b"ab")).await?;
+    queue.push(packet(&pool, b"cd")).await?;
+    queue.push(packet(&pool, b"efg")).await?;
+    assert_eq!(
+        queue.push(packet(&pool, b"hijk")).await?,
+        Dropped {
+            packets: 2,
+            bytes: 4
+        },
+        "the oldest packets make room for the new one"
+    );
+    assert_eq!(
+        queue.push(packet(&pool, b"0123456789")).await?,
+        Dropped {
+            packets: 1,
+            bytes: 10
+        },
+        "a packet larger than the limit is dropped itself"
+    );
+
+    assert_eq!(&*queue.pop().await?, b"efg");
+    assert_eq!(&*queue.pop().await?, b"hijk");
+    assert!(queue.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_packet_queue_block() -> Result<()> {
+    let pool = BufferPool::new(16, 4);
+    let queue = Arc::new(PacketQueue::new(8, BufferOverflowPolicy::Block));
+
+    queue.push(packet(&pool, b"abcde")).await?;
+    let writer = {
+        let queue = Arc::clone(&queue);
+        let packet = packet(&pool, b"fghi");
+        tokio::spawn(async move { queue.push(packet).await })
+    };
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(!writer.is_finished(), "the writer waits for room");
+    assert_eq!(queue.len(), 1);
+
+    assert_eq!(&*queue.pop().await?, b"abcde");
+    assert_eq!(writer.await.unwrap()?, Dropped::default());
+    assert_eq!(&*queue.pop().await?, b"fghi");
+
+    // Closing the queue wakes the blocked writers
+    queue.push(packet(&pool, b"abcde")).await?;
+    let writer = {
+        let queue = Arc::clone(&queue);
+        let packet = packet(&pool, b"fghi");
+        tokio::spawn(async move { queue.push(packet).await })
+    };
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    queue.close();
+    assert_eq!(
+        writer.await.unwrap(),
+        Err(Error::Buffer(BufferError::ErrBufferClosed))
+    );
+
+    Ok(())
+}
@@ -0,0 +1,317 @@
+#[cfg(test)]
+mod buffer_test;
+
+use crate::error::*;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::Notify;
+use util::buffer::error::Error as BufferError;
+
+struct PoolInner {
+    buffer_size: usize,
+    max_idle: usize,
+    idle: Mutex<Vec<Vec<u8>>>,
+}
+
+/// A pool of equally sized receive buffers. A buffer taken from the pool returns to it once it
+/// is dropped, so receiving datagrams doesn't allocate once the pool is warmed up.
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Arc<PoolInner>,
+}
+
+impl BufferPool {
+    /// Creates a pool of buffers of `buffer_size` bytes, keeping at most `max_idle` of them
+    /// around when they are not in use.
+    pub fn new(buffer_size: usize, max_idle: usize) -> Self {
+        BufferPool {
+            inner: Arc::new(PoolInner {
+                buffer_size,
+                max_idle,
+                idle: Mutex::new(vec![]),
+            }),
+        }
+    }
+
+    /// Returns the size of the buffers.
+    pub fn buffer_size(&self) -> usize {
+        self.inner.buffer_size
+    }
+
+    /// Takes a buffer of `buffer_size` bytes from the pool, allocating one if none is idle.
+    pub fn get(&self) -> PooledBuffer {
+        let buf = self.inner.idle.lock().unwrap().pop();
+        let buf = buf.unwrap_or_else(|| vec![0; self.inner.buffer_size]);
+        PooledBuffer {
+            len: buf.len(),
+            buf: Some(buf),
+            pool: Arc::downgrade(&self.inner),
+        }
+    }
+
+    /// Hands a buffer back to the pool, such as one taken with `PooledBuffer::into_vec`.
+    pub fn recycle(&self, buf: Vec<u8>) {
+        self.inner.recycle(buf);
+    }
+
+    /// Returns the number of idle buffers.
+    pub fn idle(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+}
+
+impl PoolInner {
+    fn recycle(&self, mut buf: Vec<u8>) {
+        if buf.capacity() < self.buffer_size {
+            return;
+        }
+        // Only bytes which were truncated away get zeroed again
+        buf.resize(self.buffer_size, 0);
+
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(buf);
+        }
+    }
+}
+
+/// A buffer owned by its holder until it is dropped, when it returns to its `BufferPool`. It
+/// dereferences to its first `len` bytes, which are all of them until it is truncated.
+pub struct PooledBuffer {
+    buf: Option<Vec<u8>>,
+    len: usize,
+    pool: Weak<PoolInner>,
+}
+
+impl PooledBuffer {
+    /// Shortens the buffer to `len` bytes, such as the size of the datagram received into it.
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    /// Takes the buffer out of the pool, truncated to its length.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        let mut buf = self.buf.take().unwrap_or_default();
+        buf.truncate(self.len);
+        buf
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.buf {
+            Some(buf) => &buf[..self.len],
+            None => &[],
+        }
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match &mut self.buf {
+            Some(buf) => &mut buf[..self.len],
+            None => &mut [],
+        }
+    }
+}
+
+impl AsRef<[u8]> for PooledBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for PooledBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledBuffer")
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let (Some(buf), Some(pool)) = (self.buf.take(), self.pool.upgrade()) {
+            pool.recycle(buf);
+        }
+    }
+}
+
+/// What a `PacketQueue` does with a datagram which doesn't fit into its limit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BufferOverflowPolicy {
+    /// Drops the datagram which doesn't fit.
+    DropNewest,
+    /// Drops the oldest queued datagrams until the new one fits.
+    DropOldest,
+    /// Waits until the readers made enough room. The candidate the datagram was received on
+    /// keeps handling STUN messages meanwhile, but drops the datagrams of application data
+    /// received while it waits.
+    Block,
+}
+
+impl Default for BufferOverflowPolicy {
+    fn default() -> Self {
+        Self::DropNewest
+    }
+}
+
+/// The datagrams a `PacketQueue` dropped to stay within its limit.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Dropped {
+    pub packets: usize,
+    pub bytes: usize,
+}
+
+impl Dropped {
+    fn add(&mut self, packet: &PooledBuffer) {
+        self.packets += 1;
+        self.bytes += packet.len();
+    }
+}
+
+#[derive(Default)]
+struct QueueInner {
+    packets: VecDeque<PooledBuffer>,
+    size: usize,
+    closed: bool,
+}
+
+/// A queue of received datagrams, handing the buffers they were received into to the readers.
+/// The datagrams are limited by their total size in bytes, and the `BufferOverflowPolicy`
+/// decides what happens once the limit is reached.
+pub struct PacketQueue {
+    inner: Mutex<QueueInner>,
+    notify: Notify,
+    // Wakes the writers waiting for room with `BufferOverflowPolicy::Block`
+    space: Notify,
+    limit: usize,
+    policy: BufferOverflowPolicy,
+}
+
+impl PacketQueue {
+    /// Creates a queue holding up to `limit` bytes.
+    pub fn new(limit: usize, policy: BufferOverflowPolicy) -> Self {
+        PacketQueue {
+            inner: Mutex::new(QueueInner::default()),
+            notify: Notify::new(),
+            space: Notify::new(),
+            limit,
+            policy,
+        }
+    }
+
+    /// Returns the limit of the queue in bytes.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Returns what the queue does with datagrams which don't fit into its limit.
+    pub fn policy(&self) -> BufferOverflowPolicy {
+        self.policy
+    }
+
+    /// Appends a datagram, returning the datagrams which were dropped instead as the queue is
+    /// full. With `BufferOverflowPolicy::Block` it waits for room first, only a datagram larger
+    /// than the limit is dropped. Fails with `ErrBufferClosed` once the queue is closed.
+    pub async fn push(&self, packet: PooledBuffer) -> Result<Dropped> {
+        let mut dropped = Dropped::default();
+        loop {
+            let space = {
+                let mut inner = self.inner.lock().unwrap();
+                if inner.closed {
+                    return Err(Error::Buffer(BufferError::ErrBufferClosed));
+                }
+                if packet.len() > self.limit {
+                    dropped.add(&packet);
+                    return Ok(dropped);
+                }
+                if inner.size + packet.len() <= self.limit {
+                    inner.size += packet.len();
+                    inner.packets.push_back(packet);
+                    break;
+                }
+
+                match self.policy {
+                    BufferOverflowPolicy::DropNewest => {
+                        dropped.add(&packet);
+                        return Ok(dropped);
+                    }
+                    BufferOverflowPolicy::DropOldest => {
+                        while inner.size + packet.len() > self.limit {
+                            match inner.packets.pop_front() {
+                                Some(oldest) => {
+                                    inner.size -= oldest.len();
+                                    dropped.add(&oldest);
+                                }
+                                None => break,
+                            }
+                        }
+                        continue;
+                    }
+                    // Created under the lock, so neither a pop nor close can be missed
+                    BufferOverflowPolicy::Block => self.space.notified(),
+                }
+            };
+            space.await;
+        }
+        self.notify.notify_one();
+        Ok(dropped)
+    }
+
+    /// Takes the oldest datagram, waiting for one if the queue is empty. Fails with
+    /// `ErrBufferClosed` once the queue is closed and drained.
+    pub async fn pop(&self) -> Result<PooledBuffer> {
+        loop {
+            let notified = {
+                let mut inner = self.inner.lock().unwrap();
+                if let Some(packet) = inner.packets.pop_front() {
+                    inner.size -= packet.len();
+                    if self.policy == BufferOverflowPolicy::Block {
+                        self.space.notify_waiters();
+                    }
+                    return Ok(packet);
+                }
+                if inner.closed {
+                    return Err(Error::Buffer(BufferError::ErrBufferClosed));
+                }
+                // Created under the lock, so neither a push nor close can be missed
+                self.notify.notified()
+            };
+            notified.await;
+        }
+    }
+
+    /// Returns the number of queued datagrams.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().packets.len()
+    }
+
+    /// Returns true if no datagrams are queued.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns true once the queue is closed.
+    pub fn is_closed(&self) -> bool {
+        self.inner.lock().unwrap().closed
+    }
+
+    /// Returns the number of queued bytes.
+    pub fn size(&self) -> usize {
+        self.inner.lock().unwrap().size
+    }
+
+    /// Closes the queue, waking the readers and the blocked writers. Queued datagrams can still
+    /// be read.
+    pub fn close(&self) {
+        self.inner.lock().unwrap().closed = true;
+        self.notify.notify_waiters();
+        self.space.notify_waiters();
+    }
+}
@@ -6,8 +6,6 @@ use crate::candidate::candidate_server_reflexive::CandidateServerReflexiveConfig
 use crate::error::*;
 use crate::util::*;
 
-use stun::message::*;
-
 use async_trait::async_trait;
 use crc::{Crc, CRC_32_ISCSI};
 use std::fmt;
@@ -15,6 +13,7 @@ use std::ops::Add;
 use std::sync::atomic::{AtomicU16, AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use stun::message::is_message;
 use tokio::sync::{broadcast, Mutex};
 
 #[derive(Default)]
@@ -26,6 +25,16 @@ pub struct CandidateBaseConfig {
     pub component: u16,
     pub priority: u32,
     pub foundation: String,
+    /// The zone ID of an IPv6 link-local address. A zone given in `address` (`fe80::1%2`) takes
+    /// precedence.
+    pub scope_id: u32,
+    /// The local preference used to compute the priority, zero uses the default.
+    pub local_preference: u16,
+    pub network_id: u16,
+    pub network_cost: u16,
+    /// Extension attributes in addition to the ones derived from the other fields, e.g. as
+    /// received from the remote peer.
+    pub extensions: Vec<CandidateExtension>,
     pub conn: Option<Arc<dyn util::Conn + Send + Sync>>,
     pub initialized_ch: Option<broadcast::Receiver<()>>,
 }
@@ -42,8 +51,12 @@ pub struct CandidateBase {
     pub(crate) port: u16,
     pub(crate) related_address: Option<CandidateRelatedAddress>,
     pub(crate) tcp_type: TcpType,
+    pub(crate) network_id: u16,
+    pub(crate) network_cost: u16,
+    pub(crate) extensions: Vec<CandidateExtension>,
 
-    pub(crate) resolved_addr: Mutex<SocketAddr>,
+    pub(crate) resolved_addr: std::sync::Mutex<SocketAddr>,
+    pub(crate) scope_id: u32,
 
     pub(crate) last_sent: AtomicU64,
     pub(crate) last_received: AtomicU64,
@@ -53,6 +66,7 @@ pub struct CandidateBase {
 
     pub(crate) foundation_override: String,
     pub(crate) priority_override: u32,
+    pub(crate) local_preference_override: u16,
 
     //CandidateHost
     pub(crate) network: String,
@@ -72,8 +86,12 @@ impl Default for CandidateBase {
             port: 0,
             related_address: None,
             tcp_type: TcpType::default(),
+            network_id: 0,
+            network_cost: 0,
+            extensions: vec![],
 
-            resolved_addr: Mutex::new(SocketAddr::new(IpAddr::from([0, 0, 0, 0]), 0)),
+            resolved_addr: std::sync::Mutex::new(SocketAddr::new(IpAddr::from([0, 0, 0, 0]), 0)),
+            scope_id: 0,
 
             last_sent: AtomicU64::new(0),
             last_received: AtomicU64::new(0),
@@ -83,6 +101,7 @@ impl Default for CandidateBase {
 
             foundation_override: String::new(),
             priority_override: 0,
+            local_preference_override: 0,
             network: String::new(),
             relay_client: None,
         }
@@ -204,23 +223,54 @@ impl Candidate for CandidateBase {
         self.tcp_type
     }
 
+    fn network_id(&self) -> u16 {
+        self.network_id
+    }
+
+    fn network_cost(&self) -> u16 {
+        self.network_cost
+    }
+
+    fn extensions(&self) -> Vec<CandidateExtension> {
+        let mut extensions = self.extensions.clone();
+        let mut add = |key: &str, value: String| {
+            if !extensions.iter().any(|ext| ext.key == key) {
+                extensions.push(CandidateExtension {
+                    key: key.to_owned(),
+                    value,
+                });
+            }
+        };
+
+        if self.tcp_type != TcpType::Unspecified {
+            add("tcptype", self.tcp_type.to_string());
+        }
+        if self.network_id != 0 {
+            add("network-id", self.network_id.to_string());
+        }
+        if self.network_cost != 0 {
+            add("network-cost", self.network_cost.to_string());
+        }
+
+        extensions
+    }
+
     /// Returns the string representation of the ICECandidate.
     fn marshal(&self) -> String {
+        // The zone ID of a link-local address is only meaningful on this host
+        let address = self.address();
+        let address = address.split('%').next().unwrap_or_default();
         let mut val = format!(
             "{} {} {} {} {} {} typ {}",
             self.foundation(),
             self.component(),
             self.network_type().network_short(),
             self.priority(),
-            self.address(),
+            address,
             self.port(),
             self.candidate_type()
         );
 
-        if self.tcp_type != TcpType::Unspecified {
-            val += format!(" tcptype {}", self.tcp_type()).as_str();
-        }
-
         if let Some(related_address) = self.related_address() {
             val += format!(
                 " raddr {} rport {}",
@@ -229,12 +279,15 @@ impl Candidate for CandidateBase {
             .as_str();
         }
 
+        for ext in self.extensions() {
+            val += format!(" {} {}", ext.key, ext.value).as_str();
+        }
+
         val
     }
 
-    async fn addr(&self) -> SocketAddr {
-        let resolved_addr = self.resolved_addr.lock().await;
-        *resolved_addr
+    fn addr(&self) -> SocketAddr {
+        *self.resolved_addr.lock().unwrap()
     }
 
     /// Stops the recvLoop.
@@ -242,13 +295,13 @@ impl Candidate for CandidateBase {
         {
             let mut closed_ch = self.closed_ch.lock().await;
             if closed_ch.is_none() {
-                return Err(Error::ErrClosed.into());
+                return Err(Error::ErrClosed);
             }
             closed_ch.take();
         }
 
         if let Some(relay_client) = &self.relay_client {
-            relay_client.close().await
+            Ok(relay_client.close().await?)
         } else {
             Ok(())
         }
@@ -269,7 +322,14 @@ impl Candidate for CandidateBase {
 
     async fn write_to(&self, raw: &[u8], dst: &(dyn Candidate + Send + Sync)) -> Result<usize> {
         let n = if let Some(conn) = &self.conn {
-            let addr = dst.addr().await;
+            let mut addr = dst.addr();
+            // Remote link-local addresses can only be reached through the interface of the
+            // local candidate, as the remote zone ID is meaningless on this host.
+            if let SocketAddr::V6(v6) = &mut addr {
+                if v6.scope_id() == 0 && is_link_local(&IpAddr::V6(*v6.ip())) {
+                    v6.set_scope_id(self.scope_id);
+                }
+            }
             conn.send_to(raw, addr).await?
         } else {
             0
@@ -294,8 +354,8 @@ impl Candidate for CandidateBase {
         self.network_type
             .store(network_type as u8, Ordering::SeqCst);
 
-        let mut resolved_addr = self.resolved_addr.lock().await;
-        *resolved_addr = create_addr(network_type, *ip, self.port);
+        *self.resolved_addr.lock().unwrap() =
+            create_addr(network_type, *ip, self.port, self.scope_id);
 
         Ok(())
     }
@@ -359,7 +419,11 @@ impl CandidateBase {
             // other-pref is the preference for the particular IP address from which
             // the candidate was obtained.  When there is only a single IP address,
             // this value SHOULD be set to the maximum allowed value (8191).
-            let other_pref: u16 = 8191;
+            let other_pref: u16 = if self.local_preference_override != 0 {
+                self.local_preference_override >> 3
+            } else {
+                8191
+            };
 
             let direction_pref: u16 = match self.candidate_type() {
                 CandidateType::Host | CandidateType::Relay => match self.tcp_type() {
@@ -380,6 +444,8 @@ impl CandidateBase {
             };
 
             (1 << 13) * direction_pref + other_pref
+        } else if self.local_preference_override != 0 {
+            self.local_preference_override
         } else {
             DEFAULT_LOCAL_PREFERENCE
         }
@@ -388,22 +454,22 @@ impl CandidateBase {
     pub(crate) async fn recv_loop(
         candidate: Arc<dyn Candidate + Send + Sync>,
         agent_internal: Arc<Mutex<AgentInternal>>,
+        agent_conn: Arc<AgentConn>,
         mut closed_ch_rx: broadcast::Receiver<()>,
         initialized_ch: Option<broadcast::Receiver<()>>,
         conn: Arc<dyn util::Conn + Send + Sync>,
-        addr: SocketAddr,
     ) -> Result<()> {
         if let Some(mut initialized_ch) = initialized_ch {
             tokio::select! {
                 _ = initialized_ch.recv() => {}
-                _ = closed_ch_rx.recv() => return Err(Error::ErrClosed.into()),
+                _ = closed_ch_rx.recv() => return Err(Error::ErrClosed),
             }
         }
 
-        let mut buffer = vec![0_u8; RECEIVE_MTU];
         let mut n;
         let mut src_addr;
         loop {
+            let mut buffer = agent_conn.pool.get();
             tokio::select! {
                result = conn.recv_from(&mut buffer) => {
                    match result {
@@ -411,129 +477,73 @@ impl CandidateBase {
                             n = num;
                             src_addr = src;
                        }
-                       Err(err) => return Err(Error::new(err.to_string()).into()),
+                       Err(err) => return Err(Error::new(err.to_string())),
                    }
                },
-                _  = closed_ch_rx.recv() => return Err(Error::ErrClosed.into()),
+                _  = closed_ch_rx.recv() => return Err(Error::ErrClosed),
             }
 
-            Self::handle_inbound_candidate_msg(
-                &candidate,
-                &agent_internal,
-                &buffer[..n],
-                src_addr,
-                addr,
-            )
-            .await;
-        }
-    }
-
-    async fn handle_inbound_candidate_msg(
-        c: &Arc<dyn Candidate + Send + Sync>,
-        agent_internal: &Arc<Mutex<AgentInternal>>,
-        buf: &[u8],
-        src_addr: SocketAddr,
-        addr: SocketAddr,
-    ) {
-        if stun::message::is_message(buf) {
-            let mut m = Message {
-                raw: vec![],
-                ..Message::default()
-            };
-            // Explicitly copy raw buffer so Message can own the memory.
-            m.raw.extend_from_slice(buf);
-
-            if let Err(err) = m.decode() {
-                log::warn!(
-                    "Failed to handle decode ICE from {} to {}: {}",
-                    addr,
-                    src_addr,
-                    err
-                );
-            } else {
+            // Only STUN messages go through the agent, application data is delivered directly
+            buffer.truncate(n);
+            if is_message(&buffer) {
                 let mut ai = agent_internal.lock().await;
-                ai.handle_inbound(&mut m, c, src_addr).await;
-            }
-        } else {
-            let ai = agent_internal.lock().await;
-            if !ai.validate_non_stun_traffic(c, src_addr).await {
-                log::warn!(
-                    "Discarded message from {}, not a valid remote candidate",
-                    c.addr().await
-                );
-            } else if let Err(err) = ai.agent_conn.buffer.write(buf).await {
-                // NOTE This will return packetio.ErrFull if the buffer ever manages to fill up.
-                log::warn!("failed to write packet: {}", err);
+                ai.handle_stun(&candidate, buffer, src_addr).await;
+            } else {
+                // A datagram waiting for room in a full receive buffer mustn't hold up the STUN
+                // messages behind it, so the socket is read on while it waits
+                let handled = agent_conn.handle_data(&candidate, src_addr, buffer);
+                tokio::pin!(handled);
+                loop {
+                    let mut buffer = agent_conn.pool.get();
+                    tokio::select! {
+                        biased;
+
+                        _ = &mut handled => break,
+                        _ = closed_ch_rx.recv() => return Err(Error::ErrClosed),
+                        result = conn.recv_from(&mut buffer) => {
+                            let (n, src_addr) = result.map_err(|err| Error::new(err.to_string()))?;
+                            buffer.truncate(n);
+                            if is_message(&buffer) {
+                                let mut ai = agent_internal.lock().await;
+                                ai.handle_stun(&candidate, buffer, src_addr).await;
+                            } else {
+                                agent_conn.drop_data(buffer);
+                            }
+                        }
+                    }
+                }
             }
         }
     }
 }
 
-/// Creates a Candidate from its string representation.
+/// Creates a Candidate from its string representation, following the grammar of RFC 8839
+/// section 5.1. The `candidate:` prefix used in SDP is optional.
 pub async fn unmarshal_candidate(raw: String) -> Result<impl Candidate> {
-    let split: Vec<&str> = raw.split_whitespace().collect();
-    if split.len() < 8 {
-        return Err(Error::new(format!(
-            "{:?} ({})",
-            Error::ErrAttributeTooShortIceCandidate,
-            split.len()
-        ))
-        .into());
-    }
-
-    // Foundation
-    let foundation = split[0].to_owned();
-
-    // Component
-    let component: u16 = split[1].parse()?;
-
-    // Network
-    let network = split[2].to_owned();
-
-    // Priority
-    let priority: u32 = split[3].parse()?;
-
-    // Address
-    let address = split[4].to_owned();
-
-    // Port
-    let port: u16 = split[5].parse()?;
-
-    let typ = split[7];
-
-    let mut rel_addr = String::new();
-    let mut rel_port = 0;
-    let mut tcp_type = TcpType::Unspecified;
-
-    if split.len() > 8 {
-        let split2 = &split[8..];
-
-        if split2[0] == "raddr" {
-            if split2.len() < 4 {
-                return Err(Error::new(format!(
-                    "{:?}: incorrect length",
-                    Error::ErrParseRelatedAddr
-                ))
-                .into());
-            }
-
-            // RelatedAddress
-            rel_addr = split2[1].to_owned();
-
-            // RelatedPort
-            rel_port = split2[3].parse()?;
-        } else if split2[0] == "tcptype" {
-            if split2.len() < 2 {
-                return Err(
-                    Error::new(format!("{:?}: incorrect length", Error::ErrParseType)).into(),
-                );
-            }
-
-            tcp_type = TcpType::from(split2[1]);
-        }
-    }
-
-    match typ {
+    let grammar = candidate_grammar::parse_candidate_grammar(&raw)?;
+
+    let (foundation, component, network, priority, address, port, typ) = (
+        grammar.foundation,
+        grammar.component,
+        grammar.transport.to_lowercase(),
+        grammar.priority,
+        grammar.address,
+        grammar.port,
+        grammar.typ.as_str(),
+    );
+    let (tcp_type, network_id, network_cost, extensions) = (
+        grammar.tcp_type,
+        grammar.network_id,
+        grammar.network_cost,
+        grammar.extensions,
+    );
+    let (rel_addr, rel_port) = grammar
+        .related_address
+        .map_or((String::new(), 0), |related_address| {
+            (related_address.address, related_address.port)
+        });
+
+    let mut c = match typ {
         "host" => {
             let config = CandidateHostConfig {
                 base_config: CandidateBaseConfig {
@@ -543,11 +553,14 @@ pub async fn unmarshal_candidate(raw: String) -> Result<impl Candidate> {
                     component,
                     priority,
                     foundation,
+                    network_id,
+                    network_cost,
+                    extensions,
                     ..CandidateBaseConfig::default()
                 },
                 tcp_type,
             };
-            config.new_candidate_host().await
+            config.new_candidate_host().await?
         }
         "srflx" => {
             let config = CandidateServerReflexiveConfig {
@@ -558,12 +571,15 @@ pub async fn unmarshal_candidate(raw: String) -> Result<impl Candidate> {
                     component,
                     priority,
                     foundation,
+                    network_id,
+                    network_cost,
+                    extensions,
                     ..CandidateBaseConfig::default()
                 },
                 rel_addr,
                 rel_port,
             };
-            config.new_candidate_server_reflexive().await
+            config.new_candidate_server_reflexive().await?
         }
         "prflx" => {
             let config = CandidatePeerReflexiveConfig {
@@ -574,13 +590,16 @@ pub async fn unmarshal_candidate(raw: String) -> Result<impl Candidate> {
                     component,
                     priority,
                     foundation,
+                    network_id,
+                    network_cost,
+                    extensions,
                     ..CandidateBaseConfig::default()
                 },
                 rel_addr,
                 rel_port,
             };
 
-            config.new_candidate_peer_reflexive().await
+            config.new_candidate_peer_reflexive().await?
         }
         "relay" => {
             let config = CandidateRelayConfig {
@@ -591,14 +610,28 @@ pub async fn unmarshal_candidate(raw: String) -> Result<impl Candidate> {
                     component,
                     priority,
                     foundation,
+                    network_id,
+                    network_cost,
+                    extensions,
                     ..CandidateBaseConfig::default()
                 },
                 rel_addr,
                 rel_port,
                 ..CandidateRelayConfig::default()
             };
-            config.new_candidate_relay().await
+            config.new_candidate_relay().await?
         }
-        _ => Err(Error::new(format!("{:?} ({})", Error::ErrUnknownCandidateType, typ)).into()),
-    }
+        _ => {
+            return Err(Error::new(format!(
+                "{:?} ({})",
+                Error::ErrUnknownCandidateType,
+                typ
+            )))
+        }
+    };
+
+    // Only host candidates take the TCP type through their config
+    c.tcp_type = tcp_type;
+
+    Ok(c)
 }
@@ -0,0 +1,174 @@
+use super::*;
+use crate::error::*;
+
+/// The `candidate-attribute` of RFC 8839 section 5.1, as parsed from its string representation.
+pub(crate) struct CandidateGrammar {
+    pub(crate) foundation: String,
+    pub(crate) component: u16,
+    pub(crate) transport: String,
+    pub(crate) priority: u32,
+    pub(crate) address: String,
+    pub(crate) port: u16,
+    pub(crate) typ: String,
+    pub(crate) related_address: Option<CandidateRelatedAddress>,
+    pub(crate) tcp_type: TcpType,
+    pub(crate) network_id: u16,
+    pub(crate) network_cost: u16,
+    pub(crate) extensions: Vec<CandidateExtension>,
+}
+
+/// Splits the attribute into whitespace separated tokens while keeping track of their position.
+struct Tokens<'a> {
+    raw: &'a str,
+    pos: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn peek(&self) -> Option<(usize, &'a str)> {
+        let rest = &self.raw[self.pos..];
+        let start = self.pos + (rest.len() - rest.trim_start().len());
+        let token = &self.raw[start..];
+        let end = token.find(char::is_whitespace).unwrap_or(token.len());
+        if end == 0 {
+            None
+        } else {
+            Some((start, &token[..end]))
+        }
+    }
+
+    fn next(&mut self) -> Option<(usize, &'a str)> {
+        let token = self.peek();
+        if let Some((start, token)) = token {
+            self.pos = start + token.len();
+        }
+        token
+    }
+
+    fn expect(&mut self, what: &str) -> Result<(usize, &'a str)> {
+        self.next()
+            .ok_or_else(|| parse_error(self.raw.len(), format!("missing {}", what)))
+    }
+}
+
+fn parse_error(position: usize, reason: String) -> Error {
+    Error::ErrParseCandidate { position, reason }
+}
+
+/// Parses a number of at most `max_digits` digits, as used for the component ID, priority and
+/// port.
+fn parse_number<T: std::str::FromStr>(
+    (pos, token): (usize, &str),
+    what: &str,
+    max_digits: usize,
+) -> Result<T> {
+    if token.is_empty() || token.len() > max_digits || !token.chars().all(|c| c.is_ascii_digit()) {
+        return Err(parse_error(pos, format!("invalid {} {:?}", what, token)));
+    }
+    token
+        .parse()
+        .map_err(|_| parse_error(pos, format!("{} {:?} out of range", what, token)))
+}
+
+/// `ice-char = ALPHA / DIGIT / "+" / "/"`
+fn is_ice_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '+' || c == '/'
+}
+
+/// Parses `raw` following the `candidate-attribute` grammar of RFC 8839 section 5.1. The
+/// `candidate:` and `a=candidate:` prefixes used in SDP are optional. Extension attributes are
+/// kept in their original order, the ones known to the agent are additionally decoded.
+pub(crate) fn parse_candidate_grammar(raw: &str) -> Result<CandidateGrammar> {
+    let pos = if raw.starts_with("a=candidate:") {
+        "a=candidate:".len()
+    } else if raw.starts_with("candidate:") {
+        "candidate:".len()
+    } else {
+        0
+    };
+    let mut tokens = Tokens { raw, pos };
+
+    // foundation = 1*32ice-char
+    let (pos, foundation) = tokens.expect("foundation")?;
+    if foundation.len() > 32 || !foundation.chars().all(is_ice_char) {
+        return Err(parse_error(
+            pos,
+            format!("invalid foundation {:?}", foundation),
+        ));
+    }
+
+    // component-id = 1*3DIGIT
+    let component = parse_number(tokens.expect("component")?, "component", 3)?;
+
+    // transport = "UDP" / transport-extension
+    let (_, transport) = tokens.expect("transport")?;
+
+    // priority = 1*10DIGIT
+    let priority = parse_number(tokens.expect("priority")?, "priority", 10)?;
+
+    // connection-address, which is an IP address or an FQDN such as an mDNS name
+    let (_, address) = tokens.expect("connection address")?;
+
+    let port = parse_number(tokens.expect("port")?, "port", 5)?;
+
+    // cand-type = "typ" SP candidate-types
+    let (pos, typ) = tokens.expect("candidate type")?;
+    if typ != "typ" {
+        return Err(parse_error(pos, format!("expected \"typ\", got {:?}", typ)));
+    }
+    let (_, typ) = tokens.expect("candidate type")?;
+
+    // [SP rel-addr] [SP rel-port]
+    let mut related_address = None;
+    if let Some((_, "raddr")) = tokens.peek() {
+        tokens.next();
+        let (_, address) = tokens.expect("related address")?;
+        related_address = Some(CandidateRelatedAddress {
+            address: address.to_owned(),
+            port: 0,
+        });
+    }
+    if let Some((_, "rport")) = tokens.peek() {
+        tokens.next();
+        let port = parse_number(tokens.expect("related port")?, "related port", 5)?;
+        related_address
+            .get_or_insert_with(|| CandidateRelatedAddress {
+                address: String::new(),
+                port: 0,
+            })
+            .port = port;
+    }
+
+    // *(SP cand-extension)
+    let mut tcp_type = TcpType::Unspecified;
+    let mut network_id = 0;
+    let mut network_cost = 0;
+    let mut extensions = vec![];
+    while let Some((_, key)) = tokens.next() {
+        let value = tokens.expect(&format!("value of extension attribute {:?}", key))?;
+        match key {
+            "tcptype" => tcp_type = TcpType::from(value.1),
+            "network-id" => network_id = parse_number(value, "network-id", 5)?,
+            "network-cost" => network_cost = parse_number(value, "network-cost", 5)?,
+            _ => {}
+        }
+        extensions.push(CandidateExtension {
+            key: key.to_owned(),
+            value: value.1.to_owned(),
+        });
+    }
+
+    Ok(CandidateGrammar {
+        foundation: foundation.to_owned(),
+        component,
+        transport: transport.to_owned(),
+        priority,
+        address: address.to_owned(),
+        port,
+        typ: typ.to_owned(),
+        related_address,
+        tcp_type,
+        network_id,
+        network_cost,
+        extensions,
+    })
+}
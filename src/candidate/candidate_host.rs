@@ -1,8 +1,8 @@
 use super::candidate_base::*;
 use super::*;
 use crate::rand::generate_cand_id;
+use crate::util::*;
 
-use anyhow::Result;
 use std::sync::atomic::{AtomicU16, AtomicU8};
 
 /// The config required to create a new `CandidateHost`.
@@ -21,7 +21,7 @@ impl CandidateHostConfig {
             candidate_id = generate_cand_id();
         }
 
-        let c = CandidateBase {
+        let mut c = CandidateBase {
             id: candidate_id,
             address: self.base_config.address.clone(),
             candidate_type: CandidateType::Host,
@@ -30,14 +30,22 @@ impl CandidateHostConfig {
             tcp_type: self.tcp_type,
             foundation_override: self.base_config.foundation,
             priority_override: self.base_config.priority,
+            local_preference_override: self.base_config.local_preference,
+            network_id: self.base_config.network_id,
+            network_cost: self.base_config.network_cost,
+            extensions: self.base_config.extensions,
             network: self.base_config.network,
             network_type: AtomicU8::new(NetworkType::Udp4 as u8),
+            scope_id: self.base_config.scope_id,
             conn: self.base_config.conn,
             ..CandidateBase::default()
         };
 
         if !self.base_config.address.ends_with(".local") {
-            let ip = self.base_config.address.parse()?;
+            let (ip, zone) = parse_ip_with_zone(&self.base_config.address)?;
+            if zone != 0 {
+                c.scope_id = zone;
+            }
             c.set_ip(&ip).await?;
         };
 
@@ -0,0 +1,35 @@
+use super::candidate_base::unmarshal_candidate;
+use super::*;
+
+/// The W3C `RTCIceCandidateInit` dictionary, the representation used by browsers to exchange
+/// candidates, e.g. as JSON over a signaling channel. With the `serde` feature it serializes as
+/// `{"candidate":"candidate:...","sdpMid":"0","sdpMLineIndex":0,"usernameFragment":"..."}`.
+#[derive(Default, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub struct RTCIceCandidateInit {
+    /// The candidate attribute including the `candidate:` prefix.
+    pub candidate: String,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub sdp_mid: Option<String>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub sdp_m_line_index: Option<u16>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub username_fragment: Option<String>,
+}
+
+impl RTCIceCandidateInit {
+    /// Creates the representation of `c`, without `sdpMid`, `sdpMLineIndex` and
+    /// `usernameFragment`.
+    pub fn from_candidate(c: &(dyn Candidate + Send + Sync)) -> Self {
+        Self {
+            candidate: format!("candidate:{}", c.marshal()),
+            ..Default::default()
+        }
+    }
+
+    /// Creates the candidate described by `candidate`.
+    pub async fn to_candidate(&self) -> Result<impl Candidate> {
+        unmarshal_candidate(self.candidate.clone()).await
+    }
+}
@@ -159,3 +159,49 @@ async fn test_candidate_pair_equality() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_candidate_pair_network_cost() -> Result<()> {
+    let cellular_candidate = CandidateHostConfig {
+        base_config: CandidateBaseConfig {
+            network: "udp".to_owned(),
+            address: "0.0.0.0".to_owned(),
+            component: COMPONENT_RTP,
+            network_cost: 900,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+    .new_candidate_host()
+    .await?;
+
+    let host_pair = CandidatePair::new(
+        Arc::new(host_candidate().await?),
+        Arc::new(host_candidate().await?),
+        true,
+    );
+    let cellular_pair = CandidatePair::new(
+        Arc::new(cellular_candidate),
+        Arc::new(host_candidate().await?),
+        true,
+    );
+    let relay_pair = CandidatePair::new(
+        Arc::new(relay_candidate().await?),
+        Arc::new(host_candidate().await?),
+        true,
+    );
+
+    assert_eq!(cellular_pair.network_cost(), 900);
+    assert_eq!(relay_pair.network_cost(), 0);
+
+    // The cheaper relay pair wins despite its lower priority
+    assert!(cellular_pair.priority() > relay_pair.priority());
+    assert!(relay_pair.is_preferred_over(&cellular_pair));
+    assert!(!cellular_pair.is_preferred_over(&relay_pair));
+
+    // Pairs with the same cost are ordered by priority
+    assert!(host_pair.is_preferred_over(&relay_pair));
+    assert!(!relay_pair.is_preferred_over(&host_pair));
+
+    Ok(())
+}
@@ -11,7 +11,12 @@ use turn::auth::AuthHandler;
 pub(crate) struct OptimisticAuthHandler;
 
 impl AuthHandler for OptimisticAuthHandler {
-    fn auth_handle(&self, _username: &str, _realm: &str, _src_addr: SocketAddr) -> Result<Vec<u8>> {
+    fn auth_handle(
+        &self,
+        _username: &str,
+        _realm: &str,
+        _src_addr: SocketAddr,
+    ) -> anyhow::Result<Vec<u8>> {
         Ok(turn::auth::generate_auth_key(
             "username",
             "webrtc.rs",
@@ -65,6 +70,7 @@ async fn test_relay_only_connection() -> Result<()> {
             password: "password".to_owned(),
             port: server_port,
             proto: ProtoType::Udp,
+            ..Default::default()
         }],
         candidate_types: vec![CandidateType::Relay],
         ..Default::default()
@@ -83,6 +89,7 @@ async fn test_relay_only_connection() -> Result<()> {
             password: "password".to_owned(),
             port: server_port,
             proto: ProtoType::Udp,
+            ..Default::default()
         }],
         candidate_types: vec![CandidateType::Relay],
         ..Default::default()
@@ -0,0 +1,59 @@
+//! Serializes candidates like the attributes of the W3C `RTCIceCandidate` interface, e.g.
+//! `{"candidate":"candidate:...","foundation":"...","component":1,"priority":2130706431,
+//! "address":"10.0.0.1","protocol":"udp","port":5000,"type":"host"}`. `tcpType`,
+//! `relatedAddress` and `relatedPort` are only present when set. Candidates are deserialized
+//! through `RTCIceCandidateInit`, as the `candidate` attribute holds all information.
+
+use super::candidate_base::CandidateBase;
+use super::*;
+
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+fn serialize_candidate<S: Serializer>(
+    c: &(dyn Candidate + Send + Sync),
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    let related_address = c.related_address();
+    let tcp_type = c.tcp_type();
+
+    let mut len = 8;
+    if tcp_type != TcpType::Unspecified {
+        len += 1;
+    }
+    if related_address.is_some() {
+        len += 2;
+    }
+
+    let mut state = serializer.serialize_struct("RTCIceCandidate", len)?;
+    state.serialize_field("candidate", &format!("candidate:{}", c.marshal()))?;
+    state.serialize_field("foundation", &c.foundation())?;
+    state.serialize_field("component", &c.component())?;
+    state.serialize_field("priority", &c.priority())?;
+    // The zone ID of a link-local address is only meaningful on this host, like in `candidate`
+    let address = c.address();
+    let address = address.split('%').next().unwrap_or_default();
+    state.serialize_field("address", address)?;
+    state.serialize_field("protocol", &c.network_type().network_short())?;
+    state.serialize_field("port", &c.port())?;
+    state.serialize_field("type", &c.candidate_type())?;
+    if tcp_type != TcpType::Unspecified {
+        state.serialize_field("tcpType", &tcp_type)?;
+    }
+    if let Some(related_address) = related_address {
+        state.serialize_field("relatedAddress", &related_address.address)?;
+        state.serialize_field("relatedPort", &related_address.port)?;
+    }
+    state.end()
+}
+
+impl Serialize for dyn Candidate + Send + Sync {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serialize_candidate(self, serializer)
+    }
+}
+
+impl Serialize for CandidateBase {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serialize_candidate(self, serializer)
+    }
+}
@@ -0,0 +1,175 @@
+use super::candidate_base::{unmarshal_candidate, CandidateBaseConfig};
+use super::candidate_host::CandidateHostConfig;
+use super::candidate_init::RTCIceCandidateInit;
+use super::*;
+
+use anyhow::Result;
+use serde_json::json;
+
+#[tokio::test]
+async fn test_candidate_serialize() -> Result<()> {
+    let tests = vec![
+        (
+            "candidate:4273957277 1 udp 2130706431 10.0.75.1 53634 typ host",
+            json!({
+                "candidate": "candidate:4273957277 1 udp 2130706431 10.0.75.1 53634 typ host",
+                "foundation": "4273957277",
+                "component": 1,
+                "priority": 2130706431u32,
+                "address": "10.0.75.1",
+                "protocol": "udp",
+                "port": 53634,
+                "type": "host",
+            }),
+        ),
+        (
+            "candidate:1052353102 1 tcp 1694498815 191.228.238.68 9 typ srflx raddr 192.168.0.196 rport 9 tcptype active",
+            json!({
+                "candidate": "candidate:1052353102 1 tcp 1694498815 191.228.238.68 9 typ srflx raddr 192.168.0.196 rport 9 tcptype active",
+                "foundation": "1052353102",
+                "component": 1,
+                "priority": 1694498815u32,
+                "address": "191.228.238.68",
+                "protocol": "tcp",
+                "port": 9,
+                "type": "srflx",
+                "tcpType": "active",
+                "relatedAddress": "192.168.0.196",
+                "relatedPort": 9,
+            }),
+        ),
+    ];
+
+    for (raw, expected) in tests {
+        let c: Arc<dyn Candidate + Send + Sync> =
+            Arc::new(unmarshal_candidate(raw.to_owned()).await?);
+        assert_eq!(serde_json::to_value(&c)?, expected, "{}", raw);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_candidate_serialize_link_local() -> Result<()> {
+    let c: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "udp".to_owned(),
+                address: "fe80::1%3".to_owned(),
+                port: 1234,
+                component: 1,
+                foundation: "1".to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .new_candidate_host()
+        .await?,
+    );
+
+    // The zone ID is left out of both the candidate and its address
+    let value = serde_json::to_value(&c)?;
+    assert_eq!(value["address"], "fe80::1");
+    assert_eq!(
+        value["candidate"],
+        format!("candidate:1 1 udp {} fe80::1 1234 typ host", c.priority())
+    );
+
+    // So the candidate deserialized from it serializes the same
+    let init: RTCIceCandidateInit = serde_json::from_value(value.clone())?;
+    let c2: Arc<dyn Candidate + Send + Sync> = Arc::new(init.to_candidate().await?);
+    assert_eq!(serde_json::to_value(&c2)?, value);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_candidate_init_serde() -> Result<()> {
+    let raw = r#"{"candidate":"candidate:4273957277 1 udp 2130706431 10.0.75.1 53634 typ host","sdpMid":"0","sdpMLineIndex":0,"usernameFragment":"EsAw"}"#;
+
+    let init: RTCIceCandidateInit = serde_json::from_str(raw)?;
+    assert_eq!(init.sdp_mid.as_deref(), Some("0"));
+    assert_eq!(init.sdp_m_line_index, Some(0));
+    assert_eq!(init.username_fragment.as_deref(), Some("EsAw"));
+    assert_eq!(serde_json::to_string(&init)?, raw);
+
+    let c = init.to_candidate().await?;
+    assert_eq!(c.candidate_type(), CandidateType::Host);
+    assert_eq!(c.port(), 53634);
+
+    // Optional members are omitted
+    let init = RTCIceCandidateInit::from_candidate(&c);
+    assert_eq!(
+        serde_json::to_string(&init)?,
+        r#"{"candidate":"candidate:4273957277 1 udp 2130706431 10.0.75.1 53634 typ host"}"#
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_enum_serde() -> Result<()> {
+    assert_eq!(
+        serde_json::to_string(&CandidateType::ServerReflexive)?,
+        r#""srflx""#
+    );
+    assert_eq!(
+        serde_json::to_string(&CandidatePairState::InProgress)?,
+        r#""in-progress""#
+    );
+    assert_eq!(
+        serde_json::from_str::<TcpType>(r#""so""#)?,
+        TcpType::SimultaneousOpen
+    );
+    assert_eq!(
+        serde_json::from_str::<NetworkType>(r#""udp6""#)?,
+        NetworkType::Udp6
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_url_serde() -> Result<()> {
+    let url = crate::url::Url::parse_url("turn:example.org?transport=tcp")?;
+    let expected = json!({
+        "scheme": "turn",
+        "host": "example.org",
+        "port": 3478,
+        "username": "",
+        "password": "",
+        "proto": "tcp",
+        "credentialType": "password",
+        "accessToken": "",
+    });
+    assert_eq!(serde_json::to_value(&url)?, expected);
+
+    let url2: crate::url::Url = serde_json::from_value(expected)?;
+    assert_eq!(url2.to_string(), url.to_string());
+
+    // The credential type and access token are optional
+    let url3: crate::url::Url = serde_json::from_value(json!({
+        "scheme": "turn",
+        "host": "example.org",
+        "port": 3478,
+        "username": "kid",
+        "password": "",
+        "proto": "udp",
+    }))?;
+    assert_eq!(url3.credential_type, crate::url::CredentialType::Password);
+
+    let url4: crate::url::Url = serde_json::from_value(json!({
+        "scheme": "turn",
+        "host": "example.org",
+        "port": 3478,
+        "username": "kid",
+        "password": "a2V5",
+        "proto": "udp",
+        "credentialType": "oauth",
+        "accessToken": "dG9rZW4=",
+    }))?;
+    assert_eq!(url4.credential_type, crate::url::CredentialType::Oauth);
+    assert_eq!(url4.access_token, "dG9rZW4=");
+
+    Ok(())
+}
@@ -1,6 +1,5 @@
 use super::candidate_base::*;
 use super::*;
-use crate::error::*;
 use crate::rand::generate_cand_id;
 use crate::util::*;
 use std::sync::atomic::{AtomicU16, AtomicU8};
@@ -17,9 +16,11 @@ pub struct CandidateServerReflexiveConfig {
 impl CandidateServerReflexiveConfig {
     /// Creates a new server reflective candidate.
     pub async fn new_candidate_server_reflexive(self) -> Result<CandidateBase> {
-        let ip: IpAddr = match self.base_config.address.parse() {
-            Ok(ip) => ip,
-            Err(_) => return Err(Error::ErrAddressParseFailed.into()),
+        let (ip, zone) = parse_ip_with_zone(&self.base_config.address)?;
+        let scope_id = if zone != 0 {
+            zone
+        } else {
+            self.base_config.scope_id
         };
         let network_type = determine_network_type(&self.base_config.network, &ip)?;
 
@@ -34,10 +35,20 @@ impl CandidateServerReflexiveConfig {
             candidate_type: CandidateType::ServerReflexive,
             address: self.base_config.address,
             port: self.base_config.port,
-            resolved_addr: Mutex::new(create_addr(network_type, ip, self.base_config.port)),
+            resolved_addr: std::sync::Mutex::new(create_addr(
+                network_type,
+                ip,
+                self.base_config.port,
+                scope_id,
+            )),
+            scope_id,
             component: AtomicU16::new(self.base_config.component),
             foundation_override: self.base_config.foundation,
             priority_override: self.base_config.priority,
+            local_preference_override: self.base_config.local_preference,
+            network_id: self.base_config.network_id,
+            network_cost: self.base_config.network_cost,
+            extensions: self.base_config.extensions,
             related_address: Some(CandidateRelatedAddress {
                 address: self.rel_addr,
                 port: self.rel_port,
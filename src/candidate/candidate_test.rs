@@ -1,4 +1,7 @@
+use super::candidate_host::CandidateHostConfig;
+use super::candidate_server_reflexive::CandidateServerReflexiveConfig;
 use super::*;
+use crate::error::Error;
 
 use std::time::UNIX_EPOCH;
 
@@ -13,6 +16,26 @@ fn test_candidate_priority() -> Result<()> {
             },
             2130706431,
         ),
+        (
+            CandidateBase {
+                candidate_type: CandidateType::Host,
+                component: AtomicU16::new(COMPONENT_RTP),
+                local_preference_override: 65534,
+                ..Default::default()
+            },
+            2130706175,
+        ),
+        (
+            CandidateBase {
+                candidate_type: CandidateType::Host,
+                component: AtomicU16::new(COMPONENT_RTP),
+                network_type: AtomicU8::new(NetworkType::Tcp4 as u8),
+                tcp_type: TcpType::Active,
+                local_preference_override: 65527,
+                ..Default::default()
+            },
+            2128609023,
+        ),
         (
             CandidateBase {
                 candidate_type: CandidateType::Host,
@@ -112,7 +135,7 @@ fn test_candidate_priority() -> Result<()> {
 }
 
 #[test]
-fn test_candidate_last_sent() -> Result<()> {
+fn test_candidate_last_sent() -> anyhow::Result<()> {
     let candidate = CandidateBase::default();
     assert_eq!(candidate.last_sent(), UNIX_EPOCH);
 
@@ -125,7 +148,7 @@ fn test_candidate_last_sent() -> Result<()> {
 }
 
 #[test]
-fn test_candidate_last_received() -> Result<()> {
+fn test_candidate_last_received() -> anyhow::Result<()> {
     let candidate = CandidateBase::default();
     assert_eq!(candidate.last_received(), UNIX_EPOCH);
 
@@ -249,6 +272,20 @@ async fn test_candidate_marshal() -> Result<()> {
             }),
             "750 1 udp 500 fcd9:e3b8:12ce:9fc5:74a5:c6bb:d8b:e08a 53987 typ host",
         ),
+        (
+            Some(CandidateBase{
+                    network_type:       AtomicU8::new(NetworkType::Udp4 as u8),
+                    candidate_type:      CandidateType::Host,
+                    address:            "10.0.75.1".to_owned(),
+                    port:               53634,
+                    priority_override:   500,
+                    foundation_override: "750".to_owned(),
+                    network_id:          3,
+                    network_cost:        50,
+                    ..Default::default()
+            }),
+            "750 1 udp 500 10.0.75.1 53634 typ host network-id 3 network-cost 50",
+        ),
         (
             Some(CandidateBase{
                     network_type:   AtomicU8::new(NetworkType::Udp4 as u8),
@@ -344,3 +381,170 @@ async fn test_candidate_marshal() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_candidate_link_local_scope_id() -> Result<()> {
+    // The zone ID from the address takes precedence over the configured one
+    let c = CandidateHostConfig {
+        base_config: CandidateBaseConfig {
+            network: "udp".to_owned(),
+            address: "fe80::1%3".to_owned(),
+            port: 1234,
+            scope_id: 2,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+    .new_candidate_host()
+    .await?;
+    assert_eq!(c.addr(), "[fe80::1%3]:1234".parse::<SocketAddr>()?);
+    assert!(
+        !c.marshal().contains('%'),
+        "the zone ID must not be signaled"
+    );
+
+    let c = CandidateHostConfig {
+        base_config: CandidateBaseConfig {
+            network: "udp".to_owned(),
+            address: "fe80::1".to_owned(),
+            port: 1234,
+            scope_id: 2,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+    .new_candidate_host()
+    .await?;
+    assert_eq!(c.addr(), "[fe80::1%2]:1234".parse::<SocketAddr>()?);
+    assert_eq!(c.address(), "fe80::1", "the zone ID must not be signaled");
+
+    let c = CandidateServerReflexiveConfig {
+        base_config: CandidateBaseConfig {
+            network: "udp".to_owned(),
+            address: "fe80::1%4".to_owned(),
+            port: 1234,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+    .new_candidate_server_reflexive()
+    .await?;
+    assert_eq!(c.addr(), "[fe80::1%4]:1234".parse::<SocketAddr>()?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_candidate_unmarshal_network_attributes() -> Result<()> {
+    let c = unmarshal_candidate(
+        "750 1 udp 500 10.0.75.1 53634 typ srflx raddr 192.168.0.1 rport 53991 generation 0 network-id 3 network-cost 50".to_owned(),
+    )
+    .await?;
+    assert_eq!(c.network_id(), 3);
+    assert_eq!(c.network_cost(), 50);
+    assert_eq!(
+        c.related_address(),
+        Some(CandidateRelatedAddress {
+            address: "192.168.0.1".to_owned(),
+            port: 53991,
+        })
+    );
+
+    assert!(unmarshal_candidate(
+        "750 1 udp 500 10.0.75.1 53634 typ host network-cost high".to_owned()
+    )
+    .await
+    .is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_candidate_unmarshal_lossless() -> Result<()> {
+    let tests = vec![
+        (
+            "candidate:1052353102 1 tcp 1694498815 191.228.238.68 9 typ srflx raddr 192.168.0.196 rport 9 tcptype active generation 0 ufrag EsAw network-id 1",
+            "1052353102 1 tcp 1694498815 191.228.238.68 9 typ srflx raddr 192.168.0.196 rport 9 tcptype active generation 0 ufrag EsAw network-id 1",
+        ),
+        (
+            "a=candidate:750 1 udp 500 10.0.75.1 53634 typ host network-cost 50 x-unknown some-value generation 0",
+            "750 1 udp 500 10.0.75.1 53634 typ host network-cost 50 x-unknown some-value generation 0",
+        ),
+    ];
+
+    for &(raw, marshaled) in &tests {
+        let c = unmarshal_candidate(raw.to_owned()).await?;
+        assert_eq!(c.marshal(), marshaled);
+    }
+
+    let c = unmarshal_candidate(tests[0].0.to_owned()).await?;
+    assert_eq!(c.tcp_type(), TcpType::Active);
+    assert_eq!(c.network_id(), 1);
+    assert_eq!(
+        c.related_address(),
+        Some(CandidateRelatedAddress {
+            address: "192.168.0.196".to_owned(),
+            port: 9,
+        })
+    );
+    assert_eq!(
+        c.extensions()[2],
+        CandidateExtension {
+            key: "ufrag".to_owned(),
+            value: "EsAw".to_owned(),
+        }
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_candidate_unmarshal_error_position() -> Result<()> {
+    let tests = vec![
+        ("750 1 udp 500 10.0.75.1 INVALID typ host", 24),
+        ("candidate:750 1 udp 500 10.0.75.1 53634 type host", 40),
+        ("750 1 udp 500 10.0.75.1 53634 typ host generation", 49),
+        ("75-0 1 udp 500 10.0.75.1 53634 typ host", 0),
+        (
+            "750 1 udp 500 10.0.75.1 53634 typ srflx raddr 1.2.3.4 rport 99999999",
+            60,
+        ),
+    ];
+
+    for (raw, position) in tests {
+        match unmarshal_candidate(raw.to_owned()).await {
+            Ok(_) => panic!("expected error for {}", raw),
+            Err(Error::ErrParseCandidate {
+                position: p,
+                reason,
+            }) => {
+                assert_eq!(p, position, "{}: {}", raw, reason);
+            }
+            Err(err) => panic!("unexpected error for {}: {}", raw, err),
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_candidate_init() -> Result<()> {
+    let c = unmarshal_candidate(
+        "1052353102 1 tcp 1694498815 191.228.238.68 9 typ srflx raddr 192.168.0.196 rport 9 tcptype active"
+            .to_owned(),
+    )
+    .await?;
+
+    let init = candidate_init::RTCIceCandidateInit::from_candidate(&c);
+    assert_eq!(
+        init.candidate,
+        "candidate:1052353102 1 tcp 1694498815 191.228.238.68 9 typ srflx raddr 192.168.0.196 rport 9 tcptype active"
+    );
+    assert_eq!(init.sdp_mid, None);
+
+    let c2 = init.to_candidate().await?;
+    assert!(c.equal(&c2));
+    assert_eq!(c2.tcp_type(), TcpType::Active);
+
+    Ok(())
+}
@@ -2,23 +2,30 @@
 mod candidate_pair_test;
 #[cfg(test)]
 mod candidate_relay_test;
+#[cfg(all(test, feature = "serde"))]
+mod candidate_serde_test;
 #[cfg(test)]
 mod candidate_server_reflexive_test;
 #[cfg(test)]
 mod candidate_test;
 
 pub mod candidate_base;
+mod candidate_grammar;
 pub mod candidate_host;
+pub mod candidate_init;
 pub mod candidate_peer_reflexive;
 pub mod candidate_relay;
+#[cfg(feature = "serde")]
+mod candidate_serde;
 pub mod candidate_server_reflexive;
 
+use crate::error::*;
 use crate::network_type::*;
 use crate::tcp_type::*;
 use candidate_base::*;
 
 use crate::agent::agent_internal::AgentInternal;
-use anyhow::Result;
+use crate::agent::agent_transport::AgentConn;
 use async_trait::async_trait;
 use std::fmt;
 use std::net::{IpAddr, SocketAddr};
@@ -72,9 +79,22 @@ pub trait Candidate: fmt::Display {
     fn candidate_type(&self) -> CandidateType;
     fn tcp_type(&self) -> TcpType;
 
+    /// Identifies the network the candidate was gathered on, signaled as `network-id`.
+    /// Zero means unknown.
+    fn network_id(&self) -> u16;
+
+    /// The cost of sending traffic over the network of the candidate, signaled as
+    /// `network-cost`. Lower values are preferred, zero means unknown.
+    fn network_cost(&self) -> u16;
+
+    /// The extension attributes of the candidate in the order they are marshaled. This includes
+    /// `tcptype`, `network-id` and `network-cost` as well as any unknown attribute received from
+    /// the remote peer.
+    fn extensions(&self) -> Vec<CandidateExtension>;
+
     fn marshal(&self) -> String;
 
-    async fn addr(&self) -> SocketAddr;
+    fn addr(&self) -> SocketAddr;
 
     async fn close(&self) -> Result<()>;
     fn seen(&self, outbound: bool);
@@ -88,10 +108,14 @@ pub trait Candidate: fmt::Display {
 
 /// Represents the type of candidate `CandidateType` enum.
 #[derive(PartialEq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum CandidateType {
     Unspecified,
     Host,
+    #[cfg_attr(feature = "serde", serde(rename = "srflx"))]
     ServerReflexive,
+    #[cfg_attr(feature = "serde", serde(rename = "prflx"))]
     PeerReflexive,
     Relay,
 }
@@ -134,6 +158,15 @@ impl CandidateType {
     }
 }
 
+/// Returns true if both candidates send and receive on the same socket, like the server reflexive
+/// candidates discovered from one base.
+pub(crate) fn shares_conn(a: &dyn Candidate, b: &dyn Candidate) -> bool {
+    match (a.get_conn(), b.get_conn()) {
+        (Some(a), Some(b)) => std::ptr::addr_eq(Arc::as_ptr(a), Arc::as_ptr(b)),
+        _ => false,
+    }
+}
+
 pub(crate) fn contains_candidate_type(
     candidate_type: CandidateType,
     candidate_type_list: &[CandidateType],
@@ -149,8 +182,17 @@ pub(crate) fn contains_candidate_type(
     false
 }
 
+/// An extension attribute of a candidate, e.g. `generation 0` or `network-cost 10`.
+#[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CandidateExtension {
+    pub key: String,
+    pub value: String,
+}
+
 /// Convey transport addresses related to the candidate, useful for diagnostics and other purposes.
 #[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CandidateRelatedAddress {
     pub address: String,
     pub port: u16,
@@ -165,6 +207,8 @@ impl fmt::Display for CandidateRelatedAddress {
 
 /// Represent the ICE candidate pair state.
 #[derive(PartialEq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
 pub enum CandidatePairState {
     Unspecified = 0,
 
@@ -307,6 +351,21 @@ impl CandidatePair {
             + if g > d { 1 } else { 0 }
     }
 
+    /// Returns the cost of the pair, which is the higher of the network costs of its candidates.
+    pub fn network_cost(&self) -> u16 {
+        std::cmp::max(self.local.network_cost(), self.remote.network_cost())
+    }
+
+    /// Returns true if the pair should be preferred over `other`. Pairs on cheaper networks are
+    /// preferred, and the priority decides between pairs with the same cost.
+    pub(crate) fn is_preferred_over(&self, other: &Self) -> bool {
+        match self.network_cost().cmp(&other.network_cost()) {
+            std::cmp::Ordering::Less => true,
+            std::cmp::Ordering::Greater => false,
+            std::cmp::Ordering::Equal => self.priority() > other.priority(),
+        }
+    }
+
     pub async fn write(&self, b: &[u8]) -> Result<usize> {
         self.local.write_to(b, &*self.remote).await
     }
@@ -1,6 +1,16 @@
+use std::io;
+use std::net;
+use std::num::ParseIntError;
+use std::sync::Arc;
 use thiserror::Error;
 
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The errors returned by the agent and the types of this crate. Errors of the underlying STUN,
+/// TURN, mDNS and network crates are kept as source and can be matched through the `Stun`, `Turn`,
+/// `Mdns`, `Vnet`, `Conn`, `Buffer` and `Io` variants.
 #[derive(Debug, Error, PartialEq)]
+#[non_exhaustive]
 pub enum Error {
     /// Indicates an error with Unknown info.
     #[error("Unknown type")]
@@ -26,6 +36,10 @@ pub enum Error {
     #[error("invalid port number")]
     ErrPort,
 
+    /// Indicates that no port of the configured port range could be bound.
+    #[error("all ports of the port range are in use")]
+    ErrPortRangeExhausted,
+
     /// Indicates local username fragment insufficient bits are provided.
     /// Have to be at least 24 bits long.
     #[error("local username fragment is less than 24 bits long")]
@@ -52,6 +66,14 @@ pub enum Error {
     #[error("connecting canceled by caller")]
     ErrCanceledByCaller,
 
+    /// Indicates that an operation, such as a STUN request, did not complete in time.
+    #[error("operation timed out")]
+    ErrTimeout,
+
+    /// Indicates that a demux endpoint was requested for a packet class which has one already.
+    #[error("the packet class has a demux endpoint already")]
+    ErrDemuxEndpointExists,
+
     /// Indicates agent was started twice.
     #[error("attempted to start agent twice")]
     ErrMultipleStart,
@@ -64,8 +86,8 @@ pub enum Error {
     #[error("remote pwd is empty")]
     ErrRemotePwdEmpty,
 
-    /// Indicates agent was started without on_candidate.
-    #[error("no on_candidate provided")]
+    /// Indicates agent was started without on_candidate or an event subscriber.
+    #[error("no on_candidate handler or event subscriber")]
     ErrNoOnCandidateHandler,
 
     /// Indicates GatherCandidates has been called multiple times.
@@ -80,6 +102,18 @@ pub enum Error {
     #[error("password is empty")]
     ErrPasswordEmpty,
 
+    /// Indicates agent was give TURN URL with OAuth credentials but an empty access token.
+    #[error("access token is empty")]
+    ErrAccessTokenEmpty,
+
+    /// Indicates the MAC key or access token of OAuth credentials is not base64 encoded.
+    #[error("OAuth MAC key and access token must be base64 encoded")]
+    ErrInvalidOauthCredential,
+
+    /// Indicates the expiry of TURN credentials is before the Unix epoch.
+    #[error("credential expiry is before the Unix epoch")]
+    ErrInvalidCredentialExpiry,
+
     /// Indicates we were unable to parse a candidate address.
     #[error("failed to parse address")]
     ErrAddressParseFailed,
@@ -155,6 +189,10 @@ pub enum Error {
     ErrParseType,
     #[error("unknown candidate type")]
     ErrUnknownCandidateType,
+    #[error("could not parse candidate at position {position}: {reason}")]
+    ErrParseCandidate { position: usize, reason: String },
+    #[error("could not parse SDP at line {line}: {reason}")]
+    ErrParseSdp { line: usize, reason: String },
     #[error("failed to get XOR-MAPPED-ADDRESS response")]
     ErrGetXorMappedAddrResponse,
     #[error("connection with same remote address already exists")]
@@ -177,6 +215,8 @@ pub enum Error {
     ErrUnknownRole,
     #[error("username mismatch")]
     ErrMismatchUsername,
+    #[error("ufrag of trickle ICE fragment does not match the remote ufrag")]
+    ErrSdpFragUfragMismatch,
     #[error("the ICE conn can't write STUN messages")]
     ErrIceWriteStunMessage,
     #[error("invalid url")]
@@ -184,6 +224,38 @@ pub enum Error {
     #[error("relative URL without a base")]
     ErrUrlParse,
 
+    /// Indicates that the TURN server rejected the credentials of an allocation.
+    #[error("TURN server rejected the credentials: error {0}: {1}")]
+    ErrTurnUnauthorized(u16, String),
+
+    /// Indicates that a STUN or TURN server answered with an error response, carrying its error
+    /// code and reason phrase.
+    #[error("server returned error {0}: {1}")]
+    ErrServerErrorResponse(u16, String),
+
+    #[error(transparent)]
+    ParseIp(#[from] net::AddrParseError),
+    #[error(transparent)]
+    ParseInt(#[from] ParseIntError),
+    #[error(transparent)]
+    ParseUrl(#[from] url::ParseError),
+    #[error(transparent)]
+    Io(IoError),
+    #[error(transparent)]
+    Stun(stun::error::Error),
+    #[error(transparent)]
+    Turn(turn::error::Error),
+    #[error(transparent)]
+    Mdns(mdns::error::Error),
+    #[error(transparent)]
+    Vnet(util::vnet::error::Error),
+    #[error(transparent)]
+    Conn(util::conn::error::Error),
+    #[error(transparent)]
+    Buffer(util::buffer::error::Error),
+    #[error(transparent)]
+    Other(OtherError),
+
     #[allow(non_camel_case_types)]
     #[error("{0}")]
     new(String),
@@ -193,4 +265,94 @@ impl Error {
     pub fn equal(&self, err: &anyhow::Error) -> bool {
         err.downcast_ref::<Self>().map_or(false, |e| e == self)
     }
+
+    /// Converts an error of a TURN allocation, given the error code and reason phrase of the
+    /// error response which caused it, if any.
+    pub(crate) fn from_turn_allocate(
+        err: anyhow::Error,
+        error_code: Option<(u16, String)>,
+    ) -> Self {
+        match error_code {
+            Some((code @ (401 | 441), reason)) => Self::ErrTurnUnauthorized(code, reason),
+            Some((code, reason)) => Self::ErrServerErrorResponse(code, reason),
+            None => err.into(),
+        }
+    }
+
+    /// Returns the error code and reason phrase if the error is an error response of a STUN or
+    /// TURN server.
+    pub(crate) fn server_error_code(&self) -> Option<(u16, String)> {
+        match self {
+            Self::ErrServerErrorResponse(code, reason) => Some((*code, reason.clone())),
+            Self::ErrTurnUnauthorized(code, reason) => Some((*code, reason.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// An `io::Error`, which are equal if their kinds are.
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct IoError(#[from] pub io::Error);
+
+impl PartialEq for IoError {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.kind() == other.0.kind()
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(IoError(e))
+    }
+}
+
+/// Any other error of the underlying crates, which are equal if they are the same instance.
+#[derive(Debug, Clone, Error)]
+#[error("{0}")]
+pub struct OtherError(pub Arc<anyhow::Error>);
+
+impl PartialEq for OtherError {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Converts the errors of the underlying crates, which return `anyhow::Error`, by downcasting to
+/// their typed errors.
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<Self>() {
+            Ok(e) => return e,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<io::Error>() {
+            Ok(e) => return e.into(),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<stun::error::Error>() {
+            Ok(e) => return Self::Stun(e),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<turn::error::Error>() {
+            Ok(e) => return Self::Turn(e),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<mdns::error::Error>() {
+            Ok(e) => return Self::Mdns(e),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<util::vnet::error::Error>() {
+            Ok(e) => return Self::Vnet(e),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<util::conn::error::Error>() {
+            Ok(e) => return Self::Conn(e),
+            Err(err) => err,
+        };
+        match err.downcast::<util::buffer::error::Error>() {
+            Ok(e) => Self::Buffer(e),
+            Err(err) => Self::Other(OtherError(Arc::new(err))),
+        }
+    }
 }
@@ -0,0 +1,84 @@
+use super::*;
+
+use anyhow::Result;
+
+#[test]
+fn test_ipv6_address_class() -> Result<()> {
+    let temporary = Ipv6AddressInfo {
+        temporary: true,
+        ..Default::default()
+    };
+    let deprecated = Ipv6AddressInfo {
+        temporary: true,
+        deprecated: true,
+    };
+
+    let tests = vec![
+        ("fe80::1", None, Ipv6AddressClass::LinkLocal),
+        ("fe80::1", Some(&deprecated), Ipv6AddressClass::LinkLocal),
+        ("fd12:3456::1", None, Ipv6AddressClass::UniqueLocal),
+        ("fc00::1", None, Ipv6AddressClass::UniqueLocal),
+        ("2001:db8::1", None, Ipv6AddressClass::Global),
+        ("2001:db8::1", Some(&temporary), Ipv6AddressClass::Temporary),
+        (
+            "2001:db8::1",
+            Some(&deprecated),
+            Ipv6AddressClass::Deprecated,
+        ),
+        (
+            "fd12:3456::1",
+            Some(&temporary),
+            Ipv6AddressClass::Temporary,
+        ),
+    ];
+
+    for (ip, info, expected) in tests {
+        let ip: Ipv6Addr = ip.parse()?;
+        assert_eq!(
+            Ipv6AddressClass::classify(&ip, info),
+            expected,
+            "{} with {:?}",
+            ip,
+            info
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_if_inet6() -> Result<()> {
+    let content = "\
+00000000000000000000000000000001 01 80 10 80       lo
+fe80000000000000021122fffe334455 02 40 20 80     eth0
+fe80000000000000021122fffe334455 03 40 20 80     eth1
+20010db8000000000000000000000001 02 40 00 00     eth0
+20010db80000000011223344556677aa 02 40 00 01     eth0
+20010db80000000011223344556677bb 02 40 00 21     eth0
+not-an-address 02 40 00 01     eth0
+200é111111111111111111111111111 02 40 00 00     eth0
+";
+
+    let infos = parse_if_inet6(content);
+    assert_eq!(infos.len(), 6);
+
+    // The same link-local address on two interfaces is kept apart by the interface index
+    let link_local: Ipv6Addr = "fe80::211:22ff:fe33:4455".parse()?;
+    assert!(infos.contains_key(&(2, link_local)));
+    assert!(infos.contains_key(&(3, link_local)));
+    assert!(!infos.contains_key(&(4, link_local)));
+
+    let temporary: Ipv6Addr = "2001:db8::1122:3344:5566:77aa".parse()?;
+    assert_eq!(
+        Ipv6AddressClass::classify(&temporary, infos.get(&(2, temporary))),
+        Ipv6AddressClass::Temporary
+    );
+
+    let deprecated: Ipv6Addr = "2001:db8::1122:3344:5566:77bb".parse()?;
+    assert_eq!(
+        Ipv6AddressClass::classify(&deprecated, infos.get(&(2, deprecated))),
+        Ipv6AddressClass::Deprecated
+    );
+
+    Ok(())
+}
@@ -0,0 +1,150 @@
+#[cfg(test)]
+mod ipv6_address_class_test;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv6Addr;
+
+/// Marks a privacy address generated according to RFC 8981 (`IFA_F_TEMPORARY`).
+const IFA_F_TEMPORARY: u32 = 0x01;
+/// Marks an address whose preferred lifetime has expired (`IFA_F_DEPRECATED`).
+const IFA_F_DEPRECATED: u32 = 0x20;
+
+/// Represents the class of a local IPv6 address, used to decide which addresses are used to
+/// gather ICE candidates.
+#[derive(PartialEq, Debug, Copy, Clone, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum Ipv6AddressClass {
+    /// Indicates a link-local address (fe80::/10). Candidates for these addresses carry the
+    /// zone ID of their interface.
+    LinkLocal,
+
+    /// Indicates a unique local address (fc00::/7) as described in RFC 4193.
+    UniqueLocal,
+
+    /// Indicates an address whose preferred lifetime has expired (RFC 4862).
+    Deprecated,
+
+    /// Indicates a temporary (privacy) address as described in RFC 8981.
+    Temporary,
+
+    /// Indicates any other unicast address.
+    Global,
+}
+
+impl fmt::Display for Ipv6AddressClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match *self {
+            Self::LinkLocal => "link-local",
+            Self::UniqueLocal => "unique-local",
+            Self::Deprecated => "deprecated",
+            Self::Temporary => "temporary",
+            Self::Global => "global",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Ipv6AddressClass {
+    /// Classifies `ip` using its prefix and the flags the host reported for it. Link-local
+    /// addresses are always classified as such, otherwise deprecated and temporary flags take
+    /// precedence over the prefix.
+    #[must_use]
+    pub fn classify(ip: &Ipv6Addr, info: Option<&Ipv6AddressInfo>) -> Self {
+        let first_segment = ip.segments()[0];
+        if (first_segment & 0xffc0) == 0xfe80 {
+            return Self::LinkLocal;
+        }
+
+        if let Some(info) = info {
+            if info.deprecated {
+                return Self::Deprecated;
+            }
+            if info.temporary {
+                return Self::Temporary;
+            }
+        }
+
+        if (first_segment & 0xfe00) == 0xfc00 {
+            Self::UniqueLocal
+        } else {
+            Self::Global
+        }
+    }
+}
+
+/// Per-address details the operating system reports for IPv6 addresses, which cannot be derived
+/// from the address itself.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct Ipv6AddressInfo {
+    pub temporary: bool,
+    pub deprecated: bool,
+}
+
+/// Returns the details of all IPv6 addresses assigned to the local interfaces, keyed by interface
+/// index and address, as the same link-local address may be assigned to several interfaces. The
+/// result is empty on platforms where this information is not available.
+pub(crate) fn local_ipv6_address_info() -> HashMap<(u32, Ipv6Addr), Ipv6AddressInfo> {
+    #[cfg(target_os = "linux")]
+    {
+        match std::fs::read_to_string("/proc/net/if_inet6") {
+            Ok(content) => parse_if_inet6(&content),
+            Err(err) => {
+                log::debug!("failed to read /proc/net/if_inet6: {}", err);
+                HashMap::new()
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        HashMap::new()
+    }
+}
+
+/// Parses the content of Linux's `/proc/net/if_inet6`. Every line holds the address, interface
+/// index, prefix length, scope, flags and interface name, with all numbers in hex.
+pub(crate) fn parse_if_inet6(content: &str) -> HashMap<(u32, Ipv6Addr), Ipv6AddressInfo> {
+    let mut infos = HashMap::new();
+
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // The address is sliced into hex digits, which have to be single bytes
+        if fields.len() < 5 || fields[0].len() != 32 || !fields[0].is_ascii() {
+            continue;
+        }
+
+        let mut octets = [0_u8; 16];
+        let mut valid = true;
+        for (i, octet) in octets.iter_mut().enumerate() {
+            match u8::from_str_radix(&fields[0][i * 2..i * 2 + 2], 16) {
+                Ok(v) => *octet = v,
+                Err(_) => {
+                    valid = false;
+                    break;
+                }
+            }
+        }
+
+        let (index, flags) = match (
+            u32::from_str_radix(fields[1], 16),
+            u32::from_str_radix(fields[4], 16),
+        ) {
+            (Ok(index), Ok(flags)) => (index, flags),
+            _ => continue,
+        };
+
+        if valid {
+            infos.insert(
+                (index, Ipv6Addr::from(octets)),
+                Ipv6AddressInfo {
+                    temporary: flags & IFA_F_TEMPORARY != 0,
+                    deprecated: flags & IFA_F_DEPRECATED != 0,
+                },
+            );
+        }
+    }
+
+    infos
+}
@@ -2,17 +2,22 @@
 #![allow(dead_code)]
 
 pub mod agent;
+pub mod buffer;
 pub mod candidate;
 pub mod control;
 pub mod error;
 pub mod external_ip_mapper;
+pub mod ipv6_address_class;
 pub mod mdns;
 pub mod network_type;
 pub mod priority;
 pub mod rand;
+pub mod runtime;
+pub mod sdp;
 pub mod state;
 pub mod stats;
 pub mod tcp_type;
+pub mod turn_credentials;
 pub mod url;
 pub mod use_candidate;
 mod util;
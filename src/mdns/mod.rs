@@ -4,7 +4,8 @@ mod mdns_test;
 use mdns::config::*;
 use mdns::conn::*;
 
-use anyhow::Result;
+use crate::error::*;
+
 use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -3,7 +3,6 @@ mod network_type_test;
 
 use crate::error::*;
 
-use anyhow::Result;
 use std::fmt;
 use std::net::IpAddr;
 
@@ -22,6 +21,8 @@ pub fn supported_network_types() -> Vec<NetworkType> {
 
 /// Represents the type of network.
 #[derive(PartialEq, Debug, Copy, Clone, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum NetworkType {
     Unspecified,
 
@@ -137,6 +138,6 @@ pub(crate) fn determine_network_type(network: &str, ip: &IpAddr) -> Result<Netwo
             Ok(NetworkType::Tcp6)
         }
     } else {
-        Err(Error::ErrDetermineNetworkType.into())
+        Err(Error::ErrDetermineNetworkType)
     }
 }
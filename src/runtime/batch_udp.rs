@@ -0,0 +1,471 @@
+use super::*;
+use crate::candidate::RECEIVE_MTU;
+
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+
+/// The number of datagrams received or sent with one system call.
+const BATCH_SIZE: usize = 32;
+
+/// With GRO the kernel coalesces datagrams of up to 64 KiB, which need buffers of that size.
+const GRO_BATCH_SIZE: usize = 8;
+const GRO_BUFFER_SIZE: usize = u16::MAX as usize;
+
+/// The datagrams waiting to be sent, senders wait for room beyond.
+const SEND_QUEUE_SIZE: usize = 256;
+
+/// The limits of the kernel for a single GSO send.
+const MAX_GSO_SEGMENTS: usize = 64;
+const MAX_GSO_SIZE: usize = 65000;
+
+struct Received {
+    buf: usize,
+    offset: usize,
+    len: usize,
+    addr: SocketAddr,
+}
+
+struct RecvBatch {
+    bufs: Vec<Vec<u8>>,
+    received: VecDeque<Received>,
+}
+
+/// A datagram queued for the task sending the batches, which reports the result of sending it.
+type Queued = (SocketAddr, Vec<u8>, oneshot::Sender<io::Result<()>>);
+
+/// A UDP socket receiving with `recvmmsg` and sending with `sendmmsg`, so that a system call
+/// handles a batch of datagrams instead of a single one.
+///
+/// Datagrams coalesced by the kernel (UDP GRO) are split up again when they are received.
+/// Datagrams are sent by a task of the socket, which writes the ones queued concurrently
+/// together, consecutive ones of the same size to the same address as a single segmented datagram
+/// (UDP GSO) if the kernel supports it. Each sender gets the result of its own datagram, which is
+/// sent even if the sender stops waiting for it.
+pub(crate) struct BatchUdpConn {
+    socket: Arc<UdpSocket>,
+    recv: tokio::sync::Mutex<RecvBatch>,
+    send_tx: mpsc::Sender<Queued>,
+}
+
+impl BatchUdpConn {
+    pub(crate) async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let fd = socket.as_raw_fd();
+        let gro = set_udp_option(fd, libc::UDP_GRO, 1).is_ok();
+        let gso = get_udp_option(fd, libc::UDP_SEGMENT).is_ok();
+
+        let (count, size) = if gro {
+            (GRO_BATCH_SIZE, GRO_BUFFER_SIZE)
+        } else {
+            (BATCH_SIZE, RECEIVE_MTU)
+        };
+
+        // The task stops once the socket is dropped, with the sender of its queue
+        let (send_tx, send_rx) = mpsc::channel(SEND_QUEUE_SIZE);
+        tokio::spawn(
+            BatchSender {
+                socket: Arc::clone(&socket),
+                gso,
+            }
+            .run(send_rx),
+        );
+
+        Ok(BatchUdpConn {
+            socket,
+            recv: tokio::sync::Mutex::new(RecvBatch {
+                bufs: vec![vec![0; size]; count],
+                received: VecDeque::new(),
+            }),
+            send_tx,
+        })
+    }
+}
+
+/// The task sending the datagrams queued by `BatchUdpConn::send_to`.
+struct BatchSender {
+    socket: Arc<UdpSocket>,
+    gso: bool,
+}
+
+impl BatchSender {
+    async fn run(mut self, mut send_rx: mpsc::Receiver<Queued>) {
+        let mut batch = Vec::with_capacity(SEND_QUEUE_SIZE);
+        let mut result_txs = Vec::with_capacity(SEND_QUEUE_SIZE);
+        while let Some(mut queued) = send_rx.recv().await {
+            // Whatever was queued meanwhile goes along
+            loop {
+                let (target, buf, result_tx) = queued;
+                batch.push((target, buf));
+                result_txs.push(result_tx);
+                queued = match send_rx.try_recv() {
+                    Ok(queued) => queued,
+                    Err(_) => break,
+                };
+            }
+
+            let results = self.send_batch(&batch).await;
+            for (result_tx, result) in result_txs.drain(..).zip(results) {
+                let _ = result_tx.send(result);
+            }
+            batch.clear();
+        }
+    }
+
+    /// Sends the datagrams of `batch` in order, returning the result of each. A datagram which
+    /// can't be sent doesn't stop the others.
+    async fn send_batch(&mut self, batch: &[(SocketAddr, Vec<u8>)]) -> Vec<io::Result<()>> {
+        let fd = self.socket.as_raw_fd();
+        let mut results = Vec::with_capacity(batch.len());
+        let mut start = 0;
+        // The datagrams before this are sent one by one, after a segmented one was invalid
+        let mut unsegmented_until = 0;
+        while start < batch.len() {
+            let gso = self.gso && start >= unsegmented_until;
+            let runs = send_runs(&batch[start..], gso);
+            let segmented = runs[0].len() > 1;
+            match self
+                .socket
+                .async_io(Interest::WRITABLE, || send_mmsg(fd, &batch[start..], &runs))
+                .await
+            {
+                Ok(sent) => {
+                    results.extend((0..sent).map(|_| Ok(())));
+                    start += sent;
+                }
+                Err(err) if segmented && err.raw_os_error() == Some(libc::EIO) => {
+                    log::debug!("disabling UDP GSO after send error: {}", err);
+                    self.gso = false;
+                }
+                Err(err) if segmented && err.raw_os_error() == Some(libc::EINVAL) => {
+                    log::trace!("sending segmented datagram one by one: {}", err);
+                    unsegmented_until = start + runs[0].len();
+                }
+                Err(err) => {
+                    log::trace!("failed to send to {}: {}", batch[start].0, err);
+                    results.extend(runs[0].clone().map(|_| Err(copy_error(&err))));
+                    start += runs[0].len();
+                }
+            }
+        }
+        results
+    }
+}
+
+#[async_trait]
+impl Conn for BatchUdpConn {
+    async fn connect(&self, addr: SocketAddr) -> anyhow::Result<()> {
+        Ok(self.socket.connect(addr).await?)
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        Ok(self.recv_from(buf).await?.0)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> anyhow::Result<(usize, SocketAddr)> {
+        let mut batch = self.recv.lock().await;
+        loop {
+            if let Some(r) = batch.received.pop_front() {
+                let n = r.len.min(buf.len());
+                buf[..n].copy_from_slice(&batch.bufs[r.buf][r.offset..r.offset + n]);
+                return Ok((n, r.addr));
+            }
+
+            let fd = self.socket.as_raw_fd();
+            let RecvBatch { bufs, received } = &mut *batch;
+            self.socket
+                .async_io(Interest::READABLE, || recv_mmsg(fd, bufs, received))
+                .await?;
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> anyhow::Result<usize> {
+        Ok(self.socket.send(buf).await?)
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> anyhow::Result<usize> {
+        let (result_tx, result_rx) = oneshot::channel();
+        self.send_tx
+            .send((target, buf.to_vec(), result_tx))
+            .await
+            .map_err(|_| Error::ErrClosed)?;
+        result_rx.await.map_err(|_| Error::ErrClosed)??;
+        Ok(buf.len())
+    }
+
+    async fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    async fn remote_addr(&self) -> Option<SocketAddr> {
+        self.socket.peer_addr().ok()
+    }
+
+    async fn close(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Splits `batch` into the datagrams sent with one `mmsghdr` each: runs of datagrams of the same
+/// size to the same address, of which only the last may be shorter, if `gso` is enabled and
+/// single datagrams otherwise.
+fn send_runs(batch: &[(SocketAddr, Vec<u8>)], gso: bool) -> Vec<Range<usize>> {
+    let mut runs = vec![];
+    let mut start = 0;
+    while start < batch.len() && runs.len() < BATCH_SIZE {
+        let (target, segment_size) = (batch[start].0, batch[start].1.len());
+        let mut end = start + 1;
+        let mut size = segment_size;
+        while gso
+            && segment_size > 0
+            && end < batch.len()
+            && end - start < MAX_GSO_SEGMENTS
+            && batch[end].0 == target
+            && batch[end].1.len() <= segment_size
+            && size + batch[end].1.len() <= MAX_GSO_SIZE
+        {
+            size += batch[end].1.len();
+            end += 1;
+            if batch[end - 1].1.len() < segment_size {
+                break;
+            }
+        }
+        runs.push(start..end);
+        start = end;
+    }
+    runs
+}
+
+/// Sends the `runs` of `batch` with one `sendmmsg`, returning the number of datagrams sent.
+fn send_mmsg(
+    fd: RawFd,
+    batch: &[(SocketAddr, Vec<u8>)],
+    runs: &[Range<usize>],
+) -> io::Result<usize> {
+    let mut iovecs: Vec<libc::iovec> = batch
+        .iter()
+        .map(|(_, buf)| libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect();
+    let mut addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)> = runs
+        .iter()
+        .map(|run| to_sockaddr(batch[run.start].0))
+        .collect();
+    let mut cmsgs = vec![[0u64; 4]; runs.len()];
+
+    let mut hdrs = Vec::with_capacity(runs.len());
+    for (i, run) in runs.iter().enumerate() {
+        // SAFETY: all-zero is a valid mmsghdr, the pointers set below outlive the sendmmsg call
+        let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+        hdr.msg_hdr.msg_name = &mut addrs[i].0 as *mut _ as *mut libc::c_void;
+        hdr.msg_hdr.msg_namelen = addrs[i].1;
+        hdr.msg_hdr.msg_iov = iovecs[run.clone()].as_mut_ptr();
+        hdr.msg_hdr.msg_iovlen = run.len() as _;
+        if run.len() > 1 {
+            let segment_size = batch[run.start].1.len() as u16;
+            hdr.msg_hdr.msg_control = cmsgs[i].as_mut_ptr() as *mut libc::c_void;
+            // SAFETY: the control buffer of 32 bytes holds a cmsghdr with a u16
+            unsafe {
+                hdr.msg_hdr.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as _) as _;
+                let cmsg = libc::CMSG_FIRSTHDR(&hdr.msg_hdr);
+                (*cmsg).cmsg_level = libc::SOL_UDP;
+                (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as _) as _;
+                ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size);
+            }
+        }
+        hdrs.push(hdr);
+    }
+
+    // SAFETY: the headers point into buffers which live until the call returns
+    let ret = unsafe { libc::sendmmsg(fd, hdrs.as_mut_ptr(), hdrs.len() as _, 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(runs[..ret as usize].iter().map(|run| run.len()).sum())
+}
+
+/// Receives a batch of datagrams into `bufs`, splitting the ones coalesced by GRO.
+fn recv_mmsg(fd: RawFd, bufs: &mut [Vec<u8>], received: &mut VecDeque<Received>) -> io::Result<()> {
+    let mut iovecs: Vec<libc::iovec> = bufs
+        .iter_mut()
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect();
+    // SAFETY: all-zero is a valid sockaddr_storage
+    let mut addrs: Vec<libc::sockaddr_storage> =
+        (0..bufs.len()).map(|_| unsafe { mem::zeroed() }).collect();
+    let mut cmsgs = vec![[0u64; 8]; bufs.len()];
+
+    let mut hdrs = Vec::with_capacity(bufs.len());
+    for i in 0..bufs.len() {
+        // SAFETY: all-zero is a valid mmsghdr, the pointers set below outlive the recvmmsg call
+        let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+        hdr.msg_hdr.msg_name = &mut addrs[i] as *mut _ as *mut libc::c_void;
+        hdr.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+        hdr.msg_hdr.msg_iov = &mut iovecs[i];
+        hdr.msg_hdr.msg_iovlen = 1;
+        hdr.msg_hdr.msg_control = cmsgs[i].as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_hdr.msg_controllen = mem::size_of_val(&cmsgs[i]) as _;
+        hdrs.push(hdr);
+    }
+
+    // SAFETY: the headers point into buffers which live until the call returns
+    let ret = unsafe { libc::recvmmsg(fd, hdrs.as_mut_ptr(), hdrs.len() as _, 0, ptr::null_mut()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    for (i, hdr) in hdrs.iter().enumerate().take(ret as usize) {
+        let addr = from_sockaddr(&addrs[i])?;
+        let len = hdr.msg_len as usize;
+        let segment_size = gro_segment_size(&hdr.msg_hdr)
+            .filter(|&size| size > 0)
+            .unwrap_or(len);
+
+        let mut offset = 0;
+        while offset < len {
+            let n = segment_size.min(len - offset);
+            received.push_back(Received {
+                buf: i,
+                offset,
+                len: n,
+                addr,
+            });
+            offset += n;
+        }
+    }
+    Ok(())
+}
+
+fn gro_segment_size(hdr: &libc::msghdr) -> Option<usize> {
+    // SAFETY: the control messages were written by the kernel into the buffer of `hdr`
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                let size = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                return Some(size as usize);
+            }
+            cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+        }
+    }
+    None
+}
+
+/// Returns an error like `err`, for each of the datagrams it applies to.
+fn copy_error(err: &io::Error) -> io::Error {
+    match err.raw_os_error() {
+        Some(code) => io::Error::from_raw_os_error(code),
+        None => io::Error::new(err.kind(), err.to_string()),
+    }
+}
+
+fn set_udp_option(fd: RawFd, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    // SAFETY: the option value is a c_int as expected by the UDP options used
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_UDP,
+            name,
+            &value as *const _ as *const libc::c_void,
+            mem::size_of_val(&value) as _,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn get_udp_option(fd: RawFd, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of_val(&value) as libc::socklen_t;
+    // SAFETY: the option value is a c_int as expected by the UDP options used
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_UDP,
+            name,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+fn to_sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: all-zero is a valid sockaddr_storage, which is large enough for both families
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            // SAFETY: sockaddr_storage is larger than and aligned for sockaddr_in
+            unsafe { ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, sin) };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            // SAFETY: sockaddr_storage is larger than and aligned for sockaddr_in6
+            unsafe { ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, sin6) };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+fn from_sockaddr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the kernel wrote a sockaddr_in for AF_INET
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()),
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the kernel wrote a sockaddr_in6 for AF_INET6
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        family => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected address family {}", family),
+        )),
+    }
+}
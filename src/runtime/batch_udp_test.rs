@@ -0,0 +1,129 @@
+use super::batch_udp::*;
+use super::*;
+
+async fn pair() -> Result<(Arc<BatchUdpConn>, Arc<BatchUdpConn>, SocketAddr)> {
+    let a = Arc::new(BatchUdpConn::bind("127.0.0.1:0".parse()?).await?);
+    let b = Arc::new(BatchUdpConn::bind("127.0.0.1:0".parse()?).await?);
+    let b_addr = b.local_addr().await?;
+    Ok((a, b, b_addr))
+}
+
+fn datagram(i: usize, size: usize) -> Vec<u8> {
+    (0..size).map(|j| (i + j) as u8).collect()
+}
+
+#[tokio::test]
+async fn test_batch_udp_conn_send_recv() -> Result<()> {
+    let (a, b, b_addr) = pair().await?;
+    let a_addr = a.local_addr().await?;
+
+    // Sizes vary, so that only some of the datagrams can be segmented
+    let sizes = [100, 100, 100, 40, 1200, 1200, 7, 1200];
+    for (i, size) in sizes.iter().enumerate() {
+        assert_eq!(a.send_to(&datagram(i, *size), b_addr).await?, *size);
+    }
+
+    let mut buf = vec![0u8; 2000];
+    for (i, size) in sizes.iter().enumerate() {
+        let (n, addr) = b.recv_from(&mut buf).await?;
+        assert_eq!(addr, a_addr);
+        assert_eq!(&buf[..n], &datagram(i, *size)[..], "datagram {}", i);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batch_udp_conn_concurrent_sends() -> Result<()> {
+    const SENDERS: usize = 4;
+    const DATAGRAMS: usize = 10;
+
+    let (a, b, b_addr) = pair().await?;
+
+    // Concurrent sends are written in batches, which the receiver has to split up again
+    let mut tasks = vec![];
+    for sender in 0..SENDERS {
+        let a = Arc::clone(&a);
+        tasks.push(tokio::spawn(async move {
+            for i in 0..DATAGRAMS {
+                let mut data = datagram(i, 1000);
+                data[0] = sender as u8;
+                a.send_to(&data, b_addr).await?;
+            }
+            Result::<()>::Ok(())
+        }));
+    }
+
+    let mut received = vec![0; SENDERS];
+    let mut buf = vec![0u8; 2000];
+    for _ in 0..SENDERS * DATAGRAMS {
+        let (n, _) = b.recv_from(&mut buf).await?;
+        assert_eq!(n, 1000);
+        let sender = buf[0] as usize;
+        let i = received[sender];
+        assert_eq!(
+            &buf[1..n],
+            &datagram(i, 1000)[1..],
+            "datagrams stay in order"
+        );
+        received[sender] += 1;
+    }
+    assert_eq!(received, vec![DATAGRAMS; SENDERS]);
+
+    for task in tasks {
+        task.await.unwrap()?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batch_udp_conn_short_buffer() -> Result<()> {
+    let (a, b, b_addr) = pair().await?;
+    a.send_to(&datagram(0, 100), b_addr).await?;
+    a.send_to(&datagram(1, 10), b_addr).await?;
+
+    // Like with recv_from on a socket, the rest of a datagram which doesn't fit is discarded
+    let mut buf = vec![0u8; 50];
+    let (n, _) = b.recv_from(&mut buf).await?;
+    assert_eq!(&buf[..n], &datagram(0, 100)[..50]);
+    let (n, _) = b.recv_from(&mut buf).await?;
+    assert_eq!(&buf[..n], &datagram(1, 10)[..]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batch_udp_conn_send_errors() -> Result<()> {
+    let (a, b, b_addr) = pair().await?;
+
+    // Sent in the same batch, only the datagram which can't be sent fails
+    let invalid_addr: SocketAddr = "127.0.0.1:0".parse()?;
+    let (data0, data1) = (datagram(0, 100), datagram(1, 100));
+    let (ok, err) = tokio::join!(a.send_to(&data0, b_addr), a.send_to(&data1, invalid_addr));
+    assert_eq!(ok?, 100);
+    assert!(err.is_err(), "sending to port 0 fails");
+
+    let mut buf = vec![0u8; 2000];
+    let (n, _) = b.recv_from(&mut buf).await?;
+    assert_eq!(&buf[..n], &datagram(0, 100)[..]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batch_udp_conn_cancelled_send() -> Result<()> {
+    let (a, b, b_addr) = pair().await?;
+
+    // A datagram is sent even if its sender stops waiting, and doesn't hold up the next ones
+    let _ = tokio::time::timeout(Duration::ZERO, a.send_to(&datagram(0, 100), b_addr)).await;
+    a.send_to(&datagram(1, 100), b_addr).await?;
+
+    let mut buf = vec![0u8; 2000];
+    for i in 0..2 {
+        let (n, _) = b.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..n], &datagram(i, 100)[..]);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,145 @@
+#[cfg(test)]
+pub(crate) mod runtime_test;
+
+#[cfg(all(target_os = "linux", feature = "batch-io"))]
+mod batch_udp;
+#[cfg(all(test, target_os = "linux", feature = "batch-io"))]
+mod batch_udp_test;
+
+use crate::error::*;
+
+use async_trait::async_trait;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use util::{vnet::net::*, Conn};
+
+/// A future which can be sent to another thread, as produced and consumed by a `Runtime`.
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send + 'static>>;
+
+/// The executor facilities used by the agent: spawning its background tasks, timers and UDP
+/// sockets. `TokioRuntime` is used unless another runtime is set in `AgentConfig::runtime`.
+///
+/// Sockets of a virtual network (`AgentConfig::net`) are always created by the virtual network.
+/// The mDNS and TURN clients come from other crates which require a tokio runtime, so mDNS has to
+/// be disabled and no TURN servers can be used on other runtimes.
+#[async_trait]
+pub trait Runtime: Send + Sync {
+    /// Runs `future` in the background.
+    fn spawn(&self, future: BoxFuture<()>);
+
+    /// Returns a future which completes at `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<()>;
+
+    /// Binds a UDP socket to `addr`.
+    async fn bind_udp(&self, addr: SocketAddr) -> Result<Arc<dyn Conn + Send + Sync>>;
+
+    /// Resolves `host`, given as `host:port`, to an address of the requested family.
+    async fn resolve_addr(&self, use_ipv4: bool, host: &str) -> Result<SocketAddr>;
+
+    /// Resolves `host`, given as `host:port`, to all its addresses of the requested family, to be
+    /// tried in turn. Defaults to the address returned by `resolve_addr`.
+    async fn resolve_addrs(&self, use_ipv4: bool, host: &str) -> Result<Vec<SocketAddr>> {
+        Ok(vec![self.resolve_addr(use_ipv4, host).await?])
+    }
+}
+
+/// The default runtime, which runs on the tokio runtime the agent is created in.
+///
+/// With the `batch-io` feature on Linux, its UDP sockets receive and send batches of datagrams
+/// with a single system call, using UDP segmentation offload where available.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioRuntime;
+
+#[async_trait]
+impl Runtime for TokioRuntime {
+    fn spawn(&self, future: BoxFuture<()>) {
+        tokio::spawn(future);
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<()> {
+        Box::pin(tokio::time::sleep_until(deadline.into()))
+    }
+
+    #[cfg(all(target_os = "linux", feature = "batch-io"))]
+    async fn bind_udp(&self, addr: SocketAddr) -> Result<Arc<dyn Conn + Send + Sync>> {
+        Ok(Arc::new(batch_udp::BatchUdpConn::bind(addr).await?))
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "batch-io")))]
+    async fn bind_udp(&self, addr: SocketAddr) -> Result<Arc<dyn Conn + Send + Sync>> {
+        Ok(Arc::new(tokio::net::UdpSocket::bind(addr).await?))
+    }
+
+    async fn resolve_addr(&self, use_ipv4: bool, host: &str) -> Result<SocketAddr> {
+        Ok(util::conn::lookup_host(use_ipv4, host).await?)
+    }
+
+    async fn resolve_addrs(&self, use_ipv4: bool, host: &str) -> Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host(host)
+            .await?
+            .filter(|addr| addr.is_ipv4() == use_ipv4)
+            .collect();
+        if addrs.is_empty() {
+            return Err(std::io::Error::other(format!(
+                "No available {} IP address found!",
+                if use_ipv4 { "ipv4" } else { "ipv6" },
+            ))
+            .into());
+        }
+        Ok(addrs)
+    }
+}
+
+/// Spawns a future with any output on `runtime`, discarding the output.
+pub(crate) fn spawn<F>(runtime: &Arc<dyn Runtime>, future: F)
+where
+    F: Future + Send + 'static,
+{
+    runtime.spawn(Box::pin(async move {
+        let _ = future.await;
+    }));
+}
+
+/// Waits for `future` for at most `duration`, returning `Error::ErrTimeout` once it elapsed.
+pub(crate) async fn timeout<F: Future>(
+    runtime: &Arc<dyn Runtime>,
+    duration: Duration,
+    future: F,
+) -> Result<F::Output> {
+    let sleep = runtime.sleep_until(Instant::now() + duration);
+    tokio::select! {
+        output = future => Ok(output),
+        _ = sleep => Err(Error::ErrTimeout),
+    }
+}
+
+/// Binds a UDP socket, using the virtual network if `net` is one and `runtime` otherwise.
+pub(crate) async fn bind(
+    net: &Arc<Net>,
+    runtime: &Arc<dyn Runtime>,
+    addr: SocketAddr,
+) -> Result<Arc<dyn Conn + Send + Sync>> {
+    if net.is_virtual() {
+        Ok(net.bind(addr).await?)
+    } else {
+        runtime.bind_udp(addr).await
+    }
+}
+
+/// Resolves `host` to all its addresses, using the virtual network if `net` is one and `runtime`
+/// otherwise.
+pub(crate) async fn resolve_addrs(
+    net: &Arc<Net>,
+    runtime: &Arc<dyn Runtime>,
+    use_ipv4: bool,
+    host: &str,
+) -> Result<Vec<SocketAddr>> {
+    if net.is_virtual() {
+        Ok(vec![net.resolve_addr(use_ipv4, host).await?])
+    } else {
+        runtime.resolve_addrs(use_ipv4, host).await
+    }
+}
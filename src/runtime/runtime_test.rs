@@ -0,0 +1,203 @@
+use super::*;
+use crate::agent::agent_config::AgentConfig;
+use crate::agent::agent_vnet_test::gather_and_exchange_candidates;
+use crate::agent::Agent;
+use crate::mdns::MulticastDnsMode;
+use crate::network_type::NetworkType;
+
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::Mutex;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+use tokio::sync::mpsc;
+
+/// A runtime without any tokio reactor or timer: every task runs on its own thread, timers are
+/// threads sleeping until the deadline and sockets are polled non-blocking std sockets.
+pub(crate) struct ThreadRuntime;
+
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs `future` to completion on the current thread.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+struct Sleep {
+    deadline: Instant,
+    waker: Option<Arc<Mutex<Waker>>>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        if let Some(waker) = &self.waker {
+            *waker.lock().unwrap() = cx.waker().clone();
+        } else {
+            let waker = Arc::new(Mutex::new(cx.waker().clone()));
+            let (deadline, timer_waker) = (self.deadline, Arc::clone(&waker));
+            thread::spawn(move || {
+                thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                timer_waker.lock().unwrap().wake_by_ref();
+            });
+            self.waker = Some(waker);
+        }
+        Poll::Pending
+    }
+}
+
+fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: Instant::now() + duration,
+        waker: None,
+    }
+}
+
+struct ThreadUdpConn(UdpSocket);
+
+#[async_trait]
+impl Conn for ThreadUdpConn {
+    async fn connect(&self, addr: SocketAddr) -> anyhow::Result<()> {
+        Ok(self.0.connect(addr)?)
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        Ok(self.recv_from(buf).await?.0)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> anyhow::Result<(usize, SocketAddr)> {
+        loop {
+            match self.0.recv_from(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    sleep(Duration::from_millis(1)).await
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> anyhow::Result<usize> {
+        Ok(self.0.send(buf)?)
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> anyhow::Result<usize> {
+        Ok(self.0.send_to(buf, target)?)
+    }
+
+    async fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.0.local_addr()?)
+    }
+
+    async fn remote_addr(&self) -> Option<SocketAddr> {
+        self.0.peer_addr().ok()
+    }
+
+    async fn close(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Runtime for ThreadRuntime {
+    fn spawn(&self, future: BoxFuture<()>) {
+        thread::spawn(move || block_on(future));
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<()> {
+        Box::pin(Sleep {
+            deadline,
+            waker: None,
+        })
+    }
+
+    async fn bind_udp(&self, addr: SocketAddr) -> Result<Arc<dyn Conn + Send + Sync>> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Arc::new(ThreadUdpConn(socket)))
+    }
+
+    async fn resolve_addr(&self, use_ipv4: bool, host: &str) -> Result<SocketAddr> {
+        host.to_socket_addrs()?
+            .find(|addr| addr.is_ipv4() == use_ipv4)
+            .ok_or(Error::ErrAddressParseFailed)
+    }
+}
+
+#[test]
+fn test_thread_runtime_timeout() {
+    let runtime: Arc<dyn Runtime> = Arc::new(ThreadRuntime);
+    let result = block_on(timeout(
+        &runtime,
+        Duration::from_millis(10),
+        sleep(Duration::from_secs(10)),
+    ));
+    assert_eq!(result, Err(Error::ErrTimeout));
+}
+
+// Connects two agents without any tokio runtime, which fails if the agent spawns a task, starts a
+// timer or binds a socket with tokio.
+#[test]
+fn test_connectivity_without_tokio() -> Result<()> {
+    let runtime: Arc<dyn Runtime> = Arc::new(ThreadRuntime);
+    assert!(tokio::runtime::Handle::try_current().is_err());
+
+    block_on(async {
+        let new_agent = || {
+            Agent::new(AgentConfig {
+                network_types: vec![NetworkType::Udp4],
+                multicast_dns_mode: MulticastDnsMode::Disabled,
+                include_loopback: true,
+                interface_filter: Arc::new(Some(Box::new(|name: &str| name.starts_with("lo")))),
+                runtime: Some(Arc::clone(&runtime)),
+                ..Default::default()
+            })
+        };
+        let a_agent = Arc::new(new_agent().await?);
+        let b_agent = Arc::new(new_agent().await?);
+
+        gather_and_exchange_candidates(&a_agent, &b_agent).await?;
+        let (a_ufrag, a_pwd) = a_agent.get_local_user_credentials().await;
+        let (b_ufrag, b_pwd) = b_agent.get_local_user_credentials().await;
+
+        let (accepted_tx, mut accepted_rx) = mpsc::channel(1);
+        let (_a_cancel_tx, a_cancel_rx) = mpsc::channel(1);
+        let agent = Arc::clone(&a_agent);
+        spawn(&runtime, async move {
+            let a_conn = agent.accept(a_cancel_rx, b_ufrag, b_pwd).await?;
+            let _ = accepted_tx.send(a_conn).await;
+            Result::<()>::Ok(())
+        });
+
+        let (_b_cancel_tx, b_cancel_rx) = mpsc::channel(1);
+        let b_conn = b_agent.dial(b_cancel_rx, a_ufrag, a_pwd).await?;
+        let a_conn = accepted_rx.recv().await.ok_or(Error::ErrClosed)?;
+
+        b_conn.send(b"ping").await?;
+        let mut buf = vec![0u8; 16];
+        let n = timeout(&runtime, Duration::from_secs(5), a_conn.recv(&mut buf)).await??;
+        assert_eq!(&buf[..n], b"ping");
+
+        a_agent.close().await?;
+        b_agent.close().await?;
+        Ok(())
+    })
+}
@@ -0,0 +1,185 @@
+#[cfg(test)]
+mod sdp_frag_test;
+#[cfg(test)]
+mod sdp_test;
+
+pub mod sdp_frag;
+
+use crate::agent::Agent;
+use crate::candidate::candidate_base::unmarshal_candidate;
+use crate::candidate::*;
+use crate::error::*;
+use crate::state::GatheringState;
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+const ATTR_ICE_UFRAG: &str = "ice-ufrag";
+const ATTR_ICE_PWD: &str = "ice-pwd";
+const ATTR_ICE_OPTIONS: &str = "ice-options";
+const ATTR_ICE_LITE: &str = "ice-lite";
+const ATTR_END_OF_CANDIDATES: &str = "end-of-candidates";
+const ATTR_CANDIDATE: &str = "candidate";
+
+/// The ICE attributes of an SDP description as described in RFC 8839 section 5. It collects
+/// the session-level `ice-lite` attribute as well as the media-level credentials, options and
+/// candidates of a single data stream.
+#[derive(Default, Clone)]
+pub struct IceSdpAttributes {
+    /// The value of `a=ice-ufrag`.
+    pub ufrag: String,
+    /// The value of `a=ice-pwd`.
+    pub pwd: String,
+    /// The tokens of `a=ice-options`, e.g. `trickle`.
+    pub options: Vec<String>,
+    /// Whether `a=ice-lite` is present.
+    pub lite: bool,
+    /// Whether `a=end-of-candidates` is present.
+    pub end_of_candidates: bool,
+    /// The candidates of all `a=candidate` attributes, in order.
+    pub candidates: Vec<Arc<dyn Candidate + Send + Sync>>,
+}
+
+/// `ice-char = ALPHA / DIGIT / "+" / "/"`
+fn is_ice_chars(s: &str) -> bool {
+    s.chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/')
+}
+
+fn parse_error(line: usize, reason: String) -> Error {
+    Error::ErrParseSdp { line, reason }
+}
+
+impl IceSdpAttributes {
+    /// Parses the ICE attributes from an SDP description or a fragment of it. Lines which are not
+    /// ICE attributes are ignored, so a complete session description can be passed.
+    pub async fn unmarshal(sdp: &str) -> Result<Self> {
+        let lines: Vec<(usize, &str)> = sdp.lines().enumerate().map(|(n, l)| (n + 1, l)).collect();
+        Self::unmarshal_lines(&lines).await
+    }
+
+    /// Parses the ICE attributes from `lines`, which are paired with their line number.
+    pub(crate) async fn unmarshal_lines(lines: &[(usize, &str)]) -> Result<Self> {
+        let mut attrs = Self::default();
+
+        for &(line_number, line) in lines {
+            let line = line.trim_end_matches('\r');
+            let attr = match line.strip_prefix("a=") {
+                Some(attr) => attr,
+                None => continue,
+            };
+            let (name, value) = match attr.find(':') {
+                Some(pos) => (&attr[..pos], Some(&attr[pos + 1..])),
+                None => (attr, None),
+            };
+
+            match (name, value) {
+                (ATTR_ICE_UFRAG, Some(ufrag)) => {
+                    // ice-ufrag-att = "ice-ufrag:" ufrag; ufrag = 4*256ice-char
+                    if ufrag.len() < 4 || ufrag.len() > 256 || !is_ice_chars(ufrag) {
+                        return Err(parse_error(
+                            line_number,
+                            format!("invalid {} {:?}", ATTR_ICE_UFRAG, ufrag),
+                        ));
+                    }
+                    attrs.ufrag = ufrag.to_owned();
+                }
+                (ATTR_ICE_PWD, Some(pwd)) => {
+                    // ice-pwd-att = "ice-pwd:" password; password = 22*256ice-char
+                    if pwd.len() < 22 || pwd.len() > 256 || !is_ice_chars(pwd) {
+                        return Err(parse_error(
+                            line_number,
+                            format!("invalid {} {:?}", ATTR_ICE_PWD, pwd),
+                        ));
+                    }
+                    attrs.pwd = pwd.to_owned();
+                }
+                (ATTR_ICE_OPTIONS, Some(options)) => {
+                    attrs
+                        .options
+                        .extend(options.split_whitespace().map(str::to_owned));
+                }
+                (ATTR_ICE_LITE, None) => attrs.lite = true,
+                (ATTR_END_OF_CANDIDATES, None) => attrs.end_of_candidates = true,
+                (ATTR_CANDIDATE, Some(_)) => match unmarshal_candidate(attr.to_owned()).await {
+                    Ok(c) => attrs.candidates.push(Arc::new(c)),
+                    Err(err) => {
+                        return Err(parse_error(line_number, err.to_string()));
+                    }
+                },
+                (ATTR_ICE_UFRAG, None)
+                | (ATTR_ICE_PWD, None)
+                | (ATTR_ICE_OPTIONS, None)
+                | (ATTR_CANDIDATE, None) => {
+                    return Err(parse_error(
+                        line_number,
+                        format!("missing value of {}", name),
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(attrs)
+    }
+
+    /// Returns the attributes as SDP lines terminated by CRLF. `a=ice-lite` is a session-level
+    /// attribute, all others belong to the media description of the data stream.
+    pub fn marshal(&self) -> String {
+        let mut sdp = String::new();
+
+        if self.lite {
+            sdp += &format!("a={}\r\n", ATTR_ICE_LITE);
+        }
+        if !self.ufrag.is_empty() {
+            sdp += &format!("a={}:{}\r\n", ATTR_ICE_UFRAG, self.ufrag);
+        }
+        if !self.pwd.is_empty() {
+            sdp += &format!("a={}:{}\r\n", ATTR_ICE_PWD, self.pwd);
+        }
+        if !self.options.is_empty() {
+            sdp += &format!("a={}:{}\r\n", ATTR_ICE_OPTIONS, self.options.join(" "));
+        }
+        for c in &self.candidates {
+            sdp += &format!("a={}:{}\r\n", ATTR_CANDIDATE, c.marshal());
+        }
+        if self.end_of_candidates {
+            sdp += &format!("a={}\r\n", ATTR_END_OF_CANDIDATES);
+        }
+
+        sdp
+    }
+
+    /// Collects the local credentials and candidates of `agent`. `end_of_candidates` is set once
+    /// gathering is complete.
+    pub async fn from_agent(agent: &Agent) -> Result<Self> {
+        let (ufrag, pwd) = agent.get_local_user_credentials().await;
+        let candidates = agent.get_local_candidates().await?;
+        let lite = {
+            let ai = agent.agent_internal.lock().await;
+            ai.core.lite
+        };
+        let end_of_candidates = GatheringState::from(agent.gathering_state.load(Ordering::SeqCst))
+            == GatheringState::Complete;
+
+        Ok(Self {
+            ufrag,
+            pwd,
+            options: vec![],
+            lite,
+            end_of_candidates,
+            candidates,
+        })
+    }
+
+    /// Applies the remote credentials and candidates to `agent`.
+    pub async fn apply_to_agent(&self, agent: &Agent) -> Result<()> {
+        agent
+            .set_remote_credentials(self.ufrag.clone(), self.pwd.clone())
+            .await?;
+        for c in &self.candidates {
+            agent.add_remote_candidate(c).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use super::*;
+
+/// The content type of trickle ICE fragments as defined in RFC 8840 section 9.
+pub const SDP_FRAG_CONTENT_TYPE: &str = "application/trickle-ice-sdpfrag";
+
+/// A media description of a trickle ICE fragment.
+#[derive(Default, Clone)]
+pub struct SdpFragMedia {
+    /// The value of the `m=` line, e.g. `audio 9 RTP/AVP 0`.
+    pub media: String,
+    /// The value of `a=mid`, identifying the data stream the candidates belong to.
+    pub mid: String,
+    /// The ICE attributes of the data stream.
+    pub ice: IceSdpAttributes,
+}
+
+/// A trickle ICE fragment (`application/trickle-ice-sdpfrag`) as described in RFC 8840
+/// section 9, used to convey candidates after the initial offer/answer exchange.
+#[derive(Default, Clone)]
+pub struct SdpFrag {
+    /// The session-level ICE attributes, e.g. `a=ice-options:trickle`. Credentials given here
+    /// apply to all media descriptions without their own.
+    pub session: IceSdpAttributes,
+    /// The media descriptions of the fragment.
+    pub media: Vec<SdpFragMedia>,
+}
+
+impl SdpFrag {
+    /// Parses an `application/trickle-ice-sdpfrag` body.
+    pub async fn unmarshal(body: &str) -> Result<Self> {
+        let lines: Vec<(usize, &str)> = body
+            .lines()
+            .enumerate()
+            .map(|(n, l)| (n + 1, l.trim_end_matches('\r')))
+            .collect();
+
+        // Split the body into the session section and one section per m= line
+        let mut sections: Vec<&[(usize, &str)]> = vec![];
+        let mut start = 0;
+        for (i, (_, line)) in lines.iter().enumerate() {
+            if line.starts_with("m=") {
+                sections.push(&lines[start..i]);
+                start = i;
+            }
+        }
+        sections.push(&lines[start..]);
+
+        let mut frag = Self {
+            session: IceSdpAttributes::unmarshal_lines(sections[0]).await?,
+            media: vec![],
+        };
+
+        for section in &sections[1..] {
+            let mid = section
+                .iter()
+                .find_map(|(_, line)| line.strip_prefix("a=mid:"))
+                .unwrap_or_default();
+            frag.media.push(SdpFragMedia {
+                media: section[0].1["m=".len()..].to_owned(),
+                mid: mid.to_owned(),
+                ice: IceSdpAttributes::unmarshal_lines(&section[1..]).await?,
+            });
+        }
+
+        Ok(frag)
+    }
+
+    /// Returns the fragment as `application/trickle-ice-sdpfrag` body.
+    pub fn marshal(&self) -> String {
+        let mut body = self.session.marshal();
+        for media in &self.media {
+            body += &format!("m={}\r\n", media.media);
+            if !media.mid.is_empty() {
+                body += &format!("a=mid:{}\r\n", media.mid);
+            }
+            body += &media.ice.marshal();
+        }
+        body
+    }
+
+    /// Creates a fragment carrying the local credentials and candidates of `agent` for the data
+    /// stream identified by `media` and `mid`. `end-of-candidates` is included once gathering is
+    /// complete.
+    pub async fn from_agent(agent: &Agent, media: &str, mid: &str) -> Result<Self> {
+        let mut ice = IceSdpAttributes::from_agent(agent).await?;
+        // ice-lite is a session-level attribute which is not part of fragments
+        ice.lite = false;
+
+        Ok(Self {
+            session: IceSdpAttributes {
+                options: vec!["trickle".to_owned()],
+                ..Default::default()
+            },
+            media: vec![SdpFragMedia {
+                media: media.to_owned(),
+                mid: mid.to_owned(),
+                ice,
+            }],
+        })
+    }
+
+    /// Returns the ufrag the candidates of `media` belong to, which is either the ufrag of the
+    /// media description or the session-level one.
+    pub fn ufrag<'a>(&'a self, media: &'a SdpFragMedia) -> &'a str {
+        if media.ice.ufrag.is_empty() {
+            &self.session.ufrag
+        } else {
+            &media.ice.ufrag
+        }
+    }
+
+    /// Adds the candidates of all media descriptions to `agent` as remote candidates. The
+    /// fragment is rejected if its ufrag does not match the remote ufrag of the agent, which
+    /// happens for fragments of an ICE generation that was replaced by a restart.
+    pub async fn apply_to_agent(&self, agent: &Agent) -> Result<()> {
+        let (remote_ufrag, _) = agent.get_remote_user_credentials().await;
+        for media in &self.media {
+            let ufrag = self.ufrag(media);
+            if ufrag != remote_ufrag {
+                return Err(Error::new(format!(
+                    "{:?} expected({}) actual({})",
+                    Error::ErrSdpFragUfragMismatch,
+                    remote_ufrag,
+                    ufrag,
+                )));
+            }
+        }
+
+        for media in &self.media {
+            for c in &media.ice.candidates {
+                agent.add_remote_candidate(c).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+use super::sdp_frag::*;
+use super::*;
+use crate::agent::agent_config::AgentConfig;
+
+const SDP_FRAG: &str = "a=ice-options:trickle\r
+m=audio 9 RTP/AVP 0\r
+a=mid:audio\r
+a=ice-ufrag:EsAw\r
+a=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1\r
+a=candidate:1052353102 1 udp 2130706431 192.168.0.196 50000 typ host\r
+m=video 9 RTP/AVP 31\r
+a=mid:video\r
+a=ice-ufrag:EsAw\r
+a=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1\r
+a=candidate:1052353102 1 udp 2130706431 192.168.0.196 50002 typ host\r
+a=end-of-candidates\r
+";
+
+#[tokio::test]
+async fn test_sdp_frag_unmarshal() -> Result<()> {
+    let frag = SdpFrag::unmarshal(SDP_FRAG).await?;
+
+    assert_eq!(frag.session.options, vec!["trickle".to_owned()]);
+    assert_eq!(frag.media.len(), 2);
+
+    assert_eq!(frag.media[0].media, "audio 9 RTP/AVP 0");
+    assert_eq!(frag.media[0].mid, "audio");
+    assert_eq!(frag.ufrag(&frag.media[0]), "EsAw");
+    assert_eq!(frag.media[0].ice.candidates.len(), 1);
+    assert!(!frag.media[0].ice.end_of_candidates);
+
+    assert_eq!(frag.media[1].mid, "video");
+    assert_eq!(frag.media[1].ice.candidates[0].port(), 50002);
+    assert!(frag.media[1].ice.end_of_candidates);
+
+    assert_eq!(frag.marshal(), SDP_FRAG);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sdp_frag_session_ufrag() -> Result<()> {
+    let frag = SdpFrag::unmarshal(
+        "a=ice-ufrag:EsAw\r\na=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1\r\nm=audio 9 RTP/AVP 0\r\na=mid:0\r\n",
+    )
+    .await?;
+    assert_eq!(frag.ufrag(&frag.media[0]), "EsAw");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sdp_frag_apply_to_agent() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    a.set_remote_credentials("EsAw".to_owned(), "P2uYro0UCOQ4zxjKXaWCBui1".to_owned())
+        .await?;
+
+    let frag = SdpFrag::unmarshal(SDP_FRAG).await?;
+    frag.apply_to_agent(&a).await?;
+
+    // A fragment of a previous ICE generation is rejected
+    a.set_remote_credentials("Xy9Z".to_owned(), "P2uYro0UCOQ4zxjKXaWCBui2".to_owned())
+        .await?;
+    assert!(frag.apply_to_agent(&a).await.is_err());
+
+    let local = SdpFrag::from_agent(&a, "audio 9 RTP/AVP 0", "audio").await?;
+    let (ufrag, _) = a.get_local_user_credentials().await;
+    assert_eq!(local.ufrag(&local.media[0]), ufrag);
+    assert_eq!(local.session.options, vec!["trickle".to_owned()]);
+
+    a.close().await?;
+
+    Ok(())
+}
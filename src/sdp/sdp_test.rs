@@ -0,0 +1,102 @@
+use super::*;
+use crate::agent::agent_config::AgentConfig;
+
+const SDP: &str = "v=0\r
+o=- 4215775240449105457 2 IN IP4 127.0.0.1\r
+s=-\r
+t=0 0\r
+a=ice-lite\r
+m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r
+c=IN IP4 0.0.0.0\r
+a=ice-ufrag:EsAw\r
+a=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1\r
+a=ice-options:trickle ice2\r
+a=candidate:1052353102 1 udp 2130706431 192.168.0.196 50000 typ host generation 0\r
+a=candidate:647372371 1 udp 1694498815 191.228.238.68 53991 typ srflx raddr 192.168.0.196 rport 50000\r
+a=end-of-candidates\r
+a=mid:0\r
+";
+
+#[tokio::test]
+async fn test_ice_sdp_attributes_unmarshal() -> Result<()> {
+    let attrs = IceSdpAttributes::unmarshal(SDP).await?;
+
+    assert_eq!(attrs.ufrag, "EsAw");
+    assert_eq!(attrs.pwd, "P2uYro0UCOQ4zxjKXaWCBui1");
+    assert_eq!(attrs.options, vec!["trickle".to_owned(), "ice2".to_owned()]);
+    assert!(attrs.lite);
+    assert!(attrs.end_of_candidates);
+    assert_eq!(attrs.candidates.len(), 2);
+    assert_eq!(
+        attrs.candidates[1].candidate_type(),
+        CandidateType::ServerReflexive
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ice_sdp_attributes_marshal() -> Result<()> {
+    let attrs = IceSdpAttributes::unmarshal(SDP).await?;
+
+    let expected = "a=ice-lite\r
+a=ice-ufrag:EsAw\r
+a=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1\r
+a=ice-options:trickle ice2\r
+a=candidate:1052353102 1 udp 2130706431 192.168.0.196 50000 typ host generation 0\r
+a=candidate:647372371 1 udp 1694498815 191.228.238.68 53991 typ srflx raddr 192.168.0.196 rport 50000\r
+a=end-of-candidates\r
+";
+    assert_eq!(attrs.marshal(), expected);
+
+    let attrs = IceSdpAttributes::unmarshal(&attrs.marshal()).await?;
+    assert_eq!(attrs.marshal(), expected);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ice_sdp_attributes_unmarshal_error() -> Result<()> {
+    let tests = vec![
+        ("a=ice-ufrag:abc\r\n", 1),
+        ("a=ice-ufrag:EsAw\r\na=ice-pwd:short\r\n", 2),
+        (
+            "a=ice-ufrag:EsAw\r\na=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1\r\na=candidate:1 1 udp\r\n",
+            3,
+        ),
+        ("a=ice-ufrag\r\n", 1),
+    ];
+
+    for (sdp, line) in tests {
+        match IceSdpAttributes::unmarshal(sdp).await {
+            Ok(_) => panic!("expected error for {:?}", sdp),
+            Err(Error::ErrParseSdp { line: l, reason }) => assert_eq!(l, line, "{}", reason),
+            Err(err) => panic!("unexpected error for {:?}: {}", sdp, err),
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ice_sdp_attributes_agent() -> Result<()> {
+    let a = Agent::new(AgentConfig::default()).await?;
+
+    let attrs = IceSdpAttributes::from_agent(&a).await?;
+    let (ufrag, pwd) = a.get_local_user_credentials().await;
+    assert_eq!(attrs.ufrag, ufrag);
+    assert_eq!(attrs.pwd, pwd);
+    assert!(!attrs.lite);
+    assert!(!attrs.end_of_candidates);
+
+    let remote = IceSdpAttributes::unmarshal(SDP).await?;
+    remote.apply_to_agent(&a).await?;
+    assert_eq!(
+        a.get_remote_user_credentials().await,
+        ("EsAw".to_owned(), "P2uYro0UCOQ4zxjKXaWCBui1".to_owned())
+    );
+
+    a.close().await?;
+
+    Ok(())
+}
@@ -5,6 +5,8 @@ use std::fmt;
 
 /// An enum showing the state of a ICE Connection List of supported States.
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum ConnectionState {
     Unspecified,
 
@@ -54,6 +56,8 @@ impl fmt::Display for ConnectionState {
 
 /// Describes the state of the candidate gathering process.
 #[derive(PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum GatheringState {
     Unspecified,
 
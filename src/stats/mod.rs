@@ -1,12 +1,20 @@
+#[cfg(feature = "serde")]
+mod serde_instant;
+
 use crate::candidate::*;
 use crate::network_type::*;
 
 use tokio::time::Instant;
 
-// CandidatePairStats contains ICE candidate pair statistics
+// CandidatePairStats contains ICE candidate pair statistics. With the serde feature, fields are
+// camelCase as in the W3C RTCIceCandidatePairStats and timestamps are milliseconds since the
+// UNIX epoch.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
 pub struct CandidatePairStats {
     // timestamp is the timestamp associated with this object.
+    #[cfg_attr(feature = "serde", serde(with = "serde_instant"))]
     pub timestamp: Instant,
 
     // local_candidate_id is the id of the local candidate
@@ -39,24 +47,29 @@ pub struct CandidatePairStats {
 
     // last_packet_sent_timestamp represents the timestamp at which the last packet was
     // sent on this particular candidate pair, excluding STUN packets.
+    #[cfg_attr(feature = "serde", serde(with = "serde_instant"))]
     pub last_packet_sent_timestamp: Instant,
 
     // last_packet_received_timestamp represents the timestamp at which the last packet
     // was received on this particular candidate pair, excluding STUN packets.
+    #[cfg_attr(feature = "serde", serde(with = "serde_instant"))]
     pub last_packet_received_timestamp: Instant,
 
     // first_request_timestamp represents the timestamp at which the first STUN request
     // was sent on this particular candidate pair.
+    #[cfg_attr(feature = "serde", serde(with = "serde_instant"))]
     pub first_request_timestamp: Instant,
 
     // last_request_timestamp represents the timestamp at which the last STUN request
     // was sent on this particular candidate pair. The average interval between two
     // consecutive connectivity checks sent can be calculated with
     // (last_request_timestamp - first_request_timestamp) / requests_sent.
+    #[cfg_attr(feature = "serde", serde(with = "serde_instant"))]
     pub last_request_timestamp: Instant,
 
     // last_response_timestamp represents the timestamp at which the last STUN response
     // was received on this particular candidate pair.
+    #[cfg_attr(feature = "serde", serde(with = "serde_instant"))]
     pub last_response_timestamp: Instant,
 
     // total_round_trip_time represents the sum of all round trip time measurements
@@ -122,13 +135,18 @@ pub struct CandidatePairStats {
 
     // consent_expired_timestamp represents the timestamp at which the latest valid
     // STUN binding response expired.
+    #[cfg_attr(feature = "serde", serde(with = "serde_instant"))]
     pub consent_expired_timestamp: Instant,
 }
 
-// CandidateStats contains ICE candidate statistics related to the ICETransport objects.
+// CandidateStats contains ICE candidate statistics related to the ICETransport objects. It is
+// serialized like CandidatePairStats.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
 pub struct CandidateStats {
     // timestamp is the timestamp associated with this object.
+    #[cfg_attr(feature = "serde", serde(with = "serde_instant"))]
     pub timestamp: Instant,
 
     // id is the candidate id
@@ -0,0 +1,35 @@
+//! (De)serializes an `Instant` as milliseconds since the UNIX epoch, as `Instant` itself has no
+//! meaning outside of the process.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+pub(crate) fn serialize<S: Serializer>(
+    instant: &Instant,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let now = Instant::now();
+    let system_now = SystemTime::now();
+    let system_time = if *instant <= now {
+        system_now - (now - *instant)
+    } else {
+        system_now + (*instant - now)
+    };
+    let millis = system_time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    #[allow(clippy::cast_possible_truncation)]
+    (millis as u64).serialize(serializer)
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Instant, D::Error> {
+    let system_time = UNIX_EPOCH + Duration::from_millis(u64::deserialize(deserializer)?);
+    let now = Instant::now();
+    let instant = match SystemTime::now().duration_since(system_time) {
+        Ok(elapsed) => now.checked_sub(elapsed).unwrap_or(now),
+        Err(err) => now + err.duration(),
+    };
+    Ok(instant)
+}
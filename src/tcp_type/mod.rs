@@ -6,6 +6,8 @@ use std::fmt;
 // TCPType is the type of ICE TCP candidate as described in
 // ttps://tools.ietf.org/html/rfc6544#section-4.5
 #[derive(PartialEq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum TcpType {
     /// The default value. For example UDP candidates do not need this field.
     Unspecified,
@@ -14,6 +16,7 @@ pub enum TcpType {
     /// Passive TCP candidate, only accepts TCP connections.
     Passive,
     /// Like `Active` and `Passive` at the same time.
+    #[cfg_attr(feature = "serde", serde(rename = "so"))]
     SimultaneousOpen,
 }
 
@@ -0,0 +1,114 @@
+#[cfg(test)]
+mod turn_credentials_test;
+
+use crate::error::*;
+use crate::url::{CredentialType, Url};
+
+use async_trait::async_trait;
+use ring::hmac;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The credentials to allocate a relay on a TURN server. With `CredentialType::Oauth` the
+/// username is the key id, the password the base64 encoded MAC key and `access_token` the base64
+/// encoded token, as in `Url`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TurnCredentials {
+    pub username: String,
+    pub password: String,
+    pub credential_type: CredentialType,
+    pub access_token: String,
+    /// The time after which the server rejects the credentials, `None` if they don't expire.
+    pub expires: Option<SystemTime>,
+}
+
+impl From<&Url> for TurnCredentials {
+    /// The credentials configured in `url`, which don't expire.
+    fn from(url: &Url) -> Self {
+        TurnCredentials {
+            username: url.username.clone(),
+            password: url.password.clone(),
+            credential_type: url.credential_type,
+            access_token: url.access_token.clone(),
+            expires: None,
+        }
+    }
+}
+
+/// The decoded MAC key and access token of OAuth credentials.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OauthToken {
+    pub(crate) mac_key: Vec<u8>,
+    pub(crate) access_token: Vec<u8>,
+}
+
+impl OauthToken {
+    /// Decodes the base64 encoded MAC key (the password) and access token of `credentials`.
+    pub(crate) fn new(credentials: &TurnCredentials) -> Result<Self> {
+        if credentials.access_token.is_empty() {
+            return Err(Error::ErrAccessTokenEmpty);
+        }
+        let decode = |s: &str| base64::decode(s).map_err(|_| Error::ErrInvalidOauthCredential);
+        Ok(OauthToken {
+            mac_key: decode(&credentials.password)?,
+            access_token: decode(&credentials.access_token)?,
+        })
+    }
+}
+
+/// Provides the credentials of the TURN servers, set in `AgentConfig::turn_credential_provider`.
+///
+/// The provider is asked for new credentials for each allocation. An allocation whose credentials
+/// expire is refreshed with new credentials, asked for before they do.
+#[async_trait]
+pub trait TurnCredentialProvider: Send + Sync {
+    /// Returns the credentials for the TURN server at `url`.
+    async fn credentials(&self, url: &Url) -> Result<TurnCredentials>;
+}
+
+/// Time-limited credentials of the TURN REST API, derived from a secret shared with the TURN
+/// server. The username is `expiry:user`, with the expiry in seconds since the Unix epoch, and the
+/// password is the base64 encoded HMAC-SHA1 of the username under the shared secret.
+pub struct TurnRestCredentialProvider {
+    secret: String,
+    user: String,
+    ttl: Duration,
+}
+
+impl TurnRestCredentialProvider {
+    /// Creates a provider of credentials for `user` which are valid for `ttl`. The username is
+    /// just the expiry if `user` is empty.
+    pub fn new(secret: String, user: String, ttl: Duration) -> Self {
+        TurnRestCredentialProvider { secret, user, ttl }
+    }
+
+    /// Returns the credentials which expire at `expires`.
+    pub fn credentials_until(&self, expires: SystemTime) -> Result<TurnCredentials> {
+        let expiry = expires
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| Error::ErrInvalidCredentialExpiry)?
+            .as_secs();
+        let username = if self.user.is_empty() {
+            expiry.to_string()
+        } else {
+            format!("{}:{}", expiry, self.user)
+        };
+
+        let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, self.secret.as_bytes());
+        let password = base64::encode(hmac::sign(&key, username.as_bytes()).as_ref());
+
+        Ok(TurnCredentials {
+            username,
+            password,
+            credential_type: CredentialType::Password,
+            access_token: String::new(),
+            expires: Some(UNIX_EPOCH + Duration::from_secs(expiry)),
+        })
+    }
+}
+
+#[async_trait]
+impl TurnCredentialProvider for TurnRestCredentialProvider {
+    async fn credentials(&self, _url: &Url) -> Result<TurnCredentials> {
+        self.credentials_until(SystemTime::now() + self.ttl)
+    }
+}
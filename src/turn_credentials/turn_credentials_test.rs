@@ -0,0 +1,76 @@
+use super::*;
+
+#[test]
+fn test_turn_rest_credentials() -> Result<()> {
+    let provider =
+        TurnRestCredentialProvider::new("north".to_owned(), "alice".to_owned(), Duration::ZERO);
+    let expires = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    assert_eq!(
+        provider.credentials_until(expires)?,
+        TurnCredentials {
+            username: "1700000000:alice".to_owned(),
+            password: "Cd/49soE35ICqcJF/bCTn8Z4OyE=".to_owned(),
+            credential_type: CredentialType::Password,
+            access_token: String::new(),
+            expires: Some(expires),
+        }
+    );
+
+    // Without a user the username is just the expiry
+    let provider =
+        TurnRestCredentialProvider::new("north".to_owned(), String::new(), Duration::ZERO);
+    assert_eq!(provider.credentials_until(expires)?.username, "1700000000");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_turn_rest_credentials_expire_after_ttl() -> Result<()> {
+    let ttl = Duration::from_secs(3600);
+    let provider = TurnRestCredentialProvider::new("north".to_owned(), "alice".to_owned(), ttl);
+
+    let before = SystemTime::now();
+    let credentials = provider.credentials(&Url::default()).await?;
+    let expires = credentials.expires.unwrap();
+    // The expiry is truncated to whole seconds
+    assert!(expires + Duration::from_secs(1) > before + ttl);
+    assert!(expires <= SystemTime::now() + ttl);
+    assert_eq!(
+        credentials,
+        provider.credentials_until(expires)?,
+        "the credentials only depend on the expiry"
+    );
+
+    Ok(())
+}
+
+fn oauth_credentials(access_token: &str) -> TurnCredentials {
+    TurnCredentials {
+        username: "kid".to_owned(),
+        password: base64::encode(b"mac key"),
+        credential_type: CredentialType::Oauth,
+        access_token: access_token.to_owned(),
+        expires: None,
+    }
+}
+
+#[test]
+fn test_oauth_token() -> Result<()> {
+    let token = OauthToken::new(&oauth_credentials(&base64::encode(b"token")))?;
+    assert_eq!(token.mac_key, b"mac key");
+    assert_eq!(token.access_token, b"token");
+
+    Ok(())
+}
+
+#[test]
+fn test_oauth_token_invalid() {
+    assert_eq!(
+        OauthToken::new(&oauth_credentials("")),
+        Err(Error::ErrAccessTokenEmpty)
+    );
+    assert_eq!(
+        OauthToken::new(&oauth_credentials("not base64!")),
+        Err(Error::ErrInvalidOauthCredential)
+    );
+}
@@ -3,13 +3,14 @@ mod url_test;
 
 use crate::error::*;
 
-use anyhow::Result;
 use std::borrow::Cow;
 use std::convert::From;
 use std::fmt;
 
 /// The type of server used in the ice.URL structure.
 #[derive(PartialEq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum SchemeType {
     /// The URL represents a STUN server.
     Stun,
@@ -62,6 +63,8 @@ impl fmt::Display for SchemeType {
 
 /// The transport protocol type that is used in the `ice::url::Url` structure.
 #[derive(PartialEq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum ProtoType {
     /// The URL uses a UDP transport.
     Udp,
@@ -103,8 +106,43 @@ impl fmt::Display for ProtoType {
     }
 }
 
-/// Represents a STUN (rfc7064) or TURN (rfc7065) URL.
+/// The kind of credential used to authenticate with a TURN server, as in the `credentialType` of
+/// WebRTC's `RTCIceServer`.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum CredentialType {
+    /// `username` and `password` are the long-term credentials of the TURN server.
+    Password,
+
+    /// Third-party authorization ([IETF rfc-7635](https://tools.ietf.org/html/rfc7635)):
+    /// `username` is the key id (kid) of the access token, `password` the base64 encoded MAC key
+    /// and `access_token` the base64 encoded, self-contained token.
+    Oauth,
+}
+
+impl Default for CredentialType {
+    fn default() -> Self {
+        Self::Password
+    }
+}
+
+impl fmt::Display for CredentialType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match *self {
+            Self::Password => "password",
+            Self::Oauth => "oauth",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Represents a STUN (rfc7064) or TURN (rfc7065) URL. With the `serde` feature it serializes as
+/// `{"scheme":"turn","host":"example.org","port":3478,"username":"","password":"","proto":"udp",
+/// "credentialType":"password","accessToken":""}`; the last two may be omitted.
 #[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
 pub struct Url {
     pub scheme: SchemeType,
     pub host: String,
@@ -112,6 +150,11 @@ pub struct Url {
     pub username: String,
     pub password: String,
     pub proto: ProtoType,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub credential_type: CredentialType,
+    /// The OAuth access token if `credential_type` is `CredentialType::Oauth`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub access_token: String,
 }
 
 impl fmt::Display for Url {
@@ -140,7 +183,7 @@ impl Url {
     pub fn parse_url(raw: &str) -> Result<Self> {
         // work around for url crate
         if raw.contains("//") {
-            return Err(Error::ErrInvalidUrl.into());
+            return Err(Error::ErrInvalidUrl);
         }
 
         let mut s = raw.to_string();
@@ -148,7 +191,7 @@ impl Url {
         if let Some(p) = pos {
             s.replace_range(p..=p, "://");
         } else {
-            return Err(Error::ErrSchemeType.into());
+            return Err(Error::ErrSchemeType);
         }
 
         let raw_parts = url::Url::parse(&s)?;
@@ -161,7 +204,7 @@ impl Url {
                 .trim_end_matches(']')
                 .to_owned()
         } else {
-            return Err(Error::ErrHost.into());
+            return Err(Error::ErrHost);
         };
 
         let port = if let Some(port) = raw_parts.port() {
@@ -176,29 +219,29 @@ impl Url {
         let proto = match scheme {
             SchemeType::Stun => {
                 if q_args.count() > 0 {
-                    return Err(Error::ErrStunQuery.into());
+                    return Err(Error::ErrStunQuery);
                 }
                 ProtoType::Udp
             }
             SchemeType::Stuns => {
                 if q_args.count() > 0 {
-                    return Err(Error::ErrStunQuery.into());
+                    return Err(Error::ErrStunQuery);
                 }
                 ProtoType::Tcp
             }
             SchemeType::Turn => {
                 if q_args.count() > 1 {
-                    return Err(Error::ErrInvalidQuery.into());
+                    return Err(Error::ErrInvalidQuery);
                 }
                 if let Some((key, value)) = q_args.next() {
                     if key == Cow::Borrowed("transport") {
                         let proto: ProtoType = value.as_ref().into();
                         if proto == ProtoType::Unknown {
-                            return Err(Error::ErrProtoType.into());
+                            return Err(Error::ErrProtoType);
                         }
                         proto
                     } else {
-                        return Err(Error::ErrInvalidQuery.into());
+                        return Err(Error::ErrInvalidQuery);
                     }
                 } else {
                     ProtoType::Udp
@@ -206,24 +249,24 @@ impl Url {
             }
             SchemeType::Turns => {
                 if q_args.count() > 1 {
-                    return Err(Error::ErrInvalidQuery.into());
+                    return Err(Error::ErrInvalidQuery);
                 }
                 if let Some((key, value)) = q_args.next() {
                     if key == Cow::Borrowed("transport") {
                         let proto: ProtoType = value.as_ref().into();
                         if proto == ProtoType::Unknown {
-                            return Err(Error::ErrProtoType.into());
+                            return Err(Error::ErrProtoType);
                         }
                         proto
                     } else {
-                        return Err(Error::ErrInvalidQuery.into());
+                        return Err(Error::ErrInvalidQuery);
                     }
                 } else {
                     ProtoType::Tcp
                 }
             }
             SchemeType::Unknown => {
-                return Err(Error::ErrSchemeType.into());
+                return Err(Error::ErrSchemeType);
             }
         };
 
@@ -234,6 +277,8 @@ impl Url {
             username: "".to_owned(),
             password: "".to_owned(),
             proto,
+            credential_type: CredentialType::Password,
+            access_token: "".to_owned(),
         })
     }
 
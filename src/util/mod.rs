@@ -1,24 +1,57 @@
 #[cfg(test)]
 mod util_test;
 
-use crate::agent::agent_config::InterfaceFilterFn;
+use crate::agent::agent_config::{InterfaceFilterFn, InterfacePreferenceFn, IpFilterFn};
 use crate::error::*;
+use crate::ipv6_address_class::*;
 use crate::network_type::*;
+use crate::runtime::{self, Runtime};
 
-use anyhow::Result;
-use std::net::{IpAddr, SocketAddr};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
 use std::sync::Arc;
-use stun::{agent::*, attributes::*, integrity::*, message::*, textattrs::*, xoraddr::*};
+use stun::{
+    agent::*, attributes::*, error_code::*, integrity::*, message::*, textattrs::*, xoraddr::*,
+};
 use tokio::time::Duration;
 use util::{vnet::net::*, Conn};
 
-pub fn create_addr(_network: NetworkType, ip: IpAddr, port: u16) -> SocketAddr {
+/// Creates the socket address for a candidate. `scope_id` is the zone ID of IPv6 link-local
+/// addresses and is ignored otherwise.
+pub fn create_addr(_network: NetworkType, ip: IpAddr, port: u16, scope_id: u32) -> SocketAddr {
     /*if network.is_tcp(){
         return &net.TCPAddr{IP: ip, Port: port}
     default:
         return &net.UDPAddr{IP: ip, Port: port}
     }*/
-    SocketAddr::new(ip, port)
+    match ip {
+        IpAddr::V6(ip) if scope_id != 0 => SocketAddrV6::new(ip, port, 0, scope_id).into(),
+        _ => SocketAddr::new(ip, port),
+    }
+}
+
+/// Parses a candidate address which may carry an IPv6 zone ID, e.g. `fe80::1%2`. The zone ID
+/// must be the numeric interface index, anything else is treated as unknown zone 0.
+pub fn parse_ip_with_zone(address: &str) -> Result<(IpAddr, u32)> {
+    let (ip, zone) = match address.find('%') {
+        Some(pos) => (&address[..pos], Some(&address[pos + 1..])),
+        None => (address, None),
+    };
+
+    let ip: IpAddr = match ip.parse() {
+        Ok(ip) => ip,
+        Err(_) => return Err(Error::ErrAddressParseFailed),
+    };
+
+    let scope_id = match (ip, zone) {
+        (IpAddr::V6(_), Some(zone)) => zone.parse().unwrap_or(0),
+        (IpAddr::V4(_), Some(_)) => return Err(Error::ErrAddressParseFailed),
+        _ => 0,
+    };
+
+    Ok((ip, scope_id))
 }
 
 pub fn assert_inbound_username(m: &Message, expected_username: &str) -> Result<()> {
@@ -31,8 +64,7 @@ pub fn assert_inbound_username(m: &Message, expected_username: &str) -> Result<(
             Error::ErrMismatchUsername,
             expected_username,
             username,
-        ))
-        .into());
+        )));
     }
 
     Ok(())
@@ -40,60 +72,177 @@ pub fn assert_inbound_username(m: &Message, expected_username: &str) -> Result<(
 
 pub fn assert_inbound_message_integrity(m: &mut Message, key: &[u8]) -> Result<()> {
     let message_integrity_attr = MessageIntegrity(key.to_vec());
-    message_integrity_attr.check(m)
+    Ok(message_integrity_attr.check(m)?)
+}
+
+/// The retransmissions of a STUN request over UDP (RFC 8489, section 6.2.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StunRetransmission {
+    /// The initial retransmission timeout, doubled after each retransmission (RTO).
+    pub rto: Duration,
+    /// The number of requests sent before giving up (Rc).
+    pub max_requests: u32,
+    /// The multiple of the initial RTO to wait for a response after the last request (Rm).
+    pub last_timeout_factor: u32,
+}
+
+impl StunRetransmission {
+    /// Returns the time to wait for a response after sending the `n`th request, counted from 1.
+    pub fn timeout(&self, n: u32) -> Duration {
+        if n >= self.max_requests {
+            self.rto.saturating_mul(self.last_timeout_factor)
+        } else {
+            let factor = 1u32.checked_shl(n - 1).unwrap_or(u32::MAX);
+            self.rto.saturating_mul(factor)
+        }
+    }
 }
 
 /// Initiates a stun requests to `server_addr` using conn, reads the response and returns the
 /// `XORMappedAddress` returned by the stun server.
 /// Adapted from stun v0.2.
 pub async fn get_xormapped_addr(
+    runtime: &Arc<dyn Runtime>,
     conn: &Arc<dyn Conn + Send + Sync>,
     server_addr: SocketAddr,
-    deadline: Duration,
+    retransmission: &StunRetransmission,
 ) -> Result<XorMappedAddress> {
-    let resp = stun_request(conn, server_addr, deadline).await?;
+    let resp = stun_request(runtime, conn, server_addr, retransmission).await?;
+    xormapped_addr(&resp)
+}
+
+/// Returns the `XORMappedAddress` of the response to a binding request, or the error of an error
+/// response.
+pub(crate) fn xormapped_addr(resp: &Message) -> Result<XorMappedAddress> {
+    if resp.typ.class == CLASS_ERROR_RESPONSE {
+        let (code, reason) = error_code(resp)?;
+        return Err(Error::ErrServerErrorResponse(code, reason));
+    }
     let mut addr = XorMappedAddress::default();
-    addr.get_from(&resp)?;
+    addr.get_from(resp)?;
     Ok(addr)
 }
 
+/// Returns the code and reason phrase of the ERROR-CODE attribute of an error response.
+pub(crate) fn error_code(resp: &Message) -> Result<(u16, String)> {
+    let mut attr = ErrorCodeAttribute::default();
+    attr.get_from(resp)?;
+    let reason = String::from_utf8_lossy(&attr.reason).into_owned();
+
+    // The number of an `ErrorCode` is only exposed by the display of its attribute, "401: "
+    let code = ErrorCodeAttribute {
+        code: attr.code,
+        reason: vec![],
+    }
+    .to_string();
+    match code.trim_end_matches(": ").parse() {
+        Ok(code) => Ok((code, reason)),
+        Err(_) => Err(Error::Stun(stun::error::Error::ErrAttributeSizeInvalid)),
+    }
+}
+
 const MAX_MESSAGE_SIZE: usize = 1280;
 
+/// Sends a binding request to `server_addr` and returns the response, retransmitting the request
+/// until a response arrives or `Error::ErrTimeout` once all retransmissions went unanswered.
 pub async fn stun_request(
+    runtime: &Arc<dyn Runtime>,
     conn: &Arc<dyn Conn + Send + Sync>,
     server_addr: SocketAddr,
-    deadline: Duration,
+    retransmission: &StunRetransmission,
 ) -> Result<Message> {
     let mut request = Message::new();
     request.build(&[Box::new(BINDING_REQUEST), Box::new(TransactionId::new())])?;
 
-    conn.send_to(&request.raw, server_addr).await?;
     let mut bs = vec![0_u8; MAX_MESSAGE_SIZE];
-    let (n, _) = if deadline > Duration::from_secs(0) {
-        match tokio::time::timeout(deadline, conn.recv_from(&mut bs)).await {
-            Ok(result) => match result {
-                Ok((n, addr)) => (n, addr),
-                Err(err) => return Err(Error::new(err.to_string()).into()),
-            },
-            Err(err) => return Err(Error::new(err.to_string()).into()),
+    for n in 1..=retransmission.max_requests.max(1) {
+        conn.send_to(&request.raw, server_addr).await?;
+        let response = recv_stun_response(conn, server_addr, &request, &mut bs);
+        if let Ok(result) = runtime::timeout(runtime, retransmission.timeout(n), response).await {
+            return result;
         }
-    } else {
-        conn.recv_from(&mut bs).await?
-    };
+        log::trace!("no response to STUN request {} from {}", n, server_addr);
+    }
+
+    Err(Error::ErrTimeout)
+}
+
+/// Receives the response to `request` from `server_addr`, skipping anything else like responses
+/// to earlier requests.
+async fn recv_stun_response(
+    conn: &Arc<dyn Conn + Send + Sync>,
+    server_addr: SocketAddr,
+    request: &Message,
+    bs: &mut [u8],
+) -> Result<Message> {
+    loop {
+        let (n, src_addr) = conn.recv_from(bs).await?;
+        if src_addr != server_addr {
+            continue;
+        }
+
+        let mut res = Message::new();
+        res.raw = bs[..n].to_vec();
+        if res.decode().is_ok() && res.transaction_id == request.transaction_id {
+            return Ok(res);
+        }
+    }
+}
+
+/// An IP address assigned to a local interface.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterfaceAddr {
+    /// The name of the interface the address is assigned to.
+    pub name: String,
+    pub ip: IpAddr,
+    /// The zone ID of IPv6 link-local addresses, zero otherwise.
+    pub scope_id: u32,
+}
+
+impl fmt::Display for InterfaceAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.scope_id != 0 {
+            write!(f, "{}%{}", self.ip, self.scope_id)
+        } else {
+            write!(f, "{}", self.ip)
+        }
+    }
+}
 
-    let mut res = Message::new();
-    res.raw = bs[..n].to_vec();
-    res.decode()?;
+impl InterfaceAddr {
+    /// Returns the socket address for `port` on this interface address, including the zone ID.
+    pub fn socket_addr(&self, port: u16) -> SocketAddr {
+        create_addr(NetworkType::Unspecified, self.ip, port, self.scope_id)
+    }
+}
+
+/// Returns the index of the local interface `name`, which is the zone ID of its IPv6 link-local
+/// addresses, or 0 if there is no such interface.
+#[cfg(unix)]
+pub(crate) fn interface_index(name: &str) -> u32 {
+    match std::ffi::CString::new(name) {
+        // SAFETY: `name` is a valid NUL-terminated string for the duration of the call.
+        Ok(name) => unsafe { libc::if_nametoindex(name.as_ptr()) },
+        Err(_) => 0,
+    }
+}
 
-    Ok(res)
+/// Returns 0, the interfaces enumerated on other platforms are unnamed and their index is unknown.
+#[cfg(not(unix))]
+pub(crate) fn interface_index(_name: &str) -> u32 {
+    0
 }
 
 pub async fn local_interfaces(
     vnet: &Arc<Net>,
     interface_filter: &Option<InterfaceFilterFn>,
+    ip_filter: &Option<IpFilterFn>,
     network_types: &[NetworkType],
-) -> Vec<IpAddr> {
-    let mut ips = vec![];
+    include_loopback: bool,
+    include_link_local: bool,
+    ipv6_address_classes: &[Ipv6AddressClass],
+) -> Vec<InterfaceAddr> {
+    let mut addrs = vec![];
     let interfaces = vnet.get_interfaces().await;
 
     let (mut ipv4requested, mut ipv6requested) = (false, false);
@@ -106,6 +255,13 @@ pub async fn local_interfaces(
         }
     }
 
+    // Interface indexes and address flags are only known for the interfaces of the host.
+    let ipv6_infos = if ipv6requested && !vnet.is_virtual() {
+        local_ipv6_address_info()
+    } else {
+        HashMap::new()
+    };
+
     for iface in interfaces {
         if let Some(filter) = interface_filter {
             if !filter(iface.name()) {
@@ -113,39 +269,157 @@ pub async fn local_interfaces(
             }
         }
 
+        let index = if vnet.is_virtual() {
+            0
+        } else {
+            interface_index(iface.name())
+        };
+
         for ipnet in iface.addrs() {
             let ipaddr = ipnet.addr();
-            if !ipaddr.is_loopback()
-                && ((ipv4requested && ipaddr.is_ipv4()) || (ipv6requested && ipaddr.is_ipv6()))
-            {
-                ips.push(ipaddr);
+            if (!ipv4requested || !ipaddr.is_ipv4()) && (!ipv6requested || !ipaddr.is_ipv6()) {
+                continue;
+            }
+            if (!include_loopback && ipaddr.is_loopback()) || !is_ip_allowed(ipaddr, ip_filter) {
+                continue;
+            }
+
+            let mut scope_id = 0;
+            match ipaddr {
+                IpAddr::V4(_) => {
+                    if !include_link_local && is_link_local(&ipaddr) {
+                        continue;
+                    }
+                }
+                IpAddr::V6(ip) => {
+                    if ip.is_loopback() {
+                        // ::1 is not classified and only governed by include_loopback
+                    } else {
+                        let info = ipv6_infos.get(&(index, ip));
+                        let class = Ipv6AddressClass::classify(&ip, info);
+                        let included = ipv6_address_classes.contains(&class)
+                            || (class == Ipv6AddressClass::LinkLocal && include_link_local);
+                        if !included {
+                            log::trace!("skipping {} address {} on {}", class, ip, iface.name());
+                            continue;
+                        }
+                        if class == Ipv6AddressClass::LinkLocal {
+                            scope_id = index;
+                        }
+                    }
+                }
             }
+
+            addrs.push(InterfaceAddr {
+                name: iface.name().to_owned(),
+                ip: ipaddr,
+                scope_id,
+            });
+        }
+    }
+
+    addrs
+}
+
+/// Computes a unique local preference for the host candidates of each of `addrs`, following
+/// RFC 8421 section 4. Addresses are ordered by the rank of their interface and, within the same
+/// rank, IPv6 and IPv4 addresses are interleaved starting with IPv6, so that neither family is
+/// starved. The most preferred address gets 65535, every following one a lower value.
+pub(crate) fn local_preferences(
+    addrs: &[InterfaceAddr],
+    interface_preference: &Option<InterfacePreferenceFn>,
+) -> Vec<u16> {
+    let rank = |addr: &InterfaceAddr| {
+        interface_preference
+            .as_ref()
+            .map_or(0, |preference| preference(&addr.name))
+    };
+
+    let mut ranks: Vec<u8> = addrs.iter().map(rank).collect();
+    ranks.sort_unstable_by(|a, b| b.cmp(a));
+    ranks.dedup();
+
+    let mut order = Vec::with_capacity(addrs.len());
+    for r in ranks {
+        let (mut v6, mut v4): (Vec<usize>, Vec<usize>) = (0..addrs.len())
+            .filter(|&i| rank(&addrs[i]) == r)
+            .partition(|&i| addrs[i].ip.is_ipv6());
+        v6.reverse();
+        v4.reverse();
+        while !v6.is_empty() || !v4.is_empty() {
+            order.extend(v6.pop());
+            order.extend(v4.pop());
         }
     }
 
-    ips
+    let mut preferences = vec![0; addrs.len()];
+    for (n, i) in order.into_iter().enumerate() {
+        preferences[i] = u16::try_from(n)
+            .ok()
+            .and_then(|n| u16::MAX.checked_sub(n))
+            .unwrap_or(0);
+    }
+    preferences
+}
+
+/// Assigns a network ID to each of `addrs`, starting at 1. Addresses on the same interface share
+/// the same network ID.
+pub(crate) fn network_ids(addrs: &[InterfaceAddr]) -> Vec<u16> {
+    let mut names: Vec<&str> = vec![];
+    addrs
+        .iter()
+        .map(|addr| {
+            let pos = match names.iter().position(|name| *name == addr.name) {
+                Some(pos) => pos,
+                None => {
+                    names.push(&addr.name);
+                    names.len() - 1
+                }
+            };
+            u16::try_from(pos + 1).unwrap_or(0)
+        })
+        .collect()
+}
+
+/// Returns true if `ip` is accepted by the user supplied `ip_filter`, or if there is none.
+pub fn is_ip_allowed(ip: IpAddr, ip_filter: &Option<IpFilterFn>) -> bool {
+    if let Some(filter) = ip_filter {
+        filter(ip)
+    } else {
+        true
+    }
+}
+
+/// Returns true if `ip` is an IPv4 (169.254.0.0/16) or IPv6 (fe80::/10) link-local address.
+pub fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => (ip.segments()[0] & 0xffc0) == 0xfe80,
+    }
 }
 
 pub async fn listen_udp_in_port_range(
     vnet: &Arc<Net>,
+    runtime: &Arc<dyn Runtime>,
     port_max: u16,
     port_min: u16,
     laddr: SocketAddr,
 ) -> Result<Arc<dyn Conn + Send + Sync>> {
     if laddr.port() != 0 || (port_min == 0 && port_max == 0) {
-        return vnet.bind(laddr).await;
+        return runtime::bind(vnet, runtime, laddr).await;
     }
     let i = if port_min == 0 { 1 } else { port_min };
     let j = if port_max == 0 { 0xFFFF } else { port_max };
     if i > j {
-        return Err(Error::ErrPort.into());
+        return Err(Error::ErrPort);
     }
 
     let port_start = rand::random::<u16>() % (j - i + 1) + i;
     let mut port_current = port_start;
     loop {
-        let laddr = SocketAddr::new(laddr.ip(), port_current);
-        match vnet.bind(laddr).await {
+        let mut laddr = laddr;
+        laddr.set_port(port_current);
+        match runtime::bind(vnet, runtime, laddr).await {
             Ok(c) => return Ok(c),
             Err(err) => log::debug!("failed to listen {}: {}", laddr, err),
         };
@@ -159,5 +433,5 @@ pub async fn listen_udp_in_port_range(
         }
     }
 
-    Err(Error::ErrPort.into())
+    Err(Error::ErrPortRangeExhausted)
 }
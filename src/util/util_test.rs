@@ -1,10 +1,382 @@
 use super::*;
+use crate::agent::agent_config::default_ipv6_address_classes;
+use crate::runtime::TokioRuntime;
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::net::UdpSocket;
+
+/// Runs a STUN server on loopback which answers binding requests with `mapped_addr`, ignoring the
+/// first `drop_first` requests. Returns its address and the number of requests it received.
+async fn stun_server(
+    mapped_addr: SocketAddr,
+    drop_first: usize,
+) -> Result<(SocketAddr, Arc<AtomicUsize>)> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let server_addr = socket.local_addr()?;
+    let requests = Arc::new(AtomicUsize::new(0));
+    let requests2 = Arc::clone(&requests);
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 1500];
+        while let Ok((n, src_addr)) = socket.recv_from(&mut buf).await {
+            let mut request = Message::new();
+            request.raw = buf[..n].to_vec();
+            if request.decode().is_err() || request.typ != BINDING_REQUEST {
+                continue;
+            }
+            if requests2.fetch_add(1, Ordering::SeqCst) < drop_first {
+                continue;
+            }
+
+            let mut response = Message::new();
+            response.build(&[
+                Box::new(request),
+                Box::new(BINDING_SUCCESS),
+                Box::new(XorMappedAddress {
+                    ip: mapped_addr.ip(),
+                    port: mapped_addr.port(),
+                }),
+            ])?;
+            socket.send_to(&response.raw, src_addr).await?;
+        }
+        Result::<()>::Ok(())
+    });
+    Ok((server_addr, requests))
+}
 
 #[tokio::test]
 async fn test_local_interfaces() -> Result<()> {
     let vnet = Arc::new(Net::new(None));
     let interfaces = vnet.get_interfaces().await;
-    let ips = local_interfaces(&vnet, &None, &[NetworkType::Udp4, NetworkType::Udp6]).await;
+    let ips = local_interfaces(
+        &vnet,
+        &None,
+        &None,
+        &[NetworkType::Udp4, NetworkType::Udp6],
+        false,
+        false,
+        &default_ipv6_address_classes(),
+    )
+    .await;
     log::info!("interfaces: {:?}, ips: {:?}", interfaces, ips);
     Ok(())
 }
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_local_interfaces_link_local_zone() -> Result<()> {
+    let vnet = Arc::new(Net::new(None));
+    let ips = local_interfaces(&vnet, &None, &None, &[NetworkType::Udp6], false, true, &[]).await;
+    for addr in ips {
+        if is_link_local(&addr.ip) {
+            assert_ne!(addr.scope_id, 0, "{:?} has no zone", addr);
+            assert_eq!(addr.scope_id, interface_index(&addr.name), "{:?}", addr);
+        } else {
+            assert_eq!(addr.scope_id, 0, "{:?}", addr);
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_local_interfaces_loopback_and_link_local() -> Result<()> {
+    let vnet = Arc::new(Net::new(Some(NetConfig::default())));
+
+    let ips = local_interfaces(&vnet, &None, &None, &[NetworkType::Udp4], false, false, &[]).await;
+    assert!(ips.is_empty(), "loopback should be excluded by default");
+
+    let ips = local_interfaces(&vnet, &None, &None, &[NetworkType::Udp4], true, false, &[]).await;
+    assert_eq!(
+        ips,
+        vec![InterfaceAddr {
+            name: "lo0".to_owned(),
+            ip: IpAddr::from([127, 0, 0, 1]),
+            scope_id: 0,
+        }],
+        "loopback should be included on request"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_is_link_local() -> Result<()> {
+    let tests = vec![
+        ("192.168.0.1", false),
+        ("169.254.1.1", true),
+        ("fe80::1", true),
+        ("febf::1", true),
+        ("fec0::1", false),
+        ("2001:db8::1", false),
+    ];
+
+    for (ip, expected) in tests {
+        let ip: IpAddr = ip.parse()?;
+        assert_eq!(is_link_local(&ip), expected, "{}", ip);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_is_ip_allowed() -> Result<()> {
+    assert!(is_ip_allowed("2001:db8::1".parse()?, &None));
+
+    let ip_filter: Option<IpFilterFn> = Some(Box::new(|ip: IpAddr| ip.is_ipv4()));
+    assert!(is_ip_allowed("192.168.0.1".parse()?, &ip_filter));
+    assert!(!is_ip_allowed("2001:db8::1".parse()?, &ip_filter));
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_ip_with_zone() -> Result<()> {
+    let tests = vec![
+        ("192.168.0.1", "192.168.0.1", 0),
+        ("2001:db8::1", "2001:db8::1", 0),
+        ("fe80::1%2", "fe80::1", 2),
+        ("fe80::1%eth0", "fe80::1", 0),
+    ];
+
+    for (address, expected_ip, expected_scope_id) in tests {
+        let (ip, scope_id) = parse_ip_with_zone(address)?;
+        assert_eq!(ip, expected_ip.parse::<IpAddr>()?, "{}", address);
+        assert_eq!(scope_id, expected_scope_id, "{}", address);
+    }
+
+    assert!(parse_ip_with_zone("192.168.0.1%2").is_err());
+    assert!(parse_ip_with_zone("not-an-ip").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_create_addr_with_scope_id() -> Result<()> {
+    let addr = create_addr(NetworkType::Udp6, "fe80::1".parse()?, 1234, 3);
+    assert_eq!(addr, "[fe80::1%3]:1234".parse::<SocketAddr>()?);
+
+    let addr = create_addr(NetworkType::Udp4, "10.0.0.1".parse()?, 1234, 3);
+    assert_eq!(addr, "10.0.0.1:1234".parse::<SocketAddr>()?);
+
+    Ok(())
+}
+
+#[test]
+fn test_local_preferences() -> Result<()> {
+    let addr = |name: &str, ip: &str| -> Result<InterfaceAddr> {
+        Ok(InterfaceAddr {
+            name: name.to_owned(),
+            ip: ip.parse()?,
+            scope_id: 0,
+        })
+    };
+
+    // A single address gets the highest preference
+    let addrs = vec![addr("eth0", "192.168.0.1")?];
+    assert_eq!(local_preferences(&addrs, &None), vec![65535]);
+
+    // IPv6 and IPv4 addresses are interleaved starting with IPv6
+    let addrs = vec![
+        addr("eth0", "192.168.0.1")?,
+        addr("eth0", "192.168.0.2")?,
+        addr("eth0", "2001:db8::1")?,
+        addr("eth0", "2001:db8::2")?,
+        addr("eth0", "2001:db8::3")?,
+    ];
+    assert_eq!(
+        local_preferences(&addrs, &None),
+        vec![65534, 65532, 65535, 65533, 65531]
+    );
+
+    // Preferred interfaces come first
+    let interface_preference: Option<InterfacePreferenceFn> =
+        Some(Box::new(|name: &str| match name {
+            "eth0" => 2,
+            "wlan0" => 1,
+            _ => 0,
+        }));
+    let addrs = vec![
+        addr("tun0", "10.8.0.1")?,
+        addr("wlan0", "192.168.1.2")?,
+        addr("eth0", "192.168.0.2")?,
+        addr("wlan0", "2001:db8::2")?,
+    ];
+    assert_eq!(
+        local_preferences(&addrs, &interface_preference),
+        vec![65532, 65533, 65535, 65534]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_network_ids() -> Result<()> {
+    let addrs: Vec<InterfaceAddr> = [
+        ("eth0", "192.168.0.1"),
+        ("wlan0", "192.168.1.1"),
+        ("eth0", "2001:db8::1"),
+    ]
+    .iter()
+    .map(|(name, ip)| InterfaceAddr {
+        name: (*name).to_owned(),
+        ip: ip.parse().unwrap(),
+        scope_id: 0,
+    })
+    .collect();
+
+    assert_eq!(network_ids(&addrs), vec![1, 2, 1]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_listen_udp_in_port_range_exhausted() -> Result<()> {
+    let vnet = Arc::new(Net::new(None));
+    let runtime: Arc<dyn Runtime> = Arc::new(TokioRuntime);
+    let laddr = SocketAddr::from_str("127.0.0.1:0")?;
+
+    let conn = listen_udp_in_port_range(&vnet, &runtime, 0, 0, laddr).await?;
+    let port = conn.local_addr().await?.port();
+
+    let result = listen_udp_in_port_range(&vnet, &runtime, port, port, laddr).await;
+    assert_eq!(result.err(), Some(Error::ErrPortRangeExhausted));
+
+    Ok(())
+}
+
+#[test]
+fn test_error_from_anyhow() -> Result<()> {
+    let err: anyhow::Error = stun::error::Error::ErrAttributeNotFound.into();
+    assert_eq!(
+        Error::from(err),
+        Error::Stun(stun::error::Error::ErrAttributeNotFound)
+    );
+
+    let err: anyhow::Error = Error::ErrClosed.into();
+    assert_eq!(Error::from(err), Error::ErrClosed);
+
+    let err: anyhow::Error = std::io::Error::from(std::io::ErrorKind::AddrInUse).into();
+    match Error::from(err) {
+        Error::Io(IoError(err)) => assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse),
+        err => panic!("unexpected error {}", err),
+    }
+
+    let allocate_err = || turn::error::Error::new("Allocate error response".to_owned()).into();
+    let err = Error::from_turn_allocate(allocate_err(), Some((401, "Unauthorized".to_owned())));
+    assert!(matches!(err, Error::ErrTurnUnauthorized(401, _)));
+    assert_eq!(
+        err.server_error_code(),
+        Some((401, "Unauthorized".to_owned()))
+    );
+
+    let err = Error::from_turn_allocate(
+        allocate_err(),
+        Some((486, "Allocation Quota Reached".to_owned())),
+    );
+    assert_eq!(
+        err,
+        Error::ErrServerErrorResponse(486, "Allocation Quota Reached".to_owned())
+    );
+
+    let err = Error::from_turn_allocate(Error::ErrTimeout.into(), None);
+    assert_eq!(err, Error::ErrTimeout);
+    assert_eq!(err.server_error_code(), None);
+
+    Ok(())
+}
+
+#[test]
+fn test_error_chain_message() {
+    // Errors of the underlying crates are their source, whose message isn't repeated
+    let err = stun::error::Error::ErrAttributeNotFound;
+    let message = err.to_string();
+    assert_eq!(
+        format!("{:#}", anyhow::Error::from(Error::Stun(err))),
+        message
+    );
+
+    let err = std::io::Error::from(std::io::ErrorKind::AddrInUse);
+    let message = err.to_string();
+    assert_eq!(
+        format!("{:#}", anyhow::Error::from(Error::from(err))),
+        message
+    );
+}
+
+#[test]
+fn test_xormapped_addr_error_response() -> Result<()> {
+    let mut resp = Message::new();
+    resp.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_BINDING, CLASS_ERROR_RESPONSE)),
+        Box::new(ErrorCodeAttribute {
+            code: stun::error_code::CODE_ALLOC_QUOTA_REACHED,
+            reason: b"Allocation Quota Reached".to_vec(),
+        }),
+    ])?;
+    resp.decode()?;
+
+    assert_eq!(
+        xormapped_addr(&resp).err(),
+        Some(Error::ErrServerErrorResponse(
+            486,
+            "Allocation Quota Reached".to_owned()
+        ))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_stun_retransmission_timeout() {
+    let retransmission = StunRetransmission {
+        rto: Duration::from_millis(500),
+        max_requests: 7,
+        last_timeout_factor: 16,
+    };
+    let timeouts: Vec<u64> = (1..=7)
+        .map(|n| retransmission.timeout(n).as_millis() as u64)
+        .collect();
+    // RFC 8489: requests at 0, 500, 1500, 3500, 7500, 15500 and 31500 ms, giving up at 39500 ms
+    assert_eq!(timeouts, vec![500, 1000, 2000, 4000, 8000, 16000, 8000]);
+    assert_eq!(timeouts.iter().sum::<u64>(), 39500);
+}
+
+#[tokio::test]
+async fn test_stun_request_retransmits() -> Result<()> {
+    let mapped_addr = SocketAddr::from_str("203.0.113.1:5000")?;
+    let (server_addr, requests) = stun_server(mapped_addr, 2).await?;
+
+    let runtime: Arc<dyn Runtime> = Arc::new(TokioRuntime);
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let retransmission = StunRetransmission {
+        rto: Duration::from_millis(20),
+        max_requests: 7,
+        last_timeout_factor: 16,
+    };
+    let addr = get_xormapped_addr(&runtime, &conn, server_addr, &retransmission).await?;
+    assert_eq!(SocketAddr::new(addr.ip, addr.port), mapped_addr);
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stun_request_timeout() -> Result<()> {
+    let mapped_addr = SocketAddr::from_str("203.0.113.1:5000")?;
+    let (server_addr, requests) = stun_server(mapped_addr, usize::MAX).await?;
+
+    let runtime: Arc<dyn Runtime> = Arc::new(TokioRuntime);
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let retransmission = StunRetransmission {
+        rto: Duration::from_millis(10),
+        max_requests: 3,
+        last_timeout_factor: 2,
+    };
+    let result = stun_request(&runtime, &conn, server_addr, &retransmission).await;
+    assert_eq!(result.err(), Some(Error::ErrTimeout));
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+    Ok(())
+}